@@ -25,6 +25,9 @@ in lib.mkIf cfg.enable {
       if [[ ! -e /var/lib/keyframe/streams.db ]]; then
         install -m 600 /dev/null /var/lib/keyframe/streams.db
         ${pkgs.sqlite}/bin/sqlite3 /var/lib/keyframe/streams.db < ${./cms/streamredirect/schema.sql}
+      elif ! ${pkgs.sqlite}/bin/sqlite3 /var/lib/keyframe/streams.db "SELECT hls_url FROM stream_redirects LIMIT 0" 2>/dev/null; then
+        # Databases from before audio-only streams had their HLS playlists redirected
+        ${pkgs.sqlite}/bin/sqlite3 /var/lib/keyframe/streams.db "ALTER TABLE stream_redirects ADD COLUMN hls_url TEXT"
       fi
     '';
     serviceConfig = {
@@ -5,6 +5,7 @@ import (
 	"encoding/json"
 	"net/http"
 	"net/url"
+	"strings"
 
 	_ "github.com/mattn/go-sqlite3"
 	"go.uber.org/zap"
@@ -61,7 +62,7 @@ func main() {
 }
 
 func (r *Redirector) LoadDatabase() error {
-	rows, err := r.Database.Query("SELECT streams.mpd_url, stream_redirects.mpd_url FROM streams INNER JOIN stream_redirects ON stream_id = id")
+	rows, err := r.Database.Query("SELECT streams.mpd_url, stream_redirects.mpd_url, stream_redirects.hls_url FROM streams INNER JOIN stream_redirects ON stream_id = id")
 	if err != nil {
 		return err
 	}
@@ -70,11 +71,15 @@ func (r *Redirector) LoadDatabase() error {
 	for rows.Next() {
 		var mpd_url string
 		var ingestd_mpd_url string
-		err := rows.Scan(&mpd_url, &ingestd_mpd_url)
+		var ingestd_hls_url sql.NullString
+		err := rows.Scan(&mpd_url, &ingestd_mpd_url, &ingestd_hls_url)
 		if err != nil {
 			return err
 		}
 		r.Redirects[mpd_url] = ingestd_mpd_url
+		if hls_url, ok := hlsUrl(mpd_url); ok && ingestd_hls_url.Valid {
+			r.Redirects[hls_url] = ingestd_hls_url.String
+		}
 	}
 	err = rows.Err()
 	if err != nil {
@@ -103,10 +108,21 @@ func (r *Redirector) RedirectHandler() http.Handler {
 	})
 }
 
+// hlsUrl is where the HLS playlist of the stream at mpdUrl is redirected from, for audio-only streams
+func hlsUrl(mpdUrl string) (string, bool) {
+	if !strings.HasSuffix(mpdUrl, ".mpd") {
+		return "", false
+	}
+	return strings.TrimSuffix(mpdUrl, ".mpd") + ".m3u8", true
+}
+
 type NotifyBody struct {
-	Token  string `json:"token"`
-	Online bool   `json:"online"`
-	MpdUrl string `json:"mpd_url"`
+	Event     string `json:"event"`
+	Token     string `json:"token"`
+	Online    bool   `json:"online"`
+	MpdUrl    string `json:"mpd_url"`
+	HlsUrl    string `json:"hls_url"`
+	AudioOnly bool   `json:"audio_only"`
 }
 
 func (r *Redirector) IngestdNotifyHandler() http.Handler {
@@ -120,6 +136,12 @@ func (r *Redirector) IngestdNotifyHandler() http.Handler {
 			return
 		}
 
+		// Only going online and offline change where the stream redirects to
+		if body.Event != "" && body.Event != "online" && body.Event != "offline" {
+			rw.WriteHeader(http.StatusNoContent)
+			return
+		}
+
 		tx, err := r.Database.Begin()
 		if err != nil {
 			http.Error(rw, http.StatusText(http.StatusInternalServerError), http.StatusInternalServerError)
@@ -151,11 +173,15 @@ func (r *Redirector) IngestdNotifyHandler() http.Handler {
 			return
 		}
 
+		// Only audio-only streams have an HLS playlist
+		ingestdHlsUrl := sql.NullString{String: body.HlsUrl, Valid: body.AudioOnly && body.HlsUrl != ""}
+
 		if body.Online && !currentIngestdTokenID.Valid {
 			_, err = tx.Exec(
-				"INSERT INTO stream_redirects (stream_id, mpd_url, ingestd_token) VALUES (?, ?, ?)",
+				"INSERT INTO stream_redirects (stream_id, mpd_url, hls_url, ingestd_token) VALUES (?, ?, ?, ?)",
 				streamID,
 				body.MpdUrl,
+				ingestdHlsUrl,
 				ingestdTokenID,
 			)
 			if err != nil {
@@ -164,8 +190,9 @@ func (r *Redirector) IngestdNotifyHandler() http.Handler {
 			}
 		} else if body.Online {
 			_, err = tx.Exec(
-				"UPDATE stream_redirects SET mpd_url=?, ingestd_token=? WHERE stream_id=?",
+				"UPDATE stream_redirects SET mpd_url=?, hls_url=?, ingestd_token=? WHERE stream_id=?",
 				body.MpdUrl,
+				ingestdHlsUrl,
 				ingestdTokenID,
 				streamID,
 			)
@@ -183,11 +210,19 @@ func (r *Redirector) IngestdNotifyHandler() http.Handler {
 			return
 		}
 
+		streamHlsUrl, hasHlsUrl := hlsUrl(mpdUrl)
 		if body.Online {
 			r.Redirects[mpdUrl] = body.MpdUrl
 		} else {
 			delete(r.Redirects, mpdUrl)
 		}
+		if hasHlsUrl {
+			if body.Online && ingestdHlsUrl.Valid {
+				r.Redirects[streamHlsUrl] = ingestdHlsUrl.String
+			} else {
+				delete(r.Redirects, streamHlsUrl)
+			}
+		}
 
 		rw.WriteHeader(http.StatusNoContent)
 	})
@@ -6,6 +6,8 @@ CREATE TABLE streams (
 CREATE TABLE stream_redirects (
 	stream_id INTEGER PRIMARY KEY NOT NULL REFERENCES streams ON DELETE CASCADE,
 	mpd_url TEXT NOT NULL,
+	-- Only set for audio-only streams
+	hls_url TEXT,
 	ingestd_token INTEGER NOT NULL REFERENCES ingestd_tokens ON DELETE CASCADE
 );
 
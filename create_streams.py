@@ -82,9 +82,7 @@ for row in keyframe_streams_db.execute('SELECT id, mpd_url FROM streams'):
 keyframe_streams_db.commit()
 ingestd_streams_db.commit()
 
-# Reload ingestd-srt
-if subprocess.run(['systemctl', 'is-active', 'ingestd-srt', '--quiet'], stdin=subprocess.DEVNULL).returncode == 0:
-    subprocess.run(['systemctl', 'reload', 'ingestd-srt'], stdin=subprocess.DEVNULL)
+# ingestd-srt notices the committed streams by itself
 
 xmpp_admin_password = pathlib.Path('/var/lib/keyframe/stream-muc-manager/xmpp-password').read_text().strip()
 
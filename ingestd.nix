@@ -8,9 +8,19 @@ let
     stream-logs = "/var/log/ingestd";
     httpd-url = "http://127.0.0.1:9000";
     external-url = "https://ingestd.${cfg.domain}";
+    metrics-listen = "127.0.0.1:9001";
     database = {
       url = "sqlite:/var/lib/ingestd/streams.db";
     };
+    cgroup = {
+      path = "/sys/fs/cgroup/system.slice/ingestd-srt.service";
+      cpu-weight = 100;
+      memory-max = 1073741824;
+    };
+    # Sockets come from the ingestd-srt socket unit, by FileDescriptorName
+    listener = [
+      { name = "public"; }
+    ];
   };
   ingestd-srtConfigFile = pkgs.writeText "ingestd-srt.json" (builtins.toJSON ingestd-srtConfig);
   ingestd-httpdConfig = {
@@ -34,7 +44,6 @@ in lib.mkIf cfg.enable {
     preStart = ''
       if [[ ! -e /var/lib/ingestd/streams.db ]]; then
         install -m 600 /dev/null /var/lib/ingestd/streams.db
-        ${pkgs.sqlite}/bin/sqlite3 /var/lib/ingestd/streams.db < ${./ingestd/ingestd-srt/schema.sql}
       fi
 
       if [[ ! -e /var/lib/ingestd/ingestd-srt.toml ]]; then
@@ -43,6 +52,9 @@ in lib.mkIf cfg.enable {
         secret=$(${pkgs.remarshal}/bin/toml2json /var/lib/ingestd/ingestd-srt.toml --unwrap secret)
       fi
       ${pkgs.jq}/bin/jq --argjson secret "$secret" '. + {secret: $secret}' ${ingestd-srtConfigFile} | ${pkgs.remarshal}/bin/json2toml -o /var/lib/ingestd/ingestd-srt.toml
+
+      # Before create-streams can write to the database
+      ${ingestd}/bin/ingestd-srt migrate /var/lib/ingestd/ingestd-srt.toml
     '';
     path = [ gpac ];
     serviceConfig = {
@@ -50,9 +62,9 @@ in lib.mkIf cfg.enable {
       LogsDirectory = "ingestd";
       ExecStart = "${ingestd}/bin/ingestd-srt /var/lib/ingestd/ingestd-srt.toml";
       User = "ingestd";
-      StandardInput = "socket";
       StandardOutput = "journal";
       ExecReload = "${pkgs.coreutils}/bin/kill -USR1 $MAINPID";
+      Delegate = "cpu memory";
     };
   };
 
@@ -60,7 +72,9 @@ in lib.mkIf cfg.enable {
     description = "Ingestd SRT server";
     wantedBy = [ "sockets.target" ];
     socketConfig = {
-      ListenDatagram = "0.0.0.0:3800";
+      ListenDatagram = "[::]:3800";
+      BindIPv6Only = "both";
+      FileDescriptorName = "public";
     };
   };
 
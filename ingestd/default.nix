@@ -4,7 +4,7 @@ rustPlatform.buildRustPackage rec {
   pname = "ingestd";
   version = "0.1";
   src = ./.;
-  cargoSha256 = "08fmqa6h98sr2ckyqm9j63slj00jir7a5wmwsi2chglyzdxxygpd";
+  cargoSha256 = "0p4680nw274xd0306ns23rvj2453m7wj33n3jyk30dxrl0adcdam";
   verifyCargoDeps = true;
   cargoBuildFlags = [ "-p" "ingestd-httpd" "-p" "ingestd-srt" ];
   nativeBuildInputs = [ pkgconfig llvmPackages.clang ];
@@ -13,7 +13,7 @@ rustPlatform.buildRustPackage rec {
   RUSTC_BOOTSTRAP = 1;
   preBuild = ''
     database_path=$(mktemp -d)/streams.db
-    ${sqlite}/bin/sqlite3 $database_path < ${./.}/ingestd-srt/schema.sql
+    cat ${./.}/ingestd-srt/migrations/sqlite/*.sql | ${sqlite}/bin/sqlite3 $database_path
     export DATABASE_URL=sqlite:$database_path
   '';
   doCheck = false;
@@ -250,8 +250,13 @@ fn write_200(request: Request, rw: ResponseWriter<UnixStream>, body: Body) -> im
     let path = request.url().path();
     if path.ends_with(".mpd") {
         response.set_content_type("application/dash+xml".parse().unwrap());
+    } else if path.ends_with(".m3u8") {
+        response.set_content_type("application/vnd.apple.mpegurl".parse().unwrap());
     } else if path.ends_with(".mp4") {
         response.set_content_type("video/mp4".parse().unwrap());
+    } else if path.ends_with(".m4s") || path.ends_with(".m4a") {
+        // Audio-only streams' segments
+        response.set_content_type("audio/mp4".parse().unwrap());
     } else if path.ends_with(".html") {
         response.set_content_type("text/html".parse().unwrap());
     }
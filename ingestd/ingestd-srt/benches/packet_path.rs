@@ -0,0 +1,264 @@
+//! Simulates concurrent 8 Mbit/s streams going through the packet path to gpac, and reports how many allocations and
+//! read/write syscalls that takes per second of streaming, compared to allocating and writing each message separately.
+//! The messages are first made up on the spot, to measure the path on its own, and then received over loopback SRT
+//! connections, as they are in a session.
+//!
+//! Run with `cargo bench --bench packet_path`.
+
+#[path = "../src/pool.rs"]
+#[allow(dead_code)]
+mod pool;
+
+use libsrt::options::RcvSyn;
+use libsrt::{Listener, Socket};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use pool::{Batch, BufferPool, MESSAGE_SIZE};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const STREAMS: usize = 12;
+const BITRATE: usize = 8_000_000;
+/// 7 TS packets, the usual SRT payload
+const MESSAGE_LEN: usize = 1316;
+/// How often the reactor wakes a session up
+const WAKEUPS_PER_SECOND: usize = 100;
+const SECONDS: usize = 10;
+
+struct Counts {
+    allocations: u64,
+    syscalls: u64,
+    elapsed: f64,
+}
+
+/// Read and write syscalls made by the calling thread so far, as counted by the kernel. Receiving from libsrt's
+/// buffers doesn't make any, its own threads do the UDP reads.
+fn thread_syscalls() -> u64 {
+    let io = std::fs::read_to_string("/proc/thread-self/io").unwrap();
+    io.lines()
+        .filter(|line| line.starts_with("syscr:") || line.starts_with("syscw:"))
+        .map(|line| line[6..].trim().parse::<u64>().unwrap())
+        .sum()
+}
+
+/// Counts what `run` takes on this thread
+fn count(run: impl FnOnce()) -> Counts {
+    let syscalls = thread_syscalls();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    run();
+    let elapsed = start.elapsed().as_secs_f64();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    Counts {
+        allocations,
+        syscalls: thread_syscalls() - syscalls,
+        elapsed,
+    }
+}
+
+/// Counts what `run` takes on this thread, less what counting an empty run takes, as reading /proc makes syscalls
+/// and allocates too
+fn measure(run: impl FnOnce()) -> Counts {
+    let overhead = count(|| {});
+    let counts = count(run);
+    Counts {
+        allocations: counts.allocations.saturating_sub(overhead.allocations),
+        syscalls: counts.syscalls.saturating_sub(overhead.syscalls),
+        elapsed: counts.elapsed,
+    }
+}
+
+/// Drains the gpac end of each session, so the writers never block
+fn spawn_readers() -> Vec<UnixStream> {
+    (0..STREAMS).map(|_| {
+        let (sender, mut receiver) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = vec![0; 1 << 16];
+            while receiver.read(&mut buffer).map(|len| len > 0).unwrap_or(false) {}
+        });
+        sender
+    }).collect()
+}
+
+fn messages_per_wakeup() -> usize {
+    BITRATE / 8 / MESSAGE_LEN / WAKEUPS_PER_SECOND
+}
+
+fn message(buffer: &mut [u8]) -> usize {
+    buffer[..MESSAGE_LEN].iter_mut().for_each(|b| *b = 0x47);
+    MESSAGE_LEN
+}
+
+fn write_batch(batch: &mut Batch<'_>, sender: &UnixStream) {
+    while !batch.is_written() {
+        batch.write_to(sender).unwrap();
+    }
+    batch.clear();
+}
+
+fn run_unpooled(senders: &mut [UnixStream]) -> Counts {
+    measure(|| {
+        for _ in 0..SECONDS * WAKEUPS_PER_SECOND {
+            for sender in senders.iter_mut() {
+                for _ in 0..messages_per_wakeup() {
+                    let mut buffer = vec![0; MESSAGE_SIZE];
+                    let len = message(&mut buffer);
+                    sender.write_all(&buffer[..len]).unwrap();
+                }
+            }
+        }
+    })
+}
+
+fn run_pooled(senders: &mut [UnixStream]) -> Counts {
+    let pool = BufferPool::new();
+    let mut batches = senders.iter().map(|_| pool.batch()).collect::<Vec<_>>();
+    measure(|| {
+        for _ in 0..SECONDS * WAKEUPS_PER_SECOND {
+            for (sender, batch) in senders.iter().zip(batches.iter_mut()) {
+                for _ in 0..messages_per_wakeup() {
+                    let len = message(batch.next_slot());
+                    batch.commit(len);
+                }
+                write_batch(batch, sender);
+            }
+        }
+    })
+}
+
+/// SRT connections over loopback, as (caller, accepted) pairs
+fn connect_srt() -> Vec<(Socket, Socket)> {
+    let udp_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = udp_socket.local_addr().unwrap();
+    let listener = Listener::new().unwrap();
+    listener.bind_udp(udp_socket).unwrap();
+    listener.listen(STREAMS as i32).unwrap();
+    (0..STREAMS).map(|_| {
+        let caller = Socket::new().unwrap();
+        caller.connect(addr).unwrap();
+        let (accepted, _peer_addr) = listener.accept().unwrap();
+        accepted.set::<RcvSyn>(false).unwrap();
+        (caller, accepted)
+    }).collect()
+}
+
+/// Sends each caller's share of messages in real time, like a contributor would, and then hands it back, so that it
+/// isn't disconnected before the run has received everything
+fn spawn_srt_senders(callers: Vec<Socket>) -> Vec<std::thread::JoinHandle<Socket>> {
+    callers.into_iter().map(|caller| {
+        std::thread::spawn(move || {
+            let mut buffer = vec![0; MESSAGE_SIZE];
+            let len = message(&mut buffer);
+            let start = Instant::now();
+            for wakeup in 0..SECONDS * WAKEUPS_PER_SECOND {
+                for _ in 0..messages_per_wakeup() {
+                    caller.send_msg(&buffer[..len]).unwrap();
+                }
+                let next = start + Duration::from_secs(1) * (wakeup + 1) as u32 / WAKEUPS_PER_SECOND as u32;
+                std::thread::sleep(next.saturating_duration_since(Instant::now()));
+            }
+            caller
+        })
+    }).collect()
+}
+
+/// Wakes up as often as the reactor would for the length of the run, calling `receive` for each connection
+fn run_srt(senders: &mut [UnixStream], mut receive: impl FnMut(usize, &Socket, &mut UnixStream)) -> Counts {
+    let (callers, accepted): (Vec<_>, Vec<_>) = connect_srt().into_iter().unzip();
+    let sender_threads = spawn_srt_senders(callers);
+    let counts = measure(|| {
+        let start = Instant::now();
+        for wakeup in 0..SECONDS * WAKEUPS_PER_SECOND {
+            let next = start + Duration::from_secs(1) * (wakeup + 1) as u32 / WAKEUPS_PER_SECOND as u32;
+            std::thread::sleep(next.saturating_duration_since(Instant::now()));
+            for (index, (socket, sender)) in accepted.iter().zip(senders.iter_mut()).enumerate() {
+                receive(index, socket, sender);
+            }
+        }
+    });
+    for thread in sender_threads {
+        thread.join().unwrap();
+    }
+    counts
+}
+
+fn run_srt_unpooled(senders: &mut [UnixStream]) -> Counts {
+    run_srt(senders, |_, socket, sender| {
+        loop {
+            let mut buffer = vec![0; MESSAGE_SIZE];
+            match socket.recv_msg(&mut buffer) {
+                Ok(len) => sender.write_all(&buffer[..len]).unwrap(),
+                Err(e) if e.is_would_block() => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    })
+}
+
+fn run_srt_pooled(senders: &mut [UnixStream]) -> Counts {
+    let pool = BufferPool::new();
+    let mut batches = senders.iter().map(|_| pool.batch()).collect::<Vec<_>>();
+    run_srt(senders, |index, socket, sender| {
+        let batch = &mut batches[index];
+        loop {
+            match socket.recv_msg(batch.next_slot()) {
+                Ok(len) => batch.commit(len),
+                Err(e) if e.is_would_block() => break,
+                Err(e) => panic!("{}", e),
+            }
+            if batch.is_full() {
+                write_batch(batch, sender);
+            }
+        }
+        write_batch(batch, sender);
+    })
+}
+
+fn report(name: &str, counts: &Counts) {
+    println!(
+        "{:>14}: {:>8.0} allocations/s, {:>8.0} syscalls/s ({:.3}s to push {}s of {} streams)",
+        name,
+        counts.allocations as f64 / SECONDS as f64,
+        counts.syscalls as f64 / SECONDS as f64,
+        counts.elapsed,
+        SECONDS,
+        STREAMS,
+    );
+}
+
+fn main() {
+    println!("{} streams at {} bit/s, {} messages per wakeup", STREAMS, BITRATE, messages_per_wakeup());
+    let mut senders = spawn_readers();
+    report("unpooled", &run_unpooled(&mut senders));
+    report("pooled", &run_pooled(&mut senders));
+
+    let _srt = libsrt::startup().unwrap();
+    report("srt unpooled", &run_srt_unpooled(&mut senders));
+    report("srt pooled", &run_srt_pooled(&mut senders));
+}
@@ -0,0 +1,39 @@
+CREATE TABLE streams (
+	id SERIAL PRIMARY KEY,
+	active BOOLEAN NOT NULL DEFAULT FALSE,
+	notify_url TEXT NOT NULL,
+	token TEXT NOT NULL,
+	-- SRT options overriding ingestd-srt's [srt] config for this stream, in the same TOML form
+	srt_options TEXT
+);
+
+-- Lets every ingestd-srt reload its streams as soon as they change
+CREATE FUNCTION ingestd_streams_changed() RETURNS trigger AS $$
+BEGIN
+	PERFORM pg_notify('ingestd_streams', '');
+	RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER streams_changed AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON streams
+	FOR EACH STATEMENT EXECUTE PROCEDURE ingestd_streams_changed();
+
+CREATE TABLE sessions (
+	uuid TEXT PRIMARY KEY NOT NULL,
+	stream_id INTEGER NOT NULL REFERENCES streams(id),
+	listener TEXT NOT NULL,
+	peer_addr TEXT,
+	-- Seconds since the Unix epoch
+	started_at BIGINT NOT NULL,
+	ended_at BIGINT NOT NULL,
+	bytes_received BIGINT NOT NULL,
+	packets_received BIGINT NOT NULL,
+	packets_lost BIGINT NOT NULL,
+	packets_retransmitted BIGINT NOT NULL,
+	packets_dropped BIGINT NOT NULL,
+	-- Set if gpac exited, or if it was killed by a signal
+	exit_code INTEGER,
+	exit_signal INTEGER,
+	end_reason TEXT NOT NULL
+);
+CREATE INDEX sessions_stream_id ON sessions (stream_id, started_at);
@@ -0,0 +1,15 @@
+-- 'live' streams are ingested from SRT, 'channel' streams play out their schedule whenever no one is streaming live
+ALTER TABLE streams ADD COLUMN kind TEXT NOT NULL DEFAULT 'live';
+
+CREATE TABLE schedule (
+	id SERIAL PRIMARY KEY,
+	stream_id INTEGER NOT NULL REFERENCES streams(id),
+	-- Seconds since the Unix epoch. Each entry's file is looped until the next entry starts.
+	starts_at BIGINT NOT NULL,
+	-- A local MPEG-TS file, encoded like the stream's live contributions
+	path TEXT NOT NULL
+);
+CREATE INDEX schedule_stream_id ON schedule (stream_id, starts_at);
+
+CREATE TRIGGER schedule_changed AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON schedule
+	FOR EACH STATEMENT EXECUTE PROCEDURE ingestd_streams_changed();
@@ -0,0 +1,3 @@
+-- The audio tracks to package, as a comma-separated list of ISO 639 language codes, with und for tracks that don't
+-- have one. NULL packages every audio track.
+ALTER TABLE streams ADD COLUMN audio_tracks TEXT;
@@ -0,0 +1,2 @@
+-- SRT options overriding ingestd-srt's [srt] config for this stream, in the same TOML form
+ALTER TABLE streams ADD COLUMN srt_options TEXT;
@@ -0,0 +1,19 @@
+CREATE TABLE sessions (
+	uuid TEXT PRIMARY KEY NOT NULL,
+	stream_id INTEGER NOT NULL REFERENCES streams(id),
+	listener TEXT NOT NULL,
+	peer_addr TEXT,
+	-- Seconds since the Unix epoch
+	started_at INTEGER NOT NULL,
+	ended_at INTEGER NOT NULL,
+	bytes_received INTEGER NOT NULL,
+	packets_received INTEGER NOT NULL,
+	packets_lost INTEGER NOT NULL,
+	packets_retransmitted INTEGER NOT NULL,
+	packets_dropped INTEGER NOT NULL,
+	-- Set if gpac exited, or if it was killed by a signal
+	exit_code INTEGER,
+	exit_signal INTEGER,
+	end_reason TEXT NOT NULL
+);
+CREATE INDEX sessions_stream_id ON sessions (stream_id, started_at);
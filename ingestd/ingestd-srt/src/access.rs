@@ -0,0 +1,383 @@
+use serde::Deserialize;
+use smol::Timer;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{self, Counter};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct AccessConfig {
+    /// How many failed handshakes an address may make within `failure-window` seconds before it's banned
+    pub max_failures: usize,
+    pub failure_window: u64,
+    /// Seconds a banned address is rejected for
+    pub ban_duration: u64,
+    /// If not empty, only addresses in these networks may connect
+    pub allow: Vec<Cidr>,
+    /// Addresses in these networks may never connect, even if they're also allowed
+    pub deny: Vec<Cidr>,
+}
+
+impl Default for AccessConfig {
+    fn default() -> AccessConfig {
+        AccessConfig {
+            max_failures: 10,
+            failure_window: 60,
+            ban_duration: 600,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network, e.g. `192.0.2.0/24`, or a single address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+            let full_bytes = prefix_len as usize / 8;
+            let rest_bits = prefix_len % 8;
+            a[..full_bytes] == b[..full_bytes] && (rest_bits == 0 || (a[full_bytes] ^ b[full_bytes]) >> (8 - rest_bits) == 0)
+        }
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(&net.octets(), &ip.octets(), self.prefix_len),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(&net.octets(), &ip.octets(), self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap().parse::<IpAddr>().map_err(|e| format!("{}: {}", s, e))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len.parse::<u8>().ok().filter(|&len| len <= max_prefix_len).ok_or_else(|| format!("{}: invalid prefix length", s))?,
+            None => max_prefix_len,
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Cidr, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Why a caller was turned away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    Denied,
+    NotAllowed,
+    Banned,
+    BadStreamId,
+    UnknownStream,
+    NotOnListener,
+    /// The handshake was accepted by the listen callback, but never completed, most likely due to a wrong passphrase
+    HandshakeIncomplete,
+    Internal,
+}
+
+impl Rejection {
+    /// Whether this counts towards banning the caller
+    fn is_failure(&self) -> bool {
+        match self {
+            Rejection::BadStreamId | Rejection::UnknownStream | Rejection::NotOnListener | Rejection::HandshakeIncomplete => true,
+            Rejection::Denied | Rejection::NotAllowed | Rejection::Banned | Rejection::Internal => false,
+        }
+    }
+
+    fn counter(&self) -> &'static Counter {
+        match self {
+            Rejection::Denied => &metrics::REJECTED_DENIED,
+            Rejection::NotAllowed => &metrics::REJECTED_NOT_ALLOWED,
+            Rejection::Banned => &metrics::REJECTED_BANNED,
+            Rejection::BadStreamId => &metrics::REJECTED_BAD_STREAM_ID,
+            Rejection::UnknownStream => &metrics::REJECTED_UNKNOWN_STREAM,
+            Rejection::NotOnListener => &metrics::REJECTED_NOT_ON_LISTENER,
+            Rejection::HandshakeIncomplete => &metrics::REJECTED_HANDSHAKE_INCOMPLETE,
+            Rejection::Internal => &metrics::REJECTED_INTERNAL,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::Denied => "address is denied",
+            Rejection::NotAllowed => "address is not allowed",
+            Rejection::Banned => "address is banned",
+            Rejection::BadStreamId => "bad stream id",
+            Rejection::UnknownStream => "unknown or inactive stream",
+            Rejection::NotOnListener => "stream not allowed on this listener",
+            Rejection::HandshakeIncomplete => "handshake never completed, wrong passphrase?",
+            Rejection::Internal => "internal error",
+        })
+    }
+}
+
+/// Handshakes not completed within this long are counted as failures
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often handshakes are checked for having timed out, and addresses with nothing left to track are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct PeerState {
+    failures: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+    /// When each handshake the listen callback let through started, by the caller's address and port
+    pending_handshakes: HashMap<SocketAddr, Instant>,
+}
+
+impl PeerState {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.failures.is_empty() && self.pending_handshakes.is_empty() && self.banned_until.map(|until| until <= now).unwrap_or(true)
+    }
+}
+
+/// Decides which addresses may attempt handshakes, banning those that keep failing them
+pub struct AccessControl {
+    config: AccessConfig,
+    peers: Mutex<HashMap<IpAddr, PeerState>>,
+}
+
+impl AccessControl {
+    pub fn new(config: AccessConfig) -> AccessControl {
+        AccessControl {
+            config,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the address is allowed to connect at all, before the stream id is looked at
+    pub fn check(&self, ip: IpAddr) -> Result<(), Rejection> {
+        if self.config.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return Err(Rejection::Denied);
+        }
+        if !self.config.allow.is_empty() && !self.config.allow.iter().any(|cidr| cidr.contains(ip)) {
+            return Err(Rejection::NotAllowed);
+        }
+
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        let peer = match peers.get_mut(&ip) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        self.expire_pending_handshakes(ip, peer, now);
+        match peer.banned_until {
+            Some(until) if until > now => Err(Rejection::Banned),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a rejected handshake, banning the address if it has failed too often
+    pub fn reject(&self, ip: Option<IpAddr>, rejection: Rejection) {
+        log_rejection(ip, rejection);
+        let ip = match ip {
+            Some(ip) if rejection.is_failure() => ip,
+            _ => return,
+        };
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(ip).or_insert_with(PeerState::default);
+        self.add_failure(ip, peer, now);
+    }
+
+    /// Counts handshakes from earlier attempts that never reached accept as failed after the listen callback
+    fn expire_pending_handshakes(&self, ip: IpAddr, peer: &mut PeerState, now: Instant) {
+        let pending = peer.pending_handshakes.len();
+        peer.pending_handshakes.retain(|_, &mut started| now.duration_since(started) <= HANDSHAKE_TIMEOUT);
+        for _ in peer.pending_handshakes.len()..pending {
+            log_rejection(Some(ip), Rejection::HandshakeIncomplete);
+            self.add_failure(ip, peer, now);
+        }
+    }
+
+    /// Forgets failures from before the window, which no longer count towards a ban
+    fn expire_failures(&self, peer: &mut PeerState, now: Instant) {
+        let window = Duration::from_secs(self.config.failure_window);
+        while peer.failures.front().map(|&failed| now.duration_since(failed) > window).unwrap_or(false) {
+            peer.failures.pop_front();
+        }
+    }
+
+    /// Counts every timed out handshake as failed, rather than waiting for its address to try again, and forgets the
+    /// addresses that have nothing left to track
+    fn expire(&self, now: Instant) {
+        let mut peers = self.peers.lock().unwrap();
+        for (&ip, peer) in peers.iter_mut() {
+            self.expire_pending_handshakes(ip, peer, now);
+            self.expire_failures(peer, now);
+        }
+        peers.retain(|_, peer| !peer.is_idle(now));
+    }
+
+    /// Keeps expiring timed out handshakes, so that they are counted and logged when they time out
+    pub async fn expire_handshakes(&self) {
+        loop {
+            Timer::new(SWEEP_INTERVAL).await;
+            self.expire(Instant::now());
+        }
+    }
+
+    fn add_failure(&self, ip: IpAddr, peer: &mut PeerState, now: Instant) {
+        self.expire_failures(peer, now);
+        peer.failures.push_back(now);
+        if peer.failures.len() >= self.config.max_failures {
+            eprintln!("banning {} for {} seconds after {} failed handshakes", ip, self.config.ban_duration, peer.failures.len());
+            metrics::BANS.inc();
+            peer.banned_until = Some(now + Duration::from_secs(self.config.ban_duration));
+            peer.failures.clear();
+        }
+    }
+
+    /// Notes that the listen callback let a handshake through, which now has to complete with the right passphrase.
+    /// A caller repeating its handshake keeps the time it first started.
+    pub fn handshake_pending(&self, peer_addr: SocketAddr) {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(peer_addr.ip()).or_insert_with(PeerState::default).pending_handshakes.entry(peer_addr).or_insert(now);
+    }
+
+    pub fn handshake_completed(&self, peer_addr: SocketAddr) {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(peer) = peers.get_mut(&peer_addr.ip()) {
+            peer.pending_handshakes.remove(&peer_addr);
+            if peer.is_idle(now) {
+                peers.remove(&peer_addr.ip());
+            }
+        }
+    }
+}
+
+fn log_rejection(ip: Option<IpAddr>, rejection: Rejection) {
+    match ip {
+        Some(ip) => eprintln!("rejected {}: {}", ip, rejection),
+        None => eprintln!("rejected unknown address: {}", rejection),
+    }
+    rejection.counter().inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!("192.0.2.0/24".parse(), Ok(Cidr { addr: ip("192.0.2.0"), prefix_len: 24 }));
+        assert_eq!("2001:db8::/32".parse(), Ok(Cidr { addr: ip("2001:db8::"), prefix_len: 32 }));
+        // A single address
+        assert_eq!("192.0.2.1".parse(), Ok(Cidr { addr: ip("192.0.2.1"), prefix_len: 32 }));
+        assert_eq!("::1".parse(), Ok(Cidr { addr: ip("::1"), prefix_len: 128 }));
+        assert_eq!("0.0.0.0/0".parse(), Ok(Cidr { addr: ip("0.0.0.0"), prefix_len: 0 }));
+
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/-1".parse::<Cidr>().is_err());
+        assert!("192.0.2/24".parse::<Cidr>().is_err());
+        assert!("example.com/24".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains_addresses_in_prefix() {
+        let cidr = "192.0.2.0/24".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("192.0.2.0")));
+        assert!(cidr.contains(ip("192.0.2.255")));
+        assert!(!cidr.contains(ip("192.0.3.0")));
+
+        // Prefixes that don't end on a byte boundary
+        let cidr = "198.51.100.64/26".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("198.51.100.64")));
+        assert!(cidr.contains(ip("198.51.100.127")));
+        assert!(!cidr.contains(ip("198.51.100.63")));
+        assert!(!cidr.contains(ip("198.51.100.128")));
+        let cidr = "2001:db8:8000::/33".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr.contains(ip("2001:db8:7fff::1")));
+
+        let cidr = "192.0.2.1".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("192.0.2.1")));
+        assert!(!cidr.contains(ip("192.0.2.2")));
+
+        let cidr = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("203.0.113.7")));
+        // IPv4 networks never contain IPv6 addresses, even mapped ones, and the other way around
+        assert!(!cidr.contains(ip("::ffff:203.0.113.7")));
+        assert!(!"::/0".parse::<Cidr>().unwrap().contains(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn counts_timed_out_handshakes_without_waiting_for_another_attempt() {
+        let access = AccessControl::new(AccessConfig { max_failures: 2, ..AccessConfig::default() });
+        let peer = ip("192.0.2.1");
+        access.handshake_pending(SocketAddr::new(peer, 5000));
+        access.handshake_pending(SocketAddr::new(peer, 5001));
+        let started = Instant::now();
+
+        access.expire(started + HANDSHAKE_TIMEOUT / 2);
+        assert!(access.peers.lock().unwrap()[&peer].banned_until.is_none());
+
+        access.expire(started + HANDSHAKE_TIMEOUT * 2);
+        let peers = access.peers.lock().unwrap();
+        assert!(peers[&peer].pending_handshakes.is_empty());
+        assert!(peers[&peer].banned_until.is_some());
+    }
+
+    #[test]
+    fn completes_the_handshake_of_the_caller_that_was_accepted() {
+        let access = AccessControl::new(AccessConfig { max_failures: 1, ..AccessConfig::default() });
+        let peer = ip("192.0.2.1");
+        let (first, second) = (SocketAddr::new(peer, 5000), SocketAddr::new(peer, 5001));
+        // Two callers behind the same address, the first of which repeats its handshake
+        access.handshake_pending(first);
+        access.handshake_pending(first);
+        access.handshake_pending(second);
+        access.handshake_completed(second);
+        assert_eq!(access.peers.lock().unwrap()[&peer].pending_handshakes.keys().collect::<Vec<_>>(), [&first]);
+
+        access.handshake_completed(first);
+        access.expire(Instant::now() + HANDSHAKE_TIMEOUT * 2);
+        assert!(access.peers.lock().unwrap().is_empty());
+        assert_eq!(access.check(peer), Ok(()));
+    }
+
+    #[test]
+    fn forgets_addresses_once_their_failures_are_outside_the_window() {
+        let access = AccessControl::new(AccessConfig::default());
+        let peer = ip("192.0.2.1");
+        access.reject(Some(peer), Rejection::UnknownStream);
+        let failed = Instant::now();
+
+        access.expire(failed + Duration::from_secs(access.config.failure_window / 2));
+        assert_eq!(access.peers.lock().unwrap()[&peer].failures.len(), 1);
+
+        access.expire(failed + Duration::from_secs(access.config.failure_window * 2));
+        assert!(access.peers.lock().unwrap().is_empty());
+
+        // As are those whose handshakes all completed
+        access.handshake_pending(SocketAddr::new(peer, 5000));
+        access.handshake_completed(SocketAddr::new(peer, 5000));
+        assert!(access.peers.lock().unwrap().is_empty());
+    }
+}
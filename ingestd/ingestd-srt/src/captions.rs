@@ -0,0 +1,291 @@
+use futures::channel::mpsc;
+use std::collections::VecDeque;
+
+use crate::mp4::{full_box, write_box};
+use crate::timed_metadata;
+
+/// Caption changes kept for a track whose video hasn't had a fragment since, beyond which the oldest are dropped
+const MAX_PENDING_CHANGES: usize = 1024;
+
+/// What the caption track's Representation says it needs, in bits per second
+const BANDWIDTH: u32 = 2000;
+
+/// A file of the caption track to be uploaded to httpd, with its body sent as it is written
+pub struct CaptionUpload {
+    pub path: String,
+    pub body: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl CaptionUpload {
+    fn new(path: String) -> (CaptionUpload, mpsc::UnboundedSender<Vec<u8>>) {
+        let (sender, body) = mpsc::unbounded();
+        (CaptionUpload { path, body }, sender)
+    }
+}
+
+/// A WebVTT track for the captions in a packager's video, written alongside the video representation so that its
+/// segments and chunks line up with it. Files are named after the video's, with `_cc` after the representation.
+pub struct CaptionTrack {
+    /// The key of the video representation it follows
+    pub video: String,
+    timescale: u32,
+    /// The video's upload paths up to the segment number
+    path_prefix: String,
+    /// The changes to what is on screen, by transport stream timestamp, that haven't been written yet
+    changes: VecDeque<(u64, String)>,
+    showing: String,
+    /// How far the track has been written, on the video's timeline
+    written_to: Option<u64>,
+    /// The segment being uploaded
+    segment: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    sequence_number: u32,
+}
+
+impl CaptionTrack {
+    /// Starts a track for a video representation from the path of its init segment, returning it with the upload of
+    /// its own init segment
+    pub fn new(video: String, timescale: u32, init_path: &str) -> Option<(CaptionTrack, CaptionUpload)> {
+        let path_prefix = init_path.strip_suffix("_init.mp4")?.to_string();
+        let (upload, body) = CaptionUpload::new(format!("{}_cc_init.mp4", path_prefix));
+        let _ = body.unbounded_send(init_segment(timescale));
+        let track = CaptionTrack {
+            video,
+            timescale,
+            path_prefix,
+            changes: VecDeque::new(),
+            showing: String::new(),
+            written_to: None,
+            segment: None,
+            sequence_number: 0,
+        };
+        Some((track, upload))
+    }
+
+    /// Queues a change to the text on screen, which is empty when nothing is shown
+    pub fn push(&mut self, pts: u64, text: String) {
+        if self.changes.len() == MAX_PENDING_CHANGES {
+            self.changes.pop_front();
+        }
+        self.changes.push_back((pts, text));
+    }
+
+    /// Writes the captions up to a video fragment starting at `decode_time`, which starts the segment numbered
+    /// `segment` if it is the first fragment of its file. Returns the upload of that segment.
+    pub fn fragment(&mut self, decode_time: u64, segment: Option<&str>) -> Option<CaptionUpload> {
+        let start = match self.written_to {
+            Some(written_to) if written_to <= decode_time => written_to,
+            _ => decode_time,
+        };
+        let mut samples = Vec::new();
+        let mut at = start;
+        while let Some((pts, _)) = self.changes.front() {
+            let time = timed_metadata::presentation_time(*pts, self.timescale, decode_time);
+            if time > decode_time {
+                break;
+            }
+            if time > at {
+                samples.push(((time - at) as u32, sample(&self.showing)));
+                at = time;
+            }
+            self.showing = self.changes.pop_front().map(|(_, text)| text).unwrap_or_default();
+        }
+        if decode_time > at {
+            samples.push(((decode_time - at) as u32, sample(&self.showing)));
+        }
+        if let Some(ref sender) = self.segment {
+            if !samples.is_empty() {
+                self.sequence_number += 1;
+                let _ = sender.unbounded_send(chunk(self.sequence_number, start, &samples));
+            }
+        }
+        self.written_to = Some(decode_time);
+
+        let number = segment?;
+        let (upload, body) = CaptionUpload::new(format!("{}_cc_{}.mp4", self.path_prefix, number));
+        // Dropping the last segment's sender ends its upload
+        self.segment = Some(body);
+        Some(upload)
+    }
+
+    /// An AdaptationSet for the track, to go in the Period of the MPD its video is in. It takes its SegmentTemplate from
+    /// the video's AdaptationSet, so that its segments are announced in step with it.
+    pub fn adaptation_set(&self, period: &str) -> Option<String> {
+        let file_prefix = self.path_prefix.rsplit('/').next()?;
+        let mut rest = period;
+        let template = loop {
+            let start = rest.find("<AdaptationSet")?;
+            let end = rest[start..].find("</AdaptationSet>").map(|end| start + end)?;
+            let adaptation_set = &rest[start..end];
+            let head = &adaptation_set[..adaptation_set.find('>')?];
+            if head.contains("contentType=\"video\"") || head.contains("mimeType=\"video/") {
+                break segment_template(adaptation_set)?;
+            }
+            rest = &rest[end..];
+        };
+        let template = set_attribute(&template, "media", &format!("{}_cc_$Number%05d$.mp4", file_prefix))?;
+        let template = set_attribute(&template, "initialization", &format!("{}_cc_init.mp4", file_prefix))?;
+        Some(format!(
+            concat!(
+                r#"<AdaptationSet contentType="text" mimeType="application/mp4" codecs="wvtt" segmentAlignment="true" startWithSAP="1">"#,
+                r#"<Role schemeIdUri="urn:mpeg:dash:role:2011" value="caption"/>{}<Representation id="cc" bandwidth="{}"/></AdaptationSet>"#,
+            ),
+            template,
+            BANDWIDTH,
+        ))
+    }
+}
+
+/// The SegmentTemplate element in an AdaptationSet, with its SegmentTimeline if it has one
+fn segment_template(adaptation_set: &str) -> Option<String> {
+    let start = adaptation_set.find("<SegmentTemplate")?;
+    let rest = &adaptation_set[start..];
+    let head_end = rest.find('>')?;
+    let end = if rest[..head_end].ends_with('/') {
+        head_end + 1
+    } else {
+        rest.find("</SegmentTemplate>")? + "</SegmentTemplate>".len()
+    };
+    Some(rest[..end].to_string())
+}
+
+fn set_attribute(element: &str, name: &str, value: &str) -> Option<String> {
+    let start = element.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let end = start + element[start..].find('"')?;
+    Some([&element[..start], value, &element[end..]].concat())
+}
+
+/// A WebVTT sample showing `text`, or nothing
+fn sample(text: &str) -> Vec<u8> {
+    if text.is_empty() {
+        write_box(b"vtte", &[])
+    } else {
+        write_box(b"vttc", &write_box(b"payl", text.as_bytes()))
+    }
+}
+
+const UNITY_MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+fn matrix() -> Vec<u8> {
+    UNITY_MATRIX.iter().flat_map(|value| value.to_be_bytes().to_vec()).collect()
+}
+
+/// The init segment of a WebVTT track with the video's timescale
+fn init_segment(timescale: u32) -> Vec<u8> {
+    let ftyp = write_box(b"ftyp", b"iso6\0\0\0\0iso6cmfcdash");
+
+    let mut mvhd = vec![0; 8];
+    mvhd.extend_from_slice(&timescale.to_be_bytes());
+    mvhd.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    mvhd.extend(matrix());
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&2u32.to_be_bytes());
+    let mvhd = full_box(b"mvhd", 0, 0, &mvhd);
+
+    // Enabled and in the presentation, as track 1 with no duration, layer or volume
+    let mut tkhd = vec![0; 8];
+    tkhd.extend_from_slice(&1u32.to_be_bytes());
+    tkhd.extend_from_slice(&[0; 24]);
+    tkhd.extend(matrix());
+    tkhd.extend_from_slice(&[0; 8]);
+    let tkhd = full_box(b"tkhd", 0, 3, &tkhd);
+
+    // The language is packed "und"
+    let mut mdhd = vec![0; 8];
+    mdhd.extend_from_slice(&timescale.to_be_bytes());
+    mdhd.extend_from_slice(&[0, 0, 0, 0, 0x55, 0xc4, 0, 0]);
+    let mdhd = full_box(b"mdhd", 0, 0, &mdhd);
+    let hdlr = full_box(b"hdlr", 0, 0, &[&[0; 4][..], b"text", &[0; 12], b"Captions\0"].concat());
+
+    let dinf = write_box(b"dinf", &full_box(b"dref", 0, 0, &[&1u32.to_be_bytes()[..], &full_box(b"url ", 0, 1, &[])].concat()));
+    let wvtt = write_box(b"wvtt", &[&[0, 0, 0, 0, 0, 0, 0, 1][..], &write_box(b"vttC", b"WEBVTT")].concat());
+    let stbl = write_box(b"stbl", &[
+        full_box(b"stsd", 0, 0, &[&1u32.to_be_bytes()[..], &wvtt].concat()),
+        full_box(b"stts", 0, 0, &[0; 4]),
+        full_box(b"stsc", 0, 0, &[0; 4]),
+        full_box(b"stsz", 0, 0, &[0; 8]),
+        full_box(b"stco", 0, 0, &[0; 4]),
+    ].concat());
+    let minf = write_box(b"minf", &[full_box(b"nmhd", 0, 0, &[]), dinf, stbl].concat());
+    let mdia = write_box(b"mdia", &[mdhd, hdlr, minf].concat());
+    let trak = write_box(b"trak", &[tkhd, mdia].concat());
+
+    // Track 1 with the first sample description, and no defaults
+    let trex = full_box(b"trex", 0, 0, &[0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mvex = write_box(b"mvex", &trex);
+    [ftyp, write_box(b"moov", &[mvhd, trak, mvex].concat())].concat()
+}
+
+/// A moof and mdat with WebVTT samples and their durations, starting at `decode_time`
+fn chunk(sequence_number: u32, decode_time: u64, samples: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let moof = |data_offset: u32| {
+        let mfhd = full_box(b"mfhd", 0, 0, &sequence_number.to_be_bytes());
+        // default-base-is-moof
+        let tfhd = full_box(b"tfhd", 0, 0x020000, &1u32.to_be_bytes());
+        let tfdt = full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes());
+        let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+        trun.extend_from_slice(&data_offset.to_be_bytes());
+        for (duration, sample) in samples {
+            trun.extend_from_slice(&duration.to_be_bytes());
+            trun.extend_from_slice(&(sample.len() as u32).to_be_bytes());
+        }
+        // data-offset-present, sample-duration-present and sample-size-present
+        let trun = full_box(b"trun", 0, 0x000301, &trun);
+        write_box(b"moof", &[mfhd, write_box(b"traf", &[tfhd, tfdt, trun].concat())].concat())
+    };
+    // The samples start after the moof and the mdat's header
+    let moof = moof(moof(0).len() as u32 + 8);
+    let mdat = write_box(b"mdat", &samples.iter().flat_map(|(_, sample)| sample.clone()).collect::<Vec<_>>());
+    [moof, mdat].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::mp4;
+
+    #[test]
+    fn writes_captions_in_step_with_video() {
+        let (mut track, init) = CaptionTrack::new("1".to_string(), 90000, "/live/abc_1_init.mp4").unwrap();
+        assert_eq!(init.path, "/live/abc_1_cc_init.mp4");
+        let init = block_on(init.body.collect::<Vec<_>>()).concat();
+        assert!(mp4::find(&init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"]).is_some());
+
+        track.push(90000 * 11, "Hello".to_string());
+        track.push(90000 * 13, String::new());
+        let segment = track.fragment(90000 * 10, Some("00001")).unwrap();
+        assert_eq!(segment.path, "/live/abc_1_cc_00001.mp4");
+        assert!(track.fragment(90000 * 12, None).is_none());
+        assert!(track.fragment(90000 * 14, Some("00002")).is_some());
+        let chunks = block_on(segment.body.collect::<Vec<_>>());
+        assert_eq!(chunks.len(), 2);
+
+        // Nothing, then the caption from 11s to 12s, then the caption until it was cleared at 13s and nothing after
+        let expected: &[&[(u32, &[u8; 4])]] = &[&[(90000, b"vtte"), (90000, b"vttc")], &[(90000, b"vttc"), (90000, b"vtte")]];
+        for (chunk, expected) in chunks.iter().zip(expected) {
+            let boxes = mp4::boxes(chunk).collect::<Vec<_>>();
+            let trun = mp4::find(boxes[0].1, &[b"traf", b"trun"]).unwrap();
+            let data_offset = u32::from_be_bytes([trun[8], trun[9], trun[10], trun[11]]) as usize;
+            assert_eq!(data_offset, boxes[0].1.len() + 16);
+            let samples = mp4::boxes(boxes[1].1).map(|(box_type, _)| box_type).collect::<Vec<_>>();
+            let durations = trun[12..].chunks(8).map(|entry| u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]));
+            assert_eq!(durations.zip(samples.iter()).collect::<Vec<_>>(), *expected);
+        }
+        assert!(mp4::base_media_decode_time(mp4::boxes(&chunks[1]).next().unwrap().1).is_some());
+    }
+
+    #[test]
+    fn copies_the_video_segment_template() {
+        let (track, _) = CaptionTrack::new("1".to_string(), 90000, "/abc_p1_1_init.mp4").unwrap();
+        let period = concat!(
+            r#"<Period id="p1"><AdaptationSet contentType="audio"><SegmentTemplate media="a" initialization="b"/></AdaptationSet>"#,
+            r#"<AdaptationSet mimeType="video/mp4"><SegmentTemplate media="abc_p1_$RepresentationID$_$Number%05d$.mp4" initialization="abc_p1_$RepresentationID$_init.mp4" startNumber="1" duration="2"/>"#,
+            r#"<Representation id="1"/></AdaptationSet></Period>"#,
+        );
+        let adaptation_set = track.adaptation_set(period).unwrap();
+        assert!(adaptation_set.contains(r#"<SegmentTemplate media="abc_p1_1_cc_$Number%05d$.mp4" initialization="abc_p1_1_cc_init.mp4" startNumber="1" duration="2"/>"#));
+        assert!(adaptation_set.starts_with(r#"<AdaptationSet contentType="text""#));
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+
+use crate::cea708;
+use crate::discontinuity;
+
+const ROWS: usize = 15;
+const COLUMNS: usize = 32;
+
+/// cc_type of CEA-608 data for field 1, which carries CC1
+const NTSC_CC_FIELD_1: u8 = 0;
+/// cc_types 2 and 3 carry CEA-708 DTVCC packets, this one the rest of a packet after its start
+const DTVCC_PACKET_DATA: u8 = 2;
+
+/// Frames waiting to be put back into presentation order, beyond which the earliest are decoded anyway
+const MAX_REORDERED_FRAMES: usize = 32;
+
+/// The first row each preamble address code sets, by the low bits of its first byte. The second byte's 0x20 bit picks
+/// the row below, except for the codes that only have one.
+const PAC_ROWS: [usize; 8] = [10, 0, 2, 11, 13, 4, 6, 8];
+
+const SPECIAL: [char; 16] = ['®', '°', '½', '¿', '™', '¢', '£', '♪', 'à', ' ', 'è', 'â', 'ê', 'î', 'ô', 'û'];
+
+const EXTENDED_SPANISH_FRENCH: [char; 32] = [
+    'Á', 'É', 'Ó', 'Ú', 'Ü', 'ü', '‘', '¡', '*', '\'', '—', '©', '℠', '•', '“', '”',
+    'À', 'Â', 'Ç', 'È', 'Ê', 'Ë', 'ë', 'Î', 'Ï', 'ï', 'Ô', 'Ù', 'ù', 'Û', '«', '»',
+];
+
+const EXTENDED_PORTUGUESE_GERMAN: [char; 32] = [
+    'Ã', 'ã', 'Í', 'Ì', 'ì', 'Ò', 'ò', 'Õ', 'õ', '{', '}', '\\', '^', '_', '|', '~',
+    'Ä', 'ä', 'Ö', 'ö', 'ß', '¥', '¤', '│', 'Å', 'å', 'Ø', 'ø', '┌', '┐', '└', '┘',
+];
+
+/// A character from the basic set, which is ASCII apart from a few accented letters and symbols
+fn basic_char(byte: u8) -> char {
+    match byte {
+        0x2a => 'á',
+        0x5c => 'é',
+        0x5e => 'í',
+        0x5f => 'ó',
+        0x60 => 'ú',
+        0x7b => 'ç',
+        0x7c => '÷',
+        0x7d => 'Ñ',
+        0x7e => 'ñ',
+        0x7f => '█',
+        byte => byte as char,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// Captions are built up off screen and shown all at once
+    PopOn,
+    /// Captions scroll up within the given number of rows
+    RollUp(usize),
+    /// Captions are shown as they are written
+    PaintOn,
+}
+
+type Memory = [[Option<char>; COLUMNS]; ROWS];
+
+/// Decodes the CC1 captions carried in a video stream's cc_data, following the text on screen as it changes. Once the
+/// stream is seen to carry CEA-708 captions, their primary service is followed instead, as it is usually sent alongside
+/// CC1 for the decoders that can show it.
+pub struct Decoder {
+    displayed: Memory,
+    non_displayed: Memory,
+    mode: Mode,
+    row: usize,
+    column: usize,
+    /// Whether the pairs being received are for CC1 rather than CC2, which is switched by their control codes
+    cc1: bool,
+    /// Control codes are sent twice in a row in case one is lost, so the repeat is ignored
+    last_control: Option<(u8, u8)>,
+    /// What the last change said was on screen
+    showing: String,
+    /// cc_data by presentation time, as frames arrive in decode order
+    reorder: BTreeMap<u64, Vec<u8>>,
+    /// The text on screen from each presentation time, since they were last taken
+    changes: Vec<(u64, String)>,
+    cea708: cea708::Service,
+    /// Whether `take_cea708` has said that CEA-708 captions are being followed
+    cea708_taken: bool,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder {
+            displayed: [[None; COLUMNS]; ROWS],
+            non_displayed: [[None; COLUMNS]; ROWS],
+            mode: Mode::PopOn,
+            row: ROWS - 1,
+            column: 0,
+            cc1: true,
+            last_control: None,
+            showing: String::new(),
+            reorder: BTreeMap::new(),
+            changes: Vec::new(),
+            cea708: cea708::Service::new(),
+            cea708_taken: false,
+        }
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Takes a video frame's cc_data. Frames presented no later than this one is decoded have all arrived, so their
+    /// captions are decoded.
+    pub fn frame(&mut self, pts: u64, dts: u64, cc_data: &[u8]) {
+        if !cc_data.is_empty() {
+            self.reorder.entry(pts).or_default().extend_from_slice(cc_data);
+        }
+        while let Some(&next) = self.reorder.keys().next() {
+            if discontinuity::difference(next, dts) > 0 && self.reorder.len() <= MAX_REORDERED_FRAMES {
+                break;
+            }
+            let cc_data = self.reorder.remove(&next).unwrap_or_default();
+            // Only cc_valid triplets are decoded
+            for triplet in cc_data.chunks_exact(3).filter(|triplet| triplet[0] & 0x04 != 0) {
+                match triplet[0] & 0x03 {
+                    NTSC_CC_FIELD_1 => self.pair(next, triplet[1] & 0x7f, triplet[2] & 0x7f),
+                    cc_type @ DTVCC_PACKET_DATA..=cea708::DTVCC_PACKET_START => self.dtvcc(next, cc_type, triplet[1], triplet[2]),
+                    _ => {},
+                }
+            }
+        }
+    }
+
+    /// The text on screen from each presentation time, since this was last called. Rows are separated by newlines, and
+    /// an empty string means nothing is shown.
+    pub fn take_changes(&mut self) -> Vec<(u64, String)> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Whether the stream has been seen to carry CEA-708 captions, which are followed instead of CC1 from then on, the
+    /// first time it is called after they are
+    pub fn take_cea708(&mut self) -> bool {
+        let taken = self.cea708_taken;
+        self.cea708_taken = self.cea708.seen();
+        self.cea708.seen() && !taken
+    }
+
+    /// Decodes a pair of bytes, with their parity bits removed
+    fn pair(&mut self, pts: u64, b1: u8, b2: u8) {
+        match b1 {
+            0x00 => return,
+            0x10..=0x1f => {
+                if self.last_control == Some((b1, b2)) {
+                    self.last_control = None;
+                    return;
+                }
+                self.last_control = Some((b1, b2));
+                self.cc1 = b1 & 0x08 == 0;
+                if self.cc1 {
+                    self.control(b1 & !0x08, b2);
+                }
+            },
+            _ => {
+                self.last_control = None;
+                if self.cc1 {
+                    self.write(basic_char(b1));
+                    if b2 >= 0x20 {
+                        self.write(basic_char(b2));
+                    }
+                }
+            },
+        }
+        if !self.cea708.seen() {
+            let text = self.text();
+            self.show(pts, text);
+        }
+    }
+
+    /// Decodes a pair of DTVCC packet bytes
+    fn dtvcc(&mut self, pts: u64, cc_type: u8, b1: u8, b2: u8) {
+        if self.cea708.pair(cc_type, b1, b2) {
+            let text = self.cea708.text();
+            self.show(pts, text);
+        }
+    }
+
+    /// Records what is on screen from `pts` on, if that has changed
+    fn show(&mut self, pts: u64, text: String) {
+        if text != self.showing {
+            self.showing = text.clone();
+            // Only the last of the changes a frame makes is seen
+            match self.changes.last_mut() {
+                Some(last) if last.0 == pts => last.1 = text,
+                _ => self.changes.push((pts, text)),
+            }
+        }
+    }
+
+    /// Acts on a control code, with its channel bit cleared
+    fn control(&mut self, b1: u8, b2: u8) {
+        match (b1, b2) {
+            (0x14, 0x20..=0x2f) => self.command(b2),
+            // Tab offsets
+            (0x17, 0x21..=0x23) => self.column = (self.column + (b2 - 0x20) as usize).min(COLUMNS - 1),
+            // Mid-row codes change the style, and take up a space
+            (0x11, 0x20..=0x2f) => self.write(' '),
+            (0x11, 0x30..=0x3f) => self.write(SPECIAL[(b2 - 0x30) as usize]),
+            // Extended characters replace the basic one sent before them for decoders that don't have them
+            (0x12, 0x20..=0x3f) | (0x13, 0x20..=0x3f) => {
+                self.column = self.column.saturating_sub(1);
+                let table = if b1 == 0x12 { &EXTENDED_SPANISH_FRENCH } else { &EXTENDED_PORTUGUESE_GERMAN };
+                self.write(table[(b2 - 0x20) as usize]);
+            },
+            (0x10..=0x17, 0x40..=0x7f) => self.preamble(b1, b2),
+            _ => {},
+        }
+    }
+
+    /// Moves the cursor with a preamble address code
+    fn preamble(&mut self, b1: u8, b2: u8) {
+        let first = (b1 & 0x07) as usize;
+        let row = PAC_ROWS[first] + if first != 0 && b2 & 0x20 != 0 { 1 } else { 0 };
+        if let Mode::RollUp(rows) = self.mode {
+            // The rows being rolled up move with their base row
+            if row != self.row {
+                let mut moved = [[None; COLUMNS]; ROWS];
+                for offset in 0..rows.min(row + 1).min(self.row + 1) {
+                    moved[row - offset] = self.displayed[self.row - offset];
+                }
+                self.displayed = moved;
+            }
+        }
+        self.row = row;
+        // Indents are in steps of four columns
+        self.column = if b2 & 0x10 != 0 { ((b2 & 0x0e) >> 1) as usize * 4 } else { 0 };
+    }
+
+    fn command(&mut self, b2: u8) {
+        match b2 {
+            // Resume caption loading
+            0x20 => self.mode = Mode::PopOn,
+            // Backspace
+            0x21 => {
+                self.column = self.column.saturating_sub(1);
+                let column = self.column;
+                self.memory()[column] = None;
+            },
+            // Delete to end of row
+            0x24 => {
+                let column = self.column;
+                for cell in &mut self.memory()[column..] {
+                    *cell = None;
+                }
+            },
+            // Roll-up captions, 2 to 4 rows
+            0x25..=0x27 => {
+                if !matches!(self.mode, Mode::RollUp(_)) {
+                    self.displayed = [[None; COLUMNS]; ROWS];
+                    self.non_displayed = [[None; COLUMNS]; ROWS];
+                    self.row = ROWS - 1;
+                    self.column = 0;
+                }
+                self.mode = Mode::RollUp((b2 - 0x23) as usize);
+            },
+            // Resume direct captioning
+            0x29 => self.mode = Mode::PaintOn,
+            // Erase displayed memory
+            0x2c => self.displayed = [[None; COLUMNS]; ROWS],
+            // Carriage return
+            0x2d => {
+                if let Mode::RollUp(rows) = self.mode {
+                    let top = (self.row + 1).saturating_sub(rows);
+                    for row in 0..self.row {
+                        self.displayed[row] = if row >= top { self.displayed[row + 1] } else { [None; COLUMNS] };
+                    }
+                    self.displayed[self.row] = [None; COLUMNS];
+                }
+                self.column = 0;
+            },
+            // Erase non-displayed memory
+            0x2e => self.non_displayed = [[None; COLUMNS]; ROWS],
+            // End of caption, which flips the memories
+            0x2f => {
+                std::mem::swap(&mut self.displayed, &mut self.non_displayed);
+                self.mode = Mode::PopOn;
+            },
+            _ => {},
+        }
+    }
+
+    /// The row being written to, which is off screen for pop-on captions
+    fn memory(&mut self) -> &mut [Option<char>; COLUMNS] {
+        match self.mode {
+            Mode::PopOn => &mut self.non_displayed[self.row],
+            _ => &mut self.displayed[self.row],
+        }
+    }
+
+    fn write(&mut self, c: char) {
+        let column = self.column;
+        self.memory()[column] = Some(c);
+        self.column = (column + 1).min(COLUMNS - 1);
+    }
+
+    fn text(&self) -> String {
+        let rows = self.displayed.iter().filter_map(|row| {
+            let row = row.iter().map(|cell| cell.unwrap_or(' ')).collect::<String>();
+            let row = row.trim();
+            if row.is_empty() { None } else { Some(row.to_string()) }
+        });
+        rows.collect::<Vec<_>>().join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// cc_data for field 1, with odd parity
+    fn cc_data(pairs: &[[u8; 2]]) -> Vec<u8> {
+        let parity = |byte: u8| if byte.count_ones() & 1 == 0 { byte | 0x80 } else { byte };
+        pairs.iter().flat_map(|[b1, b2]| vec![0xfc, parity(*b1), parity(*b2)]).collect()
+    }
+
+    #[test]
+    fn shows_pop_on_captions_at_end_of_caption() {
+        let mut decoder = Decoder::new();
+        // RCL twice, a PAC for row 15, "Hi É!" with the extended character replacing an 'e', then EOC
+        let loading = cc_data(&[[0x14, 0x20], [0x14, 0x20], [0x14, 0x70], [0x14, 0x70], [b'H', b'i'], [b' ', b'e'], [0x12, 0x21], [b'!', 0]]);
+        decoder.frame(3000, 0, &loading);
+        assert!(decoder.take_changes().is_empty());
+        decoder.frame(9000, 3000, &cc_data(&[[0x14, 0x2f], [0x14, 0x2f]]));
+        // Frames after the one decoded could still be presented before it
+        assert!(decoder.take_changes().is_empty());
+        decoder.frame(6000, 6000, &[]);
+        decoder.frame(12000, 9000, &[]);
+        assert_eq!(decoder.take_changes(), [(9000, "Hi É!".to_string())]);
+
+        decoder.frame(15000, 15000, &cc_data(&[[0x14, 0x2c], [0x14, 0x2c]]));
+        assert_eq!(decoder.take_changes(), [(15000, String::new())]);
+    }
+
+    #[test]
+    fn rolls_up_captions() {
+        let mut decoder = Decoder::new();
+        // RU2, then two lines separated by carriage returns
+        decoder.frame(0, 0, &cc_data(&[[0x14, 0x25], [0x14, 0x25], [b'O', b'n'], [b'e', 0]]));
+        decoder.frame(3000, 3000, &cc_data(&[[0x14, 0x2d], [0x14, 0x2d], [b'T', b'w'], [b'o', 0]]));
+        decoder.frame(6000, 6000, &cc_data(&[[0x14, 0x2d], [0x14, 0x2d], [b'3', 0]]));
+        assert_eq!(decoder.take_changes(), [
+            (0, "One".to_string()),
+            (3000, "One\nTwo".to_string()),
+            (6000, "Two\n3".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn ignores_other_channels() {
+        let mut decoder = Decoder::new();
+        // RDC for CC2, then text which belongs to it
+        decoder.frame(0, 0, &cc_data(&[[0x1c, 0x29], [0x1c, 0x29], [b'N', b'o']]));
+        assert!(decoder.take_changes().is_empty());
+    }
+
+    #[test]
+    fn follows_cea708_instead_of_cc1_once_seen() {
+        let mut decoder = Decoder::new();
+        // RDC, then "No" straight onto the screen
+        decoder.frame(0, 0, &cc_data(&[[0x14, 0x29], [0x14, 0x29], [b'N', b'o']]));
+        assert_eq!(decoder.take_changes(), [(0, "No".to_string())]);
+        assert!(!decoder.take_cea708());
+
+        // A DTVCC packet with a block of service 1, which defines a visible window of one row and writes "Hi" to it
+        let packet = [0x06, 0x29, 0x98, 0x20, 0, 0, 0, 31, 0, b'H', b'i', 0];
+        let dtvcc = packet.chunks(2).enumerate()
+            .flat_map(|(i, pair)| vec![if i == 0 { 0xff } else { 0xfe }, pair[0], pair[1]])
+            .collect::<Vec<_>>();
+        decoder.frame(3000, 3000, &dtvcc);
+        assert_eq!(decoder.take_changes(), [(3000, "Hi".to_string())]);
+        assert!(decoder.take_cea708());
+        assert!(!decoder.take_cea708());
+
+        // CC1 is left alone from then on
+        decoder.frame(6000, 6000, &cc_data(&[[0x14, 0x2c], [0x14, 0x2c], [b'N', b'o']]));
+        assert!(decoder.take_changes().is_empty());
+    }
+}
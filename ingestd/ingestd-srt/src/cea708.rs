@@ -0,0 +1,398 @@
+/// cc_type of the pair that starts a DTVCC packet, the rest of which follows in pairs of cc_type 2
+pub const DTVCC_PACKET_START: u8 = 3;
+
+/// The service carrying the primary caption language, which is the one decoded
+const PRIMARY_SERVICE: u8 = 1;
+/// Service numbers from this one on are given in the byte after the block header
+const EXTENDED_SERVICE: u8 = 7;
+
+const WINDOWS: usize = 8;
+const MAX_ROWS: usize = 15;
+const MAX_COLUMNS: usize = 42;
+
+/// A character from G2, which has symbols and a few more letters, if it has a Unicode equivalent
+fn g2_char(byte: u8) -> Option<char> {
+    Some(match byte {
+        0x20 => ' ',
+        0x21 => '\u{a0}',
+        0x25 => '…',
+        0x2a => 'Š',
+        0x2c => 'Œ',
+        0x30 => '█',
+        0x31 => '‘',
+        0x32 => '’',
+        0x33 => '“',
+        0x34 => '”',
+        0x35 => '•',
+        0x39 => '™',
+        0x3a => 'š',
+        0x3c => 'œ',
+        0x3d => '℠',
+        0x3f => 'Ÿ',
+        0x76 => '⅛',
+        0x77 => '⅜',
+        0x78 => '⅝',
+        0x79 => '⅞',
+        0x7a => '│',
+        0x7b => '┐',
+        0x7c => '└',
+        0x7d => '─',
+        0x7e => '┘',
+        0x7f => '┌',
+        _ => return None,
+    })
+}
+
+/// How many bytes follow a code as its parameters, given the byte after it. Variable length codes, which nothing is
+/// sent in yet, take the rest of the block.
+fn parameters(code: u8, next: Option<u8>) -> usize {
+    match (code, next) {
+        (0x10, Some(0x00..=0x07)) | (0x10, Some(0x20..=0x7f)) | (0x10, Some(0xa0..=0xff)) => 1,
+        (0x10, Some(0x08..=0x0f)) => 2,
+        (0x10, Some(0x10..=0x17)) => 3,
+        (0x10, Some(0x18..=0x1f)) => 4,
+        (0x10, Some(0x80..=0x87)) => 5,
+        (0x10, Some(0x88..=0x8f)) => 6,
+        (0x10, _) => usize::MAX,
+        (0x11..=0x17, _) => 1,
+        (0x18..=0x1f, _) => 2,
+        (0x88..=0x8d, _) => 1,
+        (0x90, _) | (0x92, _) => 2,
+        (0x91, _) => 3,
+        (0x97, _) => 4,
+        (0x98..=0x9f, _) => 6,
+        _ => 0,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Window {
+    visible: bool,
+    /// Windows are shown from the top of the screen down by where they are anchored
+    anchor_vertical: u8,
+    rows: usize,
+    columns: usize,
+    cells: [[Option<char>; MAX_COLUMNS]; MAX_ROWS],
+    pen_row: usize,
+    pen_column: usize,
+}
+
+impl Window {
+    fn clear(&mut self) {
+        self.cells = [[None; MAX_COLUMNS]; MAX_ROWS];
+    }
+
+    fn write(&mut self, c: char) {
+        self.cells[self.pen_row][self.pen_column] = Some(c);
+        self.pen_column = (self.pen_column + 1).min(self.columns - 1);
+    }
+
+    /// Moves the pen to the start of the next row, scrolling the rows up if it is on the last one
+    fn carriage_return(&mut self) {
+        if self.pen_row + 1 < self.rows {
+            self.pen_row += 1;
+        } else {
+            self.cells.copy_within(1..self.rows, 0);
+            self.cells[self.rows - 1] = [None; MAX_COLUMNS];
+        }
+        self.pen_column = 0;
+    }
+
+    fn rows(&self) -> impl Iterator<Item = String> + '_ {
+        self.cells[..self.rows].iter().filter_map(move |row| {
+            let row = row[..self.columns].iter().map(|cell| cell.unwrap_or(' ')).collect::<String>();
+            let row = row.trim();
+            if row.is_empty() { None } else { Some(row.to_string()) }
+        })
+    }
+}
+
+/// Decodes the primary caption service from the DTVCC packets in a video stream's cc_data, following what its windows
+/// show. Pen and window styles are left out, as is the delay command, which holds off decoding for a while.
+#[derive(Default)]
+pub struct Service {
+    /// The packet being put together, from its header on
+    packet: Vec<u8>,
+    windows: [Option<Window>; WINDOWS],
+    current: usize,
+    /// Whether any of the service has been decoded
+    seen: bool,
+}
+
+impl Service {
+    pub fn new() -> Service {
+        Service::default()
+    }
+
+    /// Takes a pair of DTVCC packet bytes, and says whether they completed a packet that had any of the service in it
+    pub fn pair(&mut self, cc_type: u8, b1: u8, b2: u8) -> bool {
+        let mut decoded = false;
+        if cc_type == DTVCC_PACKET_START {
+            // Packets are sometimes shorter than their header says
+            decoded = self.packet();
+        } else if self.packet.is_empty() {
+            // The rest of a packet whose start was missed
+            return false;
+        }
+        self.packet.extend_from_slice(&[b1, b2]);
+        if self.packet.len() > Service::packet_size(self.packet[0]) {
+            decoded |= self.packet();
+        }
+        decoded
+    }
+
+    /// Whether any of the service has been seen
+    pub fn seen(&self) -> bool {
+        self.seen
+    }
+
+    /// The text of the windows being shown, with their rows separated by newlines. An empty string means nothing is.
+    pub fn text(&self) -> String {
+        let mut windows = self.windows.iter().flatten().filter(|window| window.visible).collect::<Vec<_>>();
+        windows.sort_by_key(|window| window.anchor_vertical);
+        windows.iter().flat_map(|window| window.rows()).collect::<Vec<_>>().join("\n")
+    }
+
+    /// How many bytes of service blocks follow a packet header
+    fn packet_size(header: u8) -> usize {
+        match header & 0x3f {
+            0 => 127,
+            size_code => size_code as usize * 2 - 1,
+        }
+    }
+
+    /// Decodes the service blocks of the packet that was being put together
+    fn packet(&mut self) -> bool {
+        let packet = std::mem::take(&mut self.packet);
+        let mut blocks = match packet.split_first() {
+            Some((&header, blocks)) => &blocks[..Service::packet_size(header).min(blocks.len())],
+            None => return false,
+        };
+        let mut decoded = false;
+        while let Some(&header) = blocks.first() {
+            let (service, start) = match header >> 5 {
+                // A null block pads out the rest of the packet
+                0 => break,
+                EXTENDED_SERVICE => match blocks.get(1) {
+                    Some(extended) => (extended & 0x3f, 2),
+                    None => break,
+                },
+                service => (service, 1),
+            };
+            let end = (start + (header & 0x1f) as usize).min(blocks.len());
+            if service == PRIMARY_SERVICE {
+                self.block(&blocks[start..end]);
+                decoded = true;
+            }
+            blocks = &blocks[end..];
+        }
+        decoded
+    }
+
+    /// Carries out a service block's codes. Commands aren't split across blocks.
+    fn block(&mut self, mut data: &[u8]) {
+        self.seen = true;
+        while let Some((&code, rest)) = data.split_first() {
+            let len = parameters(code, rest.first().copied());
+            if len > rest.len() {
+                break;
+            }
+            self.code(code, &rest[..len]);
+            data = &rest[len..];
+        }
+    }
+
+    fn code(&mut self, code: u8, parameters: &[u8]) {
+        match code {
+            // Backspace
+            0x08 => if let Some(window) = self.window() {
+                window.pen_column = window.pen_column.saturating_sub(1);
+                window.cells[window.pen_row][window.pen_column] = None;
+            },
+            // Form feed clears the window and moves the pen to its start
+            0x0c => if let Some(window) = self.window() {
+                window.clear();
+                window.pen_row = 0;
+                window.pen_column = 0;
+            },
+            0x0d => if let Some(window) = self.window() {
+                window.carriage_return();
+            },
+            // Horizontal carriage return clears the row the pen is on
+            0x0e => if let Some(window) = self.window() {
+                window.cells[window.pen_row] = [None; MAX_COLUMNS];
+                window.pen_column = 0;
+            },
+            0x10 => match parameters[0] {
+                extended @ 0x20..=0x7f => if let Some(c) = g2_char(extended) {
+                    self.write(c);
+                },
+                // The closed captions sign, which is the only G3 character
+                0xa0 => self.write('㏄'),
+                _ => {},
+            },
+            0x20..=0x7e => self.write(code as char),
+            0x7f => self.write('♪'),
+            0x80..=0x87 => self.current = (code & 0x07) as usize,
+            0x88 => self.windows_in(parameters[0]).for_each(Window::clear),
+            0x89 => self.windows_in(parameters[0]).for_each(|window| window.visible = true),
+            0x8a => self.windows_in(parameters[0]).for_each(|window| window.visible = false),
+            0x8b => self.windows_in(parameters[0]).for_each(|window| window.visible = !window.visible),
+            0x8c => {
+                for (id, window) in self.windows.iter_mut().enumerate() {
+                    if parameters[0] & 1 << id != 0 {
+                        *window = None;
+                    }
+                }
+            },
+            0x8f => *self = Service { seen: true, ..Service::default() },
+            // Set pen location
+            0x92 => if let Some(window) = self.window() {
+                window.pen_row = ((parameters[0] & 0x0f) as usize).min(window.rows - 1);
+                window.pen_column = ((parameters[1] & 0x3f) as usize).min(window.columns - 1);
+            },
+            0x98..=0x9f => self.define_window((code & 0x07) as usize, parameters),
+            // G1 is Latin-1
+            0xa0..=0xff => self.write(code as char),
+            _ => {},
+        }
+    }
+
+    /// Creates a window, or changes one that already exists, and makes it the current one
+    fn define_window(&mut self, id: usize, parameters: &[u8]) {
+        let window = self.windows[id].get_or_insert(Window {
+            visible: false,
+            anchor_vertical: 0,
+            rows: 1,
+            columns: 1,
+            cells: [[None; MAX_COLUMNS]; MAX_ROWS],
+            pen_row: 0,
+            pen_column: 0,
+        });
+        window.visible = parameters[0] & 0x20 != 0;
+        window.anchor_vertical = parameters[1] & 0x7f;
+        window.rows = ((parameters[3] & 0x0f) as usize + 1).min(MAX_ROWS);
+        window.columns = ((parameters[4] & 0x3f) as usize + 1).min(MAX_COLUMNS);
+        window.pen_row = window.pen_row.min(window.rows - 1);
+        window.pen_column = window.pen_column.min(window.columns - 1);
+        self.current = id;
+    }
+
+    /// The current window, if it has been defined
+    fn window(&mut self) -> Option<&mut Window> {
+        self.windows[self.current].as_mut()
+    }
+
+    /// The defined windows among those a command's bitmap picks
+    fn windows_in(&mut self, bitmap: u8) -> impl Iterator<Item = &mut Window> + '_ {
+        self.windows.iter_mut().enumerate()
+            .filter(move |(id, _)| bitmap & 1 << id != 0)
+            .filter_map(|(_, window)| window.as_mut())
+    }
+
+    fn write(&mut self, c: char) {
+        if let Some(window) = self.window() {
+            window.write(c);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `blocks` to `service` as one packet, and says whether any of the primary service was decoded
+    fn packet(service: &mut Service, blocks: &[u8]) -> bool {
+        // Packets are padded to a whole number of pairs
+        let mut packet = vec![((blocks.len() + 2) / 2) as u8];
+        packet.extend_from_slice(blocks);
+        if packet.len() % 2 != 0 {
+            packet.push(0);
+        }
+        packet.chunks(2).enumerate().fold(false, |decoded, (i, pair)| {
+            let cc_type = if i == 0 { DTVCC_PACKET_START } else { 2 };
+            service.pair(cc_type, pair[0], pair[1]) || decoded
+        })
+    }
+
+    /// A block of the primary service
+    fn block(data: &[u8]) -> Vec<u8> {
+        let mut block = vec![PRIMARY_SERVICE << 5 | data.len() as u8];
+        block.extend_from_slice(data);
+        block
+    }
+
+    /// Defines a window of `rows` by 32 columns, visible or not
+    fn define_window(id: u8, visible: bool, anchor_vertical: u8, rows: u8) -> Vec<u8> {
+        vec![0x98 | id, if visible { 0x20 } else { 0 }, anchor_vertical, 0, rows - 1, 31, 0]
+    }
+
+    #[test]
+    fn shows_windows_as_they_are_written() {
+        let mut service = Service::new();
+        let mut data = define_window(0, true, 60, 2);
+        data.extend_from_slice(b"Caf");
+        // é from G1, then a carriage return
+        data.extend_from_slice(&[0xe9, 0x0d]);
+        data.extend_from_slice(b"au lait");
+        assert!(packet(&mut service, &block(&data)));
+        assert_eq!(service.text(), "Café\nau lait");
+
+        // A carriage return on the last row scrolls the window up
+        assert!(packet(&mut service, &block(b"\r\x10\x33Two\x10\x34")));
+        assert_eq!(service.text(), "au lait\n“Two”");
+    }
+
+    #[test]
+    fn shows_hidden_windows_when_displayed_and_orders_them_by_anchor() {
+        let mut service = Service::new();
+        let mut data = define_window(1, true, 60, 1);
+        data.extend_from_slice(b"Bottom");
+        data.extend_from_slice(&define_window(0, false, 10, 1));
+        data.extend_from_slice(b"Top");
+        packet(&mut service, &block(&data));
+        assert_eq!(service.text(), "Bottom");
+
+        // Display window 0
+        packet(&mut service, &block(&[0x89, 0x01]));
+        assert_eq!(service.text(), "Top\nBottom");
+        // Clear window 1, then delete window 0
+        packet(&mut service, &block(&[0x88, 0x02, 0x8c, 0x01]));
+        assert_eq!(service.text(), "");
+    }
+
+    #[test]
+    fn decodes_only_the_primary_service() {
+        let mut service = Service::new();
+        let mut data = define_window(0, true, 0, 1);
+        data.extend_from_slice(b"Two");
+        let mut blocks = vec![2 << 5 | data.len() as u8];
+        blocks.extend_from_slice(&data);
+        assert!(!packet(&mut service, &blocks));
+        assert!(!service.seen());
+
+        // Service 1 after an extended service's block
+        let mut blocks = vec![EXTENDED_SERVICE << 5 | 1, 10, 0];
+        let mut data = define_window(0, true, 0, 1);
+        data.extend_from_slice(b"One");
+        blocks.extend_from_slice(&block(&data));
+        assert!(packet(&mut service, &blocks));
+        assert_eq!(service.text(), "One");
+    }
+
+    #[test]
+    fn decodes_packets_cut_short_by_the_next_one() {
+        let mut service = Service::new();
+        let mut data = define_window(0, true, 0, 1);
+        data.extend_from_slice(b"Hi");
+        // A header promising more than is sent
+        let mut packet = vec![0x3f];
+        packet.extend_from_slice(&block(&data));
+        packet.resize(packet.len() + packet.len() % 2, 0);
+        for (i, pair) in packet.chunks(2).enumerate() {
+            assert!(!service.pair(if i == 0 { DTVCC_PACKET_START } else { 2 }, pair[0], pair[1]));
+        }
+        assert!(service.pair(DTVCC_PACKET_START, 0x01, 0x00));
+        assert_eq!(service.text(), "Hi");
+    }
+}
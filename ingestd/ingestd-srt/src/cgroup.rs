@@ -0,0 +1,199 @@
+use serde::Deserialize;
+use std::fs::File;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+pub const CLONE_INTO_CGROUP: u64 = 0x200000000;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CgroupConfig {
+    /// A cgroup v2 directory delegated to ingestd-srt, e.g. by systemd's Delegate=
+    pub path: PathBuf,
+    pub cpu_weight: Option<u32>,
+    /// CPU time in microseconds each packager may use per `cpu-period`
+    pub cpu_quota: Option<u64>,
+    #[serde(default = "default_cpu_period")]
+    pub cpu_period: u64,
+    /// Memory limit in bytes, after which the packager is OOM killed
+    pub memory_max: Option<u64>,
+}
+
+fn default_cpu_period() -> u64 {
+    100000
+}
+
+pub struct Cgroups {
+    config: CgroupConfig,
+}
+
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub cpu_usec: u64,
+    pub memory_current: u64,
+    pub memory_peak: Option<u64>,
+    pub oom_kills: u64,
+}
+
+pub struct SessionCgroup {
+    path: PathBuf,
+    dir: File,
+    oom_kills: AtomicU64,
+    cpu_usec_exported: AtomicU64,
+    memory_exported: AtomicU64,
+    memory_highest: AtomicU64,
+}
+
+impl Cgroups {
+    /// Moves ingestd-srt into a leaf of the delegated cgroup, so that controllers can be enabled for the packagers'
+    /// sibling cgroups without breaking the no internal processes rule
+    pub fn setup(config: CgroupConfig) -> std::io::Result<Cgroups> {
+        let supervisor = config.path.join("supervisor");
+        if let Err(e) = std::fs::create_dir(&supervisor) {
+            if e.kind() != std::io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+        }
+        std::fs::write(supervisor.join("cgroup.procs"), std::process::id().to_string())?;
+        let mut controllers = String::new();
+        if config.cpu_weight.is_some() || config.cpu_quota.is_some() {
+            controllers.push_str("+cpu ");
+        }
+        controllers.push_str("+memory");
+        std::fs::write(config.path.join("cgroup.subtree_control"), controllers)?;
+        Ok(Cgroups { config })
+    }
+
+    pub fn create(&self, stream_uuid: &Uuid) -> std::io::Result<SessionCgroup> {
+        let path = self.config.path.join(stream_uuid.to_hyphenated_ref().to_string());
+        std::fs::create_dir(&path)?;
+        if let Some(cpu_weight) = self.config.cpu_weight {
+            std::fs::write(path.join("cpu.weight"), cpu_weight.to_string())?;
+        }
+        if let Some(cpu_quota) = self.config.cpu_quota {
+            std::fs::write(path.join("cpu.max"), format!("{} {}", cpu_quota, self.config.cpu_period))?;
+        }
+        if let Some(memory_max) = self.config.memory_max {
+            std::fs::write(path.join("memory.max"), memory_max.to_string())?;
+        }
+        let dir = std::fs::OpenOptions::new().read(true).custom_flags(libc::O_DIRECTORY).open(&path)?;
+        Ok(SessionCgroup {
+            path,
+            dir,
+            oom_kills: AtomicU64::new(0),
+            cpu_usec_exported: AtomicU64::new(0),
+            memory_exported: AtomicU64::new(0),
+            memory_highest: AtomicU64::new(0),
+        })
+    }
+}
+
+fn read_key(contents: &str, key: &str) -> Option<u64> {
+    contents.lines()
+        .filter_map(|line| line.strip_prefix(key))
+        .filter_map(|value| value.strip_prefix(' '))
+        .next()
+        .and_then(|value| value.trim().parse().ok())
+}
+
+impl SessionCgroup {
+    pub fn usage(&self) -> std::io::Result<Usage> {
+        let cpu_stat = std::fs::read_to_string(self.path.join("cpu.stat"))?;
+        let memory_current = std::fs::read_to_string(self.path.join("memory.current"))?;
+        // memory.peak is only available from Linux 5.19
+        let memory_peak = std::fs::read_to_string(self.path.join("memory.peak")).ok();
+        let memory_events = std::fs::read_to_string(self.path.join("memory.events"))?;
+        Ok(Usage {
+            cpu_usec: read_key(&cpu_stat, "usage_usec").unwrap_or(0),
+            memory_current: memory_current.trim().parse().unwrap_or(0),
+            memory_peak: memory_peak.and_then(|peak| peak.trim().parse().ok()),
+            oom_kills: read_key(&memory_events, "oom_kill").unwrap_or(0),
+        })
+    }
+
+    /// Whether a process in this cgroup has been OOM killed since the last time this was called
+    pub fn oom_killed(&self, usage: &Usage) -> bool {
+        usage.oom_kills > self.oom_kills.swap(usage.oom_kills, Ordering::Relaxed)
+    }
+
+    /// CPU time used since the last time this was called, for exporting as it is used
+    pub fn cpu_usec_since(&self, usage: &Usage) -> u64 {
+        usage.cpu_usec.saturating_sub(self.cpu_usec_exported.fetch_max(usage.cpu_usec, Ordering::Relaxed))
+    }
+
+    /// Replaces the memory use last exported with `current`, returning what it was, for keeping a total over every
+    /// packager
+    pub fn swap_memory_exported(&self, current: u64) -> u64 {
+        self.memory_exported.swap(current, Ordering::Relaxed)
+    }
+
+    /// The most memory used so far, from memory.peak where the kernel has it, or else the most seen by this
+    pub fn memory_peak(&self, usage: &Usage) -> u64 {
+        let seen = self.memory_highest.fetch_max(usage.memory_current, Ordering::Relaxed).max(usage.memory_current);
+        usage.memory_peak.unwrap_or(seen)
+    }
+}
+
+impl AsRawFd for SessionCgroup {
+    fn as_raw_fd(&self) -> RawFd {
+        self.dir.as_raw_fd()
+    }
+}
+
+impl Drop for SessionCgroup {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir(&self.path) {
+            eprintln!("removing cgroup {} failed: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory standing in for a session's cgroup, with the files usage reads
+    fn session_cgroup(memory_current: u64) -> SessionCgroup {
+        let path = std::env::temp_dir().join(format!("ingestd-cgroup-{}", Uuid::new_v4()));
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("cpu.stat"), "usage_usec 1500000\nuser_usec 1000000\nsystem_usec 500000\n").unwrap();
+        std::fs::write(path.join("memory.events"), "low 0\nhigh 0\nmax 0\noom 0\noom_kill 0\n").unwrap();
+        std::fs::write(path.join("memory.current"), format!("{}\n", memory_current)).unwrap();
+        let dir = File::open(&path).unwrap();
+        SessionCgroup {
+            path,
+            dir,
+            oom_kills: AtomicU64::new(0),
+            cpu_usec_exported: AtomicU64::new(0),
+            memory_exported: AtomicU64::new(0),
+            memory_highest: AtomicU64::new(0),
+        }
+    }
+
+    fn remove(cgroup: SessionCgroup) {
+        for file in &["cpu.stat", "memory.events", "memory.current", "memory.peak"] {
+            let _ = std::fs::remove_file(cgroup.path.join(file));
+        }
+    }
+
+    #[test]
+    fn takes_the_memory_peak_from_what_was_sampled_without_memory_peak() {
+        let cgroup = session_cgroup(3000);
+        let usage = cgroup.usage().unwrap();
+        assert_eq!((usage.cpu_usec, usage.memory_current, usage.memory_peak), (1500000, 3000, None));
+        assert_eq!(cgroup.memory_peak(&usage), 3000);
+
+        std::fs::write(cgroup.path.join("memory.current"), "1000\n").unwrap();
+        let usage = cgroup.usage().unwrap();
+        assert_eq!(usage.memory_current, 1000);
+        assert_eq!(cgroup.memory_peak(&usage), 3000);
+
+        std::fs::write(cgroup.path.join("memory.peak"), "5000\n").unwrap();
+        let usage = cgroup.usage().unwrap();
+        assert_eq!(cgroup.memory_peak(&usage), 5000);
+        remove(cgroup);
+    }
+}
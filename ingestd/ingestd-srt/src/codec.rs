@@ -0,0 +1,634 @@
+use serde::Serialize;
+
+/// Reads the fields of H.264 and HEVC parameter sets, which are packed MSB first with Exp-Golomb codes
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            position: 0,
+        }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit as u32)
+    }
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.bit()?;
+        }
+        Some(value)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.position += n;
+        if self.position > self.data.len() * 8 {
+            return None;
+        }
+        Some(())
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        self.bit().map(|bit| bit != 0)
+    }
+
+    fn ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << leading_zeros) - 1 + self.bits(leading_zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i32> {
+        let value = self.ue()?;
+        Some(if value % 2 == 1 { (value / 2 + 1) as i32 } else { -((value / 2) as i32) })
+    }
+}
+
+/// Removes the emulation prevention bytes from a NAL unit
+fn unescape(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// Iterates over the NAL units in an Annex B byte stream, without their start codes
+pub fn nal_units(data: &[u8]) -> impl Iterator<Item=&[u8]> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let start = rest.windows(3).position(|window| window == [0, 0, 1])? + 3;
+        rest = &rest[start..];
+        let end = rest.windows(3).position(|window| window == [0, 0, 1]).unwrap_or(rest.len());
+        let nal = &rest[..end];
+        rest = &rest[end..];
+        // The zero of a four byte start code belongs to the next one
+        Some(match nal.last() {
+            Some(0) if !rest.is_empty() => &nal[..nal.len() - 1],
+            _ => nal,
+        })
+    })
+}
+
+pub fn h264_nal_type(nal: &[u8]) -> Option<u8> {
+    nal.first().map(|header| header & 0x1f)
+}
+
+pub fn hevc_nal_type(nal: &[u8]) -> Option<u8> {
+    nal.first().map(|header| (header >> 1) & 0x3f)
+}
+
+pub const H264_NAL_IDR: u8 = 5;
+pub const H264_NAL_SEI: u8 = 6;
+pub const H264_NAL_SPS: u8 = 7;
+pub const HEVC_NAL_SPS: u8 = 33;
+pub const HEVC_NAL_PREFIX_SEI: u8 = 39;
+
+/// The SEI payload type ATSC A/53 captions are carried in, registered user data as in ITU-T T.35
+const SEI_USER_DATA_REGISTERED: u32 = 4;
+
+/// ITU-T T.35 country code, provider code and user identifier for ATSC, then the user_data_type_code for cc_data
+const ATSC_CC_DATA: &[u8] = &[0xb5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03];
+
+/// Reads one of an SEI message's payload type or size, which are sums of bytes up to the first that isn't 0xff
+fn sei_value(data: &mut &[u8]) -> Option<u32> {
+    let mut value: u32 = 0;
+    loop {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value = value.checked_add(byte as u32)?;
+        if byte != 0xff {
+            return Some(value);
+        }
+    }
+}
+
+/// The cc_data triplets of the ATSC captions in an SEI NAL unit, which has a header of `header_len` bytes
+pub fn sei_cc_data(nal: &[u8], header_len: usize) -> Vec<u8> {
+    let rbsp = unescape(nal.get(header_len..).unwrap_or_default());
+    let mut messages = &rbsp[..];
+    let mut cc_data = Vec::new();
+    // Up to the rbsp_trailing_bits
+    while messages.len() > 1 {
+        let payload_type = match sei_value(&mut messages) {
+            Some(payload_type) => payload_type,
+            None => break,
+        };
+        let payload_size = match sei_value(&mut messages) {
+            Some(payload_size) if payload_size as usize <= messages.len() => payload_size as usize,
+            _ => break,
+        };
+        let (payload, rest) = messages.split_at(payload_size);
+        messages = rest;
+        if payload_type != SEI_USER_DATA_REGISTERED || !payload.starts_with(ATSC_CC_DATA) {
+            continue;
+        }
+        // process_cc_data_flag and cc_count, then em_data
+        let flags = match payload.get(ATSC_CC_DATA.len()) {
+            Some(&flags) if flags & 0x40 != 0 => flags,
+            _ => continue,
+        };
+        let start = ATSC_CC_DATA.len() + 2;
+        if let Some(triplets) = payload.get(start..start + (flags & 0x1f) as usize * 3) {
+            cc_data.extend_from_slice(triplets);
+        }
+    }
+    cc_data
+}
+
+/// Whether an HEVC NAL unit type is an intra random access point: BLA, IDR or CRA
+pub fn is_hevc_irap(nal_type: u8) -> bool {
+    (16..=23).contains(&nal_type)
+}
+
+/// What the NAL units of a video PES say about its frame, found in one pass over them
+pub struct VideoFrame<'a> {
+    hevc: bool,
+    /// Whether it has an IDR, or for HEVC any IRAP, NAL unit
+    pub keyframe: bool,
+    sps: Option<&'a [u8]>,
+    sei: Vec<&'a [u8]>,
+}
+
+impl<'a> VideoFrame<'a> {
+    pub fn parse(data: &'a [u8], hevc: bool) -> VideoFrame<'a> {
+        let mut frame = VideoFrame { hevc, keyframe: false, sps: None, sei: Vec::new() };
+        for nal in nal_units(data) {
+            let nal_type = if hevc { hevc_nal_type(nal) } else { h264_nal_type(nal) };
+            match (hevc, nal_type) {
+                (false, Some(H264_NAL_IDR)) => frame.keyframe = true,
+                (true, Some(nal_type)) if is_hevc_irap(nal_type) => frame.keyframe = true,
+                (false, Some(H264_NAL_SPS)) | (true, Some(HEVC_NAL_SPS)) => {
+                    frame.sps.get_or_insert(nal);
+                },
+                (false, Some(H264_NAL_SEI)) | (true, Some(HEVC_NAL_PREFIX_SEI)) => frame.sei.push(nal),
+                _ => (),
+            }
+        }
+        frame
+    }
+
+    /// The format in the frame's SPS, if it has one
+    pub fn format(&self) -> Option<VideoFormat> {
+        let sps = self.sps?;
+        if self.hevc {
+            parse_hevc_sps(sps)
+        } else {
+            parse_h264_sps(sps)
+        }
+    }
+
+    /// The cc_data in the frame's SEI
+    pub fn cc_data(&self) -> Vec<u8> {
+        let header_len = if self.hevc { 2 } else { 1 };
+        self.sei.iter().flat_map(|nal| sei_cc_data(nal, header_len)).collect()
+    }
+}
+
+/// What a sequence parameter set says about the video
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct VideoFormat {
+    pub profile: u8,
+    pub level: u8,
+    pub width: u32,
+    pub height: u32,
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Option<()> {
+    let mut last: i32 = 8;
+    let mut next = 8;
+    for _ in 0..size {
+        if next != 0 {
+            next = last.checked_add(reader.se()?)?.checked_add(256)?.rem_euclid(256);
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Some(())
+}
+
+/// A coded dimension less the conformance window's crop at either side, or None if a corrupt SPS crops more than
+/// there is
+fn cropped(coded: u32, crop_unit: u32, crop_start: u32, crop_end: u32) -> Option<u32> {
+    coded.checked_sub(crop_start.checked_add(crop_end)?.checked_mul(crop_unit)?)
+}
+
+/// Parses an H.264 SPS NAL unit, header byte included
+pub fn parse_h264_sps(nal: &[u8]) -> Option<VideoFormat> {
+    let rbsp = unescape(nal.get(1..)?);
+    let mut reader = BitReader::new(&rbsp);
+    let profile = reader.bits(8)? as u8;
+    reader.skip(8)?;
+    let level = reader.bits(8)? as u8;
+    reader.ue()?;
+    let mut chroma_format_idc = 1;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile) {
+        chroma_format_idc = reader.ue()?;
+        if chroma_format_idc == 3 {
+            reader.skip(1)?;
+        }
+        reader.ue()?;
+        reader.ue()?;
+        reader.skip(1)?;
+        if reader.flag()? {
+            for i in 0..if chroma_format_idc == 3 { 12 } else { 8 } {
+                if reader.flag()? {
+                    skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+    reader.ue()?;
+    match reader.ue()? {
+        0 => {
+            reader.ue()?;
+        },
+        1 => {
+            reader.skip(1)?;
+            reader.se()?;
+            reader.se()?;
+            for _ in 0..reader.ue()? {
+                reader.se()?;
+            }
+        },
+        _ => {},
+    }
+    reader.ue()?;
+    reader.skip(1)?;
+    let width_in_mbs = reader.ue()?.checked_add(1)?;
+    let height_in_map_units = reader.ue()?.checked_add(1)?;
+    let frame_mbs_only = reader.flag()?;
+    if !frame_mbs_only {
+        reader.skip(1)?;
+    }
+    reader.skip(1)?;
+    let (crop_left, crop_right, crop_top, crop_bottom) = if reader.flag()? {
+        (reader.ue()?, reader.ue()?, reader.ue()?, reader.ue()?)
+    } else {
+        (0, 0, 0, 0)
+    };
+    let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+        0 => (1, 1),
+        1 => (2, 2),
+        2 => (2, 1),
+        _ => (1, 1),
+    };
+    let crop_unit_y = crop_unit_y * if frame_mbs_only { 1 } else { 2 };
+    let height_in_mbs = height_in_map_units.checked_mul(if frame_mbs_only { 1 } else { 2 })?;
+    Some(VideoFormat {
+        profile,
+        level,
+        width: cropped(width_in_mbs.checked_mul(16)?, crop_unit_x, crop_left, crop_right)?,
+        height: cropped(height_in_mbs.checked_mul(16)?, crop_unit_y, crop_top, crop_bottom)?,
+    })
+}
+
+/// Parses an HEVC SPS NAL unit, two byte header included
+pub fn parse_hevc_sps(nal: &[u8]) -> Option<VideoFormat> {
+    let rbsp = unescape(nal.get(2..)?);
+    let mut reader = BitReader::new(&rbsp);
+    reader.skip(4)?;
+    let max_sub_layers = reader.bits(3)? + 1;
+    reader.skip(1)?;
+    // profile_tier_level
+    reader.skip(3)?;
+    let profile = reader.bits(5)? as u8;
+    reader.skip(32 + 48)?;
+    let level = reader.bits(8)? as u8;
+    let mut sub_layers_present = Vec::new();
+    for _ in 1..max_sub_layers {
+        sub_layers_present.push((reader.flag()?, reader.flag()?));
+    }
+    if max_sub_layers > 1 {
+        reader.skip(2 * (9 - max_sub_layers as usize))?;
+    }
+    for (profile_present, level_present) in sub_layers_present {
+        if profile_present {
+            reader.skip(88)?;
+        }
+        if level_present {
+            reader.skip(8)?;
+        }
+    }
+    reader.ue()?;
+    let chroma_format_idc = reader.ue()?;
+    if chroma_format_idc == 3 {
+        reader.skip(1)?;
+    }
+    let width = reader.ue()?;
+    let height = reader.ue()?;
+    let (crop_left, crop_right, crop_top, crop_bottom) = if reader.flag()? {
+        (reader.ue()?, reader.ue()?, reader.ue()?, reader.ue()?)
+    } else {
+        (0, 0, 0, 0)
+    };
+    let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+        1 => (2, 2),
+        2 => (2, 1),
+        _ => (1, 1),
+    };
+    Some(VideoFormat {
+        profile,
+        level,
+        width: cropped(width, crop_unit_x, crop_left, crop_right)?,
+        height: cropped(height, crop_unit_y, crop_top, crop_bottom)?,
+    })
+}
+
+/// What an audio header says about the audio
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioFormat {
+    pub channels: u8,
+    pub sample_rate: u32,
+}
+
+const AAC_SAMPLE_RATES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+
+/// Parses the ADTS header an AAC PES starts with
+pub fn parse_adts(data: &[u8]) -> Option<AudioFormat> {
+    if data.len() < 7 || data[0] != 0xff || data[1] & 0xf0 != 0xf0 {
+        return None;
+    }
+    let sample_rate = *AAC_SAMPLE_RATES.get(((data[2] >> 2) & 0x0f) as usize)?;
+    let channels = match ((data[2] & 0x01) << 2) | (data[3] >> 6) {
+        // Signalled in the stream rather than the header
+        0 => return None,
+        7 => 8,
+        config => config,
+    };
+    Some(AudioFormat {
+        channels,
+        sample_rate,
+    })
+}
+
+/// Opus in MPEG-TS carries its channel configuration in an extension descriptor rather than in the stream, and is
+/// always decoded at 48kHz
+pub fn opus_format(extension_descriptor: &[u8]) -> Option<AudioFormat> {
+    if extension_descriptor.len() < 2 || extension_descriptor[0] != 0x80 {
+        return None;
+    }
+    let channels = match extension_descriptor[1] {
+        // Dual mono
+        0 => 2,
+        channels @ 1..=8 => channels,
+        0x81 if extension_descriptor.len() >= 3 => extension_descriptor[2],
+        _ => return None,
+    };
+    Some(AudioFormat {
+        channels,
+        sample_rate: 48000,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Packs parameter set fields the way BitReader reads them
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, n: u32, value: u64) -> &mut BitWriter {
+            for i in (0..n).rev() {
+                if self.bits % 8 == 0 {
+                    self.data.push(0);
+                }
+                *self.data.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+            self
+        }
+
+        fn flag(&mut self, value: bool) -> &mut BitWriter {
+            self.bits(1, value as u64)
+        }
+
+        fn ue(&mut self, value: u32) -> &mut BitWriter {
+            let coded = value as u64 + 1;
+            let len = 64 - coded.leading_zeros();
+            self.bits(len - 1, 0).bits(len, coded)
+        }
+
+        fn se(&mut self, value: i32) -> &mut BitWriter {
+            self.ue(if value > 0 { value as u32 * 2 - 1 } else { (-(value as i64)) as u32 * 2 })
+        }
+
+        /// The NAL unit, with `header` and emulation prevention bytes added to the RBSP written so far
+        fn nal(&mut self, header: &[u8]) -> Vec<u8> {
+            // rbsp_trailing_bits
+            self.flag(true);
+            let mut nal = header.to_vec();
+            let mut zeros = 0;
+            for &byte in &self.data {
+                if zeros >= 2 && byte <= 3 {
+                    nal.push(3);
+                    zeros = 0;
+                }
+                zeros = if byte == 0 { zeros + 1 } else { 0 };
+                nal.push(byte);
+            }
+            nal
+        }
+    }
+
+    pub(crate) struct H264Sps {
+        pub(crate) profile: u8,
+        pub(crate) scaling_deltas: Option<Vec<i32>>,
+        pub(crate) width_in_mbs: u32,
+        pub(crate) height_in_map_units: u32,
+        pub(crate) frame_mbs_only: bool,
+        pub(crate) crop: Option<(u32, u32, u32, u32)>,
+    }
+
+    impl Default for H264Sps {
+        /// 1080p High profile, as most encoders send it
+        fn default() -> H264Sps {
+            H264Sps {
+                profile: 100,
+                scaling_deltas: None,
+                width_in_mbs: 120,
+                height_in_map_units: 68,
+                frame_mbs_only: true,
+                crop: Some((0, 0, 0, 4)),
+            }
+        }
+    }
+
+    impl H264Sps {
+        pub(crate) fn nal(&self) -> Vec<u8> {
+            let mut writer = BitWriter::default();
+            writer.bits(8, self.profile as u64).bits(8, 0).bits(8, 40).ue(0);
+            if self.profile == 100 {
+                // chroma_format_idc, bit depths and qpprime_y_zero_transform_bypass_flag
+                writer.ue(1).ue(0).ue(0).flag(false);
+                writer.flag(self.scaling_deltas.is_some());
+                if let Some(ref deltas) = self.scaling_deltas {
+                    // Only the first list is sent, ending early with a delta that makes the next scale 0
+                    writer.flag(true);
+                    for &delta in deltas {
+                        writer.se(delta);
+                    }
+                    for _ in 1..8 {
+                        writer.flag(false);
+                    }
+                }
+            }
+            // log2_max_frame_num_minus4, pic_order_cnt_type 0 and log2_max_pic_order_cnt_lsb_minus4
+            writer.ue(0).ue(0).ue(2);
+            // max_num_ref_frames and gaps_in_frame_num_value_allowed_flag
+            writer.ue(4).flag(false);
+            writer.ue(self.width_in_mbs - 1).ue(self.height_in_map_units - 1).flag(self.frame_mbs_only);
+            if !self.frame_mbs_only {
+                writer.flag(false);
+            }
+            writer.flag(true).flag(self.crop.is_some());
+            if let Some((left, right, top, bottom)) = self.crop {
+                writer.ue(left).ue(right).ue(top).ue(bottom);
+            }
+            // No VUI
+            writer.flag(false);
+            writer.nal(&[0x67])
+        }
+    }
+
+    #[test]
+    fn parses_h264_sps() {
+        assert_eq!(parse_h264_sps(&H264Sps::default().nal()), Some(VideoFormat { profile: 100, level: 40, width: 1920, height: 1080 }));
+
+        let sps = H264Sps { profile: 66, width_in_mbs: 80, height_in_map_units: 45, crop: None, ..H264Sps::default() };
+        assert_eq!(parse_h264_sps(&sps.nal()), Some(VideoFormat { profile: 66, level: 40, width: 1280, height: 720 }));
+
+        // Interlaced, where map units are pairs of macroblock rows and cropping is by pairs of lines
+        let sps = H264Sps { height_in_map_units: 34, frame_mbs_only: false, crop: Some((0, 0, 0, 2)), ..H264Sps::default() };
+        assert_eq!(parse_h264_sps(&sps.nal()), Some(VideoFormat { profile: 100, level: 40, width: 1920, height: 1080 }));
+
+        let sps = H264Sps { scaling_deltas: Some(vec![8, -3, 120, 123]), ..H264Sps::default() };
+        assert_eq!(parse_h264_sps(&sps.nal()).map(|format| (format.width, format.height)), Some((1920, 1080)));
+    }
+
+    #[test]
+    fn rejects_corrupt_h264_sps() {
+        let nal = H264Sps::default().nal();
+        assert_eq!(parse_h264_sps(&nal[..nal.len() - 2]), None);
+        assert_eq!(parse_h264_sps(&[0x67]), None);
+
+        let sps = H264Sps { crop: Some((1000, 0, 0, 0)), ..H264Sps::default() };
+        assert_eq!(parse_h264_sps(&sps.nal()), None);
+        // Sizes that overflow rather than just being too big
+        let sps = H264Sps { width_in_mbs: 1 << 28, ..H264Sps::default() };
+        assert_eq!(parse_h264_sps(&sps.nal()), None);
+        let sps = H264Sps { crop: Some((u32::MAX - 1, 3, 0, 0)), ..H264Sps::default() };
+        assert_eq!(parse_h264_sps(&sps.nal()), None);
+        let sps = H264Sps { height_in_map_units: u32::MAX, frame_mbs_only: false, ..H264Sps::default() };
+        assert_eq!(parse_h264_sps(&sps.nal()), None);
+        let sps = H264Sps { scaling_deltas: Some(vec![i32::MAX]), ..H264Sps::default() };
+        assert_eq!(parse_h264_sps(&sps.nal()), None);
+    }
+
+    fn hevc_sps(width: u32, height: u32, crop: Option<(u32, u32, u32, u32)>) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        // sps_video_parameter_set_id, one sub-layer and sps_temporal_id_nesting_flag
+        writer.bits(4, 0).bits(3, 0).flag(true);
+        // profile_tier_level, Main profile at level 4.1
+        writer.bits(3, 0).bits(5, 1).bits(32, 0x6000_0000).bits(48, 0x9000_0000_0000).bits(8, 123);
+        writer.ue(0).ue(1).ue(width).ue(height).flag(crop.is_some());
+        if let Some((left, right, top, bottom)) = crop {
+            writer.ue(left).ue(right).ue(top).ue(bottom);
+        }
+        writer.nal(&[0x42, 0x01])
+    }
+
+    #[test]
+    fn parses_hevc_sps() {
+        assert_eq!(parse_hevc_sps(&hevc_sps(1920, 1088, Some((0, 0, 0, 4)))), Some(VideoFormat { profile: 1, level: 123, width: 1920, height: 1080 }));
+        assert_eq!(parse_hevc_sps(&hevc_sps(3840, 2160, None)), Some(VideoFormat { profile: 1, level: 123, width: 3840, height: 2160 }));
+        assert_eq!(parse_hevc_sps(&hevc_sps(1920, 1080, Some((1000, 0, 0, 0)))), None);
+        assert_eq!(parse_hevc_sps(&hevc_sps(1920, 1080, Some((u32::MAX - 1, 0, 0, 0)))), None);
+        assert_eq!(parse_hevc_sps(&hevc_sps(1920, 1080, None)[..10]), None);
+    }
+
+    #[test]
+    fn parses_adts() {
+        // AAC LC at 48kHz in stereo
+        assert_eq!(parse_adts(&[0xff, 0xf1, 0x4c, 0x80, 0x2e, 0x7f, 0xfc]), Some(AudioFormat { channels: 2, sample_rate: 48000 }));
+        // 44.1kHz 7.1, whose channel configuration is 7
+        assert_eq!(parse_adts(&[0xff, 0xf1, 0x51, 0xc0, 0x2e, 0x7f, 0xfc]), Some(AudioFormat { channels: 8, sample_rate: 44100 }));
+        // Channel configuration 0 is in a program_config_element instead
+        assert_eq!(parse_adts(&[0xff, 0xf1, 0x4c, 0x00, 0x2e, 0x7f, 0xfc]), None);
+        // A reserved sampling_frequency_index
+        assert_eq!(parse_adts(&[0xff, 0xf1, 0x7c, 0x80, 0x2e, 0x7f, 0xfc]), None);
+        assert_eq!(parse_adts(&[0xff, 0xe1, 0x4c, 0x80, 0x2e, 0x7f, 0xfc]), None);
+        assert_eq!(parse_adts(&[0xff, 0xf1, 0x4c, 0x80]), None);
+    }
+
+    #[test]
+    fn reads_opus_channels_from_extension_descriptor() {
+        assert_eq!(opus_format(&[0x80, 0x02]), Some(AudioFormat { channels: 2, sample_rate: 48000 }));
+        assert_eq!(opus_format(&[0x80, 0x00]), Some(AudioFormat { channels: 2, sample_rate: 48000 }));
+        assert_eq!(opus_format(&[0x80, 0x08]), Some(AudioFormat { channels: 8, sample_rate: 48000 }));
+        assert_eq!(opus_format(&[0x80, 0x81, 0x0c, 0x01]), Some(AudioFormat { channels: 12, sample_rate: 48000 }));
+        assert_eq!(opus_format(&[0x80, 0x81]), None);
+        assert_eq!(opus_format(&[0x80, 0x09]), None);
+        assert_eq!(opus_format(&[0x81, 0x02]), None);
+        assert_eq!(opus_format(&[0x80]), None);
+    }
+
+    #[test]
+    fn splits_nal_units() {
+        let data = [0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 1, 0x65, 4];
+        assert_eq!(nal_units(&data).collect::<Vec<_>>(), [&[0x67, 1, 2][..], &[0x68, 3], &[0x65, 4]]);
+    }
+
+    #[test]
+    fn finds_keyframes_parameter_sets_and_captions_in_one_pass() {
+        let mut data = vec![0, 0, 0, 1];
+        data.extend(H264Sps::default().nal());
+        // An SEI with one cc_data triplet, and then an IDR slice
+        data.extend(&[0, 0, 1, 0x06, 4, 14, 0xb5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03, 0x41, 0xff, 0xfc, 0x94, 0x20, 0xff, 0x80]);
+        data.extend(&[0, 0, 1, 0x65, 0x88]);
+        let frame = VideoFrame::parse(&data, false);
+        assert!(frame.keyframe);
+        assert_eq!(frame.format(), Some(VideoFormat { profile: 100, level: 40, width: 1920, height: 1080 }));
+        assert_eq!(frame.cc_data(), [0xfc, 0x94, 0x20]);
+
+        let frame = VideoFrame::parse(&[0, 0, 1, 0x41, 0x9a], false);
+        assert!(!frame.keyframe);
+        assert_eq!(frame.format(), None);
+        assert!(frame.cc_data().is_empty());
+
+        // A CRA, which is a keyframe in HEVC but not what its header would mean in H.264
+        let mut data = vec![0, 0, 1, 0x2a, 0x01, 0xaf];
+        data.extend(&[0, 0, 1]);
+        data.extend(hevc_sps(3840, 2160, None));
+        let frame = VideoFrame::parse(&data, true);
+        assert!(frame.keyframe);
+        assert_eq!(frame.format().map(|format| format.width), Some(3840));
+        assert!(!VideoFrame::parse(&data, false).keyframe);
+    }
+}
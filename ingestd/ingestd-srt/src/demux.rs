@@ -0,0 +1,563 @@
+use std::collections::HashMap;
+
+use crate::ts;
+
+pub const PAT_PID: u16 = 0;
+
+pub const STREAM_TYPE_AAC: u8 = 0x0f;
+pub const STREAM_TYPE_H264: u8 = 0x1b;
+pub const STREAM_TYPE_HEVC: u8 = 0x24;
+/// Private data, which is Opus when it has an "Opus" registration descriptor
+pub const STREAM_TYPE_PRIVATE: u8 = 0x06;
+/// SCTE-35 splice information, carried as sections rather than PES
+pub const STREAM_TYPE_SCTE35: u8 = 0x86;
+
+pub const DESCRIPTOR_REGISTRATION: u8 = 0x05;
+pub const DESCRIPTOR_ISO_639_LANGUAGE: u8 = 0x0a;
+pub const DESCRIPTOR_EXTENSION: u8 = 0x7f;
+
+/// Largest PES that is reassembled, video keyframes at high bitrates included
+const MAX_PES_SIZE: usize = 4 * 1024 * 1024;
+
+/// An elementary stream, as listed in the PMT
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElementaryStream {
+    pub pid: u16,
+    pub stream_type: u8,
+    /// The raw ES_info descriptors
+    pub descriptors: Vec<u8>,
+}
+
+impl ElementaryStream {
+    /// Iterates over the (tag, data) of each descriptor
+    pub fn descriptors(&self) -> impl Iterator<Item=(u8, &[u8])> {
+        Descriptors(&self.descriptors)
+    }
+
+    pub fn registration(&self) -> Option<&[u8]> {
+        self.descriptors().find(|(tag, data)| *tag == DESCRIPTOR_REGISTRATION && data.len() >= 4).map(|(_, data)| &data[..4])
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.stream_type == STREAM_TYPE_H264 || self.stream_type == STREAM_TYPE_HEVC
+    }
+
+    pub fn is_opus(&self) -> bool {
+        self.stream_type == STREAM_TYPE_PRIVATE && self.registration() == Some(b"Opus")
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.stream_type == STREAM_TYPE_AAC || self.is_opus()
+    }
+
+    /// The ISO 639 language code from its language descriptor, in lower case
+    pub fn language(&self) -> Option<String> {
+        let (_, data) = self.descriptors().find(|(tag, data)| *tag == DESCRIPTOR_ISO_639_LANGUAGE && data.len() >= 4)?;
+        let language = std::str::from_utf8(&data[..3]).ok()?;
+        if !language.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+            return None;
+        }
+        Some(language.to_ascii_lowercase())
+    }
+}
+
+struct Descriptors<'a>(&'a [u8]);
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        if self.0.len() < 2 || self.0.len() < 2 + self.0[1] as usize {
+            return None;
+        }
+        let (descriptor, rest) = self.0.split_at(2 + self.0[1] as usize);
+        self.0 = rest;
+        Some((descriptor[0], &descriptor[2..]))
+    }
+}
+
+/// The program being ingested, from its PMT
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub program_number: u16,
+    pub pmt_pid: u16,
+    pub version: u8,
+    pub pcr_pid: u16,
+    pub streams: Vec<ElementaryStream>,
+}
+
+impl Program {
+    pub fn stream(&self, pid: u16) -> Option<&ElementaryStream> {
+        self.streams.iter().find(|stream| stream.pid == pid)
+    }
+}
+
+/// A reassembled PES packet
+pub struct Pes<'a> {
+    pub stream: &'a ElementaryStream,
+    /// 90kHz presentation timestamp
+    pub pts: Option<u64>,
+    pub dts: Option<u64>,
+    /// Whether the first TS packet of the PES had its random_access_indicator set
+    pub random_access: bool,
+    pub data: &'a [u8],
+}
+
+pub enum Event<'a> {
+    /// The PMT was seen for the first time, or changed
+    Program(&'a Program),
+    Pes(Pes<'a>),
+    /// A section on a PID that carries sections rather than PES
+    Section(&'a ElementaryStream, &'a [u8]),
+}
+
+#[derive(Default)]
+struct PesBuffer {
+    data: Vec<u8>,
+    random_access: bool,
+    /// Set when the start of the PES was missed or it got too big, until the next one starts
+    discard: bool,
+}
+
+#[derive(Default)]
+struct SectionBuffer {
+    /// From the start of the section being reassembled, if one has started
+    data: Vec<u8>,
+}
+
+impl SectionBuffer {
+    /// Adds a packet's payload, handing over each section it completes. Sections start where the pointer_field of a
+    /// packet with payload_unit_start_indicator set says, and are followed by another or by stuffing.
+    fn push(&mut self, payload: &[u8], unit_start: bool, on_section: &mut impl FnMut(&[u8])) {
+        let rest = if unit_start {
+            let pointer = match payload.first() {
+                Some(&pointer) => pointer as usize,
+                None => return,
+            };
+            let (end, start) = payload[1..].split_at(pointer.min(payload.len() - 1));
+            if !self.data.is_empty() {
+                self.extend(end, on_section);
+            }
+            // Whatever is left of the last one never ended
+            self.data.clear();
+            start
+        } else if self.data.is_empty() {
+            return;
+        } else {
+            payload
+        };
+        self.extend(rest, on_section);
+    }
+
+    fn extend(&mut self, data: &[u8], on_section: &mut impl FnMut(&[u8])) {
+        // Never more than a section and a packet, as section_length has 12 bits
+        self.data.extend_from_slice(data);
+        while self.data.len() >= 3 && self.data[0] != 0xff {
+            let len = 3 + ((((self.data[1] & 0x0f) as usize) << 8) | self.data[2] as usize);
+            if self.data.len() < len {
+                return;
+            }
+            on_section(&self.data[..len]);
+            self.data.drain(..len);
+        }
+        // Stuffing, or the start of a section too short to say how long it is
+        if self.data.first() == Some(&0xff) {
+            self.data.clear();
+        }
+    }
+}
+
+/// Follows the PAT and PMT of the first program in a transport stream, and reassembles the PES packets of its
+/// elementary streams. PSI sections are expected to fit in a single TS packet, which they do in practice, while
+/// private sections like SCTE-35's are reassembled across packets as they can be longer.
+#[derive(Default)]
+pub struct Demuxer {
+    pmt_pid: Option<(u16, u16)>,
+    program: Option<Program>,
+    pes: HashMap<u16, PesBuffer>,
+    sections: HashMap<u16, SectionBuffer>,
+}
+
+impl Demuxer {
+    pub fn new() -> Demuxer {
+        Demuxer::default()
+    }
+
+    pub fn program(&self) -> Option<&Program> {
+        self.program.as_ref()
+    }
+
+    pub fn push(&mut self, packet: &[u8], on_event: &mut impl FnMut(Event<'_>)) {
+        let pid = ts::pid(packet);
+        let payload = match ts::payload(packet) {
+            Some(payload) => payload,
+            None => return,
+        };
+        if pid == PAT_PID {
+            if ts::payload_unit_start(packet) {
+                self.handle_pat(payload);
+            }
+        } else if self.pmt_pid.map(|(_, pmt_pid)| pmt_pid) == Some(pid) {
+            if ts::payload_unit_start(packet) {
+                self.handle_pmt(payload, pid, on_event);
+            }
+        } else if let Some(program) = self.program.as_ref() {
+            let stream = match program.stream(pid) {
+                Some(stream) => stream,
+                None => return,
+            };
+            if stream.stream_type == STREAM_TYPE_SCTE35 {
+                let buffer = self.sections.entry(pid).or_default();
+                buffer.push(payload, ts::payload_unit_start(packet), &mut |section| on_event(Event::Section(stream, section)));
+                return;
+            }
+            let buffer = self.pes.entry(pid).or_default();
+            if ts::payload_unit_start(packet) {
+                if !buffer.discard && !buffer.data.is_empty() {
+                    if let Some(pes) = parse_pes(stream, &buffer.data, buffer.random_access) {
+                        on_event(Event::Pes(pes));
+                    }
+                }
+                buffer.data.clear();
+                buffer.discard = false;
+                buffer.random_access = ts::is_random_access(packet);
+            } else if buffer.data.is_empty() {
+                buffer.discard = true;
+            }
+            if buffer.discard {
+                return;
+            }
+            if buffer.data.len() + payload.len() > MAX_PES_SIZE {
+                buffer.discard = true;
+                buffer.data.clear();
+                return;
+            }
+            buffer.data.extend_from_slice(payload);
+            // Streams with a PES_packet_length can be handed over as soon as they are complete, rather than
+            // waiting for the next one to start
+            if let Some(len) = pes_packet_length(&buffer.data) {
+                if buffer.data.len() >= len {
+                    if let Some(pes) = parse_pes(stream, &buffer.data[..len], buffer.random_access) {
+                        on_event(Event::Pes(pes));
+                    }
+                    buffer.data.clear();
+                }
+            }
+        }
+    }
+
+    fn handle_pat(&mut self, payload: &[u8]) {
+        let section = match section(payload, 0x00) {
+            Some(section) => section,
+            None => return,
+        };
+        let first = first_program(section);
+        if first != self.pmt_pid {
+            self.pmt_pid = first;
+            self.program = None;
+            self.pes.clear();
+            self.sections.clear();
+        }
+    }
+
+    fn handle_pmt(&mut self, payload: &[u8], pmt_pid: u16, on_event: &mut impl FnMut(Event<'_>)) {
+        let section = match section(payload, 0x02) {
+            Some(section) => section,
+            None => return,
+        };
+        let program = match parse_pmt(section, pmt_pid) {
+            Some(program) => program,
+            None => return,
+        };
+        if self.program.as_ref() == Some(&program) {
+            return;
+        }
+        self.pes.retain(|pid, _| program.stream(*pid).is_some());
+        self.sections.retain(|pid, _| program.stream(*pid).is_some());
+        self.program = Some(program);
+        on_event(Event::Program(self.program.as_ref().unwrap()));
+    }
+}
+
+/// The PSI section starting in `payload`, from its table_id to its CRC inclusive, if it has the expected table_id,
+/// is the current one and fits in the packet
+pub fn section(payload: &[u8], table_id: u8) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let section = payload.get(1 + pointer..)?;
+    if section.len() < 3 || section[0] != table_id {
+        return None;
+    }
+    let len = 3 + ((((section[1] & 0x0f) as usize) << 8) | section[2] as usize);
+    // Long enough for the extended header and CRC, with current_next_indicator set
+    if len < 12 || section.len() < len || section[5] & 0x01 == 0 {
+        return None;
+    }
+    Some(&section[..len])
+}
+
+/// The program_number and PMT PID of the first program in a PAT section
+pub fn first_program(section: &[u8]) -> Option<(u16, u16)> {
+    // After the 5 byte header, up to the CRC
+    let programs = &section[8..section.len() - 4];
+    programs.chunks_exact(4)
+        .map(|entry| (u16::from_be_bytes([entry[0], entry[1]]), u16::from_be_bytes([entry[2] & 0x1f, entry[3]])))
+        .find(|(program_number, _)| *program_number != 0)
+}
+
+pub fn parse_pmt(section: &[u8], pmt_pid: u16) -> Option<Program> {
+    let program_number = u16::from_be_bytes([section[3], section[4]]);
+    let version = (section[5] >> 1) & 0x1f;
+    let pcr_pid = u16::from_be_bytes([section[8] & 0x1f, section[9]]);
+    let program_info_len = (((section[10] & 0x0f) as usize) << 8) | section[11] as usize;
+    let end = section.len() - 4;
+    let mut entries = section.get(12 + program_info_len..end)?;
+    let mut streams = Vec::new();
+    while entries.len() >= 5 {
+        let es_info_len = (((entries[3] & 0x0f) as usize) << 8) | entries[4] as usize;
+        let descriptors = entries.get(5..5 + es_info_len)?;
+        streams.push(ElementaryStream {
+            pid: u16::from_be_bytes([entries[1] & 0x1f, entries[2]]),
+            stream_type: entries[0],
+            descriptors: descriptors.to_vec(),
+        });
+        entries = &entries[5 + es_info_len..];
+    }
+    Some(Program {
+        program_number,
+        pmt_pid,
+        version,
+        pcr_pid,
+        streams,
+    })
+}
+
+/// The total length of the PES in `data`, if it isn't unbounded
+fn pes_packet_length(data: &[u8]) -> Option<usize> {
+    if data.len() < 6 {
+        return None;
+    }
+    match u16::from_be_bytes([data[4], data[5]]) {
+        0 => None,
+        len => Some(6 + len as usize),
+    }
+}
+
+fn parse_pes<'a>(stream: &'a ElementaryStream, data: &'a [u8], random_access: bool) -> Option<Pes<'a>> {
+    if data.len() < 9 || data[..3] != [0, 0, 1] {
+        return None;
+    }
+    let header_len = data[8] as usize;
+    let header = data.get(9..9 + header_len)?;
+    let (pts, dts) = match data[7] >> 6 {
+        0b10 if header.len() >= 5 => (Some(ts::timestamp(&header[0..5])), None),
+        0b11 if header.len() >= 10 => (Some(ts::timestamp(&header[0..5])), Some(ts::timestamp(&header[5..10]))),
+        _ => (None, None),
+    };
+    Some(Pes {
+        stream,
+        pts,
+        dts,
+        random_access,
+        data: &data[9 + header_len..],
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ts::PACKET_SIZE;
+
+    /// Where `pat` says the PMT is
+    pub(crate) const PMT_PID: u16 = 0x1000;
+    const VIDEO_PID: u16 = 0x100;
+    const AUDIO_PID: u16 = 0x101;
+    const SCTE35_PID: u16 = 0x1f0;
+
+    /// A packet carrying a PSI section, with its CRC added
+    pub(crate) fn section_packet(pid: u16, mut section: Vec<u8>) -> Vec<u8> {
+        let section_len = section.len() + 4 - 3;
+        section[1] = 0xb0 | (section_len >> 8) as u8;
+        section[2] = section_len as u8;
+        let crc = ts::crc32(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+        let mut packet = vec![ts::SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x10, 0];
+        packet.extend_from_slice(&section);
+        packet.resize(PACKET_SIZE, 0xff);
+        packet
+    }
+
+    /// A PAT for program 1, with its PMT on `PMT_PID`
+    pub(crate) fn pat() -> Vec<u8> {
+        section_packet(PAT_PID, vec![0x00, 0, 0, 0, 1, 0xc1, 0, 0, 0, 1, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8])
+    }
+
+    fn pmt(version: u8, streams: &[(u8, u16)]) -> Vec<u8> {
+        let mut section = vec![0x02, 0, 0, 0, 1, 0xc1 | version << 1, 0, 0, 0xe0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, 0xf0, 0];
+        for &(stream_type, pid) in streams {
+            section.extend_from_slice(&[stream_type, 0xe0 | (pid >> 8) as u8, pid as u8, 0xf0, 0]);
+        }
+        section_packet(PMT_PID, section)
+    }
+
+    /// A PES with a PTS, which gives its length if `bounded`
+    fn pes(pts: u64, payload: &[u8], bounded: bool) -> Vec<u8> {
+        let len = if bounded { 3 + 5 + payload.len() } else { 0 };
+        let mut pes = vec![0, 0, 1, 0xe0, (len >> 8) as u8, len as u8, 0x80, 0x80, 5];
+        pes.extend_from_slice(&[
+            0x21 | ((pts >> 29) & 0x0e) as u8,
+            (pts >> 22) as u8,
+            0x01 | ((pts >> 14) & 0xfe) as u8,
+            (pts >> 7) as u8,
+            0x01 | ((pts << 1) & 0xfe) as u8,
+        ]);
+        pes.extend_from_slice(payload);
+        pes
+    }
+
+    /// Splits `data` over as many packets as it takes, stuffing the last one's adaptation field
+    fn ts_packets(pid: u16, data: &[u8], random_access: bool) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut rest = data;
+        while packets.is_empty() || !rest.is_empty() {
+            let first = packets.is_empty();
+            let flags = if first && random_access { Some(0x40) } else { None };
+            let len = rest.len().min(if flags.is_some() { 182 } else { 184 });
+            let mut packet = vec![ts::SYNC_BYTE, if first { 0x40 } else { 0 } | (pid >> 8) as u8, pid as u8, 0x10];
+            if flags.is_some() || len < 184 {
+                packet[3] |= 0x20;
+                let field_len = 183 - len;
+                packet.push(field_len as u8);
+                if field_len > 0 {
+                    packet.push(flags.unwrap_or(0));
+                    packet.resize(5 + field_len, 0xff);
+                }
+            }
+            packet.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Seen {
+        Program(u8, Vec<u16>),
+        Pes(u16, Option<u64>, bool, Vec<u8>),
+        Section(u16, Vec<u8>),
+    }
+
+    fn push(demuxer: &mut Demuxer, packets: &[Vec<u8>]) -> Vec<Seen> {
+        let mut seen = Vec::new();
+        for packet in packets {
+            demuxer.push(packet, &mut |event| seen.push(match event {
+                Event::Program(program) => Seen::Program(program.version, program.streams.iter().map(|stream| stream.pid).collect()),
+                Event::Pes(pes) => Seen::Pes(pes.stream.pid, pes.pts, pes.random_access, pes.data.to_vec()),
+                Event::Section(stream, section) => Seen::Section(stream.pid, section.to_vec()),
+            }));
+        }
+        seen
+    }
+
+    fn demuxer_with_program() -> Demuxer {
+        let mut demuxer = Demuxer::new();
+        push(&mut demuxer, &[pat(), pmt(0, &[(STREAM_TYPE_H264, VIDEO_PID), (STREAM_TYPE_AAC, AUDIO_PID), (STREAM_TYPE_SCTE35, SCTE35_PID)])]);
+        demuxer
+    }
+
+    #[test]
+    fn reports_the_program_when_it_changes() {
+        let mut demuxer = Demuxer::new();
+        // Nothing until the PAT says where the PMT is
+        assert_eq!(push(&mut demuxer, &[pmt(0, &[(STREAM_TYPE_H264, VIDEO_PID)])]), []);
+        assert_eq!(push(&mut demuxer, &[pat(), pmt(0, &[(STREAM_TYPE_H264, VIDEO_PID)])]), [Seen::Program(0, vec![VIDEO_PID])]);
+        assert_eq!(push(&mut demuxer, &[pat(), pmt(0, &[(STREAM_TYPE_H264, VIDEO_PID)])]), []);
+        assert_eq!(
+            push(&mut demuxer, &[pmt(1, &[(STREAM_TYPE_H264, VIDEO_PID), (STREAM_TYPE_AAC, AUDIO_PID)])]),
+            [Seen::Program(1, vec![VIDEO_PID, AUDIO_PID])],
+        );
+        assert_eq!(demuxer.program().map(|program| program.streams.len()), Some(2));
+    }
+
+    #[test]
+    fn reassembles_unbounded_pes_when_the_next_starts() {
+        let mut demuxer = demuxer_with_program();
+        let payload = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        let packets = ts_packets(VIDEO_PID, &pes(900000, &payload, false), true);
+        assert_eq!(packets.len(), 6);
+        assert!(packets.iter().all(|packet| packet.len() == PACKET_SIZE));
+        assert_eq!(push(&mut demuxer, &packets), []);
+        let next = ts_packets(VIDEO_PID, &pes(903003, &[1, 2, 3], false), false);
+        assert_eq!(push(&mut demuxer, &next), [Seen::Pes(VIDEO_PID, Some(900000), true, payload)]);
+    }
+
+    #[test]
+    fn hands_over_bounded_pes_once_complete() {
+        let mut demuxer = demuxer_with_program();
+        let payload = vec![0x55; 300];
+        let seen = push(&mut demuxer, &ts_packets(AUDIO_PID, &pes(1 << 32, &payload, true), false));
+        assert_eq!(seen, [Seen::Pes(AUDIO_PID, Some(1 << 32), false, payload)]);
+    }
+
+    #[test]
+    fn discards_pes_whose_start_was_missed() {
+        let mut demuxer = demuxer_with_program();
+        let packets = ts_packets(VIDEO_PID, &pes(0, &[0x11; 500], false), false);
+        assert_eq!(push(&mut demuxer, &packets[1..]), []);
+        let next = ts_packets(VIDEO_PID, &pes(3003, &[0x22; 10], false), false);
+        assert_eq!(push(&mut demuxer, &next), []);
+        let after = ts_packets(VIDEO_PID, &pes(6006, &[0x33; 10], false), false);
+        assert_eq!(push(&mut demuxer, &after), [Seen::Pes(VIDEO_PID, Some(3003), false, vec![0x22; 10])]);
+    }
+
+    #[test]
+    fn passes_sections_through() {
+        let mut demuxer = demuxer_with_program();
+        let section = vec![0xfc, 0x30, 0x05, 1, 2, 3, 4, 5];
+        let mut payload = vec![0];
+        payload.extend_from_slice(&section);
+        assert_eq!(push(&mut demuxer, &ts_packets(SCTE35_PID, &payload, false)), [Seen::Section(SCTE35_PID, section)]);
+    }
+
+    /// A private section with `len` bytes after its length
+    fn private_section(len: usize, fill: u8) -> Vec<u8> {
+        let mut section = vec![0xfc, 0x30 | (len >> 8) as u8, len as u8];
+        section.resize(3 + len, fill);
+        section
+    }
+
+    #[test]
+    fn reassembles_sections_across_packets() {
+        let mut demuxer = demuxer_with_program();
+        let long = private_section(400, 0x11);
+        let mut payload = vec![0];
+        payload.extend_from_slice(&long);
+        let packets = ts_packets(SCTE35_PID, &payload, false);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(push(&mut demuxer, &packets[..2]), []);
+        assert_eq!(push(&mut demuxer, &packets[2..]), [Seen::Section(SCTE35_PID, long.clone())]);
+
+        // Two that start in the packet another ends in, followed by stuffing
+        let (first, second) = (private_section(300, 0x22), private_section(10, 0x33));
+        let mut payload = vec![0];
+        payload.extend_from_slice(&first);
+        let mut packets = ts_packets(SCTE35_PID, &payload[..184], false);
+        let end = &first[183..];
+        let mut last = vec![ts::SYNC_BYTE, 0x40 | (SCTE35_PID >> 8) as u8, SCTE35_PID as u8, 0x10, end.len() as u8];
+        last.extend_from_slice(end);
+        last.extend_from_slice(&second);
+        last.extend_from_slice(&second);
+        last.resize(PACKET_SIZE, 0xff);
+        packets.push(last);
+        assert_eq!(push(&mut demuxer, &packets), [
+            Seen::Section(SCTE35_PID, first),
+            Seen::Section(SCTE35_PID, second.clone()),
+            Seen::Section(SCTE35_PID, second),
+        ]);
+
+        // The rest of a section whose start was missed
+        let mut payload = vec![0];
+        payload.extend_from_slice(&long);
+        let packets = ts_packets(SCTE35_PID, &payload, false);
+        assert_eq!(push(&mut demuxer, &packets[1..]), []);
+    }
+}
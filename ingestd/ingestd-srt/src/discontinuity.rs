@@ -0,0 +1,240 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::ts::{self, PACKET_SIZE, SYNC_BYTE, TIMESTAMP_MODULUS};
+
+/// What to do when the stream's timestamps jump, as they do when an encoder restarts without reconnecting
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Offset every later PCR, PTS and DTS so that the timeline carries on from where it was
+    Rewrite,
+    /// Restart the packager on a new DASH Period, which starts from the new timestamps
+    NewPeriod,
+}
+
+/// Keys timestamps by PID and whether they are PCRs, as the PCR PID usually carries video PES too
+fn key(pid: u16, pcr: bool) -> u32 {
+    pid as u32 | if pcr { 1 << 16 } else { 0 }
+}
+
+/// The difference between two 33-bit timestamps, taking the shortest way around
+pub fn difference(a: u64, b: u64) -> i64 {
+    let difference = (a + TIMESTAMP_MODULUS - b) % TIMESTAMP_MODULUS;
+    if difference > TIMESTAMP_MODULUS / 2 {
+        difference as i64 - TIMESTAMP_MODULUS as i64
+    } else {
+        difference as i64
+    }
+}
+
+fn add(timestamp: u64, offset: u64) -> u64 {
+    (timestamp + offset) % TIMESTAMP_MODULUS
+}
+
+/// Follows the timestamps of a stream, comparing how far each PID's has moved on with how much time has passed
+pub struct Timeline {
+    strategy: Strategy,
+    /// In 90kHz units
+    threshold: u64,
+    /// Added to every timestamp when rewriting, modulo 2^33
+    offset: u64,
+    last: HashMap<u32, (u64, Instant)>,
+}
+
+impl Timeline {
+    pub fn new(strategy: Strategy, threshold: f64) -> Timeline {
+        Timeline {
+            strategy,
+            threshold: (threshold * 90000.0) as u64,
+            offset: 0,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Forgets where each PID's timestamps were, keeping the offset, for when a packager is started and there may have
+    /// been a pause since they were last seen
+    pub fn reset(&mut self) {
+        self.last.clear();
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Whether `timestamp` is too far from where the last one on `key` should have got to by `now`, in which case it is
+    /// where the timeline continues from for all PIDs
+    fn check(&mut self, key: u32, timestamp: u64, now: Instant) -> bool {
+        let jump = self.last.get(&key).and_then(|&(last, at)| {
+            let elapsed = (now.duration_since(at).as_secs_f64() * 90000.0) as u64;
+            let difference = difference(timestamp, add(last, elapsed));
+            if difference.abs() as u64 > self.threshold { Some(difference) } else { None }
+        });
+        if let Some(jump) = jump {
+            // Every PID jumps at once, so the others mustn't be compared with where they were before
+            self.last.clear();
+            if self.strategy == Strategy::Rewrite {
+                self.offset = (self.offset + TIMESTAMP_MODULUS - jump.rem_euclid(TIMESTAMP_MODULUS as i64) as u64) % TIMESTAMP_MODULUS;
+            }
+        }
+        self.last.insert(key, (timestamp, now));
+        jump.is_some()
+    }
+
+    /// Where a timestamp from the stream ends up, for ones carried outside PES headers, like SCTE-35 splice times
+    pub fn rewrite(&self, timestamp: u64) -> u64 {
+        add(timestamp, self.offset)
+    }
+
+    /// Checks the timestamps in an SRT message, rewriting them if that is the strategy. Returns whether they jumped.
+    pub fn process(&mut self, message: &mut [u8], now: Instant) -> bool {
+        let mut jumped = false;
+        for packet in message.chunks_exact_mut(PACKET_SIZE).filter(|packet| packet[0] == SYNC_BYTE) {
+            let pid = ts::pid(packet);
+            if let Some(pcr) = ts::pcr(packet) {
+                jumped |= self.check(key(pid, true), pcr, now);
+                if self.offset != 0 {
+                    ts::set_pcr(packet, add(pcr, self.offset));
+                }
+            }
+            let (pts, dts) = ts::pes_timestamps(packet);
+            // The DTS is monotonic where the PTS isn't, with B-frames
+            if let Some(position) = dts.or(pts) {
+                jumped |= self.check(key(pid, false), ts::timestamp(&packet[position..]), now);
+            }
+            if self.offset != 0 {
+                for position in pts.into_iter().chain(dts) {
+                    let timestamp = ts::timestamp(&packet[position..]);
+                    ts::set_timestamp(&mut packet[position..], add(timestamp, self.offset));
+                }
+            }
+        }
+        jumped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const VIDEO_PID: u16 = 0x100;
+
+    /// A packet with only an adaptation field carrying a PCR
+    fn pcr_packet(pid: u16, pcr: u64) -> Vec<u8> {
+        let mut packet = vec![0xff; PACKET_SIZE];
+        packet[..4].copy_from_slice(&[SYNC_BYTE, (pid >> 8) as u8, pid as u8, 0x20]);
+        packet[4] = 183;
+        packet[5] = 0x10;
+        packet[10] = 0x7e;
+        packet[11] = 0;
+        ts::set_pcr(&mut packet, pcr);
+        packet
+    }
+
+    /// A packet starting a video PES with a PTS and DTS
+    fn pes_packet(pid: u16, pts: u64, dts: u64) -> Vec<u8> {
+        let mut packet = vec![0; PACKET_SIZE];
+        packet[..4].copy_from_slice(&[SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x10]);
+        packet[4..13].copy_from_slice(&[0, 0, 1, 0xe0, 0, 0, 0x80, 0xc0, 10]);
+        packet[13] = 0x30;
+        ts::set_timestamp(&mut packet[13..], pts);
+        packet[18] = 0x10;
+        ts::set_timestamp(&mut packet[18..], dts);
+        packet
+    }
+
+    fn packets(pcr: u64, pts: u64, dts: u64) -> Vec<u8> {
+        let mut message = pcr_packet(VIDEO_PID, pcr);
+        message.extend(pes_packet(VIDEO_PID, pts, dts));
+        message
+    }
+
+    fn timestamps(message: &[u8]) -> (u64, u64, u64) {
+        let pes = &message[PACKET_SIZE..];
+        (ts::pcr(message).unwrap(), ts::timestamp(&pes[13..]), ts::timestamp(&pes[18..]))
+    }
+
+    #[test]
+    fn rewrite_keeps_the_timeline_continuous() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(Strategy::Rewrite, 1.0);
+        for second in 0..3 {
+            let mut message = packets(1_000_000 + second * 90000, 1_003_000 + second * 90000, 1_000_000 + second * 90000);
+            assert!(!timeline.process(&mut message, start + Duration::from_secs(second)));
+            assert_eq!(timestamps(&message), (1_000_000 + second * 90000, 1_003_000 + second * 90000, 1_000_000 + second * 90000));
+        }
+
+        // The encoder restarts from 0 a second later
+        let mut message = packets(0, 3000, 0);
+        assert!(timeline.process(&mut message, start + Duration::from_secs(3)));
+        assert_eq!(timestamps(&message), (1_270_000, 1_273_000, 1_270_000));
+
+        let mut message = packets(90000, 93000, 90000);
+        assert!(!timeline.process(&mut message, start + Duration::from_secs(4)));
+        assert_eq!(timestamps(&message), (1_360_000, 1_363_000, 1_360_000));
+    }
+
+    #[test]
+    fn rewrite_wraps_around() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(Strategy::Rewrite, 1.0);
+        timeline.process(&mut packets(TIMESTAMP_MODULUS - 45000, TIMESTAMP_MODULUS - 42000, TIMESTAMP_MODULUS - 45000), start);
+        let mut message = packets(45000, 48000, 45000);
+        assert!(!timeline.process(&mut message, start + Duration::from_secs(1)));
+        assert_eq!(timestamps(&message), (45000, 48000, 45000));
+
+        // A restart that jumps back past the wraparound
+        let mut message = packets(TIMESTAMP_MODULUS - 90000, TIMESTAMP_MODULUS - 87000, TIMESTAMP_MODULUS - 90000);
+        assert!(timeline.process(&mut message, start + Duration::from_secs(2)));
+        assert_eq!(timestamps(&message), (135000, 138000, 135000));
+    }
+
+    #[test]
+    fn new_period_reports_jumps_without_rewriting() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(Strategy::NewPeriod, 1.0);
+        assert!(!timeline.process(&mut packets(1_000_000, 1_003_000, 1_000_000), start));
+        assert!(!timeline.process(&mut packets(1_090_000, 1_093_000, 1_090_000), start + Duration::from_secs(1)));
+
+        // Timestamps jump forwards by ten minutes
+        let mut message = packets(55_000_000, 55_003_000, 55_000_000);
+        assert!(timeline.process(&mut message, start + Duration::from_secs(2)));
+        assert_eq!(timestamps(&message), (55_000_000, 55_003_000, 55_000_000));
+        assert!(!timeline.process(&mut packets(55_090_000, 55_093_000, 55_090_000), start + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn reset_forgets_timestamps_from_before_a_pause() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(Strategy::Rewrite, 1.0);
+        assert!(!timeline.process(&mut packets(1_000_000, 1_003_000, 1_000_000), start));
+        // The encoder restarts from 0, and the stream carries on from where it was
+        let mut message = packets(0, 3000, 0);
+        assert!(timeline.process(&mut message, start + Duration::from_secs(1)));
+        assert_eq!(timestamps(&message), (1_090_000, 1_093_000, 1_090_000));
+
+        // Nothing is read while the packager restarts, and the stream then carries on from where it is queued
+        timeline.reset();
+        let mut message = packets(90000, 93000, 90000);
+        assert!(!timeline.process(&mut message, start + Duration::from_secs(10)));
+        assert_eq!(timestamps(&message), (1_180_000, 1_183_000, 1_180_000));
+
+        let mut timeline = Timeline::new(Strategy::Rewrite, 1.0);
+        timeline.process(&mut packets(1_000_000, 1_003_000, 1_000_000), start);
+        // Which would otherwise look like timestamps that stopped for nine seconds
+        assert!(timeline.process(&mut packets(1_090_000, 1_093_000, 1_090_000), start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn tolerates_jitter() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(Strategy::NewPeriod, 1.0);
+        assert!(!timeline.process(&mut packets(1_000_000, 1_003_000, 1_000_000), start));
+        // Arriving in a burst after SRT held it back for latency
+        assert!(!timeline.process(&mut packets(1_045_000, 1_048_000, 1_045_000), start + Duration::from_millis(100)));
+        assert!(!timeline.process(&mut packets(1_090_000, 1_093_000, 1_090_000), start + Duration::from_millis(150)));
+    }
+}
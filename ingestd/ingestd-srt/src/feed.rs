@@ -0,0 +1,268 @@
+use futures::channel::oneshot;
+use futures::prelude::*;
+use futures::{pin_mut, select};
+use smol::Timer;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::discontinuity::{self, Timeline};
+use crate::log::Logger;
+use crate::metadata::Metadata;
+use crate::monitor::Monitor;
+use crate::playout::{Channel, Playout};
+use crate::pool::Batch;
+use crate::sessions::{Registration, Summary};
+use crate::slate::{Slate, SlateConfig};
+use crate::srt::SrtStream;
+use crate::tracks::TrackFilter;
+use crate::ts;
+
+/// How much of what the contributor sends is held for the next gpac while one is being restarted, about 16 seconds of
+/// an 8Mbit/s stream
+const BACKLOG_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long a new contributor has to send its PMT, before it is packaged as if it had video
+pub const PROGRAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A streamer's SRT connection, and what is known about what it sends
+pub struct Contribution {
+    pub stream: SrtStream,
+    pub registration: Registration<'static>,
+    pub logger: Logger,
+    pub monitor: Monitor,
+    pub tracks: TrackFilter,
+}
+
+pub enum Received {
+    Messages,
+    /// The timestamps jumped, and were rewritten to carry on from where they were
+    Rewritten,
+    /// The timestamps jumped, and the messages from the jump on were left out, for gpac to be restarted on a new Period
+    NewPeriod,
+    StreamerGone,
+}
+
+impl Contribution {
+    /// Receives everything the streamer has sent since the last wakeup
+    pub async fn recv_batch(&mut self, batch: &mut Batch<'_>, wait_for_keyframe: &mut bool, timeline: &mut Timeline) -> Received {
+        batch.clear();
+        let mut next = self.stream.recv(batch.next_slot()).await.map(Some);
+        let now = Instant::now();
+        let mut received = Received::Messages;
+        loop {
+            let len = match next {
+                Ok(Some(0)) => {
+                    eprintln!("0-length message");
+                    return Received::StreamerGone;
+                },
+                Ok(Some(len)) => len,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Received::StreamerGone;
+                },
+            };
+
+            if timeline.process(&mut batch.next_slot()[..len], now) {
+                if timeline.strategy() == discontinuity::Strategy::NewPeriod {
+                    // The new gpac waits for a keyframe anyway
+                    return Received::NewPeriod;
+                }
+                received = Received::Rewritten;
+            }
+            self.monitor.push(&batch.next_slot()[..len], now);
+            // The monitor still sees the tracks that are left out, so that they are in the metadata
+            let len = self.tracks.filter(&mut batch.next_slot()[..len]);
+            if len == 0 {
+                next = self.stream.try_recv(batch.next_slot());
+                continue;
+            }
+
+            // After a restart, gpac can't do anything useful with the stream until it sees a keyframe
+            if !*wait_for_keyframe || ts::contains_keyframe(&batch.next_slot()[..len]) {
+                *wait_for_keyframe = false;
+                batch.commit(len);
+            }
+            if batch.is_full() {
+                break;
+            }
+            next = self.stream.try_recv(batch.next_slot());
+        }
+        received
+    }
+
+    /// Receives until the PMT says whether the stream is audio-only, which gpac has to be started for. What comes
+    /// before that is left out, as gpac can't use it without the PMT.
+    pub async fn wait_for_program(&mut self, batch: &mut Batch<'_>) -> bool {
+        let deadline = Timer::new(PROGRAM_TIMEOUT).fuse();
+        pin_mut!(deadline);
+        loop {
+            if let Some(audio_only) = self.monitor.audio_only() {
+                return audio_only;
+            }
+            let received = {
+                let recv = self.stream.recv(batch.next_slot()).fuse();
+                pin_mut!(recv);
+                select! {
+                    res = recv => res,
+                    _ = deadline => return false,
+                }
+            };
+            match received {
+                Ok(len) if len > 0 => self.monitor.push(&batch.next_slot()[..len], Instant::now()),
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// What a packager is fed while no contributor is connected
+pub enum Standby {
+    Slate(Slate),
+    Playout(Playout),
+}
+
+impl Standby {
+    /// Channels play out their schedule, and other streams show the slate if they have one. None if the stream has
+    /// neither, or is a channel that has been removed.
+    pub fn open(channel: Option<&Arc<Channel>>, slate_config: Option<&SlateConfig>, logger: &Logger, now: Instant) -> io::Result<Option<Standby>> {
+        match (channel, slate_config) {
+            (Some(channel), _) if !channel.removed.load(Ordering::SeqCst) => Ok(Some(Standby::Playout(Playout::new(channel.clone(), logger.clone())))),
+            (Some(_), _) | (None, None) => Ok(None),
+            (None, Some(slate_config)) => Slate::open(&slate_config.path, now).map(|slate| Some(Standby::Slate(slate))),
+        }
+    }
+
+    pub fn due(&mut self) -> Option<Instant> {
+        match *self {
+            Standby::Slate(ref slate) => Some(slate.due()),
+            Standby::Playout(ref mut playout) => playout.due(),
+        }
+    }
+
+    fn next_due(&mut self, now: Instant) -> Option<&[u8]> {
+        match *self {
+            Standby::Slate(ref mut slate) => slate.next_due(now),
+            Standby::Playout(ref mut playout) => playout.next_due(now),
+        }
+    }
+
+    /// Adds the messages that are due to the batch
+    pub fn fill(&mut self, batch: &mut Batch<'_>, wait_for_keyframe: &mut bool, timeline: &mut Timeline, now: Instant) {
+        while !batch.is_full() {
+            let message = match self.next_due(now) {
+                Some(message) => message,
+                None => break,
+            };
+            let len = message.len();
+            batch.next_slot()[..len].copy_from_slice(message);
+            // Standby files' timestamps start over each time they loop, which is rewritten like any other jump
+            timeline.process(&mut batch.next_slot()[..len], now);
+            if !*wait_for_keyframe || ts::contains_keyframe(&batch.next_slot()[..len]) {
+                *wait_for_keyframe = false;
+                batch.commit(len);
+            }
+        }
+    }
+}
+
+/// A packager on standby
+pub struct Away {
+    pub standby: Standby,
+    /// When the session is ended if no contributor has connected, for slates
+    pub until: Option<Instant>,
+    pub handover: Option<oneshot::Receiver<Contribution>>,
+    /// Set once a contributor has been handed over, until their PMT says whether they are audio-only, or they are taken
+    /// not to be once it has passed
+    pub program_by: Option<Instant>,
+    /// The last contribution that went away, and its last known metadata, for the offline notification
+    pub ended: Option<(Summary, Option<Metadata>)>,
+}
+
+/// What a packager is being fed from
+pub struct Feed {
+    pub stream_id: u32,
+    pub packager: Uuid,
+    /// The contributor's connection. While on standby, this is one that has connected but not sent a keyframe yet.
+    pub live: Option<Contribution>,
+    pub away: Option<Away>,
+    /// Set for channels, which play out their schedule while on standby, and never end while the channel is there
+    pub channel: Option<Arc<Channel>>,
+    /// Whether the contributor's PMT had audio but no video. Audio-only streams are packaged without waiting for
+    /// keyframes, as they don't mark any. A contributor that returns with or without video unlike the last one
+    /// restarts gpac on a new Period.
+    pub audio_only: bool,
+}
+
+/// What the contributor sends while gpac is being restarted, from its first keyframe, for the next gpac to start with
+pub struct Backlog {
+    pub data: Vec<u8>,
+    pub wait_for_keyframe: bool,
+    /// The timestamps jumped while restarting, which needs a new Period even if the restart wasn't for one
+    pub jumped: bool,
+}
+
+impl Backlog {
+    /// Empty, for the first gpac, which starts from whatever it is sent
+    pub fn new() -> Backlog {
+        Backlog {
+            data: Vec::new(),
+            wait_for_keyframe: false,
+            jumped: false,
+        }
+    }
+
+    /// Starts over for another restart
+    pub fn start(&mut self, wait_for_keyframe: bool) {
+        self.data.clear();
+        self.wait_for_keyframe = wait_for_keyframe;
+        self.jumped = false;
+    }
+
+    pub fn push(&mut self, message: &[u8]) {
+        if self.data.len() + message.len() > BACKLOG_SIZE {
+            // Too far behind to catch up, so the next gpac starts from a later keyframe instead
+            self.data.clear();
+            self.wait_for_keyframe = true;
+            return;
+        }
+        self.data.extend_from_slice(message);
+    }
+}
+
+/// Packagers on standby, by stream id, waiting for a contributor to connect
+pub struct Handovers(Mutex<HashMap<u32, (Uuid, oneshot::Sender<Contribution>)>>);
+
+impl Handovers {
+    pub fn new() -> Handovers {
+        Handovers(Mutex::new(HashMap::new()))
+    }
+
+    /// Hands a contribution to the packager on standby for its stream, or back if there isn't one
+    pub fn hand_over(&self, contribution: Contribution) -> Result<(), Contribution> {
+        let stream_id = contribution.registration.session().stream_id;
+        let mut standby = self.0.lock().unwrap();
+        match standby.remove(&stream_id) {
+            // Sent with the lock held, so that a packager that has stopped waiting is sure to find it
+            Some((_, handover)) => handover.send(contribution),
+            None => Err(contribution),
+        }
+    }
+
+    pub fn wait_for_return(&self, stream_id: u32, packager: Uuid) -> oneshot::Receiver<Contribution> {
+        let (sender, receiver) = oneshot::channel();
+        self.0.lock().unwrap().insert(stream_id, (packager, sender));
+        receiver
+    }
+
+    pub fn stop_waiting(&self, stream_id: u32, packager: Uuid) {
+        let mut standby = self.0.lock().unwrap();
+        if standby.get(&stream_id).map(|(waiting, _)| *waiting == packager).unwrap_or(false) {
+            standby.remove(&stream_id);
+        }
+    }
+}
@@ -1,26 +1,234 @@
+use futures::channel::{mpsc, oneshot};
 use futures::prelude::*;
-use futures::future::poll_fn;
-use futures::task::{AtomicWaker, Poll};
-use futures::{pin_mut, select};
+use futures::{future, pin_mut, select};
 use http_types::Url;
+use openat::Dir;
 use pathsearch::find_executable_in_path;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use smol::{Async, Task, Timer};
-use sqlx::{SqliteConnection, query};
+use std::collections::{HashMap, VecDeque};
 use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs::File;
 use std::os::unix::ffi::OsStringExt;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+use crate::cgroup::{CLONE_INTO_CGROUP, Cgroups, SessionCgroup, Usage};
+use crate::discontinuity::{self, Timeline};
+use crate::feed::{Away, Backlog, Contribution, Feed, Handovers, PROGRAM_TIMEOUT, Received, Standby};
+use crate::health::{self, HealthChange, HealthConfig};
 use crate::log::Logger;
-use crate::notify::{notify_online, notify_offline};
-use crate::pidfd::Pidfd;
-use crate::shared::{Connection, NewConnection};
+use crate::metadata::Metadata;
+use crate::metrics;
+use crate::monitor::Monitor;
+use crate::notify::{notify_cue, notify_health, notify_metadata, notify_online, notify_offline, notify_playout, notify_slate};
+use crate::pidfd::{ExitStatus, Pidfd};
+use crate::pool::{Batch, BufferPool};
+use crate::sandbox::{self, Sandbox};
+use crate::playout::{Channel, Playout};
+use crate::scte35::Cue;
+use crate::sessions::{Registry, Session, Summary, unix_time};
+use crate::slate::SlateConfig;
+use crate::srt::AsyncListener;
+use crate::store::{ChannelStream, StreamStore};
+use crate::timed_metadata::TimedMetadata;
+use crate::tracks::TrackFilter;
 
-fn spawn(gpac_path: &CStr, argv: Vec<CString>, logger: &Logger) -> std::io::Result<(Pidfd, UnixStream)> {
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct SupervisionConfig {
+    /// How many times gpac may be restarted within `restart-window` seconds before the session is ended
+    pub max_restarts: usize,
+    pub restart_window: u64,
+}
+
+impl SupervisionConfig {
+    fn restart_window(&self) -> Duration {
+        Duration::from_secs(self.restart_window)
+    }
+}
+
+impl Default for SupervisionConfig {
+    fn default() -> SupervisionConfig {
+        SupervisionConfig {
+            max_restarts: 3,
+            restart_window: 60,
+        }
+    }
+}
+
+/// Why a session ended, as reported in the offline notification
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    StreamerDisconnected,
+    PackagerExited,
+    PackagerOomKilled,
+    /// The health watchdog gave up on a stream that was connected but not sending anything useful
+    Stalled,
+    /// The stream stopped being an active channel while playing out
+    ChannelRemoved,
+}
+
+impl EndReason {
+    /// As serialized, for storing in the sessions table
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            EndReason::StreamerDisconnected => "streamer_disconnected",
+            EndReason::PackagerExited => "packager_exited",
+            EndReason::PackagerOomKilled => "packager_oom_killed",
+            EndReason::Stalled => "stalled",
+            EndReason::ChannelRemoved => "channel_removed",
+        }
+    }
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            EndReason::StreamerDisconnected => "streamer disconnected",
+            EndReason::PackagerExited => "packager exited",
+            EndReason::PackagerOomKilled => "packager was OOM killed",
+            EndReason::Stalled => "stream stalled",
+            EndReason::ChannelRemoved => "channel removed",
+        })
+    }
+}
+
+/// Settings shared by every session, which live for the rest of the process
+struct Context {
+    gpac_path: CString,
+    httpd_url: String,
+    external_url: String,
+    log_dir: Dir,
+    store: &'static dyn StreamStore,
+    supervision: SupervisionConfig,
+    health: HealthConfig,
+    sandbox: Option<Sandbox>,
+    cgroups: Option<Cgroups>,
+    buffers: BufferPool,
+    sessions: &'static Registry,
+    handovers: Handovers,
+    timed_metadata: &'static TimedMetadata,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PackagingConfig {
+    /// DASH segment duration in seconds
+    pub segment_duration: f64,
+    /// Segment duration for audio-only streams, which can be cut at any audio frame rather than waiting for a keyframe
+    pub audio_only_segment_duration: f64,
+    /// CMAF chunk duration in seconds, for low latency delivery of partial segments
+    pub chunk_duration: f64,
+    /// Milliseconds of media gpac buffers before packaging
+    pub buffer: u32,
+    pub on_discontinuity: discontinuity::Strategy,
+    /// Seconds timestamps may drift from the wall clock before they are considered to have jumped
+    pub discontinuity_threshold: f64,
+    /// Shown instead of failing the stream when its contributor goes away, until they return
+    pub slate: Option<SlateConfig>,
+}
+
+impl PackagingConfig {
+    fn segment_duration(&self, audio_only: bool) -> f64 {
+        if audio_only { self.audio_only_segment_duration } else { self.segment_duration }
+    }
+
+    /// Segments can be requested as soon as their first chunk is done
+    fn availability_time_offset(&self, audio_only: bool) -> f64 {
+        self.segment_duration(audio_only) - self.chunk_duration
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.segment_duration.is_nan() || self.segment_duration <= 0.0 {
+            return Err("segment-duration must be positive");
+        }
+        if self.chunk_duration.is_nan() || self.chunk_duration <= 0.0 || self.chunk_duration > self.segment_duration {
+            return Err("chunk-duration must be positive and no longer than segment-duration");
+        }
+        if self.audio_only_segment_duration.is_nan() || self.audio_only_segment_duration < self.chunk_duration {
+            return Err("audio-only-segment-duration must be no shorter than chunk-duration");
+        }
+        if self.discontinuity_threshold.is_nan() || self.discontinuity_threshold <= 0.0 {
+            return Err("discontinuity-threshold must be positive");
+        }
+        if let Some(ref slate) = self.slate {
+            // Every switch to and from the slate is a jump
+            if self.on_discontinuity != discontinuity::Strategy::Rewrite {
+                return Err("a slate needs on-discontinuity = \"rewrite\"");
+            }
+            if slate.grace_period == 0 {
+                return Err("slate grace-period must be positive");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for PackagingConfig {
+    fn default() -> PackagingConfig {
+        PackagingConfig {
+            segment_duration: 8.0,
+            audio_only_segment_duration: 4.0,
+            chunk_duration: 0.1,
+            buffer: 1000,
+            on_discontinuity: discontinuity::Strategy::Rewrite,
+            discontinuity_threshold: 1.0,
+            slate: None,
+        }
+    }
+}
+
+/// The pipe over which the child side of spawn reports what failed, with its errno. The write end is close-on-exec, so
+/// the read end sees EOF once gpac has been exec'd.
+fn error_pipe() -> std::io::Result<(File, File)> {
+    use libc::*;
+    let mut fds = [0 as c_int; 2];
+    if unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    // Kept clear of the fds the child sets up for gpac, so that it can still report failing to do so
+    let moved = unsafe { fcntl(write.as_raw_fd(), F_DUPFD_CLOEXEC, 4) };
+    if moved == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((read, unsafe { File::from_raw_fd(moved) }))
+}
+
+/// Reports a failure, with the errno it left, to the parent and exits. Only async-signal-safe, for the child side of
+/// spawn.
+unsafe fn child_failed(error_fd: RawFd, error_message: &str) -> ! {
+    use libc::*;
+    let errno = (*__errno_location()).to_ne_bytes();
+    write(error_fd, errno.as_ptr() as *const c_void, errno.len());
+    write(error_fd, error_message.as_bytes() as *const [u8] as *const c_void, error_message.len());
+    _exit(127);
+}
+
+/// Starts gpac, returning once it has been exec'd
+async fn spawn(gpac_path: &CStr, argv: Vec<CString>, logger: &Logger, sandbox: Option<&Sandbox>, cgroup: Option<&SessionCgroup>) -> std::io::Result<(Pidfd, UnixStream, Option<UnixStream>)> {
+    let (pidfd, sender, control, error_read) = clone_gpac(gpac_path, argv, logger, sandbox, cgroup)?;
+    // Exec'ing, or setting up the sandbox before it, can take a while, which mustn't hold up the executor
+    let mut report = Vec::new();
+    Async::new(error_read)?.read_to_end(&mut report).await?;
+    if report.len() >= 4 {
+        drop(pidfd.guard());
+        let errno = i32::from_ne_bytes([report[0], report[1], report[2], report[3]]);
+        let os_error = std::io::Error::from_raw_os_error(errno);
+        return Err(std::io::Error::new(os_error.kind(), format!("{}: {}", String::from_utf8_lossy(&report[4..]), os_error)));
+    }
+    Ok((pidfd, sender, control))
+}
+
+/// The child side of spawn, returning the read end of the error pipe
+fn clone_gpac(gpac_path: &CStr, argv: Vec<CString>, logger: &Logger, sandbox: Option<&Sandbox>, cgroup: Option<&SessionCgroup>) -> std::io::Result<(Pidfd, UnixStream, Option<UnixStream>, File)> {
     use libc::*;
     use crate::syscall::{CloneArgs, clone3};
 
@@ -28,10 +236,22 @@ fn spawn(gpac_path: &CStr, argv: Vec<CString>, logger: &Logger) -> std::io::Resu
     let argv = std::iter::once(gpac_path.as_ptr()).chain(argv.iter().map(|s| s.as_ptr())).chain(std::iter::once(std::ptr::null())).collect::<Vec<_>>();
 
     let (sender, receiver) = UnixStream::pair()?;
+    let control = match sandbox {
+        Some(_) => Some(UnixStream::pair()?),
+        None => None,
+    };
+    let (error_read, error_write) = error_pipe()?;
 
     let mut clone_args = CloneArgs::default();
     let mut pidfd: RawFd = 0;
     clone_args.flags = 0x100001000; // CLONE_CLEAR_SIGHAND | CLONE_PIDFD
+    if sandbox.is_some() {
+        clone_args.flags |= crate::sandbox::CLONE_FLAGS;
+    }
+    if let Some(cgroup) = cgroup {
+        clone_args.flags |= CLONE_INTO_CGROUP;
+        clone_args.cgroup = cgroup.as_raw_fd() as u64;
+    }
     clone_args.pidfd = &mut pidfd as *mut RawFd as u64;
 
     unsafe {
@@ -44,83 +264,400 @@ fn spawn(gpac_path: &CStr, argv: Vec<CString>, logger: &Logger) -> std::io::Resu
             Err(std::io::Error::last_os_error())
         } else if gpac_pid == 0 {
             // Child process - we can only call async-signal-safe functions and *must not panic*
+            let error_fd = error_write.as_raw_fd();
+            if let (Some(sandbox), Some((_, control_child))) = (sandbox, control.as_ref()) {
+                if let Err(error_message) = sandbox.enter(control_child.as_raw_fd()) {
+                    child_failed(error_fd, error_message);
+                }
+            }
             if close(0) == -1 {
-                let error_message = "close(0) failed";
-                write(logger.as_raw_fd(), error_message.as_bytes() as *const [u8] as *const c_void, error_message.len());
-                _exit(1);
+                child_failed(error_fd, "close(0) failed");
             }
             if open(b"/dev/null\0" as *const [u8] as *const c_char, O_RDONLY) != 0 {
-                let error_message = "open(/dev/null) failed";
-                write(logger.as_raw_fd(), error_message.as_bytes() as *const [u8] as *const c_void, error_message.len());
-                _exit(1);
+                child_failed(error_fd, "open(/dev/null) failed");
             }
             if dup2(logger.as_raw_fd(), 1) == -1 {
-                let error_message = "dup2(logger, stdout) failed";
-                write(logger.as_raw_fd(), error_message.as_bytes() as *const [u8] as *const c_void, error_message.len());
-                _exit(1);
+                child_failed(error_fd, "dup2(logger, stdout) failed");
             }
             if dup2(1, 2) == -1 {
-                let error_message = "dup2(logger, stderr) failed";
-                write(logger.as_raw_fd(), error_message.as_bytes() as *const [u8] as *const c_void, error_message.len());
-                _exit(1);
+                child_failed(error_fd, "dup2(logger, stderr) failed");
             }
             if receiver.as_raw_fd() == 3 {
                 if fcntl(3, F_SETFD, fcntl(3, F_GETFD) & !FD_CLOEXEC) == -1 {
-                    let error_message = "fcntl(3, F_SETFD, !FD_CLOEXEC) failed";
-                    write(logger.as_raw_fd(), error_message.as_bytes() as *const [u8] as *const c_void, error_message.len());
-                    _exit(1);
+                    child_failed(error_fd, "fcntl(3, F_SETFD, !FD_CLOEXEC) failed");
                 }
             } else {
                 if fcntl(3, F_GETFD) != -1 || std::ptr::read(__errno_location()) != EBADF {
                     if close(3) == -1 {
-                        let error_message = "close(3) failed";
-                        write(logger.as_raw_fd(), error_message.as_bytes() as *const [u8] as *const c_void, error_message.len());
-                        _exit(1);
+                        child_failed(error_fd, "close(3) failed");
                     }
                 }
                 if dup2(receiver.as_raw_fd(), 3) == -1 {
-                    let error_message = "dup2(receiver, 3) failed";
-                    write(logger.as_raw_fd(), error_message.as_bytes() as *const [u8] as *const c_void, error_message.len());
-                    _exit(1);
+                    child_failed(error_fd, "dup2(receiver, 3) failed");
                 }
             }
             execv(gpac_path.as_ptr(), &*argv as *const [*const c_char] as *const *const c_char);
-
-            let error_message = "Error execing gpac";
-            write(2, error_message.as_bytes() as *const [u8] as *const c_void, error_message.len());
-            _exit(127);
+            child_failed(error_fd, "Error execing gpac");
         } else {
             // Parent process
             eprintln!("pidfd: {}", pidfd);
-            Ok((Pidfd(pidfd), sender))
+            let pidfd = Pidfd(pidfd);
+            drop(error_write);
+            Ok((pidfd, sender, control.map(|(control_parent, _)| control_parent), error_read))
         }
     }
 }
 
-async fn handle_gpac_sender(sender: UnixStream, connection: Arc<Connection>) -> std::io::Result<()> {
-    let mut sender = Async::new(sender)?;
-    let mut packets = Vec::new();
-    loop {
-        let closed = poll_fn(|cx| {
-            eprintln!("waiting for packet");
-            connection.gpac_waker.register(cx.waker());
-            let mut data = connection.data.lock().unwrap();
-            if data.packets.is_empty() && !data.closed {
-                Poll::Pending
+/// How often packagers' CPU and memory usage is added to the metrics while they run
+const USAGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the health watchdog checks on a session when nothing is being received
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Logs what is learnt about a session's stream while it is ingested, and sends it on to the stream's notify url
+struct Notifier {
+    logger: Logger,
+    url: Url,
+    token: String,
+    mpd_url: String,
+    /// Where gpac writes the HLS playlist, which only audio-only streams have
+    hls_url: String,
+    /// Where cues and captions are passed on to the packager's output, if those are enabled
+    timed_metadata: &'static TimedMetadata,
+    packager: Uuid,
+}
+
+impl Notifier {
+    fn metadata(&self, metadata: Metadata) {
+        self.logger.log(&format!("Stream metadata: {}", metadata));
+        let logger = self.logger.clone();
+        let url = self.url.clone();
+        let token = self.token.clone();
+        Task::spawn(async move {
+            if let Err(e) = notify_metadata(url, &token, &metadata).await {
+                logger.log(&format!("Metadata notification failed: {}", e));
+            }
+        }).detach();
+    }
+
+    /// An SCTE-35 cue, which splices at `pts` on the packager's timeline if it says when
+    fn cue(&self, cue: Cue, pts: Option<u64>) {
+        self.logger.log(&format!("SCTE-35 {}", cue));
+        if self.timed_metadata.events() {
+            if let Err(e) = self.timed_metadata.push_cue(&self.packager, &cue, pts) {
+                self.logger.log(&format!("Passing on SCTE-35 cue failed: {}", e));
+            }
+        }
+        let logger = self.logger.clone();
+        let url = self.url.clone();
+        let token = self.token.clone();
+        Task::spawn(async move {
+            if let Err(e) = notify_cue(url, &token, &cue).await {
+                logger.log(&format!("Cue notification failed: {}", e));
+            }
+        }).detach();
+    }
+
+    /// A change to the captions on screen, from `pts` on the packager's timeline
+    fn caption(&self, pts: u64, text: String) {
+        self.timed_metadata.push_caption(&self.packager, pts, text);
+    }
+
+    fn health(&self, change: HealthChange) {
+        self.logger.log(&format!("Health: {}", change));
+        if change.state == health::State::Failed {
+            // Reported by the offline notification instead
+            return;
+        }
+        let logger = self.logger.clone();
+        let url = self.url.clone();
+        let token = self.token.clone();
+        Task::spawn(async move {
+            if let Err(e) = notify_health(url, &token, &change).await {
+                logger.log(&format!("Health notification failed: {}", e));
+            }
+        }).detach();
+    }
+
+    /// The contributor has gone, and the stream is on standby until one connects
+    fn away(&self, summary: Summary, standby: &Standby) {
+        let playout = match *standby {
+            Standby::Slate(_) => false,
+            Standby::Playout(_) => true,
+        };
+        self.logger.log(&format!("Contributor gone ({}), {}", summary.end_reason, if playout { "back to playout" } else { "showing the slate" }));
+        let logger = self.logger.clone();
+        let url = self.url.clone();
+        let token = self.token.clone();
+        Task::spawn(async move {
+            let res = if playout {
+                notify_playout(url, &token, &summary).await
             } else {
-                std::mem::swap(&mut packets, &mut data.packets);
-                Poll::Ready(data.closed)
+                notify_slate(url, &token, &summary).await
+            };
+            if let Err(e) = res {
+                logger.log(&format!("Standby notification failed: {}", e));
+            }
+        }).detach();
+    }
+
+    /// A contributor has connected while on standby, and the stream carries on on the same MPD
+    fn returned(&self, contribution: &Contribution) {
+        self.logger.log(&format!("Contributor connected as session {}, switching to them once their PMT has been seen", contribution.registration.session().uuid));
+    }
+
+    /// A returning contributor's PMT has said whether their stream is audio-only
+    fn online(&self, contribution: &Contribution, audio_only: bool) {
+        let peer_addr = contribution.stream.peer_addr();
+        let logger = self.logger.clone();
+        let url = self.url.clone();
+        let token = self.token.clone();
+        let mpd_url = self.mpd_url.clone();
+        let hls_url = if audio_only { Some(self.hls_url.clone()) } else { None };
+        Task::spawn(async move {
+            if let Err(e) = notify_online(url, &token, &mpd_url, hls_url.as_deref(), audio_only, peer_addr).await {
+                logger.log(&format!("Online notification failed: {}", e));
+            }
+        }).detach();
+    }
+}
+
+/// Why forwarding to a gpac stopped, other than gpac going away
+enum Stopped {
+    Ended(EndReason),
+    /// The contributor went away for `EndReason`, and didn't return before the slate's grace period was over
+    SlateOver(EndReason),
+    NewPeriod,
+    /// A returning contributor's stream is audio-only where the last wasn't, or the other way around, which gpac has
+    /// to be restarted on a new Period for
+    AudioOnlyChanged,
+}
+
+/// What a packager keeps from one gpac to the next
+struct Packager {
+    context: &'static Context,
+    slate_config: Option<SlateConfig>,
+    feed: Feed,
+    batch: Batch<'static>,
+    backlog: Backlog,
+    timeline: Timeline,
+    notifier: Notifier,
+}
+
+impl Packager {
+    /// Forwards messages from the streamer, or the standby feed while there isn't one, to gpac, returning once this
+    /// gpac should be stopped. What was held in the backlog while it started is sent first.
+    async fn send(&mut self, sender: UnixStream) -> std::io::Result<Stopped> {
+        let mut sender = Async::new(sender)?;
+        sender.write_all(&self.backlog.data).await?;
+        let mut wait_for_keyframe = self.backlog.wait_for_keyframe;
+        self.backlog.data.clear();
+        loop {
+            let Packager { context, ref slate_config, ref mut feed, ref mut batch, ref mut timeline, ref notifier, .. } = *self;
+            // A connecting contributor takes over from standby at its first keyframe
+            let mut live_wait_for_keyframe = (wait_for_keyframe || feed.away.is_some()) && !feed.audio_only;
+            let mut wait = HEALTH_CHECK_INTERVAL;
+            if let Some(due) = feed.away.as_mut().and_then(|away| away.standby.due()) {
+                wait = wait.min(due.saturating_duration_since(Instant::now()));
+            }
+            batch.clear();
+            // A stalled stream may not be sending anything at all, so the watchdog can't only run on receiving
+            let received = {
+                let recv = match feed.live {
+                    Some(ref mut contribution) => contribution.recv_batch(batch, &mut live_wait_for_keyframe, timeline).left_future(),
+                    None => future::pending::<Received>().right_future(),
+                }.fuse();
+                let tick = Timer::new(wait).fuse();
+                pin_mut!(recv, tick);
+                select! {
+                    received = recv => Some(received),
+                    _ = tick => None,
+                }
+            };
+            let now = Instant::now();
+            let mut gone = None;
+            let mut stopped = None;
+            match received {
+                Some(Received::StreamerGone) => gone = Some(EndReason::StreamerDisconnected),
+                Some(Received::NewPeriod) => stopped = Some(Stopped::NewPeriod),
+                Some(Received::Rewritten) => notifier.logger.log("Timestamps jumped, rewriting them to carry on from where they were"),
+                Some(Received::Messages) | None => {},
+            }
+            if let Some(ref mut contribution) = feed.live {
+                if let Some(metadata) = contribution.monitor.metadata_changed() {
+                    notifier.metadata(metadata);
+                }
+                for cue in contribution.monitor.take_cues() {
+                    let pts = cue.pts.map(|pts| timeline.rewrite(pts));
+                    notifier.cue(cue, pts);
+                }
+                for (pts, text) in contribution.monitor.take_captions() {
+                    notifier.caption(timeline.rewrite(pts), text);
+                }
+                if contribution.monitor.take_cea708() {
+                    notifier.logger.log("The stream has CEA-708 captions, which are shown instead of its CEA-608 ones");
+                }
+                for change in contribution.monitor.health_changes(now) {
+                    if change.state == health::State::Failed {
+                        gone = Some(EndReason::Stalled);
+                    }
+                    notifier.health(change);
+                }
+            }
+
+            if let Some(end_reason) = gone {
+                let contribution = feed.live.take().unwrap();
+                let metadata = contribution.monitor.metadata().cloned();
+                // Channels stay on standby for as long as they are there
+                let until = match feed.channel {
+                    Some(_) => None,
+                    None => slate_config.as_ref().map(|slate_config| now + slate_config.grace_period()),
+                };
+                if let Some(ref mut away) = feed.away {
+                    // Gone again before sending a keyframe
+                    away.ended = Some((finish_contribution(context, contribution, None, end_reason).await, metadata));
+                    away.until = until;
+                    away.handover = Some(context.handovers.wait_for_return(feed.stream_id, feed.packager));
+                    away.program_by = None;
+                } else {
+                    let standby = match Standby::open(feed.channel.as_ref(), slate_config.as_ref(), &notifier.logger, now) {
+                        Ok(Some(standby)) => standby,
+                        Ok(None) => {
+                            feed.live = Some(contribution);
+                            return Ok(Stopped::Ended(end_reason));
+                        },
+                        Err(e) => {
+                            notifier.logger.log(&format!("Opening the slate failed: {}", e));
+                            feed.live = Some(contribution);
+                            return Ok(Stopped::Ended(end_reason));
+                        },
+                    };
+                    let ended = finish_contribution(context, contribution, None, end_reason).await;
+                    notifier.away(ended.clone(), &standby);
+                    feed.away = Some(Away {
+                        standby,
+                        until,
+                        handover: Some(context.handovers.wait_for_return(feed.stream_id, feed.packager)),
+                        program_by: None,
+                        ended: Some((ended, metadata)),
+                    });
+                }
+            } else if let Some(audio_only) = self.checked_program(now) {
+                self.notifier.logger.log(if audio_only {
+                    "The contributor's stream has no video unlike the last one's, restarting gpac on a new Period to package it as audio only"
+                } else {
+                    "The contributor's stream has video unlike the last one's, restarting gpac on a new Period to package it with video"
+                });
+                self.feed.audio_only = audio_only;
+                // The new gpac starts from the contributor rather than switching to them at a keyframe
+                self.feed.away = None;
+                return Ok(Stopped::AudioOnlyChanged);
+            }
+            let Packager { context, ref slate_config, ref mut feed, ref mut batch, ref mut timeline, ref notifier, .. } = *self;
+            if feed.away.is_some() && feed.live.is_some() && !batch.is_empty() {
+                notifier.logger.log("Switched from standby to the contributor");
+                feed.away = None;
+                wait_for_keyframe = false;
+            } else if feed.away.is_none() {
+                wait_for_keyframe = live_wait_for_keyframe;
+            }
+
+            if let Some(ref mut away) = feed.away {
+                if feed.live.is_none() {
+                    let over = away.until.map(|until| now >= until).unwrap_or(false);
+                    let removed = feed.channel.as_ref().map(|channel| channel.removed.load(Ordering::SeqCst)).unwrap_or(false);
+                    if over || removed {
+                        context.handovers.stop_waiting(feed.stream_id, feed.packager);
+                    }
+                    // Still checked once stopped waiting, in case a contributor connected just before
+                    let returned = match away.handover.as_mut().map(|handover| handover.try_recv()) {
+                        Some(Ok(Some(contribution))) => Some(contribution),
+                        Some(Ok(None)) | None => None,
+                        // Another packager of the same stream took over waiting for it
+                        Some(Err(oneshot::Canceled)) => {
+                            away.handover = None;
+                            None
+                        },
+                    };
+                    if let Some(contribution) = returned {
+                        contribution.logger.log(&format!("Taking over from standby in session {}", feed.packager));
+                        notifier.returned(&contribution);
+                        away.handover = None;
+                        away.program_by = Some(now + PROGRAM_TIMEOUT);
+                        feed.live = Some(contribution);
+                    } else if over {
+                        notifier.logger.log(&format!("Contributor didn't return within {}s", slate_config.as_ref().unwrap().grace_period));
+                        stopped = Some(Stopped::SlateOver(away.ended.as_ref().unwrap().0.end_reason));
+                    } else if removed {
+                        notifier.logger.log("No longer an active channel");
+                        stopped = Some(Stopped::Ended(EndReason::ChannelRemoved));
+                    }
+                }
+                away.standby.fill(batch, &mut wait_for_keyframe, timeline, now);
             }
-        }).await;
 
-        if closed {
-            return Ok(());
+            while !batch.is_written() {
+                sender.write_with(|sender| batch.write_to(sender)).await?;
+            }
+            if let Some(stopped) = stopped {
+                return Ok(stopped);
+            }
         }
+    }
 
-        for packet in packets.drain(..) {
-            eprintln!("sending packet");
-            sender.write_all(&packet.buffer).await?;
-            eprintln!("sent packet");
+    /// Checks whether a contributor handed over from standby is audio-only once their PMT has been seen, returning
+    /// whether they are if that changed. Until then, what they send is left out, as for a new contributor.
+    fn checked_program(&mut self, now: Instant) -> Option<bool> {
+        let (away, contribution) = match (self.feed.away.as_mut(), self.feed.live.as_ref()) {
+            (Some(away), Some(contribution)) => (away, contribution),
+            _ => return None,
+        };
+        let program_by = away.program_by?;
+        let audio_only = match contribution.monitor.audio_only() {
+            Some(audio_only) => audio_only,
+            None if now >= program_by => false,
+            None => {
+                self.batch.clear();
+                return None;
+            },
+        };
+        away.program_by = None;
+        self.notifier.online(contribution, audio_only);
+        if audio_only == self.feed.audio_only {
+            return None;
+        }
+        Some(audio_only)
+    }
+
+    /// Waits for `until`, holding what the contributor sends meanwhile in the backlog, so that it neither backs up in
+    /// SRT's receive buffer nor is lost while gpac restarts
+    async fn buffer_until<T>(&mut self, until: impl Future<Output = T>) -> T {
+        let until = until.fuse();
+        pin_mut!(until);
+        loop {
+            let received = {
+                let recv = match self.feed.live {
+                    Some(ref mut contribution) => contribution.recv_batch(&mut self.batch, &mut self.backlog.wait_for_keyframe, &mut self.timeline).left_future(),
+                    None => future::pending::<Received>().right_future(),
+                }.fuse();
+                pin_mut!(recv);
+                select! {
+                    output = until => return output,
+                    received = recv => received,
+                }
+            };
+            match received {
+                // Found out again by the next gpac's sender
+                Received::StreamerGone => return until.await,
+                Received::NewPeriod => {
+                    self.backlog.start(true);
+                    self.backlog.jumped = true;
+                },
+                Received::Messages | Received::Rewritten => {},
+            }
+            for message in self.batch.messages() {
+                self.backlog.push(message);
+            }
         }
     }
 }
@@ -140,115 +677,555 @@ async fn kill_gpac(pidfd: &Pidfd, logger: &Logger) -> std::io::Result<()> {
     Ok(())
 }
 
-async fn handle_gpac(gpac_path: &CStr, gpac_argv: Vec<CString>, notify_url: String, notify_token: String, stream_uuid: Uuid, external_url: &'static str, logger: Logger, connection: Arc<Connection>) -> std::io::Result<()> {
-    let (pidfd, sender) = spawn(gpac_path, gpac_argv, &logger).unwrap();
-    let pidfd_guard = pidfd.guard();
-    let pidfd_wait = pidfd.wait().fuse();
-    pin_mut!(pidfd_wait);
+/// Records a contribution that has ended, and summarises it
+async fn finish_contribution(context: &Context, contribution: Contribution, exit_status: Option<ExitStatus>, end_reason: EndReason) -> Summary {
+    let Contribution { stream, registration, logger, .. } = contribution;
+    let stats = match stream.stats() {
+        Ok(stats) => Some(stats),
+        Err(e) => {
+            logger.log(&format!("Getting SRT stats failed: {}", e));
+            None
+        },
+    };
+    let summary = Summary {
+        session: registration.session().clone(),
+        ended_at: unix_time(),
+        bytes_received: stats.as_ref().map(|stats| stats.bytes_received()).unwrap_or(0),
+        packets_received: stats.as_ref().map(|stats| stats.packets_received()).unwrap_or(0),
+        packets_lost: stats.as_ref().map(|stats| stats.packets_lost()).unwrap_or(0),
+        packets_retransmitted: stats.as_ref().map(|stats| stats.packets_retransmitted()).unwrap_or(0),
+        packets_dropped: stats.as_ref().map(|stats| stats.packets_dropped()).unwrap_or(0),
+        exit_status,
+        end_reason,
+    };
+    logger.log(&format!(
+        "Received {} bytes in {} packets, {} lost, {} retransmitted, {} dropped",
+        summary.bytes_received, summary.packets_received, summary.packets_lost, summary.packets_retransmitted, summary.packets_dropped,
+    ));
+    if let Err(e) = context.store.record_session(&summary).await {
+        logger.log(&format!("Recording session failed: {}", e));
+    }
+    summary
+}
 
+/// Runs a packager for as long as it is fed, from its contributor or standby. The packager's uuid names its MPD.
+async fn handle_gpac(context: &'static Context, packaging: PackagingConfig, notify_url: String, notify_token: String, logger: Logger, mut feed: Feed) -> std::io::Result<()> {
+    let stream_id = feed.stream_id;
+    let stream_uuid = feed.packager;
+    let started_at = unix_time();
+    let peer_addr = feed.live.as_ref().and_then(|contribution| contribution.stream.peer_addr());
     let notify_url_parsed = Url::parse(&notify_url).unwrap();
-    let mpd_url = format!("{}/{}.mpd", external_url.strip_suffix('/').unwrap_or(external_url), stream_uuid);
+    let mut batch = context.buffers.batch();
+    if let Some(ref mut contribution) = feed.live {
+        feed.audio_only = contribution.wait_for_program(&mut batch).await;
+        if feed.audio_only {
+            logger.log("Stream has no video, packaging it as audio only");
+        }
+    }
+    let external_url = context.external_url.strip_suffix('/').unwrap_or(&context.external_url);
+    let mpd_url = format!("{}/{}.mpd", external_url, stream_uuid);
+    // gpac writes an HLS playlist next to the MPD for audio-only streams, which radio players tend to want
+    let hls_url = format!("{}/{}.m3u8", external_url, stream_uuid);
+    let audio_only = feed.audio_only;
+    let notifier = Notifier {
+        logger: logger.clone(),
+        url: notify_url_parsed.clone(),
+        token: notify_token.clone(),
+        mpd_url: mpd_url.clone(),
+        hls_url: hls_url.clone(),
+        timed_metadata: context.timed_metadata,
+        packager: stream_uuid,
+    };
     let notify_task = Task::spawn({
         let logger = logger.clone();
         let notify_url_parsed = notify_url_parsed.clone();
+        let notify_url = notify_url.clone();
         async move {
-            if let Err(e) = notify_online(notify_url_parsed, &notify_token, &mpd_url).await {
+            let hls_url = if audio_only { Some(hls_url.as_str()) } else { None };
+            if let Err(e) = notify_online(notify_url_parsed, &notify_token, &mpd_url, hls_url, audio_only, peer_addr).await {
                 logger.log(&format!("Online notification to {} failed: {}", notify_url, e));
             }
             notify_token
         }
     });
 
-    let code = select! {
-        res = Task::spawn(handle_gpac_sender(sender, connection.clone())).fuse() => {
-            if let Err(e) = res {
-                logger.log("gpac sender task failed");
-            }
-            logger.log("Closed due to sender task finishing");
-            connection.data.lock().unwrap().closed = true;
-            select! {
-                res = kill_gpac(&pidfd, &logger).fuse() => {
-                    if let Err(e) = res {
-                        logger.log("Killing gpac failed");
+    let cgroup = match context.cgroups.as_ref().map(|cgroups| cgroups.create(&stream_uuid)).transpose() {
+        Ok(cgroup) => cgroup.map(Arc::new),
+        Err(e) => {
+            logger.log(&format!("Creating cgroup failed, running without resource limits: {}", e));
+            None
+        },
+    };
+    let usage_task = cgroup.clone().map(|cgroup| Task::spawn(async move {
+        loop {
+            Timer::new(USAGE_INTERVAL).await;
+            if let Ok(usage) = cgroup.usage() {
+                export_usage(&cgroup, &usage);
+            }
+        }
+    }));
+
+    let mut packager = Packager {
+        context,
+        slate_config: packaging.slate.clone(),
+        feed,
+        batch,
+        backlog: Backlog::new(),
+        timeline: Timeline::new(packaging.on_discontinuity, packaging.discontinuity_threshold),
+        notifier,
+    };
+    let mut period = 0;
+
+    let mut restarts = VecDeque::new();
+    let (status, end_reason) = loop {
+        // Reading from the stream may have paused while the last gpac exited, and mustn't be taken for a jump
+        packager.timeline.reset();
+        let gpac_argv = get_gpac_argv(&context.httpd_url, &packaging, &stream_uuid, period, packager.feed.audio_only);
+        let (pidfd, sender, control) = match spawn(&context.gpac_path, gpac_argv, &logger, context.sandbox.as_ref(), cgroup.as_deref()).await {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                logger.log(&format!("Spawning gpac failed: {}", e));
+                break (Err(e), EndReason::PackagerExited);
+            },
+        };
+        let _proxy_task = control.map(|control| {
+            let logger = logger.clone();
+            let httpd_addr = context.sandbox.as_ref().unwrap().httpd_addr();
+            Task::spawn(async move {
+                if let Err(e) = sandbox::proxy_httpd(control, httpd_addr).await {
+                    logger.log(&format!("Forwarding from sandbox to httpd failed: {}", e));
+                }
+            })
+        });
+        let pidfd_guard = pidfd.guard();
+        let pidfd_wait = pidfd.wait().fuse();
+        pin_mut!(pidfd_wait);
+
+        let mut stopped = None;
+        let sent = select! {
+            res = packager.send(sender).fuse() => Ok(res),
+            status = pidfd_wait.as_mut() => Err(status),
+        };
+        // The next gpac waits for a keyframe, as it can't do anything useful with the stream until it sees one
+        packager.backlog.start(!packager.feed.audio_only);
+        let status = match sent {
+            Ok(Ok(reason)) => {
+                match reason {
+                    Stopped::Ended(EndReason::Stalled) => logger.log("Closed due to the stream stalling"),
+                    Stopped::Ended(EndReason::ChannelRemoved) => logger.log("Closed due to the channel being removed"),
+                    Stopped::Ended(_) => logger.log("Closed due to streamer disconnecting"),
+                    Stopped::SlateOver(_) => logger.log("Closed at the end of the slate's grace period"),
+                    Stopped::NewPeriod => logger.log("Timestamps jumped, restarting gpac on a new Period"),
+                    Stopped::AudioOnlyChanged => {},
+                }
+                let restarting = match reason {
+                    Stopped::NewPeriod | Stopped::AudioOnlyChanged => true,
+                    Stopped::Ended(_) | Stopped::SlateOver(_) => false,
+                };
+                stopped = Some(reason);
+                let exited = async {
+                    select! {
+                        res = kill_gpac(&pidfd, &logger).fuse() => {
+                            if let Err(e) = res {
+                                logger.log("Killing gpac failed");
+                            }
+                            pidfd_wait.await
+                        },
+                        status = pidfd_wait.as_mut() => {
+                            status
+                        },
+                    }
+                };
+                if restarting {
+                    packager.buffer_until(exited).await
+                } else {
+                    exited.await
+                }
+            },
+            Ok(Err(e)) => {
+                // Writing to gpac only fails if it has gone away, so wait for it to be reaped
+                logger.log(&format!("Sending to gpac failed: {}", e));
+                packager.buffer_until(pidfd_wait).await
+            },
+            Err(status) => status,
+        };
+        std::mem::forget(pidfd_guard);
+
+        match status {
+            Ok(ref status) => {
+                logger.log(&format!("gpac {}", status));
+                metrics::record_gpac_exit(status);
+            },
+            Err(ref e) => logger.log(&format!("Waiting for gpac failed: {}", e)),
+        }
+
+        let mut oom_killed = false;
+        if let Some(ref cgroup) = cgroup {
+            match cgroup.usage() {
+                Ok(usage) => {
+                    logger.log(&format!("Packager usage: {}.{:06}s CPU, {} bytes memory peak",
+                        usage.cpu_usec / 1000000, usage.cpu_usec % 1000000, cgroup.memory_peak(&usage)));
+                    oom_killed = cgroup.oom_killed(&usage);
+                    if oom_killed {
+                        logger.log("gpac was OOM killed");
+                        metrics::PACKAGER_OOM_KILLS.inc();
                     }
-                    pidfd_wait.await
-                },
-                code = pidfd_wait.as_mut() => {
-                    code
                 },
+                Err(e) => logger.log(&format!("Reading cgroup usage failed: {}", e)),
+            }
+        }
+        let crash_reason = if oom_killed { EndReason::PackagerOomKilled } else { EndReason::PackagerExited };
+
+        match stopped {
+            Some(Stopped::Ended(end_reason)) | Some(Stopped::SlateOver(end_reason)) => break (status, end_reason),
+            Some(Stopped::NewPeriod) | Some(Stopped::AudioOnlyChanged) => {
+                period += 1;
+                continue;
+            },
+            None => {},
+        }
+        if packager.backlog.jumped {
+            period += 1;
+        }
+
+        let now = Instant::now();
+        while restarts.front().map(|restarted: &Instant| now.duration_since(*restarted) > context.supervision.restart_window()).unwrap_or(false) {
+            restarts.pop_front();
+        }
+        if restarts.len() >= context.supervision.max_restarts {
+            logger.log(&format!("Closed due to pidfd, gpac restarted {} times in the last {} seconds", restarts.len(), context.supervision.restart_window));
+            break (status, crash_reason);
+        }
+        restarts.push_back(now);
+        logger.log(&format!("Restarting gpac ({}/{})", restarts.len(), context.supervision.max_restarts));
+    };
+    logger.log(&format!("Session ended: {}", end_reason));
+
+    drop(usage_task);
+    if let Some(ref cgroup) = cgroup {
+        if let Ok(usage) = cgroup.usage() {
+            export_usage(cgroup, &usage);
+        }
+        // No longer running, so no longer part of the total
+        metrics::PACKAGER_MEMORY_BYTES.sub(cgroup.swap_memory_exported(0));
+    }
+    drop(cgroup);
+
+    context.handovers.stop_waiting(stream_id, stream_uuid);
+    let mut feed = packager.feed;
+    context.timed_metadata.remove(&stream_uuid);
+    if let Some(ref channel) = feed.channel {
+        channel.ended.store(true, Ordering::SeqCst);
+    }
+    let exit_status = status.as_ref().ok().copied();
+    let (summary, metadata, handed_over) = match feed.away.take() {
+        Some(away) => {
+            if let Some(contribution) = feed.live.take() {
+                finish_contribution(context, contribution, exit_status, end_reason).await;
             }
+            let handed_over = away.handover.and_then(|mut handover| handover.try_recv().ok().flatten());
+            // A channel that has only ever played out is summarised as a session of its own
+            let (mut summary, metadata) = away.ended.unwrap_or_else(|| (playout_summary(stream_id, stream_uuid, started_at), None));
+            summary.exit_status = exit_status;
+            summary.end_reason = end_reason;
+            (summary, metadata, handed_over)
         },
-        code = pidfd_wait.as_mut() => {
-            logger.log("Closed due to pidfd");
-            connection.data.lock().unwrap().closed = true;
-            code
+        None => {
+            let contribution = feed.live.take().unwrap();
+            let metadata = contribution.monitor.metadata().cloned();
+            (finish_contribution(context, contribution, exit_status, end_reason).await, metadata, None)
         },
     };
-    std::mem::forget(pidfd_guard);
 
-    let notify_url = notify_task.await;
-    if let Err(e) = notify_offline(notify_url_parsed, &notify_url).await {
+    let notify_token = notify_task.await;
+    if let Err(e) = notify_offline(notify_url_parsed, &notify_token, &summary, metadata.as_ref()).await {
         logger.log(&format!("Offline notification to {} failed: {}", notify_url, e));
     }
 
-    logger.log(&format!("code: {}", code.unwrap().si_errno));
+    // The contributor returned just as this packager was stopping, so gets one of their own
+    if let Some(contribution) = handed_over {
+        spawn_packager(context, packaging, notify_url, notify_token, contribution);
+    }
+
     Ok(())
 }
 
-fn get_gpac_argv(httpd_url: &str, stream_uuid: &Uuid) -> Vec<CString> {
+/// Adds the CPU time a packager has used since the last time to the metrics, and its memory use
+fn export_usage(cgroup: &SessionCgroup, usage: &Usage) {
+    metrics::PACKAGER_CPU_USEC.add(cgroup.cpu_usec_since(usage));
+    // Added before the last sample is taken away, so that the total never goes below zero
+    metrics::PACKAGER_MEMORY_BYTES.add(usage.memory_current);
+    metrics::PACKAGER_MEMORY_BYTES.sub(cgroup.swap_memory_exported(usage.memory_current));
+    metrics::PACKAGER_MEMORY_PEAK_BYTES.max(cgroup.memory_peak(usage));
+}
+
+fn playout_summary(stream_id: u32, uuid: Uuid, started_at: u64) -> Summary {
+    Summary {
+        session: Session {
+            uuid,
+            stream_id,
+            listener: "playout".to_string(),
+            peer_addr: None,
+            started_at,
+        },
+        ended_at: unix_time(),
+        bytes_received: 0,
+        packets_received: 0,
+        packets_lost: 0,
+        packets_retransmitted: 0,
+        packets_dropped: 0,
+        exit_status: None,
+        end_reason: EndReason::ChannelRemoved,
+    }
+}
+
+/// Starts a packager for a contributor
+fn spawn_packager(context: &'static Context, packaging: PackagingConfig, notify_url: String, notify_token: String, contribution: Contribution) {
+    let uuid = contribution.registration.session().uuid;
+    let logger = contribution.logger.clone();
+    let feed = Feed {
+        stream_id: contribution.registration.session().stream_id,
+        packager: uuid,
+        live: Some(contribution),
+        away: None,
+        channel: None,
+        audio_only: false,
+    };
+    Task::spawn(async move {
+        handle_gpac(context, packaging, notify_url, notify_token, logger, feed).await.unwrap()
+    }).detach()
+}
+
+/// Starts a packager playing out a channel, until a contributor connects
+fn spawn_channel(context: &'static Context, mut packaging: PackagingConfig, stream: ChannelStream, channel: Arc<Channel>) {
+    // Switching between recordings and contributors jumps the timestamps, which mustn't restart gpac every time
+    packaging.on_discontinuity = discontinuity::Strategy::Rewrite;
+    let uuid = Uuid::new_v4();
+    let logger = session_logger(context, &uuid);
+    logger.log(&format!("Playing out channel {}", stream.id));
+    let feed = Feed {
+        stream_id: stream.id,
+        packager: uuid,
+        live: None,
+        away: Some(Away {
+            standby: Standby::Playout(Playout::new(channel.clone(), logger.clone())),
+            until: None,
+            handover: Some(context.handovers.wait_for_return(stream.id, uuid)),
+            program_by: None,
+            ended: None,
+        }),
+        channel: Some(channel),
+        audio_only: false,
+    };
+    Task::spawn(async move {
+        handle_gpac(context, packaging, stream.notify_url, stream.token, logger, feed).await.unwrap()
+    }).detach()
+}
+
+/// How often channels are reloaded even if the store hasn't said they changed, which restarts any that stopped
+const CHANNEL_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Runs a packager for every active channel, and keeps their schedules up to date
+async fn keep_channels(context: &'static Context, packaging: PackagingConfig) {
+    let (changed_sender, mut changed) = mpsc::unbounded();
+    Task::spawn(async move {
+        if let Err(e) = context.store.watch(changed_sender).await {
+            eprintln!("watching for channel changes failed, only reloading them every minute: {}", e);
+        }
+    }).detach();
+    let mut channels = HashMap::new();
+    loop {
+        if let Err(e) = load_channels(context, &packaging, &mut channels).await {
+            eprintln!("loading channels failed: {}", e);
+        }
+        let timer = Timer::new(CHANNEL_RELOAD_INTERVAL).fuse();
+        pin_mut!(timer);
+        select! {
+            change = changed.next() => if change.is_none() {
+                timer.await;
+            },
+            _ = timer.as_mut() => {},
+        }
+    }
+}
+
+async fn load_channels<'a>(context: &'static Context, packaging: &'a PackagingConfig, channels: &'a mut HashMap<u32, Arc<Channel>>) -> Result<(), sqlx::Error> {
+    let loaded = context.store.channels().await?;
+    channels.retain(|id, channel| {
+        let keep = loaded.iter().any(|stream| stream.id == *id) && !channel.ended.load(Ordering::SeqCst);
+        if !keep {
+            channel.removed.store(true, Ordering::SeqCst);
+        }
+        keep
+    });
+    for stream in loaded {
+        let schedule = context.store.schedule(stream.id, unix_time()).await?;
+        match channels.get(&stream.id) {
+            Some(channel) => channel.schedule.store(Arc::new(schedule)),
+            None => {
+                eprintln!("starting channel {}", stream.id);
+                let channel = Arc::new(Channel::new(schedule));
+                channels.insert(stream.id, channel.clone());
+                spawn_channel(context, packaging.clone(), stream, channel);
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Opens the log file for a session or packager
+fn session_logger(context: &Context, uuid: &Uuid) -> Logger {
+    let mut filename_buf = [0; uuid::adapter::HyphenatedRef::LENGTH];
+    let filename = uuid.to_hyphenated_ref().encode_lower(&mut filename_buf);
+    let log_file = context.log_dir.append_file(filename as &str, 0o640).unwrap();
+    let mut logger = Logger::from(log_file);
+    logger.set_prefix("[ingestd-srt::gpac] ");
+    logger
+}
+
+/// `period` counts the times gpac was restarted on a new Period, whose segments are named apart from the earlier ones'.
+/// Audio-only streams are segmented by their own duration, and get an HLS playlist as well as the MPD.
+fn get_gpac_argv(httpd_url: &str, packaging: &PackagingConfig, stream_uuid: &Uuid, period: u32, audio_only: bool) -> Vec<CString> {
     let uuid_hyphenated = stream_uuid.to_hyphenated_ref();
     let mpd_url = format!("{}/{}.mpd", httpd_url.strip_suffix('/').unwrap_or(httpd_url), stream_uuid);
+    let (period_property, segment_prefix) = match period {
+        0 => (String::new(), uuid_hyphenated.to_string()),
+        period => (format!(":#Period=p{}", period), format!("{}_p{}", uuid_hyphenated, period)),
+    };
+    let audio_only_options = if audio_only { ":segext=m4s:initext=m4a:dual" } else { ":segext=mp4" };
     vec![
         CString::new("-log-utc").unwrap(),
         CString::new("-logs=all@info").unwrap(),
-        CString::new(format!("src=tcpu://inherit:#Filename={uuid}", uuid=uuid_hyphenated)).unwrap(),
-        CString::new(format!("dst={mpd_url}:gpac:template={uuid}_$RepresentationID$$FS$_$Init=init$$Number%05d$:utcs=inband:segext=mp4:hmode=push:profile=live:dmode=dynamic:muxtype=mp4:tfdt_traf:segdur=8:cdur=0.1:asto=7.9:buf=1000", mpd_url=mpd_url, uuid=uuid_hyphenated)).unwrap(),
+        CString::new(format!("src=tcpu://inherit:#Filename={uuid}{period_property}", uuid=uuid_hyphenated, period_property=period_property)).unwrap(),
+        CString::new(format!(
+            "dst={mpd_url}:gpac:template={segment_prefix}_$RepresentationID$$FS$_$Init=init$$Number%05d$:utcs=inband{audio_only_options}:hmode=push:profile=live:dmode=dynamic:muxtype=mp4:tfdt_traf:segdur={segdur}:cdur={cdur}:asto={asto}:buf={buf}",
+            mpd_url=mpd_url,
+            segment_prefix=segment_prefix,
+            audio_only_options=audio_only_options,
+            segdur=packaging.segment_duration(audio_only),
+            cdur=packaging.chunk_duration,
+            asto=packaging.availability_time_offset(audio_only),
+            buf=packaging.buffer,
+        )).unwrap(),
     ]
 }
 
-pub fn listen(waker: Arc<AtomicWaker>, new_connections: Arc<Mutex<Vec<NewConnection>>>, mut db: SqliteConnection, httpd_url: String, external_url: String) -> impl Future<Output=()> {
-    let gpac_path = find_executable_in_path("gpac").unwrap().into_os_string();
-    let gpac_path: &'static CStr = Box::leak(CString::new(gpac_path.into_vec()).unwrap().into_boxed_c_str());
+/// An SRT listener, and how the streams it accepts are packaged
+pub struct IngestListener {
+    pub name: String,
+    pub srt: AsyncListener,
+    pub packaging: PackagingConfig,
+}
 
-    let external_url: &'static str = Box::leak(external_url.into_boxed_str());
+async fn accept_loop(context: &'static Context, listener: IngestListener) {
+    loop {
+        let stream = match listener.srt.accept().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("accepting on {} failed: {}", listener.name, e);
+                continue;
+            },
+        };
+        let stream_id = match stream.stream_id() {
+            Ok(stream_id) => stream_id,
+            Err(e) => {
+                eprintln!("getting stream id failed: {}", e);
+                continue;
+            },
+        };
 
-    async move {
-        loop {
-            let new_connections = poll_fn(|cx| {
-                waker.register(cx.waker());
-                let new_connections = std::mem::replace(&mut *new_connections.lock().unwrap(), Vec::new());
-                if new_connections.len() > 0 {
-                    Poll::Ready(new_connections)
-                } else {
-                    Poll::Pending
-                }
-            }).await;
-            for connection in new_connections {
-                eprintln!("spawning");
-                let NewConnection { mut logger, stream_id, stream_uuid, connection } = connection;
-                logger.set_prefix("[ingestd-srt::gpac] ");
-                let stream_id = match std::str::from_utf8(&stream_id) {
-                    Ok(s) => s,
-                    Err(_) => { eprintln!("couldn't parse C string: {:?}", &stream_id); continue},
-                };
-                if stream_id.len() >= 6 && &stream_id[0..6] != "#!::u=" {
-                    continue;
-                }
-                let stream_userid = match stream_id[6..].parse::<u32>() {
-                    Ok(u) => u,
-                    Err(_) => continue,
+        eprintln!("spawning");
+        let stream_id = match std::str::from_utf8(&stream_id) {
+            Ok(s) => s,
+            Err(_) => { eprintln!("couldn't parse C string: {:?}", &stream_id); continue},
+        };
+        if !stream_id.starts_with("#!::u=") {
+            continue;
+        }
+        let stream_userid = match stream_id[6..].parse::<u32>() {
+            Ok(u) => u,
+            Err(_) => continue,
+        };
+
+        // Only once the stream id is known to be good, so that bad ones don't leave log files behind
+        let stream_uuid = Uuid::new_v4();
+        let logger = session_logger(context, &stream_uuid);
+        match stream.peer_addr() {
+            Some(peer_addr) => logger.log(&format!("Accepted on listener {} from {}", listener.name, peer_addr)),
+            None => logger.log(&format!("Accepted on listener {} from an unknown address", listener.name)),
+        }
+        eprintln!("spawning stream {}", stream_userid);
+        let stream_row = context.store.lookup_stream(stream_userid).await;
+        match stream_row {
+            Ok(stream_row) => {
+                let registration = context.sessions.register(stream_uuid, stream_userid, &listener.name, stream.peer_addr());
+                let contribution = Contribution {
+                    stream,
+                    registration,
+                    logger,
+                    monitor: Monitor::new(&context.health, context.timed_metadata.captions(), Instant::now()),
+                    tracks: TrackFilter::new(stream_row.audio_tracks.as_deref()),
                 };
-                eprintln!("spawning stream {}", stream_userid);
-                match query!("SELECT notify_url, token FROM streams where id = ?", stream_userid as i32).fetch_one(&mut db).await {
-                    Ok(stream_row) => {
-                        let gpac_argv = get_gpac_argv(&httpd_url, &stream_uuid);
-                        Task::spawn(async move {
-                            handle_gpac(gpac_path, gpac_argv, stream_row.notify_url, stream_row.token, stream_uuid, &external_url, logger, connection).await.unwrap()
-                        }).detach()
-                    },
-                    Err(e) => connection.data.lock().unwrap().closed = true,
+                if let Err(contribution) = context.handovers.hand_over(contribution) {
+                    spawn_packager(context, listener.packaging.clone(), stream_row.notify_url, stream_row.token, contribution);
                 }
-            }
+            },
+            Err(e) => logger.log(&format!("Looking up stream {} failed: {}", stream_userid, e)),
+        }
+    }
+}
+
+/// Accepts on every listener, and plays out channels with `channel_packaging`
+pub fn listen(listeners: Vec<IngestListener>, channel_packaging: PackagingConfig, log_dir: Dir, store: &'static dyn StreamStore, httpd_url: String, external_url: String, supervision: SupervisionConfig, health: HealthConfig, sandbox: Option<Sandbox>, cgroups: Option<Cgroups>, sessions: &'static Registry, timed_metadata: &'static TimedMetadata) -> impl Future<Output=()> {
+    let gpac_path = find_executable_in_path("gpac").unwrap().into_os_string();
+    let context: &'static Context = Box::leak(Box::new(Context {
+        gpac_path: CString::new(gpac_path.into_vec()).unwrap(),
+        httpd_url,
+        external_url,
+        log_dir,
+        store,
+        supervision,
+        health,
+        sandbox,
+        cgroups,
+        buffers: BufferPool::new(),
+        sessions,
+        handovers: Handovers::new(),
+        timed_metadata,
+    }));
+
+    Task::spawn(keep_channels(context, channel_packaging)).detach();
+    future::join_all(listeners.into_iter().map(move |listener| accept_loop(context, listener))).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The options of gpac's dst argument, split at colons, which the URL it starts with is also split at
+    fn dst_options(argv: &[CString]) -> Vec<String> {
+        argv[3].to_str().unwrap().split(':').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn packages_audio_only_streams_by_their_own_duration_with_an_hls_playlist() {
+        let packaging = PackagingConfig::default();
+        let uuid = Uuid::new_v4();
+
+        let options = dst_options(&get_gpac_argv("http://127.0.0.1:8080/", &packaging, &uuid, 0, false));
+        for option in &["segext=mp4", "segdur=8", "cdur=0.1", "asto=7.9"] {
+            assert!(options.iter().any(|found| found == option), "{} not in {:?}", option, options);
+        }
+        assert!(!options.iter().any(|option| option == "dual" || option.starts_with("initext=")));
+
+        let argv = get_gpac_argv("http://127.0.0.1:8080/", &packaging, &uuid, 2, true);
+        assert_eq!(argv[2].to_str().unwrap(), format!("src=tcpu://inherit:#Filename={}:#Period=p2", uuid));
+        let options = dst_options(&argv);
+        assert_eq!(options[..3], ["dst=http".to_string(), "//127.0.0.1".to_string(), format!("8080/{}.mpd", uuid)]);
+        assert_eq!(options[4], format!("template={}_p2_$RepresentationID$$FS$_$Init=init$$Number%05d$", uuid));
+        for option in &["segext=m4s", "initext=m4a", "dual", "segdur=4", "cdur=0.1", "asto=3.9"] {
+            assert!(options.iter().any(|found| found == option), "{} not in {:?}", option, options);
         }
+        assert!(!options.iter().any(|option| option == "segext=mp4"));
     }
 }
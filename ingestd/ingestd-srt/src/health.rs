@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::ts;
+
+/// How long something may be missing before the session is reported as degraded, and then ended. 0 disables either.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Thresholds {
+    pub degraded_after: u64,
+    pub end_after: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct HealthConfig {
+    /// Seconds without any packets other than null packets
+    pub payload: Thresholds,
+    /// Seconds without a video PES, for streams that have video
+    pub video: Thresholds,
+    /// Seconds without a keyframe, for streams that have video
+    pub keyframe: Thresholds,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            payload: Thresholds { degraded_after: 5, end_after: 30 },
+            video: Thresholds { degraded_after: 5, end_after: 30 },
+            keyframe: Thresholds { degraded_after: 15, end_after: 60 },
+        }
+    }
+}
+
+/// Something the watchdog checks is still arriving
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Payload,
+    Video,
+    Keyframe,
+}
+
+const CHECKS: [Check; 3] = [Check::Payload, Check::Video, Check::Keyframe];
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Check::Payload => "payload",
+            Check::Video => "video",
+            Check::Keyframe => "keyframe",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Degraded,
+    Recovered,
+    /// Missing for long enough that the session should be ended
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct HealthChange {
+    pub check: Check,
+    pub state: State,
+    /// How long it had been missing for
+    pub seconds: u64,
+}
+
+impl fmt::Display for HealthChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.state {
+            State::Degraded => write!(f, "no {} for {}s, degraded", self.check, self.seconds),
+            State::Recovered => write!(f, "{} recovered after {}s", self.check, self.seconds),
+            State::Failed => write!(f, "no {} for {}s, ending the session", self.check, self.seconds),
+        }
+    }
+}
+
+/// Tracks when a session last received what its viewers need, as an SRT connection can stay up while the encoder
+/// sends nothing useful
+pub struct Watchdog {
+    config: &'static HealthConfig,
+    has_video: bool,
+    last_seen: [Instant; 3],
+    /// When each degraded check was last seen before it went missing
+    degraded: [Option<Instant>; 3],
+}
+
+impl Watchdog {
+    pub fn new(config: &'static HealthConfig, now: Instant) -> Watchdog {
+        Watchdog {
+            config,
+            has_video: false,
+            last_seen: [now; 3],
+            degraded: [None; 3],
+        }
+    }
+
+    fn thresholds(&self, check: Check) -> Thresholds {
+        match check {
+            Check::Payload => self.config.payload,
+            Check::Video => self.config.video,
+            Check::Keyframe => self.config.keyframe,
+        }
+    }
+
+    fn seen(&mut self, check: Check, now: Instant) {
+        self.last_seen[check as usize] = now;
+    }
+
+    /// A TS packet arrived, which doesn't count if it is a null packet
+    pub fn packet(&mut self, packet: &[u8], now: Instant) {
+        if ts::pid(packet) != ts::NULL_PID {
+            self.seen(Check::Payload, now);
+        }
+    }
+
+    pub fn program(&mut self, has_video: bool, now: Instant) {
+        if has_video && !self.has_video {
+            self.seen(Check::Video, now);
+            self.seen(Check::Keyframe, now);
+        }
+        self.has_video = has_video;
+    }
+
+    /// A video PES arrived, with a keyframe if it has one or is flagged as a random access point
+    pub fn video(&mut self, keyframe: bool, now: Instant) {
+        self.seen(Check::Video, now);
+        if keyframe {
+            self.seen(Check::Keyframe, now);
+        }
+    }
+
+    /// Changes in health since the last check
+    pub fn check(&mut self, now: Instant) -> Vec<HealthChange> {
+        let mut changes = Vec::new();
+        for &check in &CHECKS {
+            if check != Check::Payload && !self.has_video {
+                continue;
+            }
+            let thresholds = self.thresholds(check);
+            let last_seen = self.last_seen[check as usize];
+            let missing = now.duration_since(last_seen);
+            let degraded = &mut self.degraded[check as usize];
+            let (state, missing) = if thresholds.end_after != 0 && missing >= Duration::from_secs(thresholds.end_after) {
+                (State::Failed, missing)
+            } else if thresholds.degraded_after != 0 && missing >= Duration::from_secs(thresholds.degraded_after) {
+                if degraded.is_some() {
+                    continue;
+                }
+                *degraded = Some(last_seen);
+                (State::Degraded, missing)
+            } else {
+                match degraded.take() {
+                    Some(missing_since) => (State::Recovered, last_seen.duration_since(missing_since)),
+                    None => continue,
+                }
+            };
+            changes.push(HealthChange {
+                check,
+                state,
+                seconds: missing.as_secs(),
+            });
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watchdog(config: HealthConfig, start: Instant) -> Watchdog {
+        Watchdog::new(Box::leak(Box::new(config)), start)
+    }
+
+    fn packet(pid: u16) -> [u8; ts::PACKET_SIZE] {
+        let mut packet = [0xff; ts::PACKET_SIZE];
+        packet[..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x10]);
+        packet
+    }
+
+    fn changes(watchdog: &mut Watchdog, at: Instant) -> Vec<(Check, State, u64)> {
+        watchdog.check(at).into_iter().map(|change| (change.check, change.state, change.seconds)).collect()
+    }
+
+    #[test]
+    fn degrades_recovers_and_fails() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let mut watchdog = watchdog(HealthConfig::default(), start);
+        assert_eq!(changes(&mut watchdog, secs(4)), []);
+        assert_eq!(changes(&mut watchdog, secs(5)), [(Check::Payload, State::Degraded, 5)]);
+        // Only reported once
+        assert_eq!(changes(&mut watchdog, secs(6)), []);
+        // Null packets don't count
+        watchdog.packet(&packet(ts::NULL_PID), secs(7));
+        assert_eq!(changes(&mut watchdog, secs(7)), []);
+        watchdog.packet(&packet(0x100), secs(8));
+        assert_eq!(changes(&mut watchdog, secs(8)), [(Check::Payload, State::Recovered, 8)]);
+        assert_eq!(changes(&mut watchdog, secs(9)), []);
+
+        assert_eq!(changes(&mut watchdog, secs(13)), [(Check::Payload, State::Degraded, 5)]);
+        assert_eq!(changes(&mut watchdog, secs(38)), [(Check::Payload, State::Failed, 30)]);
+    }
+
+    /// Video checks only, as nothing sends payload
+    fn video_config(video: Thresholds) -> HealthConfig {
+        HealthConfig {
+            payload: Thresholds { degraded_after: 0, end_after: 0 },
+            video,
+            ..HealthConfig::default()
+        }
+    }
+
+    #[test]
+    fn checks_video_once_the_program_has_it() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let mut watchdog = watchdog(video_config(HealthConfig::default().video), start);
+        assert_eq!(changes(&mut watchdog, secs(10)), []);
+
+        // Video is missing from when the program said there would be some
+        watchdog.program(true, secs(10));
+        assert_eq!(changes(&mut watchdog, secs(15)), [(Check::Video, State::Degraded, 5)]);
+        // Frames without keyframes keep video healthy, but not keyframes
+        watchdog.video(false, secs(16));
+        assert_eq!(changes(&mut watchdog, secs(16)), [(Check::Video, State::Recovered, 6)]);
+        watchdog.video(false, secs(25));
+        assert_eq!(changes(&mut watchdog, secs(25)), [(Check::Keyframe, State::Degraded, 15)]);
+        watchdog.video(true, secs(26));
+        assert_eq!(changes(&mut watchdog, secs(26)), [(Check::Keyframe, State::Recovered, 16)]);
+
+        // Nor once the program goes back to audio only
+        watchdog.program(false, secs(26));
+        assert_eq!(changes(&mut watchdog, secs(60)), []);
+    }
+
+    #[test]
+    fn zero_disables_a_threshold() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let mut watchdog = watchdog(video_config(Thresholds { degraded_after: 5, end_after: 0 }), start);
+        watchdog.program(true, start);
+        assert_eq!(changes(&mut watchdog, secs(5)), [(Check::Video, State::Degraded, 5)]);
+        assert_eq!(changes(&mut watchdog, secs(15)), [(Check::Keyframe, State::Degraded, 15)]);
+        // Keyframes still fail, but video never does
+        assert_eq!(changes(&mut watchdog, secs(60)), [(Check::Keyframe, State::Failed, 60)]);
+
+        let config = HealthConfig {
+            payload: Thresholds { degraded_after: 0, end_after: 10 },
+            ..HealthConfig::default()
+        };
+        let mut watchdog = self::watchdog(config, start);
+        assert_eq!(changes(&mut watchdog, secs(9)), []);
+        assert_eq!(changes(&mut watchdog, secs(10)), [(Check::Payload, State::Failed, 10)]);
+    }
+}
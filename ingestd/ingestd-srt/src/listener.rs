@@ -0,0 +1,114 @@
+use roaring::RoaringBitmap;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV6, UdpSocket};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use crate::gpac::PackagingConfig;
+use crate::srt_options::SrtOptions;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListenerConfig {
+    pub name: String,
+    /// Address to bind, if the socket isn't passed in by systemd as a file descriptor named `name`. IPv6 addresses
+    /// also accept IPv4 callers, unless `ipv6-only` is set.
+    pub address: Option<SocketAddr>,
+    #[serde(default)]
+    pub ipv6_only: bool,
+    /// SRT options for this listener, on top of the global `[srt]` ones
+    #[serde(default)]
+    pub srt: SrtOptions,
+    /// How streams on this listener are packaged, instead of the global `[packaging]`
+    pub packaging: Option<PackagingConfig>,
+    /// The streams that may connect to this listener, or all of them if unset
+    pub streams: Option<Vec<u32>>,
+}
+
+impl ListenerConfig {
+    pub fn allowed_stream_ids(&self) -> Option<RoaringBitmap> {
+        self.streams.as_ref().map(|streams| streams.iter().copied().collect())
+    }
+
+    pub fn socket(&self, activated: &mut ActivatedSockets) -> std::io::Result<UdpSocket> {
+        if let Some(socket) = activated.take(&self.name) {
+            return Ok(socket);
+        }
+        match self.address {
+            Some(SocketAddr::V6(address)) => bind_ipv6(&address, self.ipv6_only),
+            Some(address) => UdpSocket::bind(address),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no address configured and no socket named {} passed in", self.name))),
+        }
+    }
+}
+
+/// Binds an IPv6 socket, setting IPV6_V6ONLY explicitly rather than relying on the net.ipv6.bindv6only sysctl
+fn bind_ipv6(address: &SocketAddrV6, ipv6_only: bool) -> std::io::Result<UdpSocket> {
+    use libc::*;
+
+    unsafe {
+        let fd = socket(AF_INET6, SOCK_DGRAM | SOCK_CLOEXEC, 0);
+        if fd == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // Owned from here on, so that it's closed on error
+        let socket = UdpSocket::from_raw_fd(fd);
+
+        let v6only = ipv6_only as c_int;
+        if setsockopt(fd, IPPROTO_IPV6, IPV6_V6ONLY, &v6only as *const c_int as *const c_void, std::mem::size_of::<c_int>() as socklen_t) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut addr: sockaddr_in6 = std::mem::zeroed();
+        addr.sin6_family = AF_INET6 as sa_family_t;
+        addr.sin6_port = address.port().to_be();
+        addr.sin6_flowinfo = address.flowinfo();
+        addr.sin6_addr.s6_addr = address.ip().octets();
+        addr.sin6_scope_id = address.scope_id();
+        if bind(fd, &addr as *const sockaddr_in6 as *const sockaddr, std::mem::size_of::<sockaddr_in6>() as socklen_t) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+/// Sockets passed in by systemd socket activation, by their FileDescriptorName=
+pub struct ActivatedSockets {
+    fds: HashMap<String, Vec<RawFd>>,
+}
+
+const LISTEN_FDS_START: RawFd = 3;
+
+impl ActivatedSockets {
+    pub fn from_env() -> ActivatedSockets {
+        let mut fds = HashMap::new();
+        let for_us = std::env::var("LISTEN_PID").ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .map(|pid| pid == std::process::id())
+            .unwrap_or(false);
+        let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok());
+        if let (true, Some(count)) = (for_us, count) {
+            let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+            let mut names = names.split(':');
+            for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+                let name = names.next().unwrap_or("unknown").to_string();
+                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC); }
+                fds.entry(name).or_insert_with(Vec::new).push(fd);
+            }
+        }
+        // Don't pass these on to gpac
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+        ActivatedSockets { fds }
+    }
+
+    fn take(&mut self, name: &str) -> Option<UdpSocket> {
+        let fds = self.fds.get_mut(name)?;
+        if fds.is_empty() {
+            return None;
+        }
+        Some(unsafe { UdpSocket::from_raw_fd(fds.remove(0)) })
+    }
+}
@@ -3,23 +3,50 @@
 #![recursion_limit="256"]
 
 use arc_swap::ArcSwap;
+use futures::channel::mpsc;
 use futures::executor::block_on;
-use futures::task::AtomicWaker;
+use http_types::Url;
 use openat::Dir;
 use serde::Deserialize;
-use sqlx::{Connect, SqliteConnection};
-use std::net::UdpSocket;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
 use std::os::unix::io::FromRawFd;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
+mod access;
+mod captions;
+mod cea608;
+mod cea708;
+mod cgroup;
+mod codec;
+mod discontinuity;
+mod demux;
+mod feed;
 mod gpac;
+mod health;
+mod listener;
 mod log;
+mod metadata;
+mod metrics;
+mod migrate;
+mod monitor;
+mod mp4;
 mod notify;
 mod pidfd;
-mod shared;
+mod playout;
+mod pool;
+mod sandbox;
+mod scte35;
+mod sessions;
+mod slate;
 mod srt;
+mod srt_options;
+mod store;
 mod stream_db;
 mod syscall;
+mod timed_metadata;
+mod tracks;
+mod ts;
+mod upload_proxy;
 
 #[derive(Deserialize)]
 struct DatabaseConfig {
@@ -35,6 +62,32 @@ struct Config {
     httpd_url: String,
     external_url: String,
     database: DatabaseConfig,
+    #[serde(default)]
+    supervision: gpac::SupervisionConfig,
+    #[serde(default)]
+    health: health::HealthConfig,
+    /// Address to serve /metrics and the admin API on
+    metrics_listen: Option<SocketAddr>,
+    /// Bearer token for posting timed events on the admin API, which needs one if timed_metadata is enabled
+    admin_token: Option<String>,
+    sandbox: Option<sandbox::SandboxConfig>,
+    cgroup: Option<cgroup::CgroupConfig>,
+    #[serde(default)]
+    srt: srt_options::SrtOptions,
+    #[serde(default)]
+    packaging: gpac::PackagingConfig,
+    #[serde(default)]
+    access: access::AccessConfig,
+    /// Accept timed events for live sessions on the admin API, and pass on SCTE-35 cues, for them to be added to gpac's
+    /// uploads as they pass through ingestd-srt on their way to httpd
+    #[serde(default)]
+    timed_metadata: bool,
+    /// Turn CEA-608 or CEA-708 captions in the video into a WebVTT track in the MPD
+    #[serde(default)]
+    captions: bool,
+    /// Without any listeners, ingestd-srt listens on the socket passed in as stdin
+    #[serde(default, rename = "listener")]
+    listeners: Vec<listener::ListenerConfig>,
 }
 
 fn secret_from_toml<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
@@ -50,29 +103,150 @@ fn secret_from_toml<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result
     }
 }
 
-fn main() {
-    let mut args = std::env::args();
-    if args.len() != 2 {
-        eprintln!("usage: ingestd-srt config");
-        std::process::exit(1);
+fn migrate_db(store: &dyn store::StreamStore) {
+    match block_on(store.migrate()) {
+        Ok(migrated) if migrated.from != migrated.to => eprintln!("migrated database from schema version {} to {}", migrated.from, migrated.to),
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("migrating database failed: {}", e);
+            std::process::exit(1);
+        },
     }
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    let (migrate_only, config_filename) = match &args[1..] {
+        [config_filename] => (false, config_filename),
+        [command, config_filename] if command == "migrate" => (true, config_filename),
+        _ => {
+            eprintln!("usage: ingestd-srt [migrate] config");
+            std::process::exit(1);
+        },
+    };
     let config = {
-        let config_filename = args.nth(1).unwrap();
         let config_toml = std::fs::read(config_filename).unwrap();
         toml::from_slice::<Config>(&config_toml).unwrap()
     };
 
-    let listener = unsafe { UdpSocket::from_raw_fd(0) };
-    let log_dir = Dir::open(config.stream_logs).unwrap();
+    let store: &'static dyn store::StreamStore = match block_on(store::open(&config.database.url)) {
+        Ok(store) => Box::leak(store),
+        Err(e) => {
+            eprintln!("opening database failed: {}", e);
+            std::process::exit(1);
+        },
+    };
+    migrate_db(store);
+    if migrate_only {
+        return;
+    }
+
+    let srt_profiles = if config.listeners.is_empty() {
+        vec![config.srt.clone()]
+    } else {
+        config.listeners.iter().map(|listener_config| config.srt.merge(&listener_config.srt)).collect::<Vec<_>>()
+    };
+    for (i, srt_profile) in srt_profiles.iter().enumerate() {
+        if let Err(e) = srt_profile.validate() {
+            let name = config.listeners.get(i).map(|listener_config| listener_config.name.as_str()).unwrap_or("default");
+            eprintln!("invalid srt options for listener {}: {}", name, e);
+            std::process::exit(1);
+        }
+    }
+    for listener_config in &config.listeners {
+        if let Err(e) = listener_config.packaging.as_ref().unwrap_or(&config.packaging).validate() {
+            eprintln!("invalid packaging for listener {}: {}", listener_config.name, e);
+            std::process::exit(1);
+        }
+        if config.listeners.iter().filter(|other| other.name == listener_config.name).count() > 1 {
+            eprintln!("listener {} is configured more than once", listener_config.name);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = config.packaging.validate() {
+        eprintln!("invalid packaging: {}", e);
+        std::process::exit(1);
+    }
+    if config.timed_metadata && config.metrics_listen.is_some() && config.admin_token.as_deref().unwrap_or_default().is_empty() {
+        eprintln!("timed_metadata needs an admin-token, for posting events on the admin API");
+        std::process::exit(1);
+    }
+
+    let mut activated_sockets = listener::ActivatedSockets::from_env();
+    let log_dir = Dir::open(&config.stream_logs).unwrap();
+
+    let streams = Arc::new(ArcSwap::from_pointee(block_on(stream_db::load_streams(store, &srt_profiles)).unwrap()));
+
+    let httpd_addr = Url::parse(&config.httpd_url).unwrap().socket_addrs(|| None).unwrap()[0];
+    // Tasks can only be spawned from inside the executor
+    smol::block_on(async move {
+        let caption_uploads = if config.captions {
+            let (sender, receiver) = mpsc::unbounded();
+            smol::Task::spawn(upload_proxy::upload_captions(receiver, httpd_addr)).detach();
+            Some(sender)
+        } else {
+            None
+        };
+        let timed_metadata: &'static timed_metadata::TimedMetadata = Box::leak(Box::new(timed_metadata::TimedMetadata::new(config.timed_metadata, caption_uploads)));
+        // gpac uploads to the proxy instead, which listens alongside httpd so that the sandbox can reach it the same way.
+        // It always runs, as it also gives the MPD its audio languages.
+        let packager_httpd_url = {
+            let proxy_listener = TcpListener::bind((httpd_addr.ip(), 0)).unwrap();
+            let proxy_addr = proxy_listener.local_addr().unwrap();
+            smol::Task::spawn(async move {
+                if let Err(e) = upload_proxy::serve(proxy_listener, httpd_addr, timed_metadata).await {
+                    eprintln!("upload proxy failed: {}", e);
+                }
+            }).detach();
+            let mut url = Url::parse(&config.httpd_url).unwrap();
+            url.set_ip_host(proxy_addr.ip()).unwrap();
+            url.set_port(Some(proxy_addr.port())).unwrap();
+            url.to_string()
+        };
 
-    let mut bitmap_db_connection = block_on(SqliteConnection::connect(&config.database.url)).unwrap();
-    let gpac_db_connection = block_on(SqliteConnection::connect(&config.database.url)).unwrap();
-    let valid_stream_ids = Arc::new(ArcSwap::from_pointee(stream_db::generate_bitmap(&mut bitmap_db_connection)));
+        let sandbox = config.sandbox.as_ref().map(|sandbox_config| {
+            let httpd_addr = Url::parse(&packager_httpd_url).unwrap().socket_addrs(|| None).unwrap()[0];
+            sandbox::Sandbox::prepare(sandbox_config, httpd_addr).unwrap()
+        });
 
-    let gpac_waker = Arc::new(AtomicWaker::new());
-    let new_connections = Arc::new(Mutex::new(Vec::new()));
+        let cgroups = config.cgroup.map(|cgroup_config| cgroup::Cgroups::setup(cgroup_config).unwrap());
 
-    srt::spawn_listen(listener, log_dir, config.secret, valid_stream_ids.clone(), gpac_waker.clone(), new_connections.clone()).unwrap();
-    std::thread::Builder::new().name("sigusr1".to_string()).spawn(move || stream_db::listen_signal(valid_stream_ids, &mut bitmap_db_connection)).unwrap();
-    smol::block_on(gpac::listen(gpac_waker, new_connections, gpac_db_connection, config.httpd_url, config.external_url));
+        let access: &'static access::AccessControl = Box::leak(Box::new(access::AccessControl::new(config.access)));
+        let srt_runtime = srt::start().unwrap();
+        let listeners = if config.listeners.is_empty() {
+            let socket = unsafe { UdpSocket::from_raw_fd(0) };
+            vec![gpac::IngestListener {
+                name: "default".to_string(),
+                srt: srt_runtime.listen(socket, config.secret, &srt_profiles[0], None, streams.clone(), access).unwrap(),
+                packaging: config.packaging.clone(),
+            }]
+        } else {
+            // Closures capture all of config, which is partly moved by now
+            let (secret, default_packaging) = (config.secret, &config.packaging);
+            config.listeners.iter().zip(&srt_profiles).map(|(listener_config, srt_profile)| {
+                let socket = listener_config.socket(&mut activated_sockets).unwrap_or_else(|e| {
+                    eprintln!("getting socket for listener {} failed: {}", listener_config.name, e);
+                    std::process::exit(1);
+                });
+                gpac::IngestListener {
+                    name: listener_config.name.clone(),
+                    srt: srt_runtime.listen(socket, secret, srt_profile, listener_config.allowed_stream_ids(), streams.clone(), access).unwrap(),
+                    packaging: listener_config.packaging.clone().unwrap_or_else(|| default_packaging.clone()),
+                }
+            }).collect()
+        };
+        smol::Task::spawn(stream_db::keep_loaded(store, streams, srt_profiles)).detach();
+        smol::Task::spawn(access.expire_handshakes()).detach();
+        let sessions: &'static sessions::Registry = Box::leak(Box::new(sessions::Registry::new()));
+        if let Some(metrics_listen) = config.metrics_listen {
+            let metrics_listener = TcpListener::bind(metrics_listen).unwrap();
+            let admin_token: Option<&'static str> = config.admin_token.map(|admin_token| &*Box::leak(admin_token.into_boxed_str()));
+            smol::Task::spawn(async move {
+                if let Err(e) = metrics::serve(metrics_listener, sessions, timed_metadata, admin_token).await {
+                    eprintln!("metrics listener failed: {}", e);
+                }
+            }).detach();
+        }
+        gpac::listen(listeners, config.packaging, log_dir, store, packager_httpd_url, config.external_url, config.supervision, config.health, sandbox, cgroups, sessions, timed_metadata).await;
+    });
 }
@@ -0,0 +1,380 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::codec::{self, AudioFormat, VideoFormat, VideoFrame};
+use crate::demux::{self, Pes, Program};
+use crate::ts;
+
+/// How long bitrate and frame rate are averaged over, and how long after the stream starts the first metadata is
+/// published
+const WINDOW: Duration = Duration::from_secs(5);
+
+/// Frame rates closer than this are considered the same, as measured ones jitter a little
+const FRAME_RATE_TOLERANCE: f64 = 0.1;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Video {
+    pub codec: &'static str,
+    /// Known once an SPS has been seen
+    #[serde(flatten)]
+    pub format: Option<VideoFormat>,
+    /// Measured from the timestamps, once a window's worth of frames has been seen
+    pub frame_rate: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Audio {
+    pub pid: u16,
+    pub codec: &'static str,
+    pub channels: Option<u8>,
+    pub sample_rate: Option<u32>,
+    /// ISO 639 code from the PMT
+    pub language: Option<String>,
+}
+
+/// What the streamer is sending, as reported to the cms
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Metadata {
+    pub video: Option<Video>,
+    pub audio: Vec<Audio>,
+    /// Average bitrate of the whole transport stream over the last window, in bits per second
+    pub bitrate: Option<u64>,
+}
+
+impl Metadata {
+    /// Whether the format is the same, ignoring the bitrate and small changes in frame rate
+    fn same_format(&self, other: &Metadata) -> bool {
+        let same_video = match (&self.video, &other.video) {
+            (Some(a), Some(b)) => {
+                let same_frame_rate = match (a.frame_rate, b.frame_rate) {
+                    (Some(a), Some(b)) => (a - b).abs() < FRAME_RATE_TOLERANCE,
+                    (a, b) => a.is_none() == b.is_none(),
+                };
+                a.codec == b.codec && a.format == b.format && same_frame_rate
+            },
+            (a, b) => a.is_none() == b.is_none(),
+        };
+        same_video && self.audio == other.audio
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.video {
+            Some(ref video) => {
+                f.write_str(video.codec)?;
+                if let Some(format) = video.format {
+                    write!(f, " {}x{}", format.width, format.height)?;
+                }
+                if let Some(frame_rate) = video.frame_rate {
+                    write!(f, " {:.2}fps", frame_rate)?;
+                }
+            },
+            None => f.write_str("no video")?,
+        }
+        for audio in &self.audio {
+            write!(f, ", {}", audio.codec)?;
+            if let Some(channels) = audio.channels {
+                write!(f, " {}ch", channels)?;
+            }
+            if let Some(sample_rate) = audio.sample_rate {
+                write!(f, " {}Hz", sample_rate)?;
+            }
+            if let Some(ref language) = audio.language {
+                write!(f, " {}", language)?;
+            }
+        }
+        if let Some(bitrate) = self.bitrate {
+            write!(f, ", {} kbit/s", bitrate / 1000)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts frames by their decode timestamps
+#[derive(Default)]
+struct FrameCounter {
+    first: Option<u64>,
+    last: u64,
+    frames: u64,
+}
+
+impl FrameCounter {
+    fn frame(&mut self, timestamp: u64) {
+        if self.first.is_none() {
+            self.first = Some(timestamp);
+        }
+        self.last = timestamp;
+        self.frames += 1;
+    }
+
+    fn frame_rate(&self) -> Option<f64> {
+        let span = (self.last + ts::TIMESTAMP_MODULUS - self.first?) % ts::TIMESTAMP_MODULUS;
+        if self.frames < 2 || span == 0 {
+            return None;
+        }
+        Some((self.frames - 1) as f64 * 90000.0 / span as f64)
+    }
+}
+
+/// Works out the metadata of the incoming transport stream, from what the demuxer finds in it
+pub struct Analyzer {
+    /// The first video stream in the PMT, which is the only one reported, so its frames are the only ones counted
+    video_pid: Option<u16>,
+    /// From the last SPS on `video_pid`
+    video: Option<VideoFormat>,
+    audio: HashMap<u16, AudioFormat>,
+    window_start: Option<Instant>,
+    window_bytes: u64,
+    window_frames: FrameCounter,
+    bitrate: Option<u64>,
+    frame_rate: Option<f64>,
+    published: Option<Metadata>,
+}
+
+impl Analyzer {
+    pub fn new() -> Analyzer {
+        Analyzer {
+            video_pid: None,
+            video: None,
+            audio: HashMap::new(),
+            window_start: None,
+            window_bytes: 0,
+            window_frames: FrameCounter::default(),
+            bitrate: None,
+            frame_rate: None,
+            published: None,
+        }
+    }
+
+    /// The metadata last returned by `changed`
+    pub fn published(&self) -> Option<&Metadata> {
+        self.published.as_ref()
+    }
+
+    /// Counts a received message towards the bitrate
+    pub fn message(&mut self, len: usize, now: Instant) {
+        let window_start = *self.window_start.get_or_insert(now);
+        if now.duration_since(window_start) >= WINDOW {
+            let seconds = now.duration_since(window_start).as_secs_f64();
+            self.bitrate = Some((self.window_bytes as f64 * 8.0 / seconds) as u64);
+            self.frame_rate = self.window_frames.frame_rate();
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+            self.window_frames = FrameCounter::default();
+        }
+        self.window_bytes += len as u64;
+    }
+
+    pub fn program(&mut self, program: &Program) {
+        let video_pid = program.streams.iter().find(|stream| stream.is_video()).map(|stream| stream.pid);
+        if video_pid != self.video_pid {
+            self.video_pid = video_pid;
+            self.video = None;
+            self.window_frames = FrameCounter::default();
+            self.frame_rate = None;
+        }
+        self.audio.retain(|pid, _| program.stream(*pid).is_some());
+        for stream in &program.streams {
+            if stream.is_opus() {
+                if let Some(format) = stream.descriptors().find(|(tag, _)| *tag == demux::DESCRIPTOR_EXTENSION).and_then(|(_, data)| codec::opus_format(data)) {
+                    self.audio.insert(stream.pid, format);
+                }
+            }
+        }
+    }
+
+    /// A PES, along with what is in its frame if it is video
+    pub fn pes(&mut self, pes: &Pes<'_>, frame: Option<&VideoFrame<'_>>) {
+        if let Some(frame) = frame {
+            if Some(pes.stream.pid) != self.video_pid {
+                return;
+            }
+            if let Some(timestamp) = pes.dts.or(pes.pts) {
+                self.window_frames.frame(timestamp);
+            }
+            if let Some(format) = frame.format() {
+                self.video = Some(format);
+            }
+        } else if pes.stream.stream_type == demux::STREAM_TYPE_AAC {
+            if let Some(format) = codec::parse_adts(pes.data) {
+                self.audio.insert(pes.stream.pid, format);
+            }
+        }
+    }
+
+    fn metadata(&self, program: &Program) -> Metadata {
+        let video = program.streams.iter().find(|stream| stream.is_video()).map(|stream| {
+            Video {
+                codec: if stream.stream_type == demux::STREAM_TYPE_H264 { "h264" } else { "hevc" },
+                format: self.video,
+                frame_rate: self.frame_rate,
+            }
+        });
+        let audio = program.streams.iter().filter_map(|stream| {
+            let codec = if stream.stream_type == demux::STREAM_TYPE_AAC {
+                "aac"
+            } else if stream.is_opus() {
+                "opus"
+            } else {
+                return None;
+            };
+            let format = self.audio.get(&stream.pid);
+            Some(Audio {
+                pid: stream.pid,
+                codec,
+                channels: format.map(|format| format.channels),
+                sample_rate: format.map(|format| format.sample_rate),
+                language: stream.language(),
+            })
+        }).collect();
+        Metadata {
+            video,
+            audio,
+            bitrate: self.bitrate,
+        }
+    }
+
+    /// The metadata if it should be published, which is once a window's worth of the stream has been seen, and then
+    /// whenever the format changes
+    pub fn changed(&mut self, program: &Program) -> Option<Metadata> {
+        self.bitrate?;
+        let metadata = self.metadata(program);
+        if self.published.as_ref().map(|published| published.same_format(&metadata)).unwrap_or(false) {
+            return None;
+        }
+        self.published = Some(metadata.clone());
+        Some(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::tests::H264Sps;
+    use crate::demux::ElementaryStream;
+
+    use super::*;
+
+    const MESSAGE_LEN: usize = 7 * ts::PACKET_SIZE;
+
+    fn stream(pid: u16, stream_type: u8) -> ElementaryStream {
+        ElementaryStream { pid, stream_type, descriptors: Vec::new() }
+    }
+
+    /// H.264 on 0x100, AAC on 0x101, and a second H.264 stream on 0x102, which isn't reported
+    fn program() -> Program {
+        Program {
+            program_number: 1,
+            pmt_pid: 0x1000,
+            version: 0,
+            pcr_pid: 0x100,
+            streams: vec![stream(0x100, demux::STREAM_TYPE_H264), stream(0x101, demux::STREAM_TYPE_AAC), stream(0x102, demux::STREAM_TYPE_H264)],
+        }
+    }
+
+    struct Stream {
+        analyzer: Analyzer,
+        program: Program,
+        start: Instant,
+    }
+
+    impl Stream {
+        fn new() -> Stream {
+            let mut analyzer = Analyzer::new();
+            let program = program();
+            analyzer.program(&program);
+            Stream { analyzer, program, start: Instant::now() }
+        }
+
+        /// A frame on `pid` at 90kHz `timestamp`, in a message of its own that arrives when the timestamp says
+        fn frame(&mut self, pid: u16, timestamp: u64, sps: Option<&H264Sps>) {
+            self.analyzer.message(MESSAGE_LEN, self.start + Duration::from_micros(timestamp * 100 / 9));
+            let mut data = Vec::new();
+            if let Some(sps) = sps {
+                data.extend_from_slice(&[0, 0, 0, 1]);
+                data.extend(sps.nal());
+            }
+            // An IDR slice
+            data.extend_from_slice(&[0, 0, 0, 1, 0x65, 0x88]);
+            let pes = Pes { stream: self.program.stream(pid).unwrap(), pts: Some(timestamp), dts: None, random_access: true, data: &data };
+            self.analyzer.pes(&pes, Some(&VideoFrame::parse(&data, false)));
+        }
+
+        /// Frames `interval` apart on 0x100, from `from` up to but not including `until`
+        fn frames(&mut self, from: u64, until: u64, interval: u64) {
+            for timestamp in (from..until).step_by(interval as usize) {
+                self.frame(0x100, timestamp, None);
+            }
+        }
+
+        fn changed(&mut self) -> Option<Metadata> {
+            self.analyzer.changed(&self.program)
+        }
+    }
+
+    fn video(format: Option<VideoFormat>, frame_rate: Option<f64>) -> Option<Video> {
+        Some(Video { codec: "h264", format, frame_rate })
+    }
+
+    const HD: VideoFormat = VideoFormat { profile: 100, level: 40, width: 1920, height: 1080 };
+
+    #[test]
+    fn publishes_once_a_window_has_been_seen() {
+        let mut stream = Stream::new();
+        stream.frame(0x100, 0, Some(&H264Sps::default()));
+        assert_eq!(stream.changed(), None);
+        // 25fps, up to just before the window ends
+        stream.frames(3600, 5 * 90000, 3600);
+        assert_eq!(stream.changed(), None);
+
+        stream.frame(0x100, 5 * 90000, None);
+        assert_eq!(stream.changed(), Some(Metadata {
+            video: video(Some(HD), Some(25.0)),
+            audio: vec![Audio { pid: 0x101, codec: "aac", channels: None, sample_rate: None, language: None }],
+            // The 125 messages before the one that ended the window, over its 5 seconds
+            bitrate: Some(125 * MESSAGE_LEN as u64 * 8 / 5),
+        }));
+        assert_eq!(stream.changed(), None);
+    }
+
+    #[test]
+    fn publishes_format_changes_but_not_jitter() {
+        let mut stream = Stream::new();
+        stream.frame(0x100, 0, Some(&H264Sps::default()));
+        stream.frames(3600, 5 * 90000 + 1, 3600);
+        assert_eq!(stream.changed().unwrap().video, video(Some(HD), Some(25.0)));
+
+        // A little slower, with fewer bytes a second
+        stream.frames(5 * 90000 + 3597, 10 * 90000 + 3597, 3597);
+        let jittered = stream.analyzer.frame_rate.unwrap();
+        assert!(jittered != 25.0 && (jittered - 25.0).abs() < FRAME_RATE_TOLERANCE);
+        assert_eq!(stream.changed(), None);
+
+        let sps = H264Sps { width_in_mbs: 80, height_in_map_units: 45, crop: None, ..H264Sps::default() };
+        stream.frame(0x100, 10 * 90000 + 2 * 3597, Some(&sps));
+        let changed = stream.changed().unwrap();
+        assert_eq!(changed.video.unwrap().format, Some(VideoFormat { width: 1280, height: 720, ..HD }));
+        assert_eq!(stream.changed(), None);
+
+        stream.frames(10 * 90000 + 3 * 3597, 16 * 90000, 1800);
+        // The window starts with a couple of frames from before the frame rate doubled
+        let changed = stream.changed().unwrap();
+        assert_eq!(changed.video.unwrap().frame_rate.map(f64::round), Some(50.0));
+    }
+
+    #[test]
+    fn counts_frames_of_the_reported_video_stream_only() {
+        let mut stream = Stream::new();
+        let other = H264Sps { width_in_mbs: 80, height_in_map_units: 45, crop: None, ..H264Sps::default() };
+        stream.frame(0x100, 0, Some(&H264Sps::default()));
+        for timestamp in (3600..=5 * 90000).step_by(3600) {
+            // Another video stream at a different frame rate and size, between each frame of the first
+            stream.frame(0x102, timestamp - 1800, Some(&other));
+            stream.frame(0x102, timestamp - 900, None);
+            stream.frame(0x100, timestamp, None);
+        }
+        assert_eq!(stream.changed().unwrap().video, video(Some(HD), Some(25.0)));
+    }
+}
@@ -0,0 +1,238 @@
+use async_dup::Arc;
+use http_types::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use smol::{Async, Task};
+use std::fmt::Write;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+use crate::pidfd::ExitStatus;
+use crate::sessions::Registry;
+use crate::timed_metadata::{self, TimedMetadata};
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down, such as the total of something over every packager
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    const fn new() -> Gauge {
+        Gauge(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, n: u64) {
+        self.0.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// Raises the gauge to `n`, if it is lower
+    pub fn max(&self, n: u64) {
+        self.0.fetch_max(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub static GPAC_EXITED_SUCCESS: Counter = Counter::new();
+pub static GPAC_EXITED_FAILURE: Counter = Counter::new();
+pub static GPAC_SIGNALLED: Counter = Counter::new();
+pub static GPAC_CORE_DUMPED: Counter = Counter::new();
+pub static PACKAGER_OOM_KILLS: Counter = Counter::new();
+pub static PACKAGER_CPU_USEC: Counter = Counter::new();
+pub static PACKAGER_MEMORY_BYTES: Gauge = Gauge::new();
+pub static PACKAGER_MEMORY_PEAK_BYTES: Gauge = Gauge::new();
+pub static REJECTED_DENIED: Counter = Counter::new();
+pub static REJECTED_NOT_ALLOWED: Counter = Counter::new();
+pub static REJECTED_BANNED: Counter = Counter::new();
+pub static REJECTED_BAD_STREAM_ID: Counter = Counter::new();
+pub static REJECTED_UNKNOWN_STREAM: Counter = Counter::new();
+pub static REJECTED_NOT_ON_LISTENER: Counter = Counter::new();
+pub static REJECTED_HANDSHAKE_INCOMPLETE: Counter = Counter::new();
+pub static REJECTED_INTERNAL: Counter = Counter::new();
+pub static BANS: Counter = Counter::new();
+
+pub fn record_gpac_exit(status: &ExitStatus) {
+    match *status {
+        ExitStatus::Exited { code: 0 } => GPAC_EXITED_SUCCESS.inc(),
+        ExitStatus::Exited { .. } => GPAC_EXITED_FAILURE.inc(),
+        ExitStatus::Signalled { core_dumped: false, .. } => GPAC_SIGNALLED.inc(),
+        ExitStatus::Signalled { core_dumped: true, .. } => GPAC_CORE_DUMPED.inc(),
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, samples: &[(&str, &Counter)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (labels, counter) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, counter.get());
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, counter.get());
+        }
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, gauge: &Gauge) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, gauge.get());
+}
+
+/// Renders all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+    counter(&mut out, "ingestd_gpac_exits_total", "Number of times a gpac process has ended, by how it ended.", &[
+        ("status=\"success\"", &GPAC_EXITED_SUCCESS),
+        ("status=\"failure\"", &GPAC_EXITED_FAILURE),
+        ("status=\"signalled\"", &GPAC_SIGNALLED),
+        ("status=\"core_dumped\"", &GPAC_CORE_DUMPED),
+    ]);
+    counter(&mut out, "ingestd_packager_oom_kills_total", "Number of times a packager has been killed for exceeding its memory limit.", &[
+        ("", &PACKAGER_OOM_KILLS),
+    ]);
+    counter(&mut out, "ingestd_packager_cpu_usage_microseconds_total", "CPU time used by packagers, updated while they run.", &[
+        ("", &PACKAGER_CPU_USEC),
+    ]);
+    gauge(&mut out, "ingestd_packager_memory_bytes", "Memory used by the running packagers, sampled while they run.", &PACKAGER_MEMORY_BYTES);
+    gauge(&mut out, "ingestd_packager_memory_peak_bytes", "The most memory any packager has used.", &PACKAGER_MEMORY_PEAK_BYTES);
+    counter(&mut out, "ingestd_srt_rejections_total", "Number of SRT handshakes rejected, by reason.", &[
+        ("reason=\"denied\"", &REJECTED_DENIED),
+        ("reason=\"not_allowed\"", &REJECTED_NOT_ALLOWED),
+        ("reason=\"banned\"", &REJECTED_BANNED),
+        ("reason=\"bad_stream_id\"", &REJECTED_BAD_STREAM_ID),
+        ("reason=\"unknown_stream\"", &REJECTED_UNKNOWN_STREAM),
+        ("reason=\"not_on_listener\"", &REJECTED_NOT_ON_LISTENER),
+        ("reason=\"handshake_incomplete\"", &REJECTED_HANDSHAKE_INCOMPLETE),
+        ("reason=\"internal\"", &REJECTED_INTERNAL),
+    ]);
+    counter(&mut out, "ingestd_srt_bans_total", "Number of times an address has been banned for failing too many handshakes.", &[
+        ("", &BANS),
+    ]);
+    out
+}
+
+#[derive(Serialize)]
+struct PostedEventId {
+    id: u32,
+}
+
+/// Whether the request carries `token` as its bearer token. Compared by hash, as blake3 compares hashes in constant
+/// time.
+fn authorized(request: &Request, token: &str) -> bool {
+    let bearer = request.iter()
+        .find(|(name, _)| name.as_str() == "authorization")
+        .and_then(|(_, values)| values.iter().next())
+        .and_then(|value| value.as_str().strip_prefix("Bearer "));
+    match bearer {
+        Some(bearer) => blake3::hash(bearer.as_bytes()) == blake3::hash(token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Serves /metrics for Prometheus, and the admin API. Posting timed events needs `admin_token`.
+pub async fn serve(listener: TcpListener, sessions: &'static Registry, timed_metadata: &'static TimedMetadata, admin_token: Option<&'static str>) -> std::io::Result<()> {
+    let listener = Async::new(listener)?;
+    loop {
+        let (stream, _peer_addr) = listener.accept().await?;
+        let stream = Arc::new(stream);
+        Task::spawn(async move {
+            let res = async_h1::accept(stream, |mut request| async move {
+                let mut response = Response::new(StatusCode::Ok);
+                let path = request.url().path().to_string();
+                match path.as_str() {
+                    "/metrics" => {
+                        response.set_content_type("text/plain; version=0.0.4".parse().unwrap());
+                        response.set_body(render());
+                    },
+                    "/sessions" => {
+                        response.set_body(Body::from_json(&sessions.list())?);
+                    },
+                    // Timed events for a session's MPD, named by the uuid in its mpd_url
+                    path => {
+                        let packager = path.strip_prefix("/sessions/").and_then(|rest| rest.strip_suffix("/events")).and_then(|uuid| uuid.parse::<Uuid>().ok());
+                        let (admin_token, packager) = match (timed_metadata.events(), admin_token, packager) {
+                            (true, Some(admin_token), Some(packager)) => (admin_token, packager),
+                            _ => return Ok(Response::new(StatusCode::NotFound)),
+                        };
+                        if request.method() != Method::Post {
+                            return Ok(Response::new(StatusCode::MethodNotAllowed));
+                        }
+                        if !authorized(&request, admin_token) {
+                            response.set_status(StatusCode::Unauthorized);
+                            response.insert_header("www-authenticate", "Bearer");
+                            return Ok(response);
+                        }
+                        let event = match request.body_json().await {
+                            Ok(event) => event,
+                            Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+                        };
+                        match timed_metadata.push(&packager, event) {
+                            Ok(id) => {
+                                response.set_status(StatusCode::Accepted);
+                                response.set_body(Body::from_json(&PostedEventId { id })?);
+                            },
+                            Err(e @ timed_metadata::Error::UnknownPackager) => {
+                                response.set_status(StatusCode::NotFound);
+                                response.set_body(e.to_string());
+                            },
+                            Err(e @ timed_metadata::Error::InvalidEvent) => {
+                                response.set_status(StatusCode::BadRequest);
+                                response.set_body(e.to_string());
+                            },
+                        }
+                    },
+                }
+                Ok(response)
+            }).await;
+            if let Err(e) = res {
+                eprintln!("metrics request failed: {}", e);
+            }
+        }).detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request {
+        let mut request = Request::new(Method::Post, http_types::Url::parse("http://localhost/sessions/x/events").unwrap());
+        if let Some(authorization) = authorization {
+            request.insert_header("authorization", authorization);
+        }
+        request
+    }
+
+    #[test]
+    fn needs_the_bearer_token() {
+        assert!(authorized(&request(Some("Bearer secret")), "secret"));
+        assert!(!authorized(&request(Some("Bearer secre")), "secret"));
+        assert!(!authorized(&request(Some("Bearer secret2")), "secret"));
+        assert!(!authorized(&request(Some("Basic secret")), "secret"));
+        assert!(!authorized(&request(None), "secret"));
+    }
+}
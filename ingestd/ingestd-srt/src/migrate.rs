@@ -0,0 +1,129 @@
+use sqlx::postgres::PgRow;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Executor, PgConnection, Row, SqliteConnection, query};
+use thiserror::Error;
+
+/// The sqlite schema, as a series of migrations. Migration n (counting from 1) takes the database from
+/// `PRAGMA user_version` n - 1 to n. Never edit one that has been released, add a new one instead.
+const SQLITE_MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/sqlite/0001_streams.sql"),
+    include_str!("../migrations/sqlite/0002_stream_srt_options.sql"),
+    include_str!("../migrations/sqlite/0003_sessions.sql"),
+    include_str!("../migrations/sqlite/0004_channels.sql"),
+    include_str!("../migrations/sqlite/0005_stream_audio_tracks.sql"),
+];
+
+/// The Postgres schema, versioned in the ingestd_schema_version table rather than user_version
+const POSTGRES_MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/postgres/0001_schema.sql"),
+    include_str!("../migrations/postgres/0002_channels.sql"),
+    include_str!("../migrations/postgres/0003_stream_audio_tracks.sql"),
+];
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("database: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("database schema is version {0}, newer than the {1} this ingestd-srt supports")]
+    TooNew(i32, i32),
+}
+
+/// The schema versions a database was migrated between
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Migrated {
+    pub from: i32,
+    pub to: i32,
+}
+
+async fn user_version(db: &mut SqliteConnection) -> Result<i32, sqlx::Error> {
+    query("PRAGMA user_version").map(|row: SqliteRow| row.get::<i32, _>(0)).fetch_one(db).await
+}
+
+async fn has_table(db: &mut SqliteConnection, table: &str) -> Result<bool, sqlx::Error> {
+    let count = query("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?").bind(table).map(|row: SqliteRow| row.get::<i32, _>(0)).fetch_one(db).await?;
+    Ok(count > 0)
+}
+
+/// From PRAGMA table_info, as sqlx prepares statements without virtual tables, so pragma_table_info() can't be used
+async fn has_column(db: &mut SqliteConnection, table: &str, column: &str) -> Result<bool, sqlx::Error> {
+    let columns = query(&format!("PRAGMA table_info({})", table)).map(|row: SqliteRow| row.get::<String, _>("name")).fetch_all(db).await?;
+    Ok(columns.iter().any(|name| name == column))
+}
+
+/// Databases created from schema.sql before migrations existed have no user_version, so work out which migrations
+/// they already match
+async fn adopt_unversioned(db: &mut SqliteConnection) -> Result<i32, sqlx::Error> {
+    if !has_table(db, "streams").await? {
+        return Ok(0);
+    }
+    if !has_column(db, "streams", "srt_options").await? {
+        return Ok(1);
+    }
+    if !has_table(db, "sessions").await? {
+        return Ok(2);
+    }
+    Ok(3)
+}
+
+/// Brings the database up to the current schema version
+pub async fn migrate_sqlite(db: &mut SqliteConnection) -> Result<Migrated, Error> {
+    let latest = SQLITE_MIGRATIONS.len() as i32;
+    let version = user_version(db).await?;
+    if version > latest {
+        return Err(Error::TooNew(version, latest));
+    }
+    let from = match version {
+        0 => adopt_unversioned(db).await?,
+        version => version,
+    };
+    for (i, migration) in SQLITE_MIGRATIONS.iter().enumerate().skip(from as usize) {
+        // PRAGMA user_version is part of the transaction, so a failed migration leaves the database as it was
+        db.execute(&*format!("BEGIN;\n{}\nPRAGMA user_version = {};\nCOMMIT;", migration, i + 1)).await?;
+    }
+    if user_version(db).await? != latest {
+        // An adopted database that was already up to date still needs its version recorded
+        db.execute(&*format!("PRAGMA user_version = {};", latest)).await?;
+    }
+    Ok(Migrated { from, to: latest })
+}
+
+pub async fn migrate_postgres(db: &mut PgConnection) -> Result<Migrated, Error> {
+    let latest = POSTGRES_MIGRATIONS.len() as i32;
+    db.execute("CREATE TABLE IF NOT EXISTS ingestd_schema_version (version INTEGER NOT NULL)").await?;
+    // Serialises ingest nodes starting at the same time, until the end of the transaction
+    db.execute("BEGIN; LOCK TABLE ingestd_schema_version IN EXCLUSIVE MODE").await?;
+    let res = async {
+        let from = query("SELECT COALESCE(MAX(version), 0) FROM ingestd_schema_version").map(|row: PgRow| row.get::<i32, _>(0)).fetch_one(&mut *db).await?;
+        if from > latest {
+            return Err(Error::TooNew(from, latest));
+        }
+        for (i, migration) in POSTGRES_MIGRATIONS.iter().enumerate().skip(from as usize) {
+            db.execute(*migration).await?;
+            query("INSERT INTO ingestd_schema_version (version) VALUES ($1)").bind(i as i32 + 1).execute(&mut *db).await?;
+        }
+        Ok(Migrated { from, to: latest })
+    }.await;
+    match res {
+        Ok(_) => db.execute("COMMIT").await?,
+        Err(_) => db.execute("ROLLBACK").await?,
+    };
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use sqlx::Connect;
+
+    use super::*;
+
+    #[test]
+    fn adopts_databases_from_before_migrations() {
+        let mut db = block_on(SqliteConnection::connect("sqlite:%3Amemory:")).unwrap();
+        // As schema.sql left them, without a user_version
+        block_on(db.execute(&*SQLITE_MIGRATIONS[..2].concat())).unwrap();
+        assert_eq!(block_on(migrate_sqlite(&mut db)).unwrap(), Migrated { from: 2, to: SQLITE_MIGRATIONS.len() as i32 });
+        assert!(block_on(has_column(&mut db, "streams", "audio_tracks")).unwrap());
+        assert_eq!(block_on(migrate_sqlite(&mut db)).unwrap(), Migrated { from: SQLITE_MIGRATIONS.len() as i32, to: SQLITE_MIGRATIONS.len() as i32 });
+    }
+}
@@ -0,0 +1,135 @@
+use std::time::Instant;
+
+use crate::cea608;
+use crate::codec::VideoFrame;
+use crate::demux::{self, Demuxer, Event};
+use crate::health::{HealthChange, HealthConfig, Watchdog};
+use crate::metadata::{Analyzer, Metadata};
+use crate::scte35::{self, Cue};
+use crate::ts;
+
+/// Demuxes what a session receives once, for everything that needs to look inside the stream
+pub struct Monitor {
+    demuxer: Demuxer,
+    analyzer: Analyzer,
+    watchdog: Watchdog,
+    scte35: scte35::Decoder,
+    /// Decoded since they were last taken
+    cues: Vec<Cue>,
+    /// If captions are enabled
+    captions: Option<cea608::Decoder>,
+}
+
+impl Monitor {
+    pub fn new(health: &'static HealthConfig, captions: bool, now: Instant) -> Monitor {
+        Monitor {
+            demuxer: Demuxer::new(),
+            analyzer: Analyzer::new(),
+            watchdog: Watchdog::new(health, now),
+            scte35: scte35::Decoder::new(),
+            cues: Vec::new(),
+            captions: if captions { Some(cea608::Decoder::new()) } else { None },
+        }
+    }
+
+    pub fn push(&mut self, message: &[u8], now: Instant) {
+        let Monitor { demuxer, analyzer, watchdog, scte35, cues, captions } = self;
+        analyzer.message(message.len(), now);
+        for packet in ts::packets(message) {
+            watchdog.packet(packet, now);
+            demuxer.push(packet, &mut |event| match event {
+                Event::Program(program) => {
+                    analyzer.program(program);
+                    watchdog.program(program.streams.iter().any(|stream| stream.is_video()), now);
+                },
+                Event::Pes(pes) => {
+                    let frame = if pes.stream.is_video() {
+                        Some(VideoFrame::parse(pes.data, pes.stream.stream_type == demux::STREAM_TYPE_HEVC))
+                    } else {
+                        None
+                    };
+                    analyzer.pes(&pes, frame.as_ref());
+                    if let Some(frame) = &frame {
+                        watchdog.video(frame.keyframe || pes.random_access, now);
+                        if let (Some(captions), Some(pts)) = (captions.as_mut(), pes.pts) {
+                            captions.frame(pts, pes.dts.unwrap_or(pts), &frame.cc_data());
+                        }
+                    }
+                },
+                Event::Section(stream, section) => {
+                    if stream.stream_type == demux::STREAM_TYPE_SCTE35 {
+                        cues.extend(scte35.section(section));
+                    }
+                },
+            });
+        }
+    }
+
+    /// See `Analyzer::changed`
+    pub fn metadata_changed(&mut self) -> Option<Metadata> {
+        self.analyzer.changed(self.demuxer.program()?)
+    }
+
+    /// Whether the stream is audio without video, once its PMT has been seen
+    pub fn audio_only(&self) -> Option<bool> {
+        let streams = &self.demuxer.program()?.streams;
+        Some(!streams.iter().any(|stream| stream.is_video()) && streams.iter().any(|stream| stream.is_audio()))
+    }
+
+    /// The last metadata that was published
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.analyzer.published()
+    }
+
+    /// SCTE-35 cues decoded since this was last called
+    pub fn take_cues(&mut self) -> Vec<Cue> {
+        std::mem::take(&mut self.cues)
+    }
+
+    /// Changes to the captions on screen since this was last called, by the timestamp they happen at
+    pub fn take_captions(&mut self) -> Vec<(u64, String)> {
+        self.captions.as_mut().map(cea608::Decoder::take_changes).unwrap_or_default()
+    }
+
+    /// See `cea608::Decoder::take_cea708`
+    pub fn take_cea708(&mut self) -> bool {
+        self.captions.as_mut().map(cea608::Decoder::take_cea708).unwrap_or(false)
+    }
+
+    pub fn health_changes(&mut self, now: Instant) -> Vec<HealthChange> {
+        self.watchdog.check(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demux::tests::{pat, section_packet, PMT_PID};
+
+    /// A PMT with streams of `stream_types` on PIDs from 0x100 on, the first of which carries the PCR
+    fn pmt(version: u8, stream_types: &[u8]) -> Vec<u8> {
+        let mut section = vec![0x02, 0, 0, 0, 1, 0xc1 | version << 1, 0, 0, 0xe1, 0x00, 0xf0, 0];
+        for (i, &stream_type) in stream_types.iter().enumerate() {
+            section.extend_from_slice(&[stream_type, 0xe1, i as u8, 0xf0, 0]);
+        }
+        section_packet(PMT_PID, section)
+    }
+
+    #[test]
+    fn says_whether_the_stream_is_audio_only_once_its_pmt_is_seen() {
+        let health: &'static HealthConfig = Box::leak(Box::new(HealthConfig::default()));
+        let now = Instant::now();
+        let mut monitor = Monitor::new(health, false, now);
+        assert_eq!(monitor.audio_only(), None);
+        monitor.push(&pat(), now);
+        assert_eq!(monitor.audio_only(), None);
+
+        monitor.push(&pmt(0, &[demux::STREAM_TYPE_AAC]), now);
+        assert_eq!(monitor.audio_only(), Some(true));
+        monitor.push(&pmt(1, &[demux::STREAM_TYPE_H264, demux::STREAM_TYPE_AAC]), now);
+        assert_eq!(monitor.audio_only(), Some(false));
+        // Nothing to hear either
+        monitor.push(&pmt(2, &[demux::STREAM_TYPE_SCTE35]), now);
+        assert_eq!(monitor.audio_only(), Some(false));
+    }
+}
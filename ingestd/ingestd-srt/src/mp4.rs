@@ -0,0 +1,103 @@
+/// Reads a box header, returning its type, header length and total size. A size of 0 means the box runs to the end of
+/// the file.
+pub fn header(data: &[u8]) -> Option<([u8; 4], usize, u64)> {
+    if data.len() < 8 {
+        return None;
+    }
+    let mut box_type = [0; 4];
+    box_type.copy_from_slice(&data[4..8]);
+    match read_u32(data) {
+        1 => {
+            if data.len() < 16 {
+                return None;
+            }
+            Some((box_type, 16, read_u64(&data[8..])))
+        },
+        size => Some((box_type, 8, size as u64)),
+    }
+}
+
+/// The boxes directly inside `data`, as their types and bodies
+pub fn boxes(mut data: &[u8]) -> impl Iterator<Item=([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let (box_type, header_len, size) = header(data)?;
+        let size = if size == 0 { data.len() } else { size as usize };
+        if size < header_len || size > data.len() {
+            return None;
+        }
+        let body = &data[header_len..size];
+        data = &data[size..];
+        Some((box_type, body))
+    })
+}
+
+/// The body of the first box found by following `path` down from `data`
+pub fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, box_type| {
+        boxes(data).find(|(found, _)| found == *box_type).map(|(_, body)| body)
+    })
+}
+
+/// The timescale of the first track in a moov box's body
+pub fn timescale(moov: &[u8]) -> Option<u32> {
+    let mdhd = find(moov, &[b"trak", b"mdia", b"mdhd"])?;
+    // Creation and modification times are 64 bits in version 1
+    let offset = if *mdhd.first()? == 1 { 20 } else { 12 };
+    Some(read_u32(mdhd.get(offset..offset + 4)?))
+}
+
+/// The ISO 639-2/T language of the first track in a moov box's body, unless it is undetermined
+pub fn language(moov: &[u8]) -> Option<String> {
+    let mdhd = find(moov, &[b"trak", b"mdia", b"mdhd"])?;
+    // After the timescale and duration, which are also 64 bits in version 1
+    let offset = if *mdhd.first()? == 1 { 32 } else { 20 };
+    let packed = u16::from_be_bytes([*mdhd.get(offset)?, *mdhd.get(offset + 1)?]);
+    // Three letters of 5 bits each, offset from 0x60
+    let language = [10, 5, 0].iter().map(|shift| (0x60 + (packed >> shift & 0x1f)) as u8 as char).collect::<String>();
+    if !language.chars().all(|letter| letter.is_ascii_lowercase()) || language == "und" {
+        return None;
+    }
+    Some(language)
+}
+
+/// The handler type of the first track in a moov box's body, such as `vide` or `soun`
+pub fn handler_type(moov: &[u8]) -> Option<[u8; 4]> {
+    let hdlr = find(moov, &[b"trak", b"mdia", b"hdlr"])?;
+    let mut handler_type = [0; 4];
+    handler_type.copy_from_slice(hdlr.get(8..12)?);
+    Some(handler_type)
+}
+
+/// The decode time of the first sample in a moof box's body, in its track's timescale
+pub fn base_media_decode_time(moof: &[u8]) -> Option<u64> {
+    let tfdt = find(moof, &[b"traf", b"tfdt"])?;
+    if *tfdt.first()? == 1 {
+        Some(read_u64(tfdt.get(4..12)?))
+    } else {
+        Some(read_u32(tfdt.get(4..8)?) as u64)
+    }
+}
+
+/// Writes a box around `body`
+pub fn write_box(box_type: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + body.len());
+    data.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+    data.extend_from_slice(box_type);
+    data.extend_from_slice(body);
+    data
+}
+
+/// Writes a full box, with its version and flags, around `body`
+pub fn full_box(box_type: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+    let mut data = ((version as u32) << 24 | flags).to_be_bytes().to_vec();
+    data.extend_from_slice(body);
+    write_box(box_type, &data)
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    (read_u32(data) as u64) << 32 | read_u32(&data[4..]) as u64
+}
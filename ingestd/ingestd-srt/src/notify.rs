@@ -1,9 +1,16 @@
 use http_types::{Body, Request, Response, StatusCode, Url};
 use serde::Serialize;
 use smol::Async;
-use std::net::{TcpStream, ToSocketAddrs};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use thiserror::Error;
 
+use crate::gpac::EndReason;
+use crate::health::{HealthChange, State};
+use crate::metadata::Metadata;
+use crate::pidfd::ExitStatus;
+use crate::scte35::Cue;
+use crate::sessions::Summary;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("invalid url")]
@@ -20,12 +27,50 @@ pub enum Error {
     StatusCode(StatusCode)
 }
 
+/// What a notification is about, so that ones sent while the stream is online can be told apart
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Event {
+    Online,
+    Offline,
+    Metadata,
+    HealthDegraded,
+    HealthRecovered,
+    /// The contributor went away, and the stream is showing the slate until they return
+    Slate,
+    /// The contributor to a channel went away, and it is back to playing out its schedule
+    Playout,
+    /// An SCTE-35 cue was found in the stream
+    Cue,
+}
+
 #[derive(Serialize)]
 struct NotifyBody<'a> {
+    event: Event,
     online: bool,
     token: &'a str,
     #[serde(skip_serializing_if = "Option::is_none")]
     mpd_url: Option<&'a str>,
+    /// The HLS playlist gpac writes alongside the MPD, for audio-only streams
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hls_url: Option<&'a str>,
+    /// Whether the stream had no video, and is packaged as audio only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_addr: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_status: Option<&'a ExitStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_reason: Option<EndReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<&'a Summary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a Metadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health: Option<&'a HealthChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cue: Option<&'a Cue>,
 }
 
 async fn fetch(req: Request) -> Result<Response, Error> {
@@ -50,24 +95,142 @@ async fn notify(notify_url: Url, body: &NotifyBody<'_>) -> Result<(), Error> {
     let mut req = Request::post(notify_url);
     req.set_body(Body::from_json(body).unwrap());
     let resp = fetch(req).await?;
-    if resp.status() != StatusCode::Ok {
+    if !resp.status().is_success() {
         return Err(Error::StatusCode(resp.status()));
     }
     Ok(())
 }
 
-pub async fn notify_online(notify_url: Url, token: &str, mpd_url: &str) -> Result<(), Error> {
+pub async fn notify_online(notify_url: Url, token: &str, mpd_url: &str, hls_url: Option<&str>, audio_only: bool, peer_addr: Option<SocketAddr>) -> Result<(), Error> {
     notify(notify_url, &NotifyBody {
+        event: Event::Online,
         online: true,
         token: token,
         mpd_url: Some(mpd_url),
+        hls_url,
+        audio_only: Some(audio_only),
+        peer_addr,
+        exit_status: None,
+        end_reason: None,
+        session: None,
+        metadata: None,
+        health: None,
+        cue: None,
     }).await
 }
 
-pub async fn notify_offline(notify_url: Url, token: &str) -> Result<(), Error> {
+/// Notifies that the stream's metadata is first known, or has changed
+pub async fn notify_metadata(notify_url: Url, token: &str, metadata: &Metadata) -> Result<(), Error> {
     notify(notify_url, &NotifyBody {
+        event: Event::Metadata,
+        online: true,
+        token: token,
+        mpd_url: None,
+        hls_url: None,
+        audio_only: None,
+        peer_addr: None,
+        exit_status: None,
+        end_reason: None,
+        session: None,
+        metadata: Some(metadata),
+        health: None,
+        cue: None,
+    }).await
+}
+
+/// Notifies that something has gone missing from the stream for a while, or come back
+pub async fn notify_health(notify_url: Url, token: &str, change: &HealthChange) -> Result<(), Error> {
+    notify(notify_url, &NotifyBody {
+        event: if change.state == State::Recovered { Event::HealthRecovered } else { Event::HealthDegraded },
+        online: true,
+        token: token,
+        mpd_url: None,
+        hls_url: None,
+        audio_only: None,
+        peer_addr: None,
+        exit_status: None,
+        end_reason: None,
+        session: None,
+        metadata: None,
+        health: Some(change),
+        cue: None,
+    }).await
+}
+
+/// Notifies that the contributor has gone away and the stream is showing the slate, with a summary of the session that
+/// ended
+pub async fn notify_slate(notify_url: Url, token: &str, summary: &Summary) -> Result<(), Error> {
+    notify(notify_url, &NotifyBody {
+        event: Event::Slate,
+        online: true,
+        token: token,
+        mpd_url: None,
+        hls_url: None,
+        audio_only: None,
+        peer_addr: summary.session.peer_addr,
+        exit_status: None,
+        end_reason: Some(summary.end_reason),
+        session: Some(summary),
+        metadata: None,
+        health: None,
+        cue: None,
+    }).await
+}
+
+/// Notifies that the contributor to a channel has gone away and it is playing out its schedule again, with a summary
+/// of the session that ended
+pub async fn notify_playout(notify_url: Url, token: &str, summary: &Summary) -> Result<(), Error> {
+    notify(notify_url, &NotifyBody {
+        event: Event::Playout,
+        online: true,
+        token: token,
+        mpd_url: None,
+        hls_url: None,
+        audio_only: None,
+        peer_addr: summary.session.peer_addr,
+        exit_status: None,
+        end_reason: Some(summary.end_reason),
+        session: Some(summary),
+        metadata: None,
+        health: None,
+        cue: None,
+    }).await
+}
+
+/// Notifies that the stream has gone offline, with a summary of the session and its last known metadata
+pub async fn notify_offline(notify_url: Url, token: &str, summary: &Summary, metadata: Option<&Metadata>) -> Result<(), Error> {
+    notify(notify_url, &NotifyBody {
+        event: Event::Offline,
         online: false,
         token: token,
         mpd_url: None,
+        hls_url: None,
+        audio_only: None,
+        peer_addr: summary.session.peer_addr,
+        exit_status: summary.exit_status.as_ref(),
+        end_reason: Some(summary.end_reason),
+        session: Some(summary),
+        metadata,
+        health: None,
+        cue: None,
+    }).await
+}
+
+/// Notifies that an SCTE-35 splice_insert or time_signal was found in the stream
+pub async fn notify_cue(notify_url: Url, token: &str, cue: &Cue) -> Result<(), Error> {
+    notify(notify_url, &NotifyBody {
+        event: Event::Cue,
+        online: true,
+        token: token,
+        mpd_url: None,
+        hls_url: None,
+        audio_only: None,
+        peer_addr: None,
+        exit_status: None,
+        end_reason: None,
+        session: None,
+        metadata: None,
+        health: None,
+        cue: Some(cue),
     }).await
 }
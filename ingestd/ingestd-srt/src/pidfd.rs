@@ -1,9 +1,77 @@
+use serde::Serialize;
 use smol::Async;
+use std::fmt;
 use std::mem::MaybeUninit;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 const P_PIDFD: libc::idtype_t = 3;
 
+const CLD_EXITED: libc::c_int = 1;
+const CLD_DUMPED: libc::c_int = 3;
+
+// The SIGCHLD member of siginfo_t's union, which libc doesn't expose
+#[repr(C)]
+struct SiginfoChld {
+    si_signo: libc::c_int,
+    si_errno: libc::c_int,
+    si_code: libc::c_int,
+    #[cfg(target_pointer_width = "64")]
+    _pad: libc::c_int,
+    si_pid: libc::pid_t,
+    si_uid: libc::uid_t,
+    si_status: libc::c_int,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExitStatus {
+    Exited { code: i32 },
+    Signalled { signal: i32, core_dumped: bool },
+}
+
+impl ExitStatus {
+    fn from_siginfo(siginfo: &libc::siginfo_t) -> ExitStatus {
+        let siginfo = unsafe { &*(siginfo as *const libc::siginfo_t as *const SiginfoChld) };
+        match siginfo.si_code {
+            CLD_EXITED => ExitStatus::Exited { code: siginfo.si_status },
+            CLD_DUMPED => ExitStatus::Signalled { signal: siginfo.si_status, core_dumped: true },
+            // CLD_KILLED
+            _ => ExitStatus::Signalled { signal: siginfo.si_status, core_dumped: false },
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        *self == ExitStatus::Exited { code: 0 }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExitStatus::Exited { code } => write!(f, "exited with code {}", code),
+            ExitStatus::Signalled { signal, core_dumped } => {
+                let name = match signal {
+                    libc::SIGTERM => "SIGTERM",
+                    libc::SIGKILL => "SIGKILL",
+                    libc::SIGINT => "SIGINT",
+                    libc::SIGABRT => "SIGABRT",
+                    libc::SIGSEGV => "SIGSEGV",
+                    libc::SIGBUS => "SIGBUS",
+                    libc::SIGILL => "SIGILL",
+                    libc::SIGFPE => "SIGFPE",
+                    libc::SIGSYS => "SIGSYS",
+                    _ => "unknown signal",
+                };
+                write!(f, "killed by signal {} ({})", signal, name)?;
+                if core_dumped {
+                    write!(f, ", core dumped")?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
 pub struct Pidfd(pub RawFd);
 
 impl Pidfd {
@@ -20,7 +88,7 @@ impl Pidfd {
         }
     }
 
-    pub async fn wait(&self) -> std::io::Result<libc::siginfo_t> {
+    pub async fn wait(&self) -> std::io::Result<ExitStatus> {
         use libc::*;
 
         if self.0 == -1 {
@@ -52,7 +120,7 @@ impl Pidfd {
             }
         }).await?;
 
-        Ok(unsafe { siginfo.assume_init() })
+        Ok(ExitStatus::from_siginfo(unsafe { siginfo.get_ref() }))
     }
 }
 
@@ -0,0 +1,349 @@
+use arc_swap::ArcSwap;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use smol::Task;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::log::Logger;
+use crate::slate::{self, Pacing, MESSAGE_SIZE};
+use crate::store::ScheduleEntry;
+use crate::ts::{self, PACKET_SIZE, SYNC_BYTE};
+
+/// Messages read from a recording at a time
+const CHUNK_MESSAGES: usize = 64;
+/// Chunks read ahead of what is being played out
+const CHUNKS_AHEAD: usize = 4;
+/// How soon to check again when the next chunk hasn't been read yet
+const READ_WAIT: Duration = Duration::from_millis(10);
+
+/// A channel being played out, shared between its packager and what keeps its schedule loaded
+pub struct Channel {
+    /// From the entry that was playing when it was loaded on
+    pub schedule: ArcSwap<Vec<ScheduleEntry>>,
+    /// Set once the stream is no longer an active channel, for the packager to stop
+    pub removed: AtomicBool,
+    /// Set by the packager once it has stopped, so that a new one is started if the channel is still there
+    pub ended: AtomicBool,
+}
+
+impl Channel {
+    pub fn new(schedule: Vec<ScheduleEntry>) -> Channel {
+        Channel {
+            schedule: ArcSwap::from_pointee(schedule),
+            removed: AtomicBool::new(false),
+            ended: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Messages read from a recording, and when each is due
+struct Chunk {
+    data: Vec<u8>,
+    due: Vec<Instant>,
+}
+
+/// Reads up to a chunk of a recording on the blocking pool, as recordings can be too big to read all at once
+async fn read_chunk(file: File) -> io::Result<(File, Vec<u8>)> {
+    smol::unblock! {
+        let mut data = Vec::with_capacity(CHUNK_MESSAGES * MESSAGE_SIZE);
+        (&file).take((CHUNK_MESSAGES * MESSAGE_SIZE) as u64).read_to_end(&mut data).map(|_| (file, data))
+    }
+}
+
+/// Reads a recording over and over, pacing it by its PCRs like a slate, and joining it at the first keyframe `elapsed`
+/// or more into the loop as if it had been playing since `elapsed` before `now`
+async fn read_recording(path: PathBuf, elapsed: Duration, now: Instant, mut chunks: mpsc::Sender<Chunk>) -> io::Result<()> {
+    let mut position = elapsed;
+    let mut loops = 0;
+    // How long it takes to play the file, once it has been read through
+    let mut duration = Duration::default();
+    let mut joined = false;
+    loop {
+        let open_path = path.clone();
+        let mut file = smol::unblock!(File::open(open_path))?;
+        let mut pacing = Pacing::new();
+        let mut first = true;
+        loop {
+            let (next_file, mut data) = read_chunk(file).await?;
+            file = next_file;
+            if first && data.first() != Some(&SYNC_BYTE) {
+                return Err(slate::invalid("not a transport stream"));
+            }
+            first = false;
+            if data.is_empty() {
+                break;
+            }
+            data.truncate(data.len() - data.len() % PACKET_SIZE);
+
+            let mut chunk = Chunk { data: Vec::with_capacity(data.len()), due: Vec::new() };
+            for message in data.chunks(MESSAGE_SIZE) {
+                let due = pacing.message(message);
+                let since_now = (duration * loops + due).checked_sub(position);
+                let since_now = match since_now {
+                    Some(since_now) if joined || loops > 0 || ts::contains_keyframe(message) => since_now,
+                    _ => continue,
+                };
+                joined = true;
+                chunk.data.extend_from_slice(message);
+                chunk.due.push(now + since_now);
+            }
+            if !chunk.due.is_empty() && chunks.send(chunk).await.is_err() {
+                // Nothing is playing it any more
+                return Ok(());
+            }
+        }
+
+        duration = pacing.duration()?;
+        if !joined && position >= duration {
+            // Joining further into the schedule entry than the file is long, so somewhere into a later loop
+            position = Duration::from_secs_f64(position.as_secs_f64() % duration.as_secs_f64());
+        } else {
+            // Or after the last keyframe, so at the start of the next one, which starts with one
+            loops += 1;
+        }
+    }
+}
+
+/// A recording being played out, which is read ahead of when it is due
+struct Recording {
+    chunks: mpsc::Receiver<Chunk>,
+    chunk: Option<Chunk>,
+    next: usize,
+    /// Ends reading when the recording stops playing
+    _reader: Task<()>,
+}
+
+impl Recording {
+    fn open(path: PathBuf, elapsed: Duration, now: Instant, logger: Logger) -> Recording {
+        let (sender, chunks) = mpsc::channel(CHUNKS_AHEAD);
+        let reader = Task::spawn(async move {
+            let display = path.display().to_string();
+            if let Err(e) = read_recording(path, elapsed, now, sender).await {
+                logger.log(&format!("Playing out {} failed: {}", display, e));
+            }
+        });
+        Recording {
+            chunks,
+            chunk: None,
+            next: 0,
+            _reader: reader,
+        }
+    }
+
+    /// When the next message should be sent. If it hasn't been read yet, that is when to check again, unless reading
+    /// has stopped.
+    fn due(&mut self) -> Option<Instant> {
+        let exhausted = self.chunk.as_ref().map(|chunk| self.next == chunk.due.len()).unwrap_or(true);
+        if exhausted {
+            match self.chunks.try_next() {
+                Ok(Some(chunk)) => {
+                    self.chunk = Some(chunk);
+                    self.next = 0;
+                },
+                Ok(None) => return None,
+                Err(_) => return Some(Instant::now() + READ_WAIT),
+            }
+        }
+        self.chunk.as_ref().map(|chunk| chunk.due[self.next])
+    }
+
+    fn next_due(&mut self, now: Instant) -> Option<&[u8]> {
+        if self.due()? > now {
+            return None;
+        }
+        let chunk = self.chunk.as_ref()?;
+        let start = self.next * MESSAGE_SIZE;
+        self.next += 1;
+        Some(&chunk.data[start..(start + MESSAGE_SIZE).min(chunk.data.len())])
+    }
+}
+
+/// Plays out a channel's schedule in real time. Each entry's file is played like a slate, looping until the next entry
+/// starts, and joined where the schedule says it should be up to.
+pub struct Playout {
+    channel: Arc<Channel>,
+    logger: Logger,
+    /// The entry that should be playing, and its file unless it couldn't be opened
+    playing: Option<ScheduleEntry>,
+    file: Option<Recording>,
+}
+
+impl Playout {
+    pub fn new(channel: Arc<Channel>, logger: Logger) -> Playout {
+        Playout {
+            channel,
+            logger,
+            playing: None,
+            file: None,
+        }
+    }
+
+    /// Switches to the entry that should be playing by now, if it isn't already
+    fn update(&mut self, now: Instant) {
+        let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let schedule = self.channel.schedule.load();
+        let entry = schedule.iter().rev().find(|entry| entry.starts_at <= since_epoch.as_secs());
+        if entry == self.playing.as_ref() {
+            return;
+        }
+        self.playing = entry.cloned();
+        self.file = entry.map(|entry| {
+            self.logger.log(&format!("Playing out {}", entry.path));
+            Recording::open(PathBuf::from(&entry.path), since_epoch - Duration::from_secs(entry.starts_at), now, self.logger.clone())
+        });
+        if self.playing.is_none() {
+            self.logger.log("Nothing scheduled to play out");
+        }
+    }
+
+    /// When the next message should be sent, if anything is playing
+    pub fn due(&mut self) -> Option<Instant> {
+        self.file.as_mut()?.due()
+    }
+
+    /// The next message, if it is due by `now`
+    pub fn next_due(&mut self, now: Instant) -> Option<&[u8]> {
+        self.update(now);
+        self.file.as_mut()?.next_due(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::Timer;
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// A message of packets marked with `index`, the first of which carries a PCR `ms` into the file, and starts a
+    /// keyframe if `keyframe` is set
+    fn message(index: u8, ms: u64, keyframe: bool) -> Vec<u8> {
+        let mut message = Vec::new();
+        for i in 0..MESSAGE_SIZE / PACKET_SIZE {
+            let mut packet = vec![index; PACKET_SIZE];
+            packet[..4].copy_from_slice(&[SYNC_BYTE, 0x01, 0x00, 0x10]);
+            if i == 0 {
+                packet[3] = 0x30;
+                packet[4] = 7;
+                packet[5] = if keyframe { 0x50 } else { 0x10 };
+                packet[10] = 0x7e;
+                packet[11] = 0;
+                ts::set_pcr(&mut packet, ms * 90);
+            }
+            message.extend(packet);
+        }
+        message
+    }
+
+    /// Four messages 200ms apart, from `first` on, with keyframes at the first and third, so that it loops every 750ms
+    fn recording(first: u8) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ingestd-playout-{}.ts", Uuid::new_v4()));
+        let data = (0..4).flat_map(|i| message(first + i, i as u64 * 200, i % 2 == 0)).collect::<Vec<_>>();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn index(message: &[u8]) -> u8 {
+        message[PACKET_SIZE - 1]
+    }
+
+    /// The first `count` chunks read from a recording joined `elapsed` into it, as the messages in them and how long
+    /// after `now` each is due
+    fn read(path: &PathBuf, elapsed: Duration, count: usize) -> Vec<Vec<(u8, Duration)>> {
+        let now = Instant::now();
+        let (sender, mut receiver) = mpsc::channel(CHUNKS_AHEAD);
+        let reading = read_recording(path.clone(), elapsed, now, sender);
+        let chunks = async move {
+            let mut chunks = Vec::new();
+            for _ in 0..count {
+                let chunk = receiver.next().await.unwrap();
+                chunks.push(chunk.data.chunks(MESSAGE_SIZE).map(index).zip(chunk.due.iter().map(|&due| due - now)).collect());
+            }
+            // Dropping the receiver stops the reader
+            chunks
+        };
+        let (res, chunks) = smol::block_on(future::join(reading, chunks));
+        res.unwrap();
+        chunks
+    }
+
+    #[test]
+    fn joins_at_the_first_keyframe_elapsed_into_the_loop() {
+        let path = recording(0);
+        assert_eq!(read(&path, ms(250), 2), [
+            vec![(2, ms(150)), (3, ms(350))],
+            vec![(0, ms(500)), (1, ms(700)), (2, ms(900)), (3, ms(1100))],
+        ]);
+        // After the last keyframe, it is joined where the next loop starts
+        assert_eq!(read(&path, ms(500), 1), [vec![(0, ms(250)), (1, ms(450)), (2, ms(650)), (3, ms(850))]]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn wraps_around_when_joined_further_in_than_the_file_is_long() {
+        let path = recording(0);
+        assert_eq!(read(&path, ms(2 * 750 + 250), 2), [
+            vec![(2, ms(150)), (3, ms(350))],
+            vec![(0, ms(500)), (1, ms(700)), (2, ms(900)), (3, ms(1100))],
+        ]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn loops_from_the_start() {
+        let path = recording(0);
+        let loops = read(&path, Duration::default(), 3);
+        for (i, chunk) in loops.iter().enumerate() {
+            let start = ms(750) * i as u32;
+            assert_eq!(chunk, &[(0, start), (1, start + ms(200)), (2, start + ms(400)), (3, start + ms(600))]);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// The next message the playout sends, waiting up to two loops of its file for it
+    async fn next_message(playout: &mut Playout) -> Option<u8> {
+        let until = Instant::now() + ms(1500);
+        while Instant::now() < until {
+            if let Some(message) = playout.next_due(Instant::now()) {
+                return Some(index(message));
+            }
+            Timer::new(READ_WAIT).await;
+        }
+        None
+    }
+
+    #[test]
+    fn switches_to_the_entry_that_has_started() {
+        let first = recording(10);
+        let second = recording(20);
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let entry = |starts_at, path: &PathBuf| ScheduleEntry { starts_at, path: path.to_str().unwrap().to_string() };
+        let channel = Arc::new(Channel::new(vec![entry(now + 3600, &first)]));
+        let mut playout = Playout::new(channel.clone(), Logger::from(File::create("/dev/null").unwrap()));
+
+        smol::block_on(async {
+            assert_eq!(next_message(&mut playout).await, None);
+            assert_eq!(playout.due(), None);
+
+            channel.schedule.store(Arc::new(vec![entry(now - 60, &first), entry(now + 3600, &second)]));
+            let message = next_message(&mut playout).await.unwrap();
+            assert!((10..14).contains(&message));
+            // Carries on with the same file rather than starting it over
+            assert_eq!(next_message(&mut playout).await, Some(if message == 13 { 10 } else { message + 1 }));
+
+            channel.schedule.store(Arc::new(vec![entry(now - 60, &first), entry(now, &second)]));
+            assert!((20..24).contains(&next_message(&mut playout).await.unwrap()));
+        });
+        std::fs::remove_file(first).unwrap();
+        std::fs::remove_file(second).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use std::io::{IoSlice, Write};
+use std::sync::Mutex;
+
+/// Maximum size of an SRT live mode message, SRT_LIVE_MAX_PLSIZE
+pub const MESSAGE_SIZE: usize = 1456;
+/// Number of messages received per wakeup, and written to gpac with a single writev
+pub const BATCH_SIZE: usize = 64;
+
+/// Hands out message arenas to sessions, so that steady state streaming doesn't allocate
+pub struct BufferPool {
+    free: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool {
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn batch(&self) -> Batch<'_> {
+        let arena = self.free.lock().unwrap().pop().unwrap_or_else(|| vec![0; MESSAGE_SIZE * BATCH_SIZE].into_boxed_slice());
+        Batch {
+            pool: self,
+            arena: Some(arena),
+            lens: [0; BATCH_SIZE],
+            messages: 0,
+            written_messages: 0,
+            written_offset: 0,
+        }
+    }
+}
+
+/// A set of received messages, stored back to back in fixed size slots
+pub struct Batch<'a> {
+    pool: &'a BufferPool,
+    arena: Option<Box<[u8]>>,
+    lens: [usize; BATCH_SIZE],
+    messages: usize,
+    written_messages: usize,
+    written_offset: usize,
+}
+
+impl Batch<'_> {
+    fn arena(&self) -> &[u8] {
+        self.arena.as_ref().unwrap()
+    }
+
+    pub fn clear(&mut self) {
+        self.messages = 0;
+        self.written_messages = 0;
+        self.written_offset = 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.messages == BATCH_SIZE
+    }
+
+    /// The slot the next message should be received into
+    pub fn next_slot(&mut self) -> &mut [u8] {
+        let start = self.messages * MESSAGE_SIZE;
+        &mut self.arena.as_mut().unwrap()[start..start+MESSAGE_SIZE]
+    }
+
+    /// Keeps the first `len` bytes of the next slot as a message
+    pub fn commit(&mut self, len: usize) {
+        self.lens[self.messages] = len;
+        self.messages += 1;
+    }
+
+    pub fn message(&self, index: usize) -> &[u8] {
+        let start = index * MESSAGE_SIZE;
+        &self.arena()[start..start+self.lens[index]]
+    }
+
+    /// The messages kept so far
+    pub fn messages(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.messages).map(move |index| self.message(index))
+    }
+
+    pub fn is_written(&self) -> bool {
+        self.written_messages == self.messages
+    }
+
+    /// Writes as much of the batch as possible with one writev, picking up from any previous partial write
+    pub fn write_to(&mut self, mut writer: impl Write) -> std::io::Result<()> {
+        let mut slices = [IoSlice::new(&[]); BATCH_SIZE];
+        let mut slices_len = 0;
+        for index in self.written_messages..self.messages {
+            let message = self.message(index);
+            slices[slices_len] = IoSlice::new(if index == self.written_messages { &message[self.written_offset..] } else { message });
+            slices_len += 1;
+        }
+
+        let mut written = writer.write_vectored(&slices[..slices_len])?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        while written > 0 {
+            let remaining = self.lens[self.written_messages] - self.written_offset;
+            if written >= remaining {
+                written -= remaining;
+                self.written_messages += 1;
+                self.written_offset = 0;
+            } else {
+                self.written_offset += written;
+                written = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        if let Some(arena) = self.arena.take() {
+            self.pool.free.lock().unwrap().push(arena);
+        }
+    }
+}
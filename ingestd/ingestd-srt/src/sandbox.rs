@@ -0,0 +1,413 @@
+use futures::io::copy;
+use futures::prelude::*;
+use serde::Deserialize;
+use smol::{Async, Task};
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+pub const CLONE_FLAGS: u64 = (libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET | libc::CLONE_NEWIPC) as u64;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SandboxConfig {
+    #[serde(default = "default_seccomp")]
+    pub seccomp: bool,
+    /// Paths which are left writable when the rest of the filesystem is remounted read-only
+    #[serde(default)]
+    pub writable_paths: Vec<PathBuf>,
+}
+
+fn default_seccomp() -> bool {
+    true
+}
+
+// Pseudo filesystems which either can't be remounted from inside a user namespace or don't matter
+const SKIPPED_FILESYSTEMS: &[&str] = &[
+    "proc", "sysfs", "devpts", "mqueue", "cgroup", "cgroup2", "pstore", "bpf", "securityfs",
+    "debugfs", "tracefs", "configfs", "fusectl", "hugetlbfs", "autofs", "binfmt_misc", "efivarfs", "nsfs",
+];
+
+const ST_RELATIME: libc::c_ulong = 4096;
+
+struct Mount {
+    path: CString,
+    flags: libc::c_ulong,
+}
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+#[repr(C)]
+struct Ifreq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// Everything the child needs to enter the sandbox, prepared in advance because the child can't allocate
+pub struct Sandbox {
+    setgroups_path: CString,
+    uid_map_path: CString,
+    gid_map_path: CString,
+    uid_map: String,
+    gid_map: String,
+    writable_paths: Vec<CString>,
+    read_only_mounts: Vec<Mount>,
+    tmp_path: CString,
+    tmpfs: CString,
+    httpd_addr: SocketAddr,
+    seccomp_filter: Option<Vec<SockFilter>>,
+}
+
+fn cstring(path: &std::path::Path) -> std::io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+fn unescape_mountinfo(path: &str) -> String {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let octal = chars.by_ref().take(3).collect::<String>();
+            match u8::from_str_radix(&octal, 8) {
+                Ok(byte) => unescaped.push(byte as char),
+                Err(_) => { unescaped.push('\\'); unescaped.push_str(&octal); },
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+fn read_only_mounts() -> std::io::Result<Vec<Mount>> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    let mut mounts = Vec::new();
+    for line in mountinfo.lines() {
+        let mut halves = line.splitn(2, " - ");
+        let (mount, fs) = match (halves.next(), halves.next()) {
+            (Some(mount), Some(fs)) => (mount, fs),
+            _ => continue,
+        };
+        let path = match mount.split(' ').nth(4) {
+            Some(path) => unescape_mountinfo(path),
+            None => continue,
+        };
+        let fstype = fs.split(' ').next().unwrap_or("");
+        if SKIPPED_FILESYSTEMS.contains(&fstype) {
+            continue;
+        }
+        let path = CString::new(path).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        // Remounting must keep the flags which are locked by the user namespace
+        let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } == -1 {
+            // Mounts we can't see, e.g. those hidden under other mounts, can't be remounted either
+            continue;
+        }
+        let stat_flags = unsafe { stat.assume_init() }.f_flag;
+        let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+        for &(stat_flag, mount_flag) in &[
+            (libc::ST_NOSUID, libc::MS_NOSUID),
+            (libc::ST_NODEV, libc::MS_NODEV),
+            (libc::ST_NOEXEC, libc::MS_NOEXEC),
+            (libc::ST_NOATIME, libc::MS_NOATIME),
+            (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+            (ST_RELATIME, libc::MS_RELATIME),
+        ] {
+            if stat_flags & stat_flag != 0 {
+                flags |= mount_flag;
+            }
+        }
+        mounts.push(Mount { path, flags });
+    }
+    Ok(mounts)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn seccomp_filter() -> std::io::Result<Vec<SockFilter>> {
+    const AUDIT_ARCH_X86_64: u32 = 0xc000003e;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x80000000;
+    const SECCOMP_RET_ERRNO: u32 = 0x00050000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff0000;
+    const SYS_RSEQ: libc::c_long = 334;
+    const SYS_CLONE3: libc::c_long = 435;
+
+    // BPF_LD|BPF_W|BPF_ABS, BPF_JMP|BPF_JEQ|BPF_K and BPF_RET|BPF_K
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    const BPF_JSET_K: u16 = 0x45;
+    const BPF_RET_K: u16 = 0x06;
+
+    // Everything gpac needs to demux, package and push over HTTP - notably no ptrace or mount. clone is only allowed for
+    // threads, below, and execve only because the filter is installed before gpac itself is exec'd; without fork it can
+    // only replace gpac, with something from the read-only filesystem and no new privileges.
+    let allowed = [
+        libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_pread64, libc::SYS_pwrite64,
+        libc::SYS_open, libc::SYS_openat, libc::SYS_close, libc::SYS_stat, libc::SYS_fstat, libc::SYS_lstat,
+        libc::SYS_newfstatat, libc::SYS_statx, libc::SYS_lseek, libc::SYS_access, libc::SYS_faccessat,
+        libc::SYS_readlink, libc::SYS_readlinkat, libc::SYS_getdents64, libc::SYS_getcwd, libc::SYS_fcntl,
+        libc::SYS_dup, libc::SYS_dup2, libc::SYS_dup3, libc::SYS_pipe, libc::SYS_pipe2, libc::SYS_ioctl,
+        libc::SYS_mmap, libc::SYS_mprotect, libc::SYS_munmap, libc::SYS_mremap, libc::SYS_madvise, libc::SYS_brk,
+        libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+        libc::SYS_poll, libc::SYS_ppoll, libc::SYS_select, libc::SYS_pselect6,
+        libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_wait, libc::SYS_epoll_pwait,
+        libc::SYS_eventfd2, libc::SYS_timerfd_create, libc::SYS_timerfd_settime,
+        libc::SYS_nanosleep, libc::SYS_clock_nanosleep, libc::SYS_clock_gettime, libc::SYS_clock_getres, libc::SYS_gettimeofday,
+        libc::SYS_sched_yield, libc::SYS_sched_getaffinity, libc::SYS_futex, libc::SYS_set_robust_list, libc::SYS_get_robust_list,
+        libc::SYS_set_tid_address, SYS_RSEQ, libc::SYS_membarrier,
+        libc::SYS_getpid, libc::SYS_gettid, libc::SYS_getppid, libc::SYS_getuid, libc::SYS_geteuid, libc::SYS_getgid, libc::SYS_getegid,
+        libc::SYS_tgkill, libc::SYS_uname, libc::SYS_prlimit64, libc::SYS_getrandom, libc::SYS_arch_prctl, libc::SYS_prctl,
+        libc::SYS_socket, libc::SYS_connect, libc::SYS_accept, libc::SYS_accept4, libc::SYS_bind, libc::SYS_listen,
+        libc::SYS_sendto, libc::SYS_recvfrom, libc::SYS_sendmsg, libc::SYS_recvmsg, libc::SYS_shutdown,
+        libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_setsockopt, libc::SYS_getsockopt,
+        libc::SYS_execve, libc::SYS_exit, libc::SYS_exit_group,
+    ];
+
+    let mut filter = Vec::with_capacity(allowed.len() * 2 + 12);
+    // Load the architecture and kill anything not using the x86_64 syscall ABI
+    filter.push(SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: 4 });
+    filter.push(SockFilter { code: BPF_JEQ_K, jt: 1, jf: 0, k: AUDIT_ARCH_X86_64 });
+    filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_KILL_PROCESS });
+    // Load the syscall number
+    filter.push(SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: 0 });
+    // clone only with CLONE_THREAD, checked in the low half of its flags argument (args[0] is at offset 16)
+    filter.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 4, k: libc::SYS_clone as u32 });
+    filter.push(SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: 16 });
+    filter.push(SockFilter { code: BPF_JSET_K, jt: 0, jf: 1, k: libc::CLONE_THREAD as u32 });
+    filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ALLOW });
+    filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ERRNO | libc::EPERM as u32 });
+    // clone3's flags are behind a pointer, out of the filter's reach, so make the C library fall back to clone
+    filter.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 1, k: SYS_CLONE3 as u32 });
+    filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ERRNO | libc::ENOSYS as u32 });
+    for &syscall in allowed.iter() {
+        filter.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 1, k: syscall as u32 });
+        filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ALLOW });
+    }
+    filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ERRNO | libc::EPERM as u32 });
+    Ok(filter)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn seccomp_filter() -> std::io::Result<Vec<SockFilter>> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "seccomp filter is only available on x86_64"))
+}
+
+impl Sandbox {
+    pub fn prepare(config: &SandboxConfig, httpd_addr: SocketAddr) -> std::io::Result<Sandbox> {
+        if !httpd_addr.ip().is_loopback() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "httpd must be on a loopback address to be reachable from the sandbox"));
+        }
+        let writable_paths = config.writable_paths.iter().map(|path| cstring(path)).collect::<Result<Vec<_>, _>>()?;
+        let read_only_mounts = read_only_mounts()?.into_iter().filter(|mount| !writable_paths.contains(&mount.path)).collect();
+        let uid = unsafe { libc::geteuid() };
+        let gid = unsafe { libc::getegid() };
+        Ok(Sandbox {
+            setgroups_path: CString::new("/proc/self/setgroups").unwrap(),
+            uid_map_path: CString::new("/proc/self/uid_map").unwrap(),
+            gid_map_path: CString::new("/proc/self/gid_map").unwrap(),
+            uid_map: format!("{} {} 1\n", uid, uid),
+            gid_map: format!("{} {} 1\n", gid, gid),
+            writable_paths,
+            read_only_mounts,
+            tmp_path: CString::new("/tmp").unwrap(),
+            tmpfs: CString::new("tmpfs").unwrap(),
+            httpd_addr,
+            seccomp_filter: if config.seccomp { Some(seccomp_filter()?) } else { None },
+        })
+    }
+
+    pub fn httpd_addr(&self) -> SocketAddr {
+        self.httpd_addr
+    }
+
+    unsafe fn write_file(path: &CString, contents: &[u8]) -> bool {
+        use libc::*;
+        let fd = open(path.as_ptr(), O_WRONLY|O_CLOEXEC);
+        if fd == -1 {
+            return false;
+        }
+        let written = write(fd, contents as *const [u8] as *const c_void, contents.len());
+        close(fd);
+        written == contents.len() as isize
+    }
+
+    /// Sets up the sandbox from inside the freshly cloned child, before it execs gpac.
+    ///
+    /// Like the rest of the child side of spawn, this must only call async-signal-safe functions and must not panic.
+    /// Returns a message to report through the logger on failure.
+    pub unsafe fn enter(&self, control_fd: RawFd) -> Result<(), &'static str> {
+        use libc::*;
+
+        if !Sandbox::write_file(&self.setgroups_path, b"deny") {
+            return Err("writing /proc/self/setgroups failed");
+        }
+        if !Sandbox::write_file(&self.uid_map_path, self.uid_map.as_bytes()) {
+            return Err("writing /proc/self/uid_map failed");
+        }
+        if !Sandbox::write_file(&self.gid_map_path, self.gid_map.as_bytes()) {
+            return Err("writing /proc/self/gid_map failed");
+        }
+
+        // Stop any of our mount changes propagating back to the host
+        let root = b"/\0" as *const [u8] as *const c_char;
+        if mount(std::ptr::null(), root, std::ptr::null(), MS_REC|MS_PRIVATE, std::ptr::null()) == -1 {
+            return Err("making mounts private failed");
+        }
+        // Writable paths become their own mounts so that remounting their parent read-only doesn't affect them
+        for path in &self.writable_paths {
+            if mount(path.as_ptr(), path.as_ptr(), std::ptr::null(), MS_BIND|MS_REC, std::ptr::null()) == -1 {
+                return Err("bind mounting writable path failed");
+            }
+        }
+        for read_only_mount in &self.read_only_mounts {
+            if mount(std::ptr::null(), read_only_mount.path.as_ptr(), std::ptr::null(), read_only_mount.flags, std::ptr::null()) == -1 {
+                return Err("remounting read-only failed");
+            }
+        }
+        if mount(self.tmpfs.as_ptr(), self.tmp_path.as_ptr(), self.tmpfs.as_ptr(), MS_NOSUID|MS_NODEV, std::ptr::null()) == -1 {
+            return Err("mounting tmpfs on /tmp failed");
+        }
+
+        // The new network namespace only has a loopback interface, and it starts down
+        let sock = socket(AF_INET, SOCK_DGRAM|SOCK_CLOEXEC, 0);
+        if sock == -1 {
+            return Err("creating socket to configure loopback failed");
+        }
+        let mut ifreq = Ifreq { name: [0; IFNAMSIZ], flags: 0, _pad: [0; 22] };
+        ifreq.name[..2].copy_from_slice(b"lo");
+        ifreq.flags = (IFF_UP|IFF_RUNNING) as c_short;
+        if ioctl(sock, SIOCSIFFLAGS, &ifreq as *const Ifreq) == -1 {
+            return Err("bringing up loopback failed");
+        }
+        close(sock);
+
+        // Listen where gpac expects httpd to be, and hand the listener to the parent to forward to the real httpd
+        let listener = self.listen_httpd()?;
+        let mut cmsg_buf = [0u64; 4];
+        let mut iov_buf = [0u8; 1];
+        let mut iov = iovec { iov_base: iov_buf.as_mut_ptr() as *mut c_void, iov_len: iov_buf.len() };
+        let mut msg: msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = CMSG_SPACE(std::mem::size_of::<c_int>() as c_uint) as usize;
+        let cmsg = CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = SOL_SOCKET;
+        (*cmsg).cmsg_type = SCM_RIGHTS;
+        (*cmsg).cmsg_len = CMSG_LEN(std::mem::size_of::<c_int>() as c_uint) as usize;
+        std::ptr::write_unaligned(CMSG_DATA(cmsg) as *mut c_int, listener);
+        if sendmsg(control_fd, &msg, 0) == -1 {
+            return Err("sending httpd listener to parent failed");
+        }
+        close(listener);
+        close(control_fd);
+
+        if prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1 {
+            return Err("setting no_new_privs failed");
+        }
+        if let Some(ref filter) = self.seccomp_filter {
+            let prog = SockFprog {
+                len: filter.len() as c_ushort,
+                filter: filter.as_ptr(),
+            };
+            if prctl(PR_SET_SECCOMP, SECCOMP_MODE_FILTER, &prog as *const SockFprog) == -1 {
+                return Err("installing seccomp filter failed");
+            }
+        }
+
+        Ok(())
+    }
+
+    unsafe fn listen_httpd(&self) -> Result<RawFd, &'static str> {
+        use libc::*;
+        let (domain, addr, addr_len) = match self.httpd_addr {
+            SocketAddr::V4(addr) => {
+                let mut sockaddr: sockaddr_in = std::mem::zeroed();
+                sockaddr.sin_family = AF_INET as sa_family_t;
+                sockaddr.sin_port = addr.port().to_be();
+                sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                let mut storage: sockaddr_storage = std::mem::zeroed();
+                std::ptr::write(&mut storage as *mut sockaddr_storage as *mut sockaddr_in, sockaddr);
+                (AF_INET, storage, std::mem::size_of::<sockaddr_in>())
+            },
+            SocketAddr::V6(addr) => {
+                let mut sockaddr: sockaddr_in6 = std::mem::zeroed();
+                sockaddr.sin6_family = AF_INET6 as sa_family_t;
+                sockaddr.sin6_port = addr.port().to_be();
+                sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+                let mut storage: sockaddr_storage = std::mem::zeroed();
+                std::ptr::write(&mut storage as *mut sockaddr_storage as *mut sockaddr_in6, sockaddr);
+                (AF_INET6, storage, std::mem::size_of::<sockaddr_in6>())
+            },
+        };
+        let listener = socket(domain, SOCK_STREAM|SOCK_CLOEXEC, 0);
+        if listener == -1 {
+            return Err("creating httpd listener failed");
+        }
+        if bind(listener, &addr as *const sockaddr_storage as *const sockaddr, addr_len as socklen_t) == -1 {
+            return Err("binding httpd listener failed");
+        }
+        if listen(listener, 16) == -1 {
+            return Err("listening on httpd listener failed");
+        }
+        Ok(listener)
+    }
+}
+
+async fn receive_listener(control: &Async<UnixStream>) -> std::io::Result<TcpListener> {
+    use libc::*;
+    control.read_with(|control| {
+        let mut cmsg_buf = [0u64; 4];
+        let mut iov_buf = [0u8; 1];
+        let mut iov = iovec { iov_base: iov_buf.as_mut_ptr() as *mut c_void, iov_len: iov_buf.len() };
+        let mut msg: msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = std::mem::size_of_val(&cmsg_buf);
+        let len = unsafe { recvmsg(control.as_raw_fd(), &mut msg, MSG_CMSG_CLOEXEC) };
+        if len == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let cmsg = unsafe { CMSG_FIRSTHDR(&msg) };
+        if len == 0 || cmsg.is_null() || unsafe { (*cmsg).cmsg_type } != SCM_RIGHTS {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "sandboxed child exited before sending httpd listener"));
+        }
+        let fd = unsafe { std::ptr::read_unaligned(CMSG_DATA(cmsg) as *const c_int) };
+        Ok(unsafe { TcpListener::from_raw_fd(fd) })
+    }).await
+}
+
+/// Forwards connections from gpac to the loopback listener inside its network namespace on to the real httpd
+pub async fn proxy_httpd(control: UnixStream, httpd_addr: SocketAddr) -> std::io::Result<()> {
+    let control = Async::new(control)?;
+    let listener = Async::new(receive_listener(&control).await?)?;
+    drop(control);
+    loop {
+        let (inside, _peer_addr) = listener.accept().await?;
+        Task::spawn(async move {
+            let outside = Async::<TcpStream>::connect(httpd_addr).await?;
+            let (inside_read, mut inside_write) = inside.split();
+            let (outside_read, mut outside_write) = outside.split();
+            future::try_join(copy(inside_read, &mut outside_write), copy(outside_read, &mut inside_write)).await?;
+            Ok::<(), std::io::Error>(())
+        }).detach();
+    }
+}
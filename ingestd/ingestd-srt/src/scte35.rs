@@ -0,0 +1,347 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::ts::{self, TIMESTAMP_MODULUS};
+
+const TABLE_ID: u8 = 0xfc;
+
+/// How cues are carried in DASH, with the splice_info_section as the message data
+pub const SCHEME_ID_URI: &str = "urn:scte:scte35:2013:bin";
+
+const SPLICE_INSERT: u8 = 0x05;
+const TIME_SIGNAL: u8 = 0x06;
+const SEGMENTATION_DESCRIPTOR: u8 = 0x02;
+const CUEI: &[u8] = b"CUEI";
+
+/// Encoders repeat cues until the splice happens, so this many of the last ones seen are remembered to skip repeats
+const RECENT_CUES: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    SpliceInsert {
+        splice_event_id: u32,
+        cancel: bool,
+        /// Leaving the network feed for a break, rather than returning to it
+        out_of_network: bool,
+        immediate: bool,
+        /// In seconds
+        break_duration: Option<f64>,
+        auto_return: bool,
+    },
+    TimeSignal,
+}
+
+/// A segmentation_descriptor, which says what a time_signal is for
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Segmentation {
+    pub segmentation_event_id: u32,
+    pub cancel: bool,
+    /// Unknown for cancellations
+    pub segmentation_type_id: Option<u8>,
+    /// In seconds
+    pub duration: Option<f64>,
+}
+
+/// A decoded splice_insert or time_signal
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Cue {
+    #[serde(flatten)]
+    pub command: Command,
+    /// When the splice happens, in the stream's 90kHz timestamps with pts_adjustment applied. Not known for immediate
+    /// splices, or time_signals without a time.
+    pub pts: Option<u64>,
+    pub segmentations: Vec<Segmentation>,
+    /// The whole splice_info_section, as passed on to players
+    #[serde(skip)]
+    pub section: Vec<u8>,
+}
+
+impl Cue {
+    /// How long the break or segment lasts, in seconds
+    pub fn duration(&self) -> Option<f64> {
+        match self.command {
+            Command::SpliceInsert { break_duration: Some(duration), .. } => Some(duration),
+            _ => self.segmentations.iter().find_map(|segmentation| segmentation.duration),
+        }
+    }
+}
+
+impl fmt::Display for Cue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.command {
+            Command::SpliceInsert { splice_event_id, cancel, out_of_network, immediate, break_duration, .. } => {
+                write!(f, "splice_insert {}", splice_event_id)?;
+                if cancel {
+                    return f.write_str(" cancelled");
+                }
+                f.write_str(if out_of_network { " out" } else { " in" })?;
+                if immediate {
+                    f.write_str(" immediately")?;
+                }
+                if let Some(duration) = break_duration {
+                    write!(f, ", {:.3}s break", duration)?;
+                }
+            },
+            Command::TimeSignal => f.write_str("time_signal")?,
+        }
+        if let Some(pts) = self.pts {
+            write!(f, " at {}", pts)?;
+        }
+        for segmentation in &self.segmentations {
+            write!(f, ", segmentation {}", segmentation.segmentation_event_id)?;
+            if let Some(type_id) = segmentation.segmentation_type_id {
+                write!(f, " type 0x{:02x}", type_id)?;
+            }
+            if segmentation.cancel {
+                f.write_str(" cancelled")?;
+            }
+            if let Some(duration) = segmentation.duration {
+                write!(f, " {:.3}s", duration)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the cues on a stream's SCTE-35 PID, skipping repeats
+#[derive(Default)]
+pub struct Decoder {
+    recent: VecDeque<Vec<u8>>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// The cue in a splice_info_section, if it is an intact splice_insert or time_signal that hasn't been seen lately
+    pub fn section(&mut self, section: &[u8]) -> Option<Cue> {
+        if self.recent.iter().any(|recent| recent.as_slice() == section) {
+            return None;
+        }
+        let cue = parse(section)?;
+        if self.recent.len() == RECENT_CUES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(section.to_vec());
+        Some(cue)
+    }
+}
+
+fn read_u32(data: &[u8]) -> Option<u32> {
+    data.get(..4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// A 33-bit time in its 5 bytes, below 7 bits of flags and reserved bits
+fn read_33_bits(data: &[u8]) -> Option<u64> {
+    Some(((*data.first()? as u64 & 1) << 32) | read_u32(data.get(1..)?)? as u64)
+}
+
+/// Reads a splice_time(), returning the time if it is specified and how long it is
+fn splice_time(data: &[u8]) -> Option<(Option<u64>, usize)> {
+    if *data.first()? & 0x80 != 0 {
+        Some((Some(read_33_bits(data)?), 5))
+    } else {
+        Some((None, 1))
+    }
+}
+
+pub fn parse(section: &[u8]) -> Option<Cue> {
+    if section.len() < 17 || section[0] != TABLE_ID || ts::crc32(section) != 0 {
+        return None;
+    }
+    // Encrypted cues can't be read
+    if section[4] & 0x80 != 0 {
+        return None;
+    }
+    let pts_adjustment = read_33_bits(&section[4..])?;
+    let command_len = (((section[11] & 0x0f) as usize) << 8) | section[12] as usize;
+    let command_type = section[13];
+    let command = &section[14..section.len() - 4];
+
+    let (command, pts, parsed_len) = match command_type {
+        SPLICE_INSERT => splice_insert(command)?,
+        TIME_SIGNAL => {
+            let (pts, len) = splice_time(command)?;
+            (Command::TimeSignal, pts, len)
+        },
+        _ => return None,
+    };
+    // Older encoders leave splice_command_length as 0xfff, in which case it is as long as it was parsed to be
+    let command_len = if command_len == 0xfff { parsed_len } else { command_len };
+    let segmentations = section.get(14 + command_len..section.len() - 4)
+        .and_then(|rest| {
+            let loop_len = rest.get(..2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))? as usize;
+            rest.get(2..2 + loop_len)
+        })
+        .map(segmentations)
+        .unwrap_or_default();
+
+    Some(Cue {
+        command,
+        pts: pts.map(|pts| (pts + pts_adjustment) % TIMESTAMP_MODULUS),
+        segmentations,
+        section: section.to_vec(),
+    })
+}
+
+fn splice_insert(command: &[u8]) -> Option<(Command, Option<u64>, usize)> {
+    let splice_event_id = read_u32(command)?;
+    if *command.get(4)? & 0x80 != 0 {
+        let cancelled = Command::SpliceInsert {
+            splice_event_id,
+            cancel: true,
+            out_of_network: false,
+            immediate: false,
+            break_duration: None,
+            auto_return: false,
+        };
+        return Some((cancelled, None, 5));
+    }
+    let flags = *command.get(5)?;
+    let out_of_network = flags & 0x80 != 0;
+    let program_splice = flags & 0x40 != 0;
+    let has_duration = flags & 0x20 != 0;
+    let immediate = flags & 0x10 != 0;
+
+    let mut position = 6;
+    let mut pts = None;
+    if program_splice {
+        if !immediate {
+            let (time, len) = splice_time(command.get(position..)?)?;
+            pts = time;
+            position += len;
+        }
+    } else {
+        // Each component can splice at its own time, but they are all expected to be the same
+        let components = *command.get(position)?;
+        position += 1;
+        for _ in 0..components {
+            position += 1;
+            if !immediate {
+                let (time, len) = splice_time(command.get(position..)?)?;
+                pts = pts.or(time);
+                position += len;
+            }
+        }
+    }
+    let (break_duration, auto_return) = if has_duration {
+        let data = command.get(position..position + 5)?;
+        position += 5;
+        (Some(read_33_bits(data)? as f64 / 90000.0), data[0] & 0x80 != 0)
+    } else {
+        (None, false)
+    };
+    // unique_program_id, avail_num and avails_expected
+    position += 4;
+
+    let command = Command::SpliceInsert {
+        splice_event_id,
+        cancel: false,
+        out_of_network,
+        immediate,
+        break_duration,
+        auto_return,
+    };
+    Some((command, pts, position))
+}
+
+fn segmentations(mut descriptors: &[u8]) -> Vec<Segmentation> {
+    let mut segmentations = Vec::new();
+    while descriptors.len() >= 2 && descriptors.len() >= 2 + descriptors[1] as usize {
+        let (descriptor, rest) = descriptors.split_at(2 + descriptors[1] as usize);
+        descriptors = rest;
+        if descriptor[0] == SEGMENTATION_DESCRIPTOR && descriptor[2..].starts_with(CUEI) {
+            if let Some(segmentation) = segmentation(&descriptor[6..]) {
+                segmentations.push(segmentation);
+            }
+        }
+    }
+    segmentations
+}
+
+/// Reads a segmentation_descriptor, after its identifier
+fn segmentation(data: &[u8]) -> Option<Segmentation> {
+    let segmentation_event_id = read_u32(data)?;
+    if *data.get(4)? & 0x80 != 0 {
+        return Some(Segmentation {
+            segmentation_event_id,
+            cancel: true,
+            segmentation_type_id: None,
+            duration: None,
+        });
+    }
+    let flags = *data.get(5)?;
+    let mut position = 6;
+    if flags & 0x80 == 0 {
+        // Components, each with a tag and a 33-bit offset
+        position += 1 + *data.get(position)? as usize * 6;
+    }
+    let duration = if flags & 0x40 != 0 {
+        let duration = (*data.get(position)? as u64) << 32 | read_u32(data.get(position + 1..)?)? as u64;
+        position += 5;
+        Some(duration as f64 / 90000.0)
+    } else {
+        None
+    };
+    // segmentation_upid_type, then the upid with its length
+    position += 2 + *data.get(position + 1)? as usize;
+    Some(Segmentation {
+        segmentation_event_id,
+        cancel: false,
+        segmentation_type_id: Some(*data.get(position)?),
+        duration,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The examples from the SCTE-35 specification
+    const SPLICE_INSERT_EXAMPLE: &str = "/DAvAAAAAAAA///wFAVIAACPf+/+c2nALv4AUsz1AAAAAAAKAAhDVUVJAAABNWLbowo=";
+    const TIME_SIGNAL_EXAMPLE: &str = "/DA0AAAAAAAA///wBQb+cr0AUAAeAhxDVUVJSAAAjn/PAAGlmbAICAAAAAAsoKGKNAIAmsnRfg==";
+
+    #[test]
+    fn decodes_splice_insert() {
+        let section = base64::decode(SPLICE_INSERT_EXAMPLE).unwrap();
+        let cue = parse(&section).unwrap();
+        assert_eq!(cue.command, Command::SpliceInsert {
+            splice_event_id: 0x4800008f,
+            cancel: false,
+            out_of_network: true,
+            immediate: false,
+            break_duration: Some(0x52ccf5 as f64 / 90000.0),
+            auto_return: true,
+        });
+        assert_eq!(cue.pts, Some(0x07369c02e));
+        assert!(cue.segmentations.is_empty());
+        assert_eq!(cue.section, section);
+    }
+
+    #[test]
+    fn decodes_time_signal_with_segmentation() {
+        let cue = parse(&base64::decode(TIME_SIGNAL_EXAMPLE).unwrap()).unwrap();
+        assert_eq!(cue.command, Command::TimeSignal);
+        assert_eq!(cue.pts, Some(0x072bd0050));
+        assert_eq!(cue.segmentations, [Segmentation {
+            segmentation_event_id: 0x4800008e,
+            cancel: false,
+            segmentation_type_id: Some(0x34),
+            duration: Some(0x1a599b0 as f64 / 90000.0),
+        }]);
+        assert_eq!(cue.duration(), Some(307.0));
+    }
+
+    #[test]
+    fn skips_repeats_and_corrupt_sections() {
+        let mut section = base64::decode(SPLICE_INSERT_EXAMPLE).unwrap();
+        let mut decoder = Decoder::new();
+        assert!(decoder.section(&section).is_some());
+        assert!(decoder.section(&section).is_none());
+        section[20] ^= 1;
+        assert!(decoder.section(&section).is_none());
+    }
+}
@@ -0,0 +1,92 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+use crate::gpac::EndReason;
+use crate::pidfd::ExitStatus;
+
+/// A session currently being ingested, as listed by the admin API
+#[derive(Clone, Serialize)]
+pub struct Session {
+    pub uuid: Uuid,
+    pub stream_id: u32,
+    pub listener: String,
+    pub peer_addr: Option<SocketAddr>,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+}
+
+/// What happened in a session, recorded once it has ended
+#[derive(Clone, Serialize)]
+pub struct Summary {
+    #[serde(flatten)]
+    pub session: Session,
+    pub ended_at: u64,
+    pub bytes_received: u64,
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    pub packets_retransmitted: u64,
+    pub packets_dropped: u64,
+    pub exit_status: Option<ExitStatus>,
+    pub end_reason: EndReason,
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
+
+/// All sessions currently being ingested
+pub struct Registry {
+    sessions: Mutex<HashMap<Uuid, Session>>,
+}
+
+/// Keeps a session listed for as long as it is alive
+pub struct Registration<'a> {
+    registry: &'a Registry,
+    session: Session,
+}
+
+impl Registration<'_> {
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&self, uuid: Uuid, stream_id: u32, listener: &str, peer_addr: Option<SocketAddr>) -> Registration<'_> {
+        let session = Session {
+            uuid,
+            stream_id,
+            listener: listener.to_string(),
+            peer_addr,
+            started_at: unix_time(),
+        };
+        self.sessions.lock().unwrap().insert(uuid, session.clone());
+        Registration {
+            registry: self,
+            session,
+        }
+    }
+
+    /// The current sessions, oldest first
+    pub fn list(&self) -> Vec<Session> {
+        let mut sessions = self.sessions.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.started_at);
+        sessions
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.session.uuid);
+    }
+}
@@ -0,0 +1,220 @@
+use serde::Deserialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::ts::{self, PACKET_SIZE, SYNC_BYTE, TIMESTAMP_MODULUS};
+
+/// Packets per message, as streamers send them over SRT
+const PACKETS_PER_MESSAGE: usize = 7;
+pub const MESSAGE_SIZE: usize = PACKETS_PER_MESSAGE * PACKET_SIZE;
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SlateConfig {
+    /// A transport stream to loop while the contributor is away. It should start with a keyframe and be encoded like
+    /// the contributions, with the same PIDs and codec parameters, so that the packager can carry on from one to the
+    /// other.
+    pub path: PathBuf,
+    /// Seconds the slate is shown for before the session is ended, if the contributor doesn't return
+    #[serde(default = "default_grace_period")]
+    pub grace_period: u64,
+}
+
+fn default_grace_period() -> u64 {
+    300
+}
+
+impl SlateConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Works out when each message of a transport stream is due, which is when the last PCR before its end says
+pub struct Pacing {
+    first_pcr: Option<u64>,
+    pcr: u64,
+    messages: u32,
+}
+
+impl Pacing {
+    pub fn new() -> Pacing {
+        Pacing {
+            first_pcr: None,
+            pcr: 0,
+            messages: 0,
+        }
+    }
+
+    /// When the next message is due, from the start of the file
+    pub fn message(&mut self, message: &[u8]) -> Duration {
+        for packet in message.chunks_exact(PACKET_SIZE) {
+            if let Some(base) = ts::pcr(packet) {
+                let first = *self.first_pcr.get_or_insert(base);
+                self.pcr = (base + TIMESTAMP_MODULUS - first) % TIMESTAMP_MODULUS;
+            }
+        }
+        self.messages += 1;
+        Duration::from_micros(self.pcr * 100 / 9)
+    }
+
+    /// How long it takes to play all the messages so far, with the last followed by the first as if it were the next
+    /// one in the file
+    pub fn duration(&self) -> io::Result<Duration> {
+        if self.first_pcr.is_none() || self.pcr == 0 {
+            return Err(invalid("no PCRs to pace it by"));
+        }
+        let last = Duration::from_micros(self.pcr * 100 / 9);
+        Ok(last + last / self.messages)
+    }
+}
+
+/// A slate file being played out in real time, paced by its PCRs. Its timestamps start over each time it loops, and
+/// are left for the timeline to rewrite.
+pub struct Slate {
+    data: Vec<u8>,
+    /// When each message is due, from the start of the file
+    due: Vec<Duration>,
+    /// How long it takes to play the whole file
+    duration: Duration,
+    next: usize,
+    loop_start: Instant,
+}
+
+impl Slate {
+    pub fn open(path: &Path, now: Instant) -> io::Result<Slate> {
+        Slate::new(std::fs::read(path)?, now)
+    }
+
+    /// Plays out a transport stream from memory, starting at `now`
+    pub fn new(mut data: Vec<u8>, now: Instant) -> io::Result<Slate> {
+        data.truncate(data.len() - data.len() % PACKET_SIZE);
+        if data.first() != Some(&SYNC_BYTE) {
+            return Err(invalid("not a transport stream"));
+        }
+
+        let mut pacing = Pacing::new();
+        let due = data.chunks(MESSAGE_SIZE).map(|message| pacing.message(message)).collect::<Vec<_>>();
+        let duration = pacing.duration()?;
+
+        Ok(Slate {
+            data,
+            due,
+            duration,
+            next: 0,
+            loop_start: now,
+        })
+    }
+
+    /// When the next message should be sent
+    pub fn due(&self) -> Instant {
+        self.loop_start + self.due[self.next]
+    }
+
+    /// The next message, if it is due by `now`
+    pub fn next_due(&mut self, now: Instant) -> Option<&[u8]> {
+        if self.due() > now {
+            return None;
+        }
+        let start = self.next * MESSAGE_SIZE;
+        self.next += 1;
+        if self.next == self.due.len() {
+            self.next = 0;
+            self.loop_start += self.duration;
+        }
+        Some(&self.data[start..(start + MESSAGE_SIZE).min(self.data.len())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message of packets marked with `index`, the first of which carries `pcr` if there is one
+    fn message(index: u8, pcr: Option<u64>) -> Vec<u8> {
+        let mut message = Vec::new();
+        for i in 0..PACKETS_PER_MESSAGE {
+            let mut packet = vec![index; PACKET_SIZE];
+            packet[..4].copy_from_slice(&[SYNC_BYTE, 0x01, 0x00, 0x10]);
+            if let (0, Some(pcr)) = (i, pcr) {
+                packet[3] = 0x30;
+                packet[4] = 7;
+                packet[5] = 0x10;
+                packet[10] = 0x7e;
+                packet[11] = 0;
+                ts::set_pcr(&mut packet, pcr);
+            }
+            message.extend(packet);
+        }
+        message
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Three messages over 200ms, with PCRs that wrap around and none in the middle one
+    fn slate(start: Instant) -> Slate {
+        let mut data = message(0, Some(TIMESTAMP_MODULUS - 4500));
+        data.extend(message(1, None));
+        data.extend(message(2, Some(13500)));
+        // A partial packet at the end is left out
+        data.extend(&[SYNC_BYTE, 0x01, 0x00]);
+        Slate::new(data, start).unwrap()
+    }
+
+    fn index(message: Option<&[u8]>) -> Option<u8> {
+        message.map(|message| {
+            assert_eq!(message.len(), MESSAGE_SIZE);
+            message[PACKET_SIZE - 1]
+        })
+    }
+
+    #[test]
+    fn paces_messages_by_pcr() {
+        let start = Instant::now();
+        let mut slate = slate(start);
+        assert_eq!(slate.due(), start);
+        assert_eq!(index(slate.next_due(start)), Some(0));
+        // Without a PCR of its own, the second message is due with the first
+        assert_eq!(slate.due(), start);
+        assert_eq!(index(slate.next_due(start)), Some(1));
+        assert_eq!(slate.due(), start + ms(200));
+        assert_eq!(index(slate.next_due(start + ms(199))), None);
+        assert_eq!(index(slate.next_due(start + ms(200))), Some(2));
+    }
+
+    #[test]
+    fn loops_as_if_the_first_message_followed_the_last() {
+        let start = Instant::now();
+        let mut slate = slate(start);
+        let late = start + ms(1000);
+        // Catches up on what is overdue, and loops a third of the file's length after the last message
+        assert_eq!((0..3).map(|_| index(slate.next_due(late)).unwrap()).collect::<Vec<_>>(), [0, 1, 2]);
+        let duration = ms(200) + ms(200) / 3;
+        assert_eq!(slate.due(), start + duration);
+        assert_eq!(index(slate.next_due(late)), Some(0));
+        assert_eq!(slate.due(), start + duration);
+        assert_eq!(index(slate.next_due(late)), Some(1));
+        assert_eq!(slate.due(), start + duration + ms(200));
+    }
+
+    #[test]
+    fn rejects_files_it_cannot_play() {
+        let start = Instant::now();
+        assert!(Slate::new(vec![0; MESSAGE_SIZE], start).is_err());
+        assert!(Slate::new(Vec::new(), start).is_err());
+        let mut data = message(0, None);
+        data.extend(message(1, None));
+        assert!(Slate::new(data, start).is_err());
+        // A single PCR can't say how fast to go
+        let mut data = message(0, Some(1000));
+        data.extend(message(1, Some(1000)));
+        assert!(Slate::new(data, start).is_err());
+    }
+}
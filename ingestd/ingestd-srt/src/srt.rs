@@ -1,254 +1,283 @@
 use arc_swap::ArcSwap;
-use thiserror::Error;
-use libsrt_sys::*;
-use futures::task::AtomicWaker;
-use openat::Dir;
+use futures::future::poll_fn;
+use futures::prelude::*;
+use futures::task::{AtomicWaker, Context, Poll};
+use libsrt::options::{LossMaxTtl, Passphrase, RcvSyn, StreamId};
+use libsrt::{Epoll, EpollFlags, Listener, Socket, SocketId, SocketStatus, SrtError, Stats};
 use roaring::RoaringBitmap;
+use smol::{Task, Timer};
 use std::collections::HashMap;
 use std::ffi::CStr;
-use std::mem::MaybeUninit;
-use std::net::UdpSocket;
-use std::os::unix::io::IntoRawFd;
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
-use uuid::Uuid;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::access::{AccessControl, Rejection};
+use crate::srt_options::SrtOptions;
+use crate::stream_db::Streams;
+
+/// How long the reactor waits before trying srt_epoll_wait again after it failed
+const EPOLL_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Bridges libsrt's readiness notifications to the wakers of tasks on the smol executor
+struct Reactor {
+    epoll: Epoll,
+    /// Replaced whole as sockets come and go, so that waking never waits for a lock
+    wakers: ArcSwap<HashMap<SocketId, Arc<AtomicWaker>>>,
+}
 
-use crate::log::Logger;
-use crate::shared::{Connection, NewConnection, Packet};
+impl Reactor {
+    fn register(&self, socket: SocketId) -> Result<Arc<AtomicWaker>, SrtError> {
+        let waker = Arc::new(AtomicWaker::new());
+        self.wakers.rcu(|wakers| {
+            let mut wakers = HashMap::clone(wakers);
+            wakers.insert(socket, waker.clone());
+            wakers
+        });
+        // Edge triggered, so that sockets with data waiting for a busy task don't keep the reactor spinning
+        if let Err(e) = self.epoll.add(socket, EpollFlags::IN | EpollFlags::ERR | EpollFlags::ET) {
+            self.remove_waker(socket);
+            return Err(e);
+        }
+        Ok(waker)
+    }
 
-struct ShutdownGuard;
+    fn deregister(&self, socket: SocketId) {
+        let _ = self.epoll.remove(socket);
+        self.remove_waker(socket);
+    }
 
-impl Drop for ShutdownGuard {
-    fn drop(&mut self) {
-        unsafe { srt_cleanup(); }
+    fn remove_waker(&self, socket: SocketId) {
+        self.wakers.rcu(|wakers| {
+            let mut wakers = HashMap::clone(wakers);
+            wakers.remove(&socket);
+            wakers
+        });
     }
-}
 
-struct EpollReleaseGuard(libc::c_int);
+    /// libsrt only reports readiness through srt_epoll_wait, which blocks, so each wait is run on the executor's
+    /// blocking pool
+    async fn run(&'static self) {
+        let mut ready = [0; 256];
+        loop {
+            let (ready_len, waited) = smol::unblock! {
+                let ready_len = self.epoll.wait(&mut ready, -1);
+                (ready_len, ready)
+            };
+            ready = waited;
+            let wakers = self.wakers.load();
+            match ready_len {
+                Ok(ready_len) => for socket in &ready[..ready_len] {
+                    if let Some(waker) = wakers.get(socket) {
+                        waker.wake();
+                    }
+                },
+                Err(e) => {
+                    // Whatever became ready meanwhile went unreported, so every socket finds out for itself, and the
+                    // wait is retried after a pause in case the error persists
+                    eprintln!("waiting for SRT sockets failed: {}", e);
+                    for waker in wakers.values() {
+                        waker.wake();
+                    }
+                    Timer::new(EPOLL_RETRY_INTERVAL).await;
+                },
+            }
+        }
+    }
+}
 
-impl Drop for EpollReleaseGuard {
-    fn drop(&mut self) {
-        unsafe { srt_epoll_release(self.0); }
+/// Shows IPv4 callers of a dual-stack listener as the IPv4 addresses they are, rather than as ::ffff:a.b.c.d
+fn unmap_ipv4(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => SocketAddr::new(v6.ip().to_ipv4().unwrap().into(), v6.port()),
+            _ => addr,
+        },
+        SocketAddr::V4(_) => addr,
     }
 }
 
-#[derive(Error, Debug)]
-#[error("{}", unsafe { CStr::from_ptr(srt_strerror(self.0, 0)) }.to_string_lossy())]
-pub struct SrtError(SRT_ERRNO);
+pub struct AsyncListener {
+    listener: Listener,
+    waker: Arc<AtomicWaker>,
+    reactor: &'static Reactor,
+    access: &'static AccessControl,
+}
 
-fn srt(res: libc::c_int) -> Result<libc::c_int, SrtError> {
-    if res != -1 {
-        Ok(res)
-    } else {
-        Err(SrtError(unsafe { srt_getlasterror(std::ptr::null_mut()) }))
+impl AsyncListener {
+    pub async fn accept(&self) -> Result<SrtStream, SrtError> {
+        let (socket, peer_addr) = poll_fn(|cx| {
+            // Register before trying, so that readiness between the attempt and registering isn't missed
+            self.waker.register(cx.waker());
+            match self.listener.accept() {
+                Err(e) if e.is_would_block() => Poll::Pending,
+                res => Poll::Ready(res),
+            }
+        }).await?;
+        let peer_addr = peer_addr.map(unmap_ipv4);
+        if let Some(peer_addr) = peer_addr {
+            self.access.handshake_completed(peer_addr);
+        }
+        eprintln!("accepted fd: {} from {:?}", socket.id(), peer_addr);
+        let waker = self.reactor.register(socket.id())?;
+        Ok(SrtStream {
+            socket,
+            peer_addr,
+            waker,
+            reactor: self.reactor,
+        })
     }
 }
 
-unsafe fn remove_connection(map: &mut HashMap<SRTSOCKET, Arc<Connection>>, epoll: libc::c_int, fd: SRTSOCKET) -> Result<(), SrtError> {
-    eprintln!("closed in remove_connection");
-    srt(srt_epoll_remove_usock(epoll, fd))?;
-    let connection = map.remove(&fd).unwrap();
-    connection.data.lock().unwrap().closed = true;
-    connection.gpac_waker.wake();
-    Ok(())
+/// An accepted SRT connection, read from asynchronously
+pub struct SrtStream {
+    socket: Socket,
+    peer_addr: Option<SocketAddr>,
+    waker: Arc<AtomicWaker>,
+    reactor: &'static Reactor,
 }
 
-unsafe fn process_socket(fd: SRTSOCKET, connection: &Connection) -> bool {
-    let mut data = connection.data.lock().unwrap();
-
-    if data.closed {
-        eprintln!("closed");
-        return false;
+impl SrtStream {
+    pub fn stream_id(&self) -> Result<Box<[u8]>, SrtError> {
+        Ok(self.socket.get::<StreamId>()?.into_boxed_slice())
     }
 
-    eprintln!("backlog: {}", data.packets.len());
-    for _ in 0..1024-data.packets.len() {
-        let mut buffer = vec![0; SRT_LIVE_MAX_PLSIZE as usize];
-
-        let mut msg_ctrl = MaybeUninit::<SRT_MSGCTRL>::uninit();
-        srt_msgctrl_init(msg_ctrl.as_mut_ptr());
-        let mut msg_ctrl = msg_ctrl.assume_init();
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
 
-        let len = match srt(srt_recvmsg2(fd, buffer.as_mut_ptr() as *mut libc::c_char, buffer.len() as i32, &mut msg_ctrl as *mut SRT_MSGCTRL)) {
-            Err(e) if e.0 == SRT_EASYNCRCV => continue,
-            Err(s) => {
-                eprintln!("{}", s);
-                return false;
-            },
-            Ok(len) => len,
-        };
+    /// Totals since the connection was accepted
+    pub fn stats(&self) -> Result<Stats, SrtError> {
+        self.socket.stats(false)
+    }
 
-        if len == 0 {
-            eprintln!("0-length message");
-            return false;
+    pub fn poll_recv(&self, cx: &mut Context, buffer: &mut [u8]) -> Poll<Result<usize, SrtError>> {
+        self.waker.register(cx.waker());
+        if self.socket.status() == SocketStatus::Broken {
+            return Poll::Ready(Ok(0));
+        }
+        match self.socket.recv_msg(buffer) {
+            Err(e) if e.is_would_block() => Poll::Pending,
+            res => Poll::Ready(res),
         }
-
-        buffer.truncate(len as usize);
-
-        data.packets.push(Packet {
-            buffer,
-            received: Instant::now(),
-        });
     }
 
-    true
-}
+    /// Receives the next message, or 0 bytes once the connection has ended
+    pub fn recv<'a>(&'a self, buffer: &'a mut [u8]) -> impl Future<Output=Result<usize, SrtError>> + 'a {
+        poll_fn(move |cx| self.poll_recv(cx, buffer))
+    }
 
-unsafe fn listen(epoll: libc::c_int, listener: SRTSOCKET, log_dir: Dir, gpac_waker: Arc<AtomicWaker>, new_connections: Arc<Mutex<Vec<NewConnection>>>) -> Result<(), SrtError> {
-    let mut connections = HashMap::new();
-    loop {
-        let mut read_fds = [0; 256];
-        let mut read_fds_size = 256;
-
-        eprintln!("polling");
-        srt(srt_epoll_wait(epoll,
-            read_fds.as_mut_ptr(), &mut read_fds_size as *mut libc::c_int,
-            std::ptr::null_mut(), std::ptr::null_mut(),
-            -1,
-            std::ptr::null_mut(), std::ptr::null_mut(),
-            std::ptr::null_mut(), std::ptr::null_mut(),
-        ))?;
-
-        for i in 0..read_fds_size as usize {
-            let fd = read_fds[i];
-            if fd == listener {
-                let mut addr = MaybeUninit::<libc::sockaddr_in>::uninit();
-                let mut addr_size = std::mem::size_of_val(&addr) as libc::c_int;
-                let fd = match srt(srt_accept(listener, addr.as_mut_ptr() as *mut libc::sockaddr, &mut addr_size as *mut libc::c_int)) {
-                    Err(e) if e.0 == SRT_EASYNCRCV => continue,
-                    Err(e) => return Err(e.into()),
-                    Ok(fd) => fd,
-                };
-
-                eprintln!("accepted fd: {}", fd);
-
-                let epoll_flags = (SRT_EPOLL_IN|SRT_EPOLL_ERR) as SRT_EPOLL_T;
-                srt(srt_epoll_add_usock(epoll, fd, &epoll_flags as *const SRT_EPOLL_T))?;
-
-                let connection = Arc::new(Connection::default());
-                connections.insert(fd, connection.clone());
-
-                let stream_uuid = Uuid::new_v4();
-                let logger = {
-                    let mut filename_buf = [0; uuid::adapter::HyphenatedRef::LENGTH];
-                    let filename = stream_uuid.to_hyphenated_ref().encode_lower(&mut filename_buf);
-                    let log_file = log_dir.append_file(filename as &str, 0o640).unwrap();
-                    Logger::from(log_file)
-                };
-
-                let stream_id = {
-                    let mut stream_id = [0u8; 512];
-                    let mut stream_id_size = std::mem::size_of_val(&stream_id) as libc::c_int;
-                    srt(srt_getsockflag(fd, SRTO_STREAMID, stream_id.as_mut_ptr() as *mut libc::c_void, &mut stream_id_size as *mut libc::c_int))?;
-                    eprintln!("stream id: {:?}", &stream_id[0..32]);
-                    Box::from(&stream_id[..stream_id_size as usize])
-                };
-
-                new_connections.lock().unwrap().push(NewConnection {
-                    logger,
-                    stream_id,
-                    stream_uuid,
-                    connection,
-                });
-                gpac_waker.wake();
-            } else {
-                match srt_getsockstate(fd) {
-                    SRTS_BROKEN => {
-                        remove_connection(&mut connections, epoll, fd)?;
-                        continue;
-                    },
-                    _ => {},
-                }
-
-                let connection = &connections[&fd];
-                if process_socket(fd, connection) {
-                    connection.gpac_waker.wake();
-                } else {
-                    remove_connection(&mut connections, epoll, fd)?;
-                    srt(srt_close(fd))?;
-                }
-            }
+    /// Receives a message that has already arrived, without waiting
+    pub fn try_recv(&self, buffer: &mut [u8]) -> Result<Option<usize>, SrtError> {
+        if self.socket.status() == SocketStatus::Broken {
+            return Ok(Some(0));
+        }
+        match self.socket.recv_msg(buffer) {
+            Err(e) if e.is_would_block() => Ok(None),
+            res => res.map(Some),
         }
     }
 }
 
-struct AuthUserData {
-    global_secret: [u8; 32],
-    valid_stream_ids: Arc<ArcSwap<RoaringBitmap>>,
+impl Drop for SrtStream {
+    fn drop(&mut self) {
+        self.reactor.deregister(self.socket.id());
+    }
 }
 
-unsafe extern "C" fn auth(userdata: *mut libc::c_void, sock: SRTSOCKET, _hsversion: libc::c_int, _peeraddr: *const libc::sockaddr, stream_id: *const libc::c_char) -> libc::c_int {
-    let userdata = &*(userdata as *const AuthUserData);
-
-    eprintln!("authing");
-
-    let stream_id = match CStr::from_ptr(stream_id).to_str() {
-        Ok(s) => s,
-        Err(_) => {eprintln!("bad streamid"); return -1},
-    };
-    if stream_id.len() >= 6 && &stream_id[0..6] != "#!::u=" {
-        eprintln!("bad start");
-        return -1;
+fn auth(global_secret: &[u8; 32], streams: &ArcSwap<Streams>, allowed_stream_ids: Option<&RoaringBitmap>, socket: &libsrt::PendingSocket, stream_id: &CStr) -> Result<(), Rejection> {
+    let stream_id = stream_id.to_str().map_err(|_| Rejection::BadStreamId)?;
+    if !stream_id.starts_with("#!::u=") {
+        return Err(Rejection::BadStreamId);
     }
+    let stream_userid = stream_id[6..].parse::<u32>().map_err(|_| Rejection::BadStreamId)?;
 
-    let stream_userid = match stream_id[6..].parse::<u32>() {
-        Ok(u) => u,
-        Err(_) => {
-            eprintln!("couldn't parse user id");
-            return -1;
-        },
-    };
-
-    if !userdata.valid_stream_ids.load().contains(stream_userid) {
-        eprintln!("stream id invalid");
-        return -1;
+    let streams = streams.load();
+    if !streams.ids.contains(stream_userid) {
+        return Err(Rejection::UnknownStream);
+    }
+    if !allowed_stream_ids.map(|allowed| allowed.contains(stream_userid)).unwrap_or(true) {
+        return Err(Rejection::NotOnListener);
+    }
+    if let Some(options) = streams.srt_options.get(&stream_userid) {
+        if let Err(e) = options.apply(socket) {
+            eprintln!("setting stream's srt options failed: {}", e);
+            return Err(Rejection::Internal);
+        }
     }
 
     // Perform a keyed hash of stream_id to get the passphrase
-    let hash = blake3::keyed_hash(&userdata.global_secret, stream_id.as_bytes());
-    let hash_hex = hash.to_hex();
-    srt_setsockflag(sock, SRTO_PASSPHRASE, hash_hex.as_ptr() as *const libc::c_void, hash_hex.len() as libc::c_int);
-    eprintln!("hash: {}", hash_hex);
-    eprintln!("authed");
-    return 0;
+    let hash = blake3::keyed_hash(global_secret, stream_id.as_bytes());
+    if let Err(e) = socket.set::<Passphrase>(hash.to_hex().to_string()) {
+        eprintln!("setting passphrase failed: {}", e);
+        return Err(Rejection::Internal);
+    }
+    Ok(())
 }
 
-pub fn spawn_listen(listener_sock: UdpSocket, log_dir: Dir, global_secret: [u8; 32], valid_stream_ids: Arc<ArcSwap<RoaringBitmap>>, gpac_waker: Arc<AtomicWaker>, new_connections: Arc<Mutex<Vec<NewConnection>>>) -> Result<(), SrtError> {
-    unsafe {
-        srt_setloglevel(7);
-
-        srt(srt_startup())?;
-        let guard = ShutdownGuard;
+/// libsrt, initialised, with a task waking the tasks waiting on its sockets
+#[derive(Clone, Copy)]
+pub struct Runtime {
+    reactor: &'static Reactor,
+}
 
-        let listener = srt(srt_create_socket())?;
+pub fn start() -> Result<Runtime, SrtError> {
+    libsrt::set_log_level(7);
 
-        let auth_user_data = Box::leak(Box::new(AuthUserData {
-            global_secret,
-            valid_stream_ids,
-        }));
-        srt(srt_listen_callback(listener, Some(auth), auth_user_data as *mut AuthUserData as *mut libc::c_void))?;
+    let srt = libsrt::startup()?;
 
-        let no = false;
-        srt(srt_setsockflag(listener, SRTO_RCVSYN, &no as *const bool as *const libc::c_void, std::mem::size_of_val(&no) as libc::c_int))?;
-        //let yes = 1 as libc::c_int;
-        //srt(srt_setsockflag(listener, SRTO_GROUPCONNECT, &yes as *const libc::c_int as *const libc::c_void, std::mem::size_of_val(&yes) as libc::c_int))?;
-        let lossmaxttl = 10 as libc::c_int;
-        srt(srt_setsockflag(listener, SRTO_LOSSMAXTTL, &lossmaxttl as *const libc::c_int as *const libc::c_void, std::mem::size_of_val(&lossmaxttl) as libc::c_int))?;
+    let reactor: &'static Reactor = Box::leak(Box::new(Reactor {
+        epoll: Epoll::new()?,
+        wakers: ArcSwap::from_pointee(HashMap::new()),
+    }));
 
-        srt(srt_bind_peerof(listener, listener_sock.into_raw_fd()))?;
-        srt(srt_listen(listener, 10))?;
+    Task::spawn(async move {
+        let _srt = srt;
+        reactor.run().await;
+    }).detach();
 
-        let epoll = srt(srt_epoll_create())?;
-        let listener_epoll_flags = (SRT_EPOLL_IN|SRT_EPOLL_ERR) as SRT_EPOLL_T;
-        srt(srt_epoll_add_usock(epoll, listener, &listener_epoll_flags as *const SRT_EPOLL_T))?;
+    Ok(Runtime { reactor })
+}
 
-        std::thread::Builder::new().name("srt".to_string()).spawn(move || {
-            let _guard = guard;
-            if let Err(e) = listen(epoll, listener, log_dir, gpac_waker, new_connections) {
-                eprintln!("{}", e);
+impl Runtime {
+    /// Listens on `listener_sock`, accepting the active streams in `allowed_stream_ids`, or all of them if it's None
+    pub fn listen(&self, listener_sock: UdpSocket, global_secret: [u8; 32], options: &SrtOptions, allowed_stream_ids: Option<RoaringBitmap>, streams: Arc<ArcSwap<Streams>>, access: &'static AccessControl) -> Result<AsyncListener, SrtError> {
+        let mut listener = Listener::new()?;
+
+        listener.set_listen_callback(move |socket, _hsversion, peer_addr, stream_id| {
+            let peer_addr = peer_addr.map(unmap_ipv4);
+            let ip = peer_addr.map(|peer_addr| peer_addr.ip());
+            let res = ip.map(|ip| access.check(ip)).unwrap_or(Ok(()))
+                .and_then(|()| auth(&global_secret, &streams, allowed_stream_ids.as_ref(), socket, stream_id));
+            match res {
+                Ok(()) => {
+                    if let Some(peer_addr) = peer_addr {
+                        access.handshake_pending(peer_addr);
+                    }
+                    true
+                },
+                Err(rejection) => {
+                    access.reject(ip, rejection);
+                    false
+                },
             }
-        }).unwrap();
+        })?;
+
+        listener.set::<RcvSyn>(false)?;
+        listener.set::<LossMaxTtl>(10)?;
+        // Accepted sockets inherit these
+        options.apply(&listener)?;
+
+        listener.bind_udp(listener_sock)?;
+        listener.listen(10)?;
+
+        let waker = self.reactor.register(listener.id())?;
 
-        Ok(())
+        Ok(AsyncListener {
+            listener,
+            waker,
+            reactor: self.reactor,
+            access,
+        })
     }
 }
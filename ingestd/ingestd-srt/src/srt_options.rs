@@ -0,0 +1,250 @@
+use libsrt::options::*;
+use libsrt::SrtError;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::pool::MESSAGE_SIZE;
+
+macro_rules! srt_options {
+    ($($(#[$attr:meta])* $field:ident: $value:ty => $option:ident,)*) => {
+        /// SRT socket options, all left at libsrt's defaults unless set
+        #[derive(Clone, Debug, Default, Deserialize)]
+        #[serde(rename_all = "kebab-case", deny_unknown_fields)]
+        pub struct SrtOptions {
+            $($(#[$attr])* pub $field: Option<$value>,)*
+        }
+
+        impl SrtOptions {
+            /// These options, with any set in `overrides` taking precedence
+            pub fn merge(&self, overrides: &SrtOptions) -> SrtOptions {
+                SrtOptions {
+                    $($field: overrides.$field.clone().or_else(|| self.$field.clone()),)*
+                }
+            }
+
+            pub fn apply(&self, socket: &impl Configure) -> Result<(), SrtError> {
+                $(
+                    if let Some(value) = &self.$field {
+                        socket.set_option::<$option>(value.clone())?;
+                    }
+                )*
+                Ok(())
+            }
+        }
+    }
+}
+
+srt_options! {
+    /// Latency in milliseconds, for both directions
+    latency: i32 => Latency,
+    rcv_latency: i32 => RcvLatency,
+    peer_latency: i32 => PeerLatency,
+    pbkeylen: i32 => PbKeyLen,
+    enforced_encryption: bool => EnforcedEncryption,
+    maxbw: i64 => MaxBw,
+    inputbw: i64 => InputBw,
+    oheadbw: i32 => OheadBw,
+    rcvbuf: i32 => RcvBuf,
+    fc: i32 => Fc,
+    mss: i32 => Mss,
+    payloadsize: i32 => PayloadSize,
+    packet_filter: String => PacketFilter,
+    #[serde(rename = "lossmaxttl")]
+    loss_max_ttl: i32 => LossMaxTtl,
+    tlpktdrop: bool => TlPktDrop,
+    nakreport: bool => NakReport,
+    peer_idle_timeout: i32 => PeerIdleTimeo,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum InvalidOptions {
+    #[error("{0} must not be negative")]
+    Negative(&'static str),
+    #[error("pbkeylen must be 0, 16, 24 or 32")]
+    PbKeyLen,
+    #[error("enforced-encryption can't be disabled, as the passphrase is what authenticates streamers")]
+    EncryptionNotEnforced,
+    #[error("mss must be between 76 and 1500")]
+    Mss,
+    #[error("payloadsize must be at most {0}")]
+    PayloadSize(usize),
+    #[error("maxbw must be -1 (infinite), 0 (relative to inputbw) or positive")]
+    MaxBw,
+    #[error("oheadbw must be between 5 and 100")]
+    OheadBw,
+    #[error("fc must be at least 32")]
+    Fc,
+    #[error("rcvbuf is larger than fc packets of mss, libsrt would silently shrink it")]
+    RcvBuf,
+    #[error("packet-filter must be a comma separated list starting with the filter type, then key:value pairs")]
+    PacketFilter,
+}
+
+/// Headers on top of the SRT payload within the MSS: IPv4, UDP and SRT
+const PACKET_OVERHEAD: i32 = 20 + 8 + 16;
+
+impl SrtOptions {
+    /// Rejects options libsrt would refuse when a streamer connects, or silently adjust
+    pub fn validate(&self) -> Result<(), InvalidOptions> {
+        let non_negative = [
+            ("latency", self.latency),
+            ("rcv-latency", self.rcv_latency),
+            ("peer-latency", self.peer_latency),
+            ("rcvbuf", self.rcvbuf),
+            ("lossmaxttl", self.loss_max_ttl),
+            ("peer-idle-timeout", self.peer_idle_timeout),
+        ];
+        for (name, value) in non_negative.iter() {
+            if value.map(|value| value < 0).unwrap_or(false) {
+                return Err(InvalidOptions::Negative(name));
+            }
+        }
+        if self.inputbw.map(|inputbw| inputbw < 0).unwrap_or(false) {
+            return Err(InvalidOptions::Negative("inputbw"));
+        }
+
+        if let Some(pbkeylen) = self.pbkeylen {
+            if ![0, 16, 24, 32].contains(&pbkeylen) {
+                return Err(InvalidOptions::PbKeyLen);
+            }
+        }
+        if self.enforced_encryption == Some(false) {
+            return Err(InvalidOptions::EncryptionNotEnforced);
+        }
+
+        let mss = self.mss.unwrap_or(1500);
+        if mss < 76 || mss > 1500 {
+            return Err(InvalidOptions::Mss);
+        }
+        // Received messages are stored in fixed size slots, so larger payloads would be truncated
+        let max_payload_size = MESSAGE_SIZE.min((mss - PACKET_OVERHEAD) as usize);
+        if let Some(payloadsize) = self.payloadsize {
+            if payloadsize < 0 {
+                return Err(InvalidOptions::Negative("payloadsize"));
+            }
+            if payloadsize as usize > max_payload_size {
+                return Err(InvalidOptions::PayloadSize(max_payload_size));
+            }
+        }
+
+        if self.maxbw.map(|maxbw| maxbw < -1).unwrap_or(false) {
+            return Err(InvalidOptions::MaxBw);
+        }
+        if let Some(oheadbw) = self.oheadbw {
+            if oheadbw < 5 || oheadbw > 100 {
+                return Err(InvalidOptions::OheadBw);
+            }
+        }
+
+        let fc = self.fc.unwrap_or(25600);
+        if fc < 32 {
+            return Err(InvalidOptions::Fc);
+        }
+        if let Some(rcvbuf) = self.rcvbuf {
+            if rcvbuf as i64 > fc as i64 * (mss - 28) as i64 {
+                return Err(InvalidOptions::RcvBuf);
+            }
+        }
+
+        if let Some(packet_filter) = &self.packet_filter {
+            let mut parts = packet_filter.split(',');
+            let filter_type = parts.next().unwrap_or("");
+            if filter_type.is_empty() || filter_type.contains(':') {
+                return Err(InvalidOptions::PacketFilter);
+            }
+            for part in parts {
+                let mut key_value = part.splitn(2, ':');
+                match (key_value.next(), key_value.next()) {
+                    (Some(key), Some(value)) if !key.is_empty() && !value.is_empty() => {},
+                    _ => return Err(InvalidOptions::PacketFilter),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(SrtOptions::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_negative_values() {
+        let options = SrtOptions { rcv_latency: Some(-1), ..SrtOptions::default() };
+        assert_eq!(options.validate(), Err(InvalidOptions::Negative("rcv-latency")));
+        let options = SrtOptions { inputbw: Some(-1), ..SrtOptions::default() };
+        assert_eq!(options.validate(), Err(InvalidOptions::Negative("inputbw")));
+        let options = SrtOptions { payloadsize: Some(-1), ..SrtOptions::default() };
+        assert_eq!(options.validate(), Err(InvalidOptions::Negative("payloadsize")));
+        let options = SrtOptions { latency: Some(0), ..SrtOptions::default() };
+        assert_eq!(options.validate(), Ok(()));
+    }
+
+    #[test]
+    fn checks_encryption() {
+        for &pbkeylen in &[0, 16, 24, 32] {
+            assert_eq!(SrtOptions { pbkeylen: Some(pbkeylen), ..SrtOptions::default() }.validate(), Ok(()));
+        }
+        assert_eq!(SrtOptions { pbkeylen: Some(8), ..SrtOptions::default() }.validate(), Err(InvalidOptions::PbKeyLen));
+        assert_eq!(SrtOptions { enforced_encryption: Some(true), ..SrtOptions::default() }.validate(), Ok(()));
+        assert_eq!(SrtOptions { enforced_encryption: Some(false), ..SrtOptions::default() }.validate(), Err(InvalidOptions::EncryptionNotEnforced));
+    }
+
+    #[test]
+    fn limits_payload_size_to_mss_and_message_slots() {
+        assert_eq!(SrtOptions { mss: Some(75), ..SrtOptions::default() }.validate(), Err(InvalidOptions::Mss));
+        assert_eq!(SrtOptions { mss: Some(1501), ..SrtOptions::default() }.validate(), Err(InvalidOptions::Mss));
+        assert_eq!(SrtOptions { payloadsize: Some(1456), ..SrtOptions::default() }.validate(), Ok(()));
+        assert_eq!(SrtOptions { payloadsize: Some(1457), ..SrtOptions::default() }.validate(), Err(InvalidOptions::PayloadSize(MESSAGE_SIZE)));
+        let options = SrtOptions { mss: Some(1000), payloadsize: Some(1316), ..SrtOptions::default() };
+        assert_eq!(options.validate(), Err(InvalidOptions::PayloadSize(956)));
+    }
+
+    #[test]
+    fn checks_bandwidth() {
+        for &maxbw in &[-1, 0, 1_000_000] {
+            assert_eq!(SrtOptions { maxbw: Some(maxbw), ..SrtOptions::default() }.validate(), Ok(()));
+        }
+        assert_eq!(SrtOptions { maxbw: Some(-2), ..SrtOptions::default() }.validate(), Err(InvalidOptions::MaxBw));
+        assert_eq!(SrtOptions { oheadbw: Some(4), ..SrtOptions::default() }.validate(), Err(InvalidOptions::OheadBw));
+        assert_eq!(SrtOptions { oheadbw: Some(101), ..SrtOptions::default() }.validate(), Err(InvalidOptions::OheadBw));
+        assert_eq!(SrtOptions { oheadbw: Some(25), ..SrtOptions::default() }.validate(), Ok(()));
+    }
+
+    #[test]
+    fn keeps_rcvbuf_within_flow_control_window() {
+        assert_eq!(SrtOptions { fc: Some(31), ..SrtOptions::default() }.validate(), Err(InvalidOptions::Fc));
+        let options = SrtOptions { fc: Some(100), rcvbuf: Some(100 * 1472), ..SrtOptions::default() };
+        assert_eq!(options.validate(), Ok(()));
+        let options = SrtOptions { fc: Some(100), rcvbuf: Some(100 * 1472 + 1), ..SrtOptions::default() };
+        assert_eq!(options.validate(), Err(InvalidOptions::RcvBuf));
+    }
+
+    #[test]
+    fn parses_packet_filter() {
+        for &packet_filter in &["fec", "fec,cols:10,rows:5", "fec,layout:staircase,arq:onreq"] {
+            let options = SrtOptions { packet_filter: Some(packet_filter.to_string()), ..SrtOptions::default() };
+            assert_eq!(options.validate(), Ok(()), "{}", packet_filter);
+        }
+        for &packet_filter in &["", "cols:10", "fec,cols", "fec,cols:", "fec,:10", "fec,,rows:5"] {
+            let options = SrtOptions { packet_filter: Some(packet_filter.to_string()), ..SrtOptions::default() };
+            assert_eq!(options.validate(), Err(InvalidOptions::PacketFilter), "{}", packet_filter);
+        }
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let global = SrtOptions { latency: Some(120), maxbw: Some(0), ..SrtOptions::default() };
+        let stream = SrtOptions { latency: Some(500), ..SrtOptions::default() };
+        let merged = global.merge(&stream);
+        assert_eq!(merged.latency, Some(500));
+        assert_eq!(merged.maxbw, Some(0));
+        assert_eq!(merged.fc, None);
+    }
+}
@@ -0,0 +1,80 @@
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::BoxFuture;
+use thiserror::Error;
+
+use crate::migrate::{self, Migrated};
+use crate::sessions::Summary;
+
+mod postgres;
+mod sqlite;
+
+pub use self::postgres::PostgresStore;
+pub use self::sqlite::SqliteStore;
+
+/// A stream that may connect
+pub struct ActiveStream {
+    pub id: u32,
+    /// SRT option overrides, in TOML
+    pub srt_options: Option<String>,
+}
+
+/// Where to send a stream's notifications, and how it is packaged
+pub struct StreamRow {
+    pub notify_url: String,
+    pub token: String,
+    /// Languages of the audio tracks to keep, comma-separated
+    pub audio_tracks: Option<String>,
+}
+
+/// A stream that plays out a schedule of recordings whenever no one is streaming to it live
+pub struct ChannelStream {
+    pub id: u32,
+    pub notify_url: String,
+    pub token: String,
+}
+
+/// A recording scheduled on a channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduleEntry {
+    /// Seconds since the Unix epoch
+    pub starts_at: u64,
+    pub path: String,
+}
+
+/// Where streams are configured and sessions are recorded, which may be shared between several ingest nodes
+pub trait StreamStore: Send + Sync {
+    fn migrate(&self) -> BoxFuture<'_, Result<Migrated, migrate::Error>>;
+
+    fn active_streams(&self) -> BoxFuture<'_, Result<Vec<ActiveStream>, sqlx::Error>>;
+
+    fn lookup_stream(&self, id: u32) -> BoxFuture<'_, Result<StreamRow, sqlx::Error>>;
+
+    /// The active streams that are channels
+    fn channels(&self) -> BoxFuture<'_, Result<Vec<ChannelStream>, sqlx::Error>>;
+
+    /// A channel's schedule from the entry playing at `now` on, in order
+    fn schedule(&self, stream_id: u32, now: u64) -> BoxFuture<'_, Result<Vec<ScheduleEntry>, sqlx::Error>>;
+
+    fn record_session<'a>(&'a self, summary: &'a Summary) -> BoxFuture<'a, Result<(), sqlx::Error>>;
+
+    /// Sends on `changed` whenever the streams may have changed, for as long as the returned future is polled
+    fn watch(&self, changed: UnboundedSender<()>) -> BoxFuture<'_, Result<(), sqlx::Error>>;
+}
+
+#[derive(Error, Debug)]
+pub enum OpenError {
+    #[error("unsupported database url scheme {0:?}, expected sqlite or postgres")]
+    UnsupportedScheme(String),
+    #[error("connecting to database: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Connects to the store `url` points at, by its scheme
+pub async fn open(url: &str) -> Result<Box<dyn StreamStore>, OpenError> {
+    let scheme = url.split(':').next().unwrap_or("");
+    match scheme {
+        "sqlite" => Ok(Box::new(SqliteStore::connect(url).await?)),
+        "postgres" | "postgresql" => Ok(Box::new(PostgresStore::connect(url).await?)),
+        _ => Err(OpenError::UnsupportedScheme(scheme.to_string())),
+    }
+}
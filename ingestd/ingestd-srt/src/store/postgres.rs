@@ -0,0 +1,147 @@
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::{BoxFuture, FutureExt};
+use smol::Timer;
+use sqlx::postgres::{PgListener, PgRow};
+use sqlx::{PgPool, Row, query};
+use std::time::Duration;
+
+use super::{ActiveStream, ChannelStream, ScheduleEntry, StreamRow, StreamStore};
+use crate::migrate::{self, Migrated};
+use crate::pidfd::ExitStatus;
+use crate::sessions::Summary;
+
+/// Notified by a trigger on the streams table
+const CHANNEL: &str = "ingestd_streams";
+/// How long to wait between attempts to listen again after losing the connection
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A Postgres database, which can be shared with the cms and between ingest nodes. Queries are checked at runtime,
+/// as `query!` can only check against the sqlite schema at build time.
+pub struct PostgresStore {
+    url: String,
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub async fn connect(url: &str) -> Result<PostgresStore, sqlx::Error> {
+        Ok(PostgresStore {
+            url: url.to_string(),
+            pool: PgPool::new(url).await?,
+        })
+    }
+}
+
+impl StreamStore for PostgresStore {
+    fn migrate(&self) -> BoxFuture<'_, Result<Migrated, migrate::Error>> {
+        async move {
+            let mut db = self.pool.acquire().await?;
+            migrate::migrate_postgres(&mut db).await
+        }.boxed()
+    }
+
+    fn active_streams(&self) -> BoxFuture<'_, Result<Vec<ActiveStream>, sqlx::Error>> {
+        async move {
+            query("SELECT id, srt_options FROM streams WHERE active")
+                .map(|row: PgRow| ActiveStream {
+                    id: row.get::<i32, _>("id") as u32,
+                    srt_options: row.get::<Option<String>, _>("srt_options"),
+                })
+                .fetch_all(&self.pool).await
+        }.boxed()
+    }
+
+    fn lookup_stream(&self, id: u32) -> BoxFuture<'_, Result<StreamRow, sqlx::Error>> {
+        async move {
+            query("SELECT notify_url, token, audio_tracks FROM streams WHERE id = $1")
+                .bind(id as i32)
+                .map(|row: PgRow| StreamRow {
+                    notify_url: row.get("notify_url"),
+                    token: row.get("token"),
+                    audio_tracks: row.get("audio_tracks"),
+                })
+                .fetch_one(&self.pool).await
+        }.boxed()
+    }
+
+    fn channels(&self) -> BoxFuture<'_, Result<Vec<ChannelStream>, sqlx::Error>> {
+        async move {
+            query("SELECT id, notify_url, token FROM streams WHERE active AND kind = 'channel'")
+                .map(|row: PgRow| ChannelStream {
+                    id: row.get::<i32, _>("id") as u32,
+                    notify_url: row.get("notify_url"),
+                    token: row.get("token"),
+                })
+                .fetch_all(&self.pool).await
+        }.boxed()
+    }
+
+    fn schedule(&self, stream_id: u32, now: u64) -> BoxFuture<'_, Result<Vec<ScheduleEntry>, sqlx::Error>> {
+        async move {
+            query("SELECT starts_at, path FROM schedule WHERE stream_id = $1 AND starts_at >= (SELECT COALESCE(MAX(starts_at), 0) FROM schedule WHERE stream_id = $1 AND starts_at <= $2) ORDER BY starts_at")
+                .bind(stream_id as i32)
+                .bind(now as i64)
+                .map(|row: PgRow| ScheduleEntry {
+                    starts_at: row.get::<i64, _>("starts_at") as u64,
+                    path: row.get("path"),
+                })
+                .fetch_all(&self.pool).await
+        }.boxed()
+    }
+
+    fn record_session<'a>(&'a self, summary: &'a Summary) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        async move {
+            let (exit_code, exit_signal) = match summary.exit_status {
+                Some(ExitStatus::Exited { code }) => (Some(code), None),
+                Some(ExitStatus::Signalled { signal, .. }) => (None, Some(signal)),
+                None => (None, None),
+            };
+            query("INSERT INTO sessions (uuid, stream_id, listener, peer_addr, started_at, ended_at, bytes_received, packets_received, packets_lost, packets_retransmitted, packets_dropped, exit_code, exit_signal, end_reason) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)")
+                .bind(summary.session.uuid.to_hyphenated_ref().to_string())
+                .bind(summary.session.stream_id as i32)
+                .bind(summary.session.listener.as_str())
+                .bind(summary.session.peer_addr.map(|peer_addr| peer_addr.to_string()))
+                .bind(summary.session.started_at as i64)
+                .bind(summary.ended_at as i64)
+                .bind(summary.bytes_received as i64)
+                .bind(summary.packets_received as i64)
+                .bind(summary.packets_lost as i64)
+                .bind(summary.packets_retransmitted as i64)
+                .bind(summary.packets_dropped as i64)
+                .bind(exit_code)
+                .bind(exit_signal)
+                .bind(summary.end_reason.as_str())
+                .execute(&self.pool).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    fn watch(&self, changed: UnboundedSender<()>) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        async move {
+            let mut listener = listen(&self.url).await?;
+            // Anything that changed between the initial load and listening would otherwise be missed
+            let _ = changed.unbounded_send(());
+            loop {
+                if let Err(e) = listener.recv().await {
+                    eprintln!("listening for stream changes failed, reconnecting: {}", e);
+                    listener = loop {
+                        Timer::new(RECONNECT_INTERVAL).await;
+                        match listen(&self.url).await {
+                            Ok(listener) => break listener,
+                            Err(e) => eprintln!("reconnecting to listen for stream changes failed: {}", e),
+                        }
+                    };
+                    // Falls through to reload, as anything that changed while disconnected was missed
+                }
+                if changed.unbounded_send(()).is_err() {
+                    return Ok(());
+                }
+            }
+        }.boxed()
+    }
+}
+
+async fn listen(url: &str) -> Result<PgListener, sqlx::Error> {
+    let mut listener = PgListener::new(url).await?;
+    listener.listen(CHANNEL).await?;
+    Ok(listener)
+}
@@ -0,0 +1,149 @@
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::{BoxFuture, FutureExt};
+use futures::lock::Mutex;
+use smol::Timer;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Connect, Row, SqliteConnection, query};
+use std::time::Duration;
+
+use super::{ActiveStream, ChannelStream, ScheduleEntry, StreamRow, StreamStore};
+use crate::migrate::{self, Migrated};
+use crate::pidfd::ExitStatus;
+use crate::sessions::Summary;
+
+/// How often to check whether another process has committed to the database
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A sqlite database, local to this ingest node. Reloaded when another process commits to it.
+pub struct SqliteStore {
+    db: Mutex<SqliteConnection>,
+}
+
+impl SqliteStore {
+    pub async fn connect(url: &str) -> Result<SqliteStore, sqlx::Error> {
+        Ok(SqliteStore::from_connection(SqliteConnection::connect(url).await?))
+    }
+
+    pub fn from_connection(db: SqliteConnection) -> SqliteStore {
+        SqliteStore {
+            db: Mutex::new(db),
+        }
+    }
+
+    /// The connection, for tests to check what was written
+    #[cfg(test)]
+    pub async fn connection(&self) -> futures::lock::MutexGuard<'_, SqliteConnection> {
+        self.db.lock().await
+    }
+}
+
+impl StreamStore for SqliteStore {
+    fn migrate(&self) -> BoxFuture<'_, Result<Migrated, migrate::Error>> {
+        async move {
+            migrate::migrate_sqlite(&mut *self.db.lock().await).await
+        }.boxed()
+    }
+
+    fn active_streams(&self) -> BoxFuture<'_, Result<Vec<ActiveStream>, sqlx::Error>> {
+        async move {
+            let rows = query!("SELECT id, srt_options from streams WHERE active = TRUE").fetch_all(&mut *self.db.lock().await).await?;
+            Ok(rows.into_iter().map(|row| ActiveStream {
+                id: row.id as u32,
+                srt_options: row.srt_options,
+            }).collect())
+        }.boxed()
+    }
+
+    fn lookup_stream(&self, id: u32) -> BoxFuture<'_, Result<StreamRow, sqlx::Error>> {
+        async move {
+            let row = query!("SELECT notify_url, token, audio_tracks FROM streams where id = ?", id as i32).fetch_one(&mut *self.db.lock().await).await?;
+            Ok(StreamRow {
+                notify_url: row.notify_url,
+                token: row.token,
+                audio_tracks: row.audio_tracks,
+            })
+        }.boxed()
+    }
+
+    fn channels(&self) -> BoxFuture<'_, Result<Vec<ChannelStream>, sqlx::Error>> {
+        async move {
+            let rows = query!("SELECT id, notify_url, token FROM streams WHERE active = TRUE AND kind = 'channel'").fetch_all(&mut *self.db.lock().await).await?;
+            Ok(rows.into_iter().map(|row| ChannelStream {
+                id: row.id as u32,
+                notify_url: row.notify_url,
+                token: row.token,
+            }).collect())
+        }.boxed()
+    }
+
+    fn schedule(&self, stream_id: u32, now: u64) -> BoxFuture<'_, Result<Vec<ScheduleEntry>, sqlx::Error>> {
+        async move {
+            let rows = query!(
+                "SELECT starts_at, path FROM schedule WHERE stream_id = ? AND starts_at >= (SELECT COALESCE(MAX(starts_at), 0) FROM schedule WHERE stream_id = ? AND starts_at <= ?) ORDER BY starts_at",
+                stream_id as i32,
+                stream_id as i32,
+                now as i64,
+            ).fetch_all(&mut *self.db.lock().await).await?;
+            Ok(rows.into_iter().map(|row| ScheduleEntry {
+                starts_at: row.starts_at as u64,
+                path: row.path,
+            }).collect())
+        }.boxed()
+    }
+
+    fn record_session<'a>(&'a self, summary: &'a Summary) -> BoxFuture<'a, Result<(), sqlx::Error>> {
+        async move {
+            let (exit_code, exit_signal) = match summary.exit_status {
+                Some(ExitStatus::Exited { code }) => (Some(code), None),
+                Some(ExitStatus::Signalled { signal, .. }) => (None, Some(signal)),
+                None => (None, None),
+            };
+            query!(
+                "INSERT INTO sessions (uuid, stream_id, listener, peer_addr, started_at, ended_at, bytes_received, packets_received, packets_lost, packets_retransmitted, packets_dropped, exit_code, exit_signal, end_reason) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                summary.session.uuid.to_hyphenated_ref().to_string(),
+                summary.session.stream_id as i64,
+                summary.session.listener,
+                summary.session.peer_addr.map(|peer_addr| peer_addr.to_string()),
+                summary.session.started_at as i64,
+                summary.ended_at as i64,
+                summary.bytes_received as i64,
+                summary.packets_received as i64,
+                summary.packets_lost as i64,
+                summary.packets_retransmitted as i64,
+                summary.packets_dropped as i64,
+                exit_code,
+                exit_signal,
+                summary.end_reason.as_str(),
+            ).execute(&mut *self.db.lock().await).await?;
+            Ok(())
+        }.boxed()
+    }
+
+    fn watch(&self, changed: UnboundedSender<()>) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        async move {
+            // data_version changes whenever another connection commits, whichever tables it touched, and never for
+            // this connection's own writes
+            let mut last_version = data_version(&mut *self.db.lock().await).await?;
+            loop {
+                Timer::new(POLL_INTERVAL).await;
+                let version = match data_version(&mut *self.db.lock().await).await {
+                    Ok(version) => version,
+                    Err(e) => {
+                        eprintln!("checking the database for changes failed, trying again: {}", e);
+                        continue;
+                    },
+                };
+                if version != last_version {
+                    last_version = version;
+                    if changed.unbounded_send(()).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }.boxed()
+    }
+}
+
+async fn data_version(db: &mut SqliteConnection) -> Result<i64, sqlx::Error> {
+    query("PRAGMA data_version").map(|row: SqliteRow| row.get::<i64, _>(0)).fetch_one(db).await
+}
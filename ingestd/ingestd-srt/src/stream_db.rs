@@ -1,30 +1,200 @@
 use arc_swap::ArcSwap;
-use futures::executor::block_on_stream;
+use futures::channel::mpsc;
+use futures::prelude::*;
 use roaring::RoaringBitmap;
 use signal_hook::iterator::Signals;
 use signal_hook::SIGUSR1;
+use smol::Task;
+use std::collections::HashMap;
 use std::sync::Arc;
-use sqlx::{SqliteConnection, query};
 
-pub fn listen_signal(valid_stream_ids: Arc<ArcSwap<RoaringBitmap>>, mut db: &mut SqliteConnection) {
-    let signals = Signals::new(&[SIGUSR1]).unwrap();
-    for _ in signals.forever() {
-        valid_stream_ids.swap(Arc::new(generate_bitmap(&mut db)));
-    }
+use crate::srt_options::SrtOptions;
+use crate::store::StreamStore;
+
+/// The streams that may currently connect, and their SRT options where they override the listener's ones
+pub struct Streams {
+    pub ids: RoaringBitmap,
+    pub srt_options: HashMap<u32, SrtOptions>,
 }
 
-pub fn generate_bitmap(db: &mut SqliteConnection) -> RoaringBitmap {
-    let stream_ids = query!("SELECT id from streams WHERE active = TRUE").fetch(db);
-    let mut bitmap = RoaringBitmap::new();
-    for res in block_on_stream(stream_ids) {
-        match res {
-            Ok(id) => {
-                bitmap.insert(id.id as u32);
-            },
+/// Reloads the streams whenever the store says they have changed, or on SIGUSR1
+pub async fn keep_loaded(store: &'static dyn StreamStore, streams: Arc<ArcSwap<Streams>>, listener_options: Vec<SrtOptions>) {
+    let (changed_sender, mut changed) = mpsc::unbounded();
+    let signalled = changed_sender.clone();
+    std::thread::Builder::new().name("sigusr1".to_string()).spawn(move || {
+        let signals = Signals::new(&[SIGUSR1]).unwrap();
+        for _ in signals.forever() {
+            if signalled.unbounded_send(()).is_err() {
+                break;
+            }
+        }
+    }).unwrap();
+    Task::spawn(async move {
+        if let Err(e) = store.watch(changed_sender).await {
+            eprintln!("watching for stream changes failed, only reloading on SIGUSR1: {}", e);
+        }
+    }).detach();
+    while let Some(()) = changed.next().await {
+        let loaded = match load_streams(store, &listener_options).await {
+            Ok(loaded) => loaded,
             Err(e) => {
-                panic!(e);
+                eprintln!("reloading streams failed: {}", e);
+                continue;
+            },
+        };
+        log_changes(&streams.load().ids, &loaded.ids);
+        streams.store(Arc::new(loaded));
+    }
+}
+
+fn log_changes(previous: &RoaringBitmap, current: &RoaringBitmap) {
+    let mut added = current.clone();
+    added.difference_with(previous);
+    let mut removed = previous.clone();
+    removed.difference_with(current);
+    if !added.is_empty() {
+        eprintln!("streams added: {:?}", added.iter().collect::<Vec<_>>());
+    }
+    if !removed.is_empty() {
+        eprintln!("streams removed: {:?}", removed.iter().collect::<Vec<_>>());
+    }
+}
+
+/// Loads the active streams, leaving out any whose SRT options are invalid on top of those of any listener
+pub async fn load_streams(store: &dyn StreamStore, listener_options: &[SrtOptions]) -> Result<Streams, sqlx::Error> {
+    let mut streams = Streams {
+        ids: RoaringBitmap::new(),
+        srt_options: HashMap::new(),
+    };
+    for stream in store.active_streams().await? {
+        if let Some(srt_options) = stream.srt_options.filter(|srt_options| !srt_options.trim().is_empty()) {
+            let overrides = match toml::from_str::<SrtOptions>(&srt_options) {
+                Ok(overrides) => overrides,
+                Err(e) => {
+                    eprintln!("stream {} has unparseable srt_options, not allowing it to connect: {}", stream.id, e);
+                    continue;
+                },
+            };
+            if let Some(e) = listener_options.iter().filter_map(|options| options.merge(&overrides).validate().err()).next() {
+                eprintln!("stream {} has invalid srt_options, not allowing it to connect: {}", stream.id, e);
+                continue;
             }
+            streams.srt_options.insert(stream.id, overrides);
+        }
+        streams.ids.insert(stream.id);
+    }
+    Ok(streams)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use sqlx::sqlite::SqliteRow;
+    use sqlx::{Connect, Executor, Row, SqliteConnection};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::gpac::EndReason;
+    use crate::pidfd::ExitStatus;
+    use crate::sessions::{Session, Summary};
+    use crate::store::SqliteStore;
+
+    fn migrated_db() -> SqliteStore {
+        migrated_db_with("")
+    }
+
+    /// The test streams, and whatever else `sql` adds
+    fn migrated_db_with(sql: &str) -> SqliteStore {
+        // sqlx strips every colon after the scheme, so "sqlite::memory:" would be a file named "memory:"
+        let mut db = block_on(SqliteConnection::connect("sqlite:%3Amemory:")).unwrap();
+        block_on(crate::migrate::migrate_sqlite(&mut db)).unwrap();
+        block_on(db.execute("
+            INSERT INTO streams (id, active, notify_url, token, srt_options) VALUES
+                (1, TRUE, 'https://example.com/notify', 'token1', NULL),
+                (2, FALSE, 'https://example.com/notify', 'token2', NULL),
+                (3, TRUE, 'https://example.com/notify', 'token3', 'latency = 2000'),
+                (4, TRUE, 'https://example.com/notify', 'token4', 'pbkeylen = 7');
+        ")).unwrap();
+        if !sql.is_empty() {
+            block_on(db.execute(sql)).unwrap();
         }
+        SqliteStore::from_connection(db)
+    }
+
+    #[test]
+    fn loads_active_streams_with_valid_options() {
+        let db = migrated_db();
+        let streams = block_on(load_streams(&db, &[SrtOptions::default()])).unwrap();
+        assert_eq!(streams.ids.iter().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(streams.srt_options[&3].latency, Some(2000));
+        assert!(!streams.srt_options.contains_key(&1));
+    }
+
+    #[test]
+    fn looks_up_streams() {
+        let db = migrated_db();
+        let row = block_on(db.lookup_stream(3)).unwrap();
+        assert_eq!(row.token, "token3");
+        assert_eq!(row.notify_url, "https://example.com/notify");
+        assert_eq!(row.audio_tracks, None);
+        assert!(block_on(db.lookup_stream(5)).is_err());
+    }
+
+    #[test]
+    fn loads_channels_and_their_schedules() {
+        let db = migrated_db_with("
+            UPDATE streams SET kind = 'channel' WHERE id IN (2, 3);
+            INSERT INTO schedule (stream_id, starts_at, path) VALUES
+                (3, 1000, '/srv/old.ts'),
+                (3, 2000, '/srv/current.ts'),
+                (3, 3000, '/srv/next.ts'),
+                (1, 1500, '/srv/other.ts');
+        ");
+        let channels = block_on(db.channels()).unwrap();
+        assert_eq!(channels.iter().map(|channel| channel.id).collect::<Vec<_>>(), vec![3]);
+        let schedule = block_on(db.schedule(3, 2500)).unwrap();
+        assert_eq!(schedule.iter().map(|entry| (entry.starts_at, entry.path.as_str())).collect::<Vec<_>>(), vec![(2000, "/srv/current.ts"), (3000, "/srv/next.ts")]);
+        assert_eq!(block_on(db.schedule(3, 500)).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn records_sessions() {
+        let db = migrated_db();
+        let summary = Summary {
+            session: Session {
+                uuid: Uuid::new_v4(),
+                stream_id: 1,
+                listener: "default".to_string(),
+                peer_addr: Some("[2001:db8::1]:5000".parse().unwrap()),
+                started_at: 1600000000,
+            },
+            ended_at: 1600003600,
+            bytes_received: 3600000000,
+            packets_received: 2735562,
+            packets_lost: 12,
+            packets_retransmitted: 10,
+            packets_dropped: 2,
+            exit_status: Some(ExitStatus::Signalled { signal: libc::SIGKILL, core_dumped: false }),
+            end_reason: EndReason::StreamerDisconnected,
+        };
+        block_on(db.record_session(&summary)).unwrap();
+
+        let mut connection = block_on(db.connection());
+        let row = block_on(sqlx::query!("SELECT uuid, stream_id, listener, peer_addr, started_at, ended_at, packets_received, packets_lost, packets_retransmitted, packets_dropped, exit_code, exit_signal, end_reason FROM sessions").fetch_one(&mut *connection)).unwrap();
+        assert_eq!(row.uuid, summary.session.uuid.to_hyphenated_ref().to_string());
+        assert_eq!(row.stream_id as i64, 1);
+        assert_eq!(row.listener, "default");
+        assert_eq!(row.peer_addr.as_deref(), Some("[2001:db8::1]:5000"));
+        assert_eq!((row.started_at as i64, row.ended_at as i64), (1600000000, 1600003600));
+        // query! reads INTEGER columns as i32, which this is too big for
+        let bytes_received = block_on(sqlx::query("SELECT bytes_received FROM sessions").map(|row: SqliteRow| row.get::<i64, _>(0)).fetch_one(&mut *connection)).unwrap();
+        assert_eq!(bytes_received, 3600000000);
+        assert_eq!(
+            (row.packets_received as i64, row.packets_lost as i64, row.packets_retransmitted as i64, row.packets_dropped as i64),
+            (2735562, 12, 10, 2),
+        );
+        assert_eq!(row.exit_code, None);
+        assert_eq!(row.exit_signal.map(|signal| signal as i64), Some(libc::SIGKILL as i64));
+        assert_eq!(row.end_reason, "streamer_disconnected");
     }
-    bitmap
 }
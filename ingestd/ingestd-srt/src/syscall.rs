@@ -10,7 +10,8 @@ pub struct CloneArgs {
     pub stack_size: u64,
     pub tls: u64,
     pub set_tid: u64,
-    pub set_tid_size: u64
+    pub set_tid_size: u64,
+    pub cgroup: u64,
 }
 
 pub unsafe fn clone3(clone_args: *const CloneArgs) -> libc::c_long {
@@ -0,0 +1,769 @@
+use futures::channel::mpsc;
+use futures::io::AsyncRead;
+use futures::ready;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::captions::{CaptionTrack, CaptionUpload};
+use crate::discontinuity;
+use crate::mp4;
+use crate::scte35::{self, Cue};
+use crate::ts::TIMESTAMP_MODULUS;
+
+/// Events kept for representations that haven't had a fragment since they were posted, beyond which the oldest are
+/// dropped
+const MAX_PENDING_EVENTS: usize = 256;
+
+/// Cues further than this ahead of a fragment, in 90kHz units, are held back for a later one. Cues and captions further
+/// behind are taken not to be on the same timeline, and are put at the start of the fragment instead.
+const MAX_CUE_DISTANCE: i64 = 60 * 90000;
+
+/// Boxes larger than this are passed straight through rather than buffered, as mdat always is
+const MAX_BUFFERED_BOX: u64 = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("no packager is uploading a stream by that name")]
+    UnknownPackager,
+    #[error("scheme_id_uri must be given, and neither it nor value may contain NUL")]
+    InvalidEvent,
+}
+
+/// An event as posted to the admin API
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PostedEvent {
+    pub scheme_id_uri: String,
+    #[serde(default)]
+    pub value: String,
+    /// In seconds, if known
+    pub duration: Option<f64>,
+    /// Carried as the event's message data, serialized as JSON
+    pub data: serde_json::Value,
+}
+
+struct EventMessage {
+    id: u32,
+    scheme_id_uri: String,
+    value: String,
+    duration: Option<f64>,
+    message_data: Vec<u8>,
+    /// The transport stream timestamp it happens at, for events from the stream itself. Others happen at the start of
+    /// the fragment they go in front of.
+    pts: Option<u64>,
+}
+
+/// Where a 90kHz transport stream timestamp falls on a representation's timeline, going by a fragment's decode time.
+/// gpac carries the transport stream's timestamps over, so they differ only by the timescale and wrapping around.
+pub fn presentation_time(pts: u64, timescale: u32, decode_time: u64) -> u64 {
+    let difference = distance(pts, timescale, decode_time);
+    if difference.abs() > MAX_CUE_DISTANCE {
+        return decode_time;
+    }
+    (decode_time as i64 + difference * timescale as i64 / 90000).max(0) as u64
+}
+
+/// How far a 90kHz timestamp is ahead of a fragment's decode time, in 90kHz units
+fn distance(pts: u64, timescale: u32, decode_time: u64) -> i64 {
+    let decode_time_90khz = (decode_time as u128 * 90000 / timescale as u128) as u64 % TIMESTAMP_MODULUS;
+    discontinuity::difference(pts, decode_time_90khz)
+}
+
+impl EventMessage {
+    /// How far it happens after a fragment starting at `decode_time`, if it is from the stream
+    fn distance(&self, timescale: u32, decode_time: u64) -> Option<i64> {
+        self.pts.map(|pts| distance(pts, timescale, decode_time))
+    }
+
+    /// A version 1 emsg box, which carries the event's presentation time rather than a delta from the segment's, to go
+    /// in front of a fragment starting at `decode_time`
+    fn emsg(&self, timescale: u32, decode_time: u64) -> Vec<u8> {
+        let presentation_time = match self.pts {
+            Some(pts) => presentation_time(pts, timescale, decode_time),
+            None => decode_time,
+        };
+        let duration = match self.duration {
+            Some(duration) => (duration * timescale as f64).min((u32::MAX - 1) as f64) as u32,
+            None => u32::MAX,
+        };
+        let mut body = vec![1, 0, 0, 0];
+        body.extend_from_slice(&timescale.to_be_bytes());
+        body.extend_from_slice(&presentation_time.to_be_bytes());
+        body.extend_from_slice(&duration.to_be_bytes());
+        body.extend_from_slice(&self.id.to_be_bytes());
+        body.extend_from_slice(self.scheme_id_uri.as_bytes());
+        body.push(0);
+        body.extend_from_slice(self.value.as_bytes());
+        body.push(0);
+        body.extend_from_slice(&self.message_data);
+        mp4::write_box(b"emsg", &body)
+    }
+}
+
+struct Representation {
+    timescale: u32,
+    /// The id of the first event it hasn't been given yet
+    next: u32,
+    /// Events before `next` held back for a later fragment, as they are too far ahead, in order
+    held: Vec<u32>,
+    /// The language of its track, if it is audio and has one
+    language: Option<String>,
+}
+
+#[derive(Default)]
+struct Packager {
+    next_id: u32,
+    pending: VecDeque<EventMessage>,
+    /// Every scheme_id_uri and value posted, for the MPD to declare
+    schemes: BTreeSet<(String, String)>,
+    representations: HashMap<String, Representation>,
+    /// Follows the first video representation, if captions are enabled
+    captions: Option<CaptionTrack>,
+}
+
+impl Packager {
+    /// Drops events every representation has been given
+    fn prune(&mut self) {
+        let given = self.representations.values()
+            .map(|representation| representation.held.first().map_or(representation.next, |&held| held.min(representation.next)))
+            .min()
+            .unwrap_or(0);
+        while self.pending.front().map(|event| event.id < given).unwrap_or(false) || self.pending.len() > MAX_PENDING_EVENTS {
+            self.pending.pop_front();
+        }
+    }
+}
+
+/// Timed events posted for live sessions, waiting to be inserted into their next fragments as emsg boxes. Packagers
+/// are known by the uuid their MPD is named after, from when they upload their first init segment.
+pub struct TimedMetadata {
+    packagers: Mutex<HashMap<Uuid, Packager>>,
+    /// Whether events are taken at all. Without them, uploads are still looked at for their audio languages.
+    events: bool,
+    /// Where the files of caption tracks are sent to be uploaded, if captions are enabled
+    caption_uploads: Option<mpsc::UnboundedSender<CaptionUpload>>,
+}
+
+impl TimedMetadata {
+    pub fn new(events: bool, caption_uploads: Option<mpsc::UnboundedSender<CaptionUpload>>) -> TimedMetadata {
+        TimedMetadata {
+            packagers: Mutex::new(HashMap::new()),
+            events,
+            caption_uploads,
+        }
+    }
+
+    /// Queues an event for the next fragment of each of the packager's representations, returning its id
+    pub fn push(&self, packager: &Uuid, event: PostedEvent) -> Result<u32, Error> {
+        if event.scheme_id_uri.is_empty() || event.scheme_id_uri.contains('\0') || event.value.contains('\0') {
+            return Err(Error::InvalidEvent);
+        }
+        self.queue(packager, event.scheme_id_uri, event.value, event.duration, event.data.to_string().into_bytes(), None)
+    }
+
+    /// Queues an SCTE-35 cue from the stream, to happen at `pts` on the packager's timeline if it says when
+    pub fn push_cue(&self, packager: &Uuid, cue: &Cue, pts: Option<u64>) -> Result<u32, Error> {
+        self.queue(packager, scte35::SCHEME_ID_URI.to_string(), String::new(), cue.duration(), cue.section.clone(), pts)
+    }
+
+    fn queue(&self, packager: &Uuid, scheme_id_uri: String, value: String, duration: Option<f64>, message_data: Vec<u8>, pts: Option<u64>) -> Result<u32, Error> {
+        let mut packagers = self.packagers.lock().unwrap();
+        let packager = packagers.get_mut(packager).ok_or(Error::UnknownPackager)?;
+        let id = packager.next_id;
+        packager.next_id += 1;
+        packager.schemes.insert((scheme_id_uri.clone(), value.clone()));
+        packager.pending.push_back(EventMessage {
+            id,
+            scheme_id_uri,
+            value,
+            duration,
+            message_data,
+            pts,
+        });
+        packager.prune();
+        Ok(id)
+    }
+
+    /// Queues a change to the captions on screen, from `pts` on the packager's timeline
+    pub fn push_caption(&self, packager: &Uuid, pts: u64, text: String) {
+        let mut packagers = self.packagers.lock().unwrap();
+        if let Some(captions) = packagers.get_mut(packager).and_then(|packager| packager.captions.as_mut()) {
+            captions.push(pts, text);
+        }
+    }
+
+    /// Whether posted events and SCTE-35 cues are passed on
+    pub fn events(&self) -> bool {
+        self.events
+    }
+
+    /// Whether captions are turned into a WebVTT track
+    pub fn captions(&self) -> bool {
+        self.caption_uploads.is_some()
+    }
+
+    pub fn remove(&self, packager: &Uuid) {
+        self.packagers.lock().unwrap().remove(packager);
+    }
+
+    fn init_segment(&self, packager: Uuid, representation: &str, timescale: u32, video: bool, language: Option<String>, path: &str) {
+        let mut packagers = self.packagers.lock().unwrap();
+        let packager = packagers.entry(packager).or_default();
+        let entry = packager.representations.entry(representation.to_string())
+            .or_insert(Representation { timescale, next: 0, held: Vec::new(), language: None });
+        entry.timescale = timescale;
+        entry.language = language;
+
+        let caption_uploads = match self.caption_uploads {
+            Some(ref caption_uploads) if video => caption_uploads,
+            _ => return,
+        };
+        // A new Period's representations replace the last one's
+        if packager.captions.as_ref().map(|captions| captions.video == representation).unwrap_or(false) {
+            return;
+        }
+        if let Some((captions, upload)) = CaptionTrack::new(representation.to_string(), timescale, path) {
+            packager.captions = Some(captions);
+            let _ = caption_uploads.unbounded_send(upload);
+        }
+    }
+
+    /// emsg boxes for the events a representation hasn't been given yet, to go before a fragment starting at
+    /// `decode_time`. The fragment is the first of the segment numbered `segment` if that is given.
+    fn fragment(&self, packager: &Uuid, representation: &str, decode_time: u64, segment: Option<&str>) -> Vec<u8> {
+        let name = format!("{}_{}", packager, representation);
+        let mut packagers = self.packagers.lock().unwrap();
+        let packager = match packagers.get_mut(packager) {
+            Some(packager) => packager,
+            None => return Vec::new(),
+        };
+        if let (Some(captions), Some(caption_uploads)) = (packager.captions.as_mut(), self.caption_uploads.as_ref()) {
+            if captions.video == representation {
+                if let Some(upload) = captions.fragment(decode_time, segment) {
+                    let _ = caption_uploads.unbounded_send(upload);
+                }
+            }
+        }
+        let representation = match packager.representations.get_mut(representation) {
+            Some(representation) => representation,
+            None => return Vec::new(),
+        };
+        let next = representation.next;
+        let held = std::mem::take(&mut representation.held);
+        let mut boxes = Vec::new();
+        for event in packager.pending.iter().filter(|event| event.id >= next || held.contains(&event.id)) {
+            match event.distance(representation.timescale, decode_time) {
+                Some(distance) if distance > MAX_CUE_DISTANCE => {
+                    if event.id >= next {
+                        eprintln!("cue {} is {}s ahead of the next fragment of {}, holding it until a fragment closer to it", event.id, distance / 90000, name);
+                    }
+                    representation.held.push(event.id);
+                    continue;
+                },
+                Some(distance) if distance < -MAX_CUE_DISTANCE => {
+                    eprintln!("cue {} is {}s behind the next fragment of {}, putting it at the fragment's start", event.id, -distance / 90000, name);
+                },
+                _ => {},
+            }
+            boxes.extend(event.emsg(representation.timescale, decode_time));
+        }
+        representation.next = packager.next_id;
+        packager.prune();
+        boxes
+    }
+
+    /// Declares the packager's event schemes in each AdaptationSet of its MPD, so that players look for them
+    pub fn declare_schemes(&self, packager: &Uuid, mpd: String) -> String {
+        let packagers = self.packagers.lock().unwrap();
+        match packagers.get(packager) {
+            Some(packager) if !packager.schemes.is_empty() => declare_schemes(&mpd, &packager.schemes),
+            _ => mpd,
+        }
+    }
+
+    /// Gives each AdaptationSet of the packager's MPD that gpac left without a lang the language of its audio, from
+    /// its representations' init segments, so that players can offer a choice of them
+    pub fn add_languages(&self, packager: &Uuid, mpd: String) -> String {
+        let packagers = self.packagers.lock().unwrap();
+        match packagers.get(packager) {
+            Some(packager) if packager.representations.values().any(|representation| representation.language.is_some()) => {
+                add_languages(&mpd, |name| packager.representations.get(name).and_then(|representation| representation.language.as_deref()))
+            },
+            _ => mpd,
+        }
+    }
+
+    /// Adds the packager's caption track to the last Period of its MPD, which is the one it is writing
+    pub fn add_captions(&self, packager: &Uuid, mpd: String) -> String {
+        let packagers = self.packagers.lock().unwrap();
+        let captions = match packagers.get(packager).and_then(|packager| packager.captions.as_ref()) {
+            Some(captions) => captions,
+            None => return mpd,
+        };
+        let period = match (mpd.rfind("<Period"), mpd.rfind("</Period>")) {
+            (Some(start), Some(end)) if start < end => start..end,
+            _ => return mpd,
+        };
+        match captions.adaptation_set(&mpd[period.clone()]) {
+            Some(adaptation_set) => [&mpd[..period.end], &adaptation_set, &mpd[period.end..]].concat(),
+            None => mpd,
+        }
+    }
+}
+
+/// Elements that come after InbandEventStream in an AdaptationSet
+const AFTER_INBAND_EVENT_STREAM: &[&str] = &["<Accessibility", "<Role", "<Rating", "<Viewpoint", "<ContentComponent", "<BaseURL", "<SegmentBase", "<SegmentList", "<SegmentTemplate", "<Representation", "</AdaptationSet"];
+
+fn declare_schemes(mpd: &str, schemes: &BTreeSet<(String, String)>) -> String {
+    let declarations = schemes.iter().map(|(scheme_id_uri, value)| {
+        format!("<InbandEventStream schemeIdUri=\"{}\" value=\"{}\"/>", escape(scheme_id_uri), escape(value))
+    }).collect::<String>();
+    let mut out = String::with_capacity(mpd.len() + declarations.len() * 4);
+    let mut rest = mpd;
+    while let Some(start) = rest.find("<AdaptationSet") {
+        let insert_at = AFTER_INBAND_EVENT_STREAM.iter().filter_map(|element| rest[start..].find(element)).min();
+        let insert_at = match insert_at {
+            Some(insert_at) => start + insert_at,
+            None => break,
+        };
+        out.push_str(&rest[..insert_at]);
+        out.push_str(&declarations);
+        // Past the element found, so that the next AdaptationSet is looked for after it
+        let skip = rest[insert_at + 1..].find('<').map(|next| insert_at + 1 + next).unwrap_or(rest.len());
+        out.push_str(&rest[insert_at..skip]);
+        rest = &rest[skip..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn add_languages<'a>(mpd: &str, language: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut out = String::with_capacity(mpd.len());
+    let mut rest = mpd;
+    while let Some(start) = rest.find("<AdaptationSet") {
+        let head_end = match rest[start..].find('>') {
+            Some(head_end) => start + head_end,
+            None => break,
+        };
+        // Without children if it closes itself
+        let (head_end, end) = if rest[..head_end].ends_with('/') {
+            (head_end - 1, head_end - 1)
+        } else {
+            (head_end, rest[start..].find("</AdaptationSet>").map(|end| start + end).unwrap_or(head_end))
+        };
+        out.push_str(&rest[..head_end]);
+        if !rest[start..head_end].contains(" lang=\"") {
+            if let Some(found) = adaptation_set_language(&rest[start..end], &language) {
+                out.push_str(&format!(" lang=\"{}\"", escape(found)));
+            }
+        }
+        rest = &rest[head_end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The language of the first of an AdaptationSet's representations to have one. Their uploads are named by putting
+/// their ids in the SegmentTemplate's initialization.
+fn adaptation_set_language<'a>(adaptation_set: &str, language: impl Fn(&str) -> Option<&'a str>) -> Option<&'a str> {
+    let initialization = attribute(&adaptation_set[adaptation_set.find("<SegmentTemplate")?..], "initialization")?;
+    adaptation_set.match_indices("<Representation ")
+        .filter_map(|(at, _)| attribute(&adaptation_set[at..], "id"))
+        .filter_map(|id| segment_name(&initialization.replace("$RepresentationID$", id)).and_then(|(_, name)| language(name)))
+        .next()
+}
+
+/// The value of an attribute of the element `element` starts with
+fn attribute<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    let head = &element[..element.find('>')?];
+    let start = head.find(&format!(" {}=\"", name))? + name.len() + 3;
+    Some(&head[start..start + head[start..].find('"')?])
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// The extensions gpac gives fMP4 files: audio-only streams' segments are `.m4s`, and their init segments `.m4a`
+const FMP4_EXTENSIONS: [&str; 3] = [".mp4", ".m4s", ".m4a"];
+
+fn strip_fmp4_extension(path: &str) -> Option<&str> {
+    FMP4_EXTENSIONS.iter().find_map(|extension| path.strip_suffix(extension))
+}
+
+/// Which packager and representation an uploaded file belongs to, from names like `{uuid}_{representation}_init.mp4`
+/// and `{uuid}_p1_{representation}_00001.mp4`. Representations of different Periods are kept apart.
+pub fn segment_name(path: &str) -> Option<(Uuid, &str)> {
+    let file_name = strip_fmp4_extension(path.rsplit('/').next()?)?;
+    let packager = file_name.get(..36)?.parse().ok()?;
+    let rest = file_name[36..].strip_prefix('_')?;
+    Some((packager, &rest[..rest.rfind('_')?]))
+}
+
+/// Which packager an uploaded MPD belongs to
+pub fn mpd_name(path: &str) -> Option<Uuid> {
+    path.rsplit('/').next()?.strip_suffix(".mpd")?.parse().ok()
+}
+
+/// Passes an uploaded fMP4 file through, picking the timescale out of init segments and putting pending events in
+/// front of each moof at its decode time. Boxes are passed on as soon as they have been read, and mdat as it arrives,
+/// so that chunks still reach httpd as soon as gpac writes them.
+pub struct EmsgInserter<R> {
+    inner: R,
+    timed_metadata: &'static TimedMetadata,
+    packager: Uuid,
+    representation: String,
+    path: String,
+    /// The segment number from the file name, until its first moof has been seen
+    segment: Option<String>,
+    /// The box being read
+    buffer: Vec<u8>,
+    /// What is ready to be read, and how much of it has been
+    out: Vec<u8>,
+    out_pos: usize,
+    /// Bytes to pass straight through before the next box, or all the rest
+    passthrough: Option<u64>,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> EmsgInserter<R> {
+    pub fn new(inner: R, timed_metadata: &'static TimedMetadata, packager: Uuid, representation: String, path: String) -> EmsgInserter<R> {
+        let segment = strip_fmp4_extension(&path).and_then(|name| name.rsplit('_').next()).filter(|number| *number != "init").map(str::to_string);
+        EmsgInserter {
+            inner,
+            timed_metadata,
+            packager,
+            representation,
+            path,
+            segment,
+            buffer: Vec::new(),
+            out: Vec::new(),
+            out_pos: 0,
+            passthrough: Some(0),
+            eof: false,
+        }
+    }
+
+    /// How many bytes of the box being read are needed before anything can be done with it
+    fn needed(&self) -> usize {
+        match mp4::header(&self.buffer) {
+            Some((_, _, size)) if size <= MAX_BUFFERED_BOX => size as usize,
+            _ if self.buffer.len() < 8 => 8,
+            _ => 16,
+        }
+    }
+
+    /// Decides what to do with the box being read, once enough of it has been
+    fn process(&mut self) {
+        let (box_type, header_len, size) = match mp4::header(&self.buffer) {
+            Some(header) => header,
+            None => return,
+        };
+        if box_type == *b"mdat" || size > MAX_BUFFERED_BOX || size < header_len as u64 {
+            // Malformed sizes mean the rest can't be followed, and 0 that the box runs to the end
+            self.passthrough = if size < header_len as u64 { None } else { Some(size - self.buffer.len() as u64) };
+            self.out = std::mem::take(&mut self.buffer);
+            return;
+        }
+        if self.buffer.len() < size as usize {
+            return;
+        }
+        let body = &self.buffer[header_len..];
+        match &box_type {
+            b"moov" => {
+                if let Some(timescale) = mp4::timescale(body) {
+                    let handler_type = mp4::handler_type(body);
+                    let language = if handler_type == Some(*b"soun") { mp4::language(body) } else { None };
+                    self.timed_metadata.init_segment(self.packager, &self.representation, timescale, handler_type == Some(*b"vide"), language, &self.path);
+                }
+            },
+            b"moof" => {
+                if let Some(decode_time) = mp4::base_media_decode_time(body) {
+                    let segment = self.segment.take();
+                    self.out = self.timed_metadata.fragment(&self.packager, &self.representation, decode_time, segment.as_deref());
+                }
+            },
+            _ => {},
+        }
+        self.out.append(&mut self.buffer);
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for EmsgInserter<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if this.out_pos < this.out.len() {
+                let n = buf.len().min(this.out.len() - this.out_pos);
+                buf[..n].copy_from_slice(&this.out[this.out_pos..this.out_pos + n]);
+                this.out_pos += n;
+                if this.out_pos == this.out.len() {
+                    this.out.clear();
+                    this.out_pos = 0;
+                }
+                return Poll::Ready(Ok(n));
+            }
+            match this.passthrough {
+                Some(0) => {},
+                passthrough => {
+                    let max = passthrough.map(|passthrough| passthrough.min(buf.len() as u64) as usize).unwrap_or(buf.len());
+                    let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..max]))?;
+                    if let Some(ref mut passthrough) = this.passthrough {
+                        *passthrough -= n as u64;
+                    }
+                    return Poll::Ready(Ok(n));
+                },
+            }
+            if this.eof {
+                // Whatever is left of a truncated box
+                if this.buffer.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+                this.out = std::mem::take(&mut this.buffer);
+                continue;
+            }
+
+            let start = this.buffer.len();
+            this.buffer.resize(this.needed(), 0);
+            let read = Pin::new(&mut this.inner).poll_read(cx, &mut this.buffer[start..]);
+            let n = match read {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => {
+                    this.buffer.truncate(start);
+                    return Poll::Ready(Err(e));
+                },
+                Poll::Pending => {
+                    this.buffer.truncate(start);
+                    return Poll::Pending;
+                },
+            };
+            this.buffer.truncate(start + n);
+            if n == 0 {
+                this.eof = true;
+            } else {
+                this.process();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, Cursor};
+
+    use super::*;
+
+    const TIMESCALE: u32 = 90000;
+
+    fn init_segment() -> Vec<u8> {
+        let mut mdhd = vec![0; 8];
+        mdhd.extend_from_slice(&TIMESCALE.to_be_bytes());
+        mdhd.extend_from_slice(&[0; 8]);
+        track(mp4::write_box(b"mdia", &mp4::full_box(b"mdhd", 0, 0, &mdhd)))
+    }
+
+    /// An init segment for an audio track in `language`, with the 64-bit times of a version 1 mdhd
+    pub(crate) fn audio_init_segment(language: &[u8; 3]) -> Vec<u8> {
+        let packed = language.iter().fold(0, |packed, letter| packed << 5 | (letter - 0x60) as u16);
+        let mut mdhd = vec![0; 16];
+        mdhd.extend_from_slice(&48000u32.to_be_bytes());
+        mdhd.extend_from_slice(&[0; 8]);
+        mdhd.extend_from_slice(&packed.to_be_bytes());
+        mdhd.extend_from_slice(&[0; 2]);
+        let hdlr = mp4::full_box(b"hdlr", 0, 0, &[0, 0, 0, 0, b's', b'o', b'u', b'n', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        track(mp4::write_box(b"mdia", &[mp4::full_box(b"mdhd", 1, 0, &mdhd), hdlr].concat()))
+    }
+
+    fn track(mdia: Vec<u8>) -> Vec<u8> {
+        let trak = mp4::write_box(b"trak", &[mp4::full_box(b"tkhd", 0, 0, &[0; 80]), mdia].concat());
+        let moov = mp4::write_box(b"moov", &[mp4::full_box(b"mvhd", 0, 0, &[0; 96]), trak].concat());
+        [mp4::write_box(b"ftyp", b"iso6\0\0\0\0"), moov].concat()
+    }
+
+    /// A chunk of a segment, with its mdat's size bigger than what is buffered so that it is passed through
+    fn chunk(decode_time: u64, mdat_len: usize) -> Vec<u8> {
+        let traf = mp4::write_box(b"traf", &[mp4::full_box(b"tfhd", 0, 0, &[0, 0, 0, 1]), mp4::full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes())].concat());
+        let moof = mp4::write_box(b"moof", &[mp4::full_box(b"mfhd", 0, 0, &[0, 0, 0, 1]), traf].concat());
+        let mdat = mp4::write_box(b"mdat", &vec![0xab; mdat_len]);
+        [moof, mdat].concat()
+    }
+
+    fn upload(timed_metadata: &'static TimedMetadata, packager: Uuid, representation: &str, data: Vec<u8>) -> Vec<u8> {
+        let path = format!("/{}_{}_00001.mp4", packager, representation);
+        let mut inserter = EmsgInserter::new(Cursor::new(data), timed_metadata, packager, representation.to_string(), path);
+        let mut out = Vec::new();
+        block_on(inserter.read_to_end(&mut out)).unwrap();
+        out
+    }
+
+    fn event(scheme_id_uri: &str, data: &str) -> PostedEvent {
+        PostedEvent {
+            scheme_id_uri: scheme_id_uri.to_string(),
+            value: "1".to_string(),
+            duration: Some(2.0),
+            data: serde_json::from_str(data).unwrap(),
+        }
+    }
+
+    /// The fields of an emsg box's body, as players read them
+    fn parse_emsg(body: &[u8]) -> (u32, u64, u32, u32, String, String, Vec<u8>) {
+        assert_eq!(body[0], 1);
+        let read_u32 = |at: usize| u32::from_be_bytes([body[at], body[at + 1], body[at + 2], body[at + 3]]);
+        let presentation_time = (read_u32(8) as u64) << 32 | read_u32(12) as u64;
+        let strings = body[24..].splitn(3, |&b| b == 0).collect::<Vec<_>>();
+        (
+            read_u32(4),
+            presentation_time,
+            read_u32(16),
+            read_u32(20),
+            String::from_utf8(strings[0].to_vec()).unwrap(),
+            String::from_utf8(strings[1].to_vec()).unwrap(),
+            strings[2].to_vec(),
+        )
+    }
+
+    #[test]
+    fn inserts_events_before_the_next_fragment() {
+        let timed_metadata: &'static TimedMetadata = Box::leak(Box::new(TimedMetadata::new(true, None)));
+        let packager = Uuid::new_v4();
+        assert!(matches!(timed_metadata.push(&packager, event("urn:example:ad", "{}")), Err(Error::UnknownPackager)));
+
+        let init = init_segment();
+        assert_eq!(upload(timed_metadata, packager, "1", init.clone()), init);
+        let first = chunk(900_000, 4000);
+        assert_eq!(upload(timed_metadata, packager, "1", first.clone()), first);
+
+        assert_eq!(timed_metadata.push(&packager, event("urn:example:ad", r#"{"break":30}"#)).unwrap(), 0);
+        let segment = [chunk(1_080_000, 2_000_000), chunk(1_260_000, 100)].concat();
+        let uploaded = upload(timed_metadata, packager, "1", segment.clone());
+
+        let boxes = mp4::boxes(&uploaded).collect::<Vec<_>>();
+        let types = boxes.iter().map(|(box_type, _)| box_type).collect::<Vec<_>>();
+        assert_eq!(types, [b"emsg", b"moof", b"mdat", b"moof", b"mdat"]);
+        let (timescale, presentation_time, duration, id, scheme_id_uri, value, message_data) = parse_emsg(boxes[0].1);
+        assert_eq!((timescale, presentation_time, duration, id), (TIMESCALE, 1_080_000, 180_000, 0));
+        assert_eq!((scheme_id_uri.as_str(), value.as_str()), ("urn:example:ad", "1"));
+        assert_eq!(message_data, br#"{"break":30}"#);
+        // Everything after the emsg is as gpac wrote it
+        assert_eq!(&uploaded[uploaded.len() - segment.len()..], &segment[..]);
+
+        // Given to each representation once
+        let again = chunk(1_440_000, 100);
+        assert_eq!(upload(timed_metadata, packager, "1", again.clone()), again);
+    }
+
+    #[test]
+    fn places_cues_at_their_splice_time() {
+        let timed_metadata: &'static TimedMetadata = Box::leak(Box::new(TimedMetadata::new(true, None)));
+        let packager = Uuid::new_v4();
+        upload(timed_metadata, packager, "1", init_segment());
+
+        // The example splice_insert from the SCTE-35 specification, splicing 4 seconds after the next fragment starts
+        let section = base64::decode("/DAvAAAAAAAA///wFAVIAACPf+/+c2nALv4AUsz1AAAAAAAKAAhDVUVJAAABNWLbowo=").unwrap();
+        let cue = scte35::parse(&section).unwrap();
+        let splice = cue.pts.unwrap();
+        timed_metadata.push_cue(&packager, &cue, Some(splice)).unwrap();
+        let uploaded = upload(timed_metadata, packager, "1", chunk(TIMESTAMP_MODULUS + splice - 4 * 90000, 100));
+
+        let (_, emsg) = mp4::boxes(&uploaded).next().unwrap();
+        let (timescale, presentation_time, duration, _, scheme_id_uri, value, message_data) = parse_emsg(emsg);
+        // gpac's timeline carries on past where the transport stream's timestamps wrap around
+        assert_eq!((timescale, presentation_time), (TIMESCALE, TIMESTAMP_MODULUS + splice));
+        assert_eq!(duration, 0x52ccf5);
+        assert_eq!((scheme_id_uri.as_str(), value.as_str()), (scte35::SCHEME_ID_URI, ""));
+        assert_eq!(message_data, section);
+    }
+
+    #[test]
+    fn holds_cues_far_ahead_until_a_fragment_closer_to_them() {
+        let timed_metadata: &'static TimedMetadata = Box::leak(Box::new(TimedMetadata::new(true, None)));
+        let packager = Uuid::new_v4();
+        upload(timed_metadata, packager, "1", init_segment());
+        upload(timed_metadata, packager, "2", init_segment());
+
+        let section = base64::decode("/DAvAAAAAAAA///wFAVIAACPf+/+c2nALv4AUsz1AAAAAAAKAAhDVUVJAAABNWLbowo=").unwrap();
+        let cue = scte35::parse(&section).unwrap();
+        let splice = 100 * 90000;
+        timed_metadata.push_cue(&packager, &cue, Some(splice)).unwrap();
+        timed_metadata.push(&packager, event("urn:example:ad", "{}")).unwrap();
+        let emsgs = |uploaded: Vec<u8>| mp4::boxes(&uploaded).filter(|(box_type, _)| box_type == b"emsg").map(|(_, emsg)| parse_emsg(emsg)).map(|emsg| (emsg.1, emsg.4)).collect::<Vec<_>>();
+
+        // Representation 1 is far behind the splice, and gets the other event straight away
+        assert_eq!(emsgs(upload(timed_metadata, packager, "1", chunk(10 * 90000, 100))), [(10 * 90000, "urn:example:ad".to_string())]);
+        assert_eq!(emsgs(upload(timed_metadata, packager, "2", chunk(50 * 90000, 100))), [
+            (splice, scte35::SCHEME_ID_URI.to_string()),
+            (50 * 90000, "urn:example:ad".to_string()),
+        ]);
+        assert_eq!(emsgs(upload(timed_metadata, packager, "1", chunk(20 * 90000, 100))), []);
+        // Held for as long as it takes, even as later events go ahead of it
+        timed_metadata.push(&packager, event("urn:example:later", "{}")).unwrap();
+        assert_eq!(emsgs(upload(timed_metadata, packager, "1", chunk(30 * 90000, 100))), [(30 * 90000, "urn:example:later".to_string())]);
+        assert_eq!(emsgs(upload(timed_metadata, packager, "1", chunk(40 * 90000, 100))), [(splice, scte35::SCHEME_ID_URI.to_string())]);
+        assert_eq!(emsgs(upload(timed_metadata, packager, "1", chunk(50 * 90000, 100))), []);
+    }
+
+    #[test]
+    fn declares_schemes_in_each_adaptation_set() {
+        let timed_metadata: &'static TimedMetadata = Box::leak(Box::new(TimedMetadata::new(true, None)));
+        let packager = Uuid::new_v4();
+        upload(timed_metadata, packager, "1", init_segment());
+        timed_metadata.push(&packager, event("urn:example:\"ad\"", "{}")).unwrap();
+
+        let mpd = concat!(
+            r#"<MPD><Period id="1">"#,
+            r#"<AdaptationSet segmentAlignment="true"><SupplementalProperty schemeIdUri="x"/><SegmentTemplate media="v"/><Representation id="1"/></AdaptationSet>"#,
+            r#"<AdaptationSet lang="en"><Representation id="2"/></AdaptationSet>"#,
+            r#"</Period></MPD>"#,
+        );
+        let declaration = r#"<InbandEventStream schemeIdUri="urn:example:&quot;ad&quot;" value="1"/>"#;
+        assert_eq!(timed_metadata.declare_schemes(&packager, mpd.to_string()), [
+            r#"<MPD><Period id="1">"#,
+            r#"<AdaptationSet segmentAlignment="true"><SupplementalProperty schemeIdUri="x"/>"#, declaration, r#"<SegmentTemplate media="v"/><Representation id="1"/></AdaptationSet>"#,
+            r#"<AdaptationSet lang="en">"#, declaration, r#"<Representation id="2"/></AdaptationSet>"#,
+            r#"</Period></MPD>"#,
+        ].concat());
+    }
+
+    #[test]
+    fn adds_audio_languages_to_adaptation_sets() {
+        // Without events, as by default
+        let timed_metadata: &'static TimedMetadata = Box::leak(Box::new(TimedMetadata::new(false, None)));
+        let packager = Uuid::new_v4();
+        let mpd = format!(
+            concat!(
+                r#"<MPD><Period id="p1">"#,
+                r#"<AdaptationSet contentType="video"><SegmentTemplate initialization="{0}_p1_$RepresentationID$_init.mp4"/><Representation id="1"/></AdaptationSet>"#,
+                r#"<AdaptationSet contentType="audio"><SegmentTemplate initialization="{0}_p1_$RepresentationID$_init.mp4"/><Representation id="2"/></AdaptationSet>"#,
+                r#"<AdaptationSet contentType="audio"><SegmentTemplate initialization="{0}_p1_$RepresentationID$_init.mp4"/><Representation id="3"/></AdaptationSet>"#,
+                r#"<AdaptationSet contentType="audio" lang="de"><SegmentTemplate initialization="{0}_p1_$RepresentationID$_init.mp4"/><Representation id="4"/></AdaptationSet>"#,
+                r#"</Period></MPD>"#,
+            ),
+            packager,
+        );
+        assert_eq!(timed_metadata.add_languages(&packager, mpd.clone()), mpd);
+
+        upload(timed_metadata, packager, "p1_1", init_segment());
+        upload(timed_metadata, packager, "p1_2", audio_init_segment(b"eng"));
+        upload(timed_metadata, packager, "p1_3", audio_init_segment(b"und"));
+        upload(timed_metadata, packager, "p1_4", audio_init_segment(b"fra"));
+        let expected = mpd.replacen(r#"<AdaptationSet contentType="audio">"#, r#"<AdaptationSet contentType="audio" lang="eng">"#, 1);
+        assert_eq!(timed_metadata.add_languages(&packager, mpd), expected);
+    }
+
+    #[test]
+    fn names_uploads_by_packager_and_representation() {
+        let packager = Uuid::new_v4();
+        assert_eq!(segment_name(&format!("/{}_1_init.mp4", packager)), Some((packager, "1")));
+        assert_eq!(segment_name(&format!("/live/{}_p2_1_00042.mp4", packager)), Some((packager, "p2_1")));
+        assert_eq!(segment_name(&format!("/{}_0_init.m4a", packager)), Some((packager, "0")));
+        assert_eq!(segment_name(&format!("/{}_0_00003.m4s", packager)), Some((packager, "0")));
+        assert_eq!(segment_name(&format!("/{}.mpd", packager)), None);
+        assert_eq!(mpd_name(&format!("/{}.mpd", packager)), Some(packager));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::demux::{self, ElementaryStream, PAT_PID};
+use crate::ts::{self, PACKET_SIZE};
+
+/// Language given to audio tracks without a language descriptor
+const UNDETERMINED: &str = "und";
+
+/// Leaves the audio tracks a stream isn't set to keep out of what gpac is sent, by taking them out of the PMT and
+/// dropping their packets. gpac then only packages the ones that are left.
+pub struct TrackFilter {
+    /// The languages of the audio tracks to keep, or empty to keep them all
+    languages: Vec<String>,
+    pmt_pid: Option<u16>,
+    /// The PIDs left out of the PMT
+    removed: Vec<u16>,
+    /// The PCR PID, whose packets are still needed for their PCR if its track was removed
+    pcr_pid: Option<u16>,
+    /// The last PMT section gpac was sent, before its version was set, and the version it was given
+    sent: Option<(Vec<u8>, u8)>,
+}
+
+impl TrackFilter {
+    /// Keeps the audio tracks in the comma-separated languages of a stream's audio_tracks, or all of them if it is
+    /// unset
+    pub fn new(audio_tracks: Option<&str>) -> TrackFilter {
+        let languages = audio_tracks.unwrap_or("").split(',')
+            .map(|language| language.trim().to_ascii_lowercase())
+            .filter(|language| !language.is_empty())
+            .collect();
+        TrackFilter {
+            languages,
+            pmt_pid: None,
+            removed: Vec::new(),
+            pcr_pid: None,
+            sent: None,
+        }
+    }
+
+    /// Filters the packets of a message in place, returning how long it is now
+    pub fn filter(&mut self, message: &mut [u8]) -> usize {
+        if self.languages.is_empty() {
+            return message.len();
+        }
+        let mut len = 0;
+        for start in (0..message.len() / PACKET_SIZE).map(|i| i * PACKET_SIZE) {
+            let packet = &mut message[start..start + PACKET_SIZE];
+            if packet[0] == ts::SYNC_BYTE && !self.packet(packet) {
+                continue;
+            }
+            message.copy_within(start..start + PACKET_SIZE, len);
+            len += PACKET_SIZE;
+        }
+        len
+    }
+
+    /// Rewrites the packet if it is the PMT, returning whether it is kept
+    fn packet(&mut self, packet: &mut [u8]) -> bool {
+        let pid = ts::pid(packet);
+        if pid == PAT_PID {
+            if ts::payload_unit_start(packet) {
+                if let Some(section) = ts::payload(packet).and_then(|payload| demux::section(payload, 0x00)) {
+                    self.pmt_pid = demux::first_program(section).map(|(_, pmt_pid)| pmt_pid);
+                }
+            }
+            return true;
+        }
+        if Some(pid) == self.pmt_pid {
+            if ts::payload_unit_start(packet) {
+                self.pmt(packet, pid);
+            }
+            return true;
+        }
+        !self.removed.contains(&pid) || Some(pid) == self.pcr_pid
+    }
+
+    fn pmt(&mut self, packet: &mut [u8], pmt_pid: u16) {
+        let payload_start = match ts::payload(packet) {
+            Some(payload) => PACKET_SIZE - payload.len(),
+            None => return,
+        };
+        let section = match demux::section(&packet[payload_start..], 0x02) {
+            Some(section) => section,
+            None => return,
+        };
+        let program = match demux::parse_pmt(section, pmt_pid) {
+            Some(program) => program,
+            None => return,
+        };
+        self.removed = removed_tracks(&program.streams, &self.languages);
+        self.pcr_pid = Some(program.pcr_pid);
+        let mut rewritten = without_streams(section, &self.removed);
+        // gpac only looks at a PMT again when its version changes, which the source's doesn't when the tracks kept
+        // change, or when a new contributor starts over from the same version
+        let version = match self.sent {
+            Some((ref sent, version)) if *sent == rewritten => version,
+            Some((_, version)) => (version + 1) % 32,
+            None => (section[5] >> 1) & 0x1f,
+        };
+        self.sent = Some((rewritten.clone(), version));
+        set_version(&mut rewritten, version);
+        // The rest of the packet moves up after the shorter section, and is stuffed at the end
+        let section_start = payload_start + 1 + packet[payload_start] as usize;
+        let section_end = section_start + section.len();
+        let rewritten_end = section_start + rewritten.len();
+        packet[section_start..rewritten_end].copy_from_slice(&rewritten);
+        packet.copy_within(section_end.., rewritten_end);
+        for byte in &mut packet[PACKET_SIZE - (section_end - rewritten_end)..] {
+            *byte = 0xff;
+        }
+    }
+}
+
+/// The PIDs of the audio tracks that aren't in `languages`. If none of them are, the first audio track is kept, so
+/// that there is still sound.
+fn removed_tracks(streams: &[ElementaryStream], languages: &[String]) -> Vec<u16> {
+    let audio = streams.iter().filter(|stream| stream.is_audio()).collect::<Vec<_>>();
+    let kept = |stream: &ElementaryStream| {
+        let language = stream.language().unwrap_or_else(|| UNDETERMINED.to_string());
+        languages.contains(&language)
+    };
+    if !audio.iter().any(|stream| kept(stream)) {
+        return audio.iter().skip(1).map(|stream| stream.pid).collect();
+    }
+    audio.iter().filter(|stream| !kept(stream)).map(|stream| stream.pid).collect()
+}
+
+/// A PMT section without the streams on `pids`, with its length and CRC updated
+fn without_streams(section: &[u8], pids: &[u16]) -> Vec<u8> {
+    let program_info_len = (((section[10] & 0x0f) as usize) << 8) | section[11] as usize;
+    let entries_start = (12 + program_info_len).min(section.len() - 4);
+    let mut rewritten = section[..entries_start].to_vec();
+    let mut entries = &section[entries_start..section.len() - 4];
+    while entries.len() >= 5 {
+        let entry_len = (5 + ((((entries[3] & 0x0f) as usize) << 8) | entries[4] as usize)).min(entries.len());
+        let (entry, rest) = entries.split_at(entry_len);
+        entries = rest;
+        if !pids.contains(&u16::from_be_bytes([entry[1] & 0x1f, entry[2]])) {
+            rewritten.extend_from_slice(entry);
+        }
+    }
+    // section_length counts from after itself, CRC included
+    let section_len = rewritten.len() + 4 - 3;
+    rewritten[1] = (rewritten[1] & 0xf0) | (section_len >> 8) as u8;
+    rewritten[2] = section_len as u8;
+    let crc = ts::crc32(&rewritten);
+    rewritten.extend_from_slice(&crc.to_be_bytes());
+    rewritten
+}
+
+/// Sets the version_number of a section, updating its CRC
+fn set_version(section: &mut Vec<u8>, version: u8) {
+    section[5] = (section[5] & 0xc1) | version << 1;
+    section.truncate(section.len() - 4);
+    let crc = ts::crc32(section);
+    section.extend_from_slice(&crc.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demux::tests::{pat, section_packet, PMT_PID};
+
+    /// Video on 0x100 and AAC on 0x101 and 0x102 in English and French, with the PCR on `pcr_pid`
+    fn pmt(pcr_pid: u16) -> Vec<u8> {
+        pmt_with_audio(pcr_pid, &[b"eng", b"FRA"])
+    }
+
+    /// Video on 0x100 and AAC from 0x101 on in `languages`, at version 0
+    fn pmt_with_audio(pcr_pid: u16, languages: &[&[u8; 3]]) -> Vec<u8> {
+        let mut section = vec![0x02, 0, 0, 0, 1, 0xc1, 0, 0, 0xe0 | (pcr_pid >> 8) as u8, pcr_pid as u8, 0xf0, 0];
+        section.extend_from_slice(&[demux::STREAM_TYPE_H264, 0xe1, 0x00, 0xf0, 0]);
+        for (i, language) in languages.iter().enumerate() {
+            section.extend_from_slice(&[demux::STREAM_TYPE_AAC, 0xe1, 0x01 + i as u8, 0xf0, 6, demux::DESCRIPTOR_ISO_639_LANGUAGE, 4]);
+            section.extend_from_slice(&language[..]);
+            section.push(0);
+        }
+        section_packet(PMT_PID, section)
+    }
+
+    fn pes_packet(pid: u16) -> Vec<u8> {
+        let mut packet = vec![ts::SYNC_BYTE, (pid >> 8) as u8, pid as u8, 0x10];
+        packet.resize(PACKET_SIZE, pid as u8);
+        packet
+    }
+
+    fn filtered(filter: &mut TrackFilter, pcr_pid: u16) -> Vec<u8> {
+        let mut message = [pat(), pmt(pcr_pid), pes_packet(0x100), pes_packet(0x101), pes_packet(0x102)].concat();
+        let len = filter.filter(&mut message);
+        message.truncate(len);
+        message
+    }
+
+    fn pmt_section(message: &[u8]) -> &[u8] {
+        demux::section(ts::payload(&message[PACKET_SIZE..]).unwrap(), 0x02).unwrap()
+    }
+
+    fn streams(message: &[u8]) -> Vec<(u16, Option<String>)> {
+        let section = pmt_section(message);
+        assert_eq!(ts::crc32(section), 0);
+        let program = demux::parse_pmt(section, PMT_PID).unwrap();
+        program.streams.iter().map(|stream| (stream.pid, stream.language())).collect()
+    }
+
+    #[test]
+    fn keeps_every_track_by_default() {
+        let mut filter = TrackFilter::new(None);
+        let message = filtered(&mut filter, 0x100);
+        assert_eq!(message.len(), 5 * PACKET_SIZE);
+        assert_eq!(streams(&message), [(0x100, None), (0x101, Some("eng".to_string())), (0x102, Some("fra".to_string()))]);
+    }
+
+    #[test]
+    fn removes_tracks_in_other_languages() {
+        let mut filter = TrackFilter::new(Some("fra, deu"));
+        let message = filtered(&mut filter, 0x100);
+        assert_eq!(streams(&message), [(0x100, None), (0x102, Some("fra".to_string()))]);
+        let pids = ts::packets(&message).map(ts::pid).collect::<Vec<_>>();
+        assert_eq!(pids, [PAT_PID, PMT_PID, 0x100, 0x102]);
+    }
+
+    #[test]
+    fn keeps_the_pcr_and_some_sound() {
+        // The PCR is on the English track, which still has its packets sent for it
+        let mut filter = TrackFilter::new(Some("fra"));
+        let message = filtered(&mut filter, 0x101);
+        assert_eq!(streams(&message), [(0x100, None), (0x102, Some("fra".to_string()))]);
+        assert_eq!(ts::packets(&message).count(), 5);
+
+        let mut filter = TrackFilter::new(Some("spa"));
+        let message = filtered(&mut filter, 0x100);
+        assert_eq!(streams(&message), [(0x100, None), (0x101, Some("eng".to_string()))]);
+    }
+
+    #[test]
+    fn changes_the_pmt_version_when_the_tracks_kept_change() {
+        let mut filter = TrackFilter::new(Some("fra"));
+        let mut send = |pmt: Vec<u8>| {
+            let mut message = [pat(), pmt].concat();
+            let len = filter.filter(&mut message);
+            message.truncate(len);
+            let section = pmt_section(&message);
+            assert_eq!(ts::crc32(section), 0);
+            ((section[5] >> 1) & 0x1f, streams(&message).len())
+        };
+        assert_eq!(send(pmt(0x100)), (0, 2));
+        assert_eq!(send(pmt(0x100)), (0, 2));
+        // A new contributor's PMT at the same version, with only English, which is kept for want of French
+        assert_eq!(send(pmt_with_audio(0x100, &[b"eng"])), (1, 2));
+        assert_eq!(send(pmt_with_audio(0x100, &[b"eng", b"fra", b"deu"])), (2, 2));
+        assert_eq!(send(pmt_with_audio(0x100, &[b"eng", b"fra", b"deu"])), (2, 2));
+    }
+}
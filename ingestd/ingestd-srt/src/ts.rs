@@ -0,0 +1,113 @@
+pub const PACKET_SIZE: usize = 188;
+pub const SYNC_BYTE: u8 = 0x47;
+/// Packets on this PID are stuffing, sent to keep the bitrate constant
+pub const NULL_PID: u16 = 0x1fff;
+/// PCR bases and PES timestamps are 33 bits, counting at 90kHz
+pub const TIMESTAMP_MODULUS: u64 = 1 << 33;
+
+/// Iterates over the 188-byte transport stream packets in an SRT message, skipping any that are misaligned
+pub fn packets(buffer: &[u8]) -> impl Iterator<Item=&[u8]> {
+    buffer.chunks_exact(PACKET_SIZE).filter(|packet| packet[0] == SYNC_BYTE)
+}
+
+pub fn pid(packet: &[u8]) -> u16 {
+    (((packet[1] & 0x1f) as u16) << 8) | packet[2] as u16
+}
+
+pub fn adaptation_field(packet: &[u8]) -> Option<&[u8]> {
+    if packet[3] & 0x20 == 0 {
+        return None;
+    }
+    let len = packet[4] as usize;
+    if len == 0 || 5 + len > PACKET_SIZE {
+        return None;
+    }
+    Some(&packet[5..5+len])
+}
+
+/// Whether the packet has its random_access_indicator set, which encoders use to mark the start of a keyframe
+pub fn is_random_access(packet: &[u8]) -> bool {
+    adaptation_field(packet).map(|field| field[0] & 0x40 != 0).unwrap_or(false)
+}
+
+pub fn contains_keyframe(buffer: &[u8]) -> bool {
+    packets(buffer).any(is_random_access)
+}
+
+pub fn payload_unit_start(packet: &[u8]) -> bool {
+    packet[1] & 0x40 != 0
+}
+
+/// The packet's payload, after any adaptation field
+pub fn payload(packet: &[u8]) -> Option<&[u8]> {
+    if packet[3] & 0x10 == 0 {
+        return None;
+    }
+    let start = if packet[3] & 0x20 != 0 { 5 + packet[4] as usize } else { 4 };
+    if start >= PACKET_SIZE {
+        return None;
+    }
+    Some(&packet[start..])
+}
+
+/// The base of the packet's PCR, in 90kHz units
+pub fn pcr(packet: &[u8]) -> Option<u64> {
+    let field = adaptation_field(packet)?;
+    if field[0] & 0x10 == 0 || field.len() < 7 {
+        return None;
+    }
+    Some((field[1] as u64) << 25 | (field[2] as u64) << 17 | (field[3] as u64) << 9 | (field[4] as u64) << 1 | (field[5] as u64) >> 7)
+}
+
+/// Replaces the base of the packet's PCR, which must have one, leaving its extension as it was
+pub fn set_pcr(packet: &mut [u8], base: u64) {
+    let field = &mut packet[5..12];
+    field[1] = (base >> 25) as u8;
+    field[2] = (base >> 17) as u8;
+    field[3] = (base >> 9) as u8;
+    field[4] = (base >> 1) as u8;
+    field[5] = ((base & 1) as u8) << 7 | (field[5] & 0x7f);
+}
+
+/// Reads a 33-bit timestamp, as coded in PES headers
+pub fn timestamp(bytes: &[u8]) -> u64 {
+    ((bytes[0] as u64 >> 1) & 0x07) << 30
+        | (bytes[1] as u64) << 22
+        | (bytes[2] as u64 >> 1) << 15
+        | (bytes[3] as u64) << 7
+        | bytes[4] as u64 >> 1
+}
+
+/// Writes a 33-bit timestamp into a PES header, keeping the prefix bits that say which timestamp it is
+pub fn set_timestamp(bytes: &mut [u8], timestamp: u64) {
+    bytes[0] = (bytes[0] & 0xf0) | ((timestamp >> 29) & 0x0e) as u8 | 1;
+    bytes[1] = (timestamp >> 22) as u8;
+    bytes[2] = ((timestamp >> 14) & 0xfe) as u8 | 1;
+    bytes[3] = (timestamp >> 7) as u8;
+    bytes[4] = ((timestamp << 1) & 0xfe) as u8 | 1;
+}
+
+/// Where the PTS and DTS are in a packet that starts a PES, as offsets into the packet, if it has them and they fit
+pub fn pes_timestamps(packet: &[u8]) -> (Option<usize>, Option<usize>) {
+    let start = match payload(packet) {
+        Some(payload) if payload_unit_start(packet) && payload.len() >= 9 && payload[..3] == [0, 0, 1] => PACKET_SIZE - payload.len(),
+        _ => return (None, None),
+    };
+    // Stream ids without the optional PES header
+    if [0xbc, 0xbe, 0xbf, 0xf0, 0xf1, 0xf2, 0xf8, 0xff].contains(&packet[start + 3]) {
+        return (None, None);
+    }
+    let pts = start + 9;
+    match packet[start + 7] >> 6 {
+        0b10 if pts + 5 <= PACKET_SIZE => (Some(pts), None),
+        0b11 if pts + 10 <= PACKET_SIZE => (Some(pts), Some(pts + 5)),
+        _ => (None, None),
+    }
+}
+
+/// The CRC_32 of PSI sections. Over a whole section, CRC included, it comes to 0 if the section is intact.
+pub fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xffff_ffff, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u32) << 24, |crc, _| if crc & 0x8000_0000 != 0 { crc << 1 ^ 0x04c1_1db7 } else { crc << 1 })
+    })
+}
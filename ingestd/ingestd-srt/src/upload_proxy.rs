@@ -0,0 +1,290 @@
+use async_dup::Arc;
+use futures::channel::mpsc;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, Cursor};
+use futures::stream::{StreamExt, TryStreamExt};
+use http_types::{Method, Request, Response};
+use smol::{Async, Task};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+
+use crate::captions::CaptionUpload;
+use crate::timed_metadata::{self, EmsgInserter, TimedMetadata};
+
+/// Headers about the connection to the proxy rather than the upload, which aren't forwarded
+const HOP_BY_HOP: &[&str] = &["host", "connection", "keep-alive", "content-length", "transfer-encoding", "expect", "te", "upgrade"];
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A connection to httpd, which is kept for the next request for as long as httpd keeps it open
+struct Upstream {
+    httpd_addr: SocketAddr,
+    stream: Mutex<Option<Arc<Async<TcpStream>>>>,
+}
+
+impl Upstream {
+    fn new(httpd_addr: SocketAddr) -> Upstream {
+        Upstream {
+            httpd_addr,
+            stream: Mutex::new(None),
+        }
+    }
+
+    /// The kept connection, or a new one. Requests on one connection from gpac are handled one at a time, each once
+    /// the last response has been forwarded, so a kept connection is never shared.
+    async fn connect(&self) -> std::io::Result<Arc<Async<TcpStream>>> {
+        let kept = self.stream.lock().unwrap().take();
+        if let Some(stream) = kept {
+            // httpd sends nothing between responses, so a kept connection that can be read from has been closed, as
+            // it is once it has been idle for a while
+            match stream.get_ref().peek(&mut [0]) {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(stream),
+                _ => {},
+            }
+        }
+        let stream = Async::<TcpStream>::connect(self.httpd_addr).await?;
+        stream.get_ref().set_nodelay(true)?;
+        Ok(Arc::new(stream))
+    }
+
+    fn keep(&self, stream: Arc<Async<TcpStream>>) {
+        *self.stream.lock().unwrap() = Some(stream);
+    }
+}
+
+/// Sits between gpac and httpd, adding timed metadata to gpac's uploads on their way through
+pub async fn serve(listener: TcpListener, httpd_addr: SocketAddr, timed_metadata: &'static TimedMetadata) -> std::io::Result<()> {
+    let listener = Async::new(listener)?;
+    loop {
+        let (stream, _peer_addr) = listener.accept().await?;
+        let stream = Arc::new(stream);
+        Task::spawn(async move {
+            let upstream = &Upstream::new(httpd_addr);
+            let res = async_h1::accept(stream, |request| forward(request, upstream, timed_metadata)).await;
+            if let Err(e) = res {
+                eprintln!("forwarding upload to httpd failed: {}", e);
+            }
+        }).detach();
+    }
+}
+
+async fn forward(mut request: Request, upstream: &Upstream, timed_metadata: &'static TimedMetadata) -> http_types::Result<Response> {
+    let upload = request.method() == Method::Put || request.method() == Method::Post;
+    let path = request.url().path().to_string();
+
+    let mut head = format!("{} {}", request.method(), path);
+    if let Some(query) = request.url().query() {
+        head.push('?');
+        head.push_str(query);
+    }
+    head.push_str(&format!(" HTTP/1.1\r\nhost: {}\r\n", upstream.httpd_addr));
+    for (name, values) in request.iter() {
+        if HOP_BY_HOP.contains(&name.as_str()) {
+            continue;
+        }
+        for value in values.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+
+    // Segments are streamed as gpac writes them, so their length isn't known
+    let (body, len): (Box<dyn AsyncRead + Unpin + Send>, Option<usize>) = match (upload, timed_metadata::mpd_name(&path), timed_metadata::segment_name(&path)) {
+        (true, Some(packager), _) => {
+            let mpd = timed_metadata.declare_schemes(&packager, request.body_string().await?);
+            let mpd = timed_metadata.add_languages(&packager, mpd);
+            let mpd = timed_metadata.add_captions(&packager, mpd);
+            let len = mpd.len();
+            (Box::new(Cursor::new(mpd.into_bytes())), Some(len))
+        },
+        (true, _, Some((packager, representation))) => {
+            (Box::new(EmsgInserter::new(request.take_body(), timed_metadata, packager, representation.to_string(), path.clone())), None)
+        },
+        _ => {
+            let body = request.take_body();
+            let len = body.len();
+            (Box::new(body), len)
+        },
+    };
+    send(upstream, head, body, len).await
+}
+
+/// Uploads the files of caption tracks to httpd as they are written
+pub async fn upload_captions(mut uploads: mpsc::UnboundedReceiver<CaptionUpload>, httpd_addr: SocketAddr) {
+    while let Some(upload) = uploads.next().await {
+        Task::spawn(async move {
+            let head = format!("PUT {} HTTP/1.1\r\nhost: {}\r\n", upload.path, httpd_addr);
+            let body = upload.body.map(Ok::<_, std::io::Error>).into_async_read();
+            match send(&Upstream::new(httpd_addr), head, Box::new(body), None).await {
+                Ok(response) if response.status().is_success() => {},
+                Ok(response) => eprintln!("uploading {} to httpd failed: {}", upload.path, response.status()),
+                Err(e) => eprintln!("uploading {} to httpd failed: {}", upload.path, e),
+            }
+        }).detach();
+    }
+}
+
+/// Sends a request to httpd, given its head up to the body's length, which is sent chunked if it isn't known
+async fn send(upstream: &Upstream, mut head: String, mut body: Box<dyn AsyncRead + Unpin + Send>, len: Option<usize>) -> http_types::Result<Response> {
+    match len {
+        Some(len) => head.push_str(&format!("content-length: {}\r\n\r\n", len)),
+        None => head.push_str("transfer-encoding: chunked\r\n\r\n"),
+    }
+
+    let mut stream = upstream.connect().await?;
+    stream.write_all(head.as_bytes()).await?;
+    match len {
+        Some(_) => {
+            futures::io::copy(body, &mut stream).await?;
+        },
+        None => {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            loop {
+                let n = body.read(&mut chunk).await?;
+                // The last chunk is empty
+                stream.write_all(format!("{:x}\r\n", n).as_bytes()).await?;
+                stream.write_all(&chunk[..n]).await?;
+                stream.write_all(b"\r\n").await?;
+                if n == 0 {
+                    break;
+                }
+            }
+        },
+    }
+
+    let mut response = async_h1::client::decode(stream.clone()).await?;
+    let mut forwarded = Response::new(response.status());
+    let mut close = false;
+    for (name, values) in response.iter() {
+        if name.as_str() == "connection" {
+            close |= values.iter().any(|value| value.as_str().eq_ignore_ascii_case("close"));
+        }
+        if HOP_BY_HOP.contains(&name.as_str()) {
+            continue;
+        }
+        for value in values.iter() {
+            forwarded.append_header(name.clone(), value.clone());
+        }
+    }
+    forwarded.set_body(response.take_body());
+    // The body is read from the connection as it is forwarded, which is done before the next request is sent on it
+    if !close {
+        upstream.keep(stream);
+    }
+    Ok(forwarded)
+}
+
+#[cfg(test)]
+mod tests {
+    use http_types::{Body, StatusCode, Url};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::Shutdown;
+    use std::sync::mpsc as std_mpsc;
+    use std::thread;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::timed_metadata::tests::audio_init_segment;
+
+    /// Stands in for httpd, answering every request with 201 and passing on each path and body it was sent once it
+    /// has. Without `keep_alive`, it then closes the connection without having said it would.
+    fn httpd(keep_alive: bool) -> (SocketAddr, std_mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = std_mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                while let Some(upload) = read_request(&mut stream) {
+                    stream.get_mut().write_all(b"HTTP/1.1 201 Created\r\ncontent-length: 0\r\n\r\n").unwrap();
+                    if !keep_alive {
+                        stream.get_mut().shutdown(Shutdown::Both).unwrap();
+                    }
+                    let _ = sender.send(upload);
+                }
+            }
+        });
+        (addr, receiver)
+    }
+
+    fn read_request(stream: &mut BufReader<TcpStream>) -> Option<(String, Vec<u8>)> {
+        let mut line = String::new();
+        if stream.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let path = line.split(' ').nth(1)?.to_string();
+        let (mut len, mut chunked) = (0, false);
+        loop {
+            line.clear();
+            stream.read_line(&mut line).ok()?;
+            let header = line.trim_end().to_ascii_lowercase();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("content-length: ") {
+                len = value.parse().ok()?;
+            }
+            chunked |= header == "transfer-encoding: chunked";
+        }
+        let mut body = Vec::new();
+        if !chunked {
+            body.resize(len, 0);
+            stream.read_exact(&mut body).ok()?;
+            return Some((path, body));
+        }
+        loop {
+            line.clear();
+            stream.read_line(&mut line).ok()?;
+            let len = usize::from_str_radix(line.trim_end(), 16).ok()?;
+            let start = body.len();
+            body.resize(start + len + 2, 0);
+            stream.read_exact(&mut body[start..]).ok()?;
+            body.truncate(start + len);
+            if len == 0 {
+                return Some((path, body));
+            }
+        }
+    }
+
+    fn put(upstream: &Upstream, timed_metadata: &'static TimedMetadata, path: &str, body: Vec<u8>) -> StatusCode {
+        let mut request = Request::new(Method::Put, Url::parse(&format!("http://localhost{}", path)).unwrap());
+        request.set_body(Body::from(body));
+        smol::block_on(async {
+            let mut response = forward(request, upstream, timed_metadata).await.unwrap();
+            response.body_bytes().await.unwrap();
+            response.status()
+        })
+    }
+
+    #[test]
+    fn adds_languages_to_the_mpd_with_the_default_config() {
+        let (httpd_addr, uploads) = httpd(true);
+        let upstream = Upstream::new(httpd_addr);
+        // timed_metadata is off by default, and captions with it
+        let timed_metadata: &'static TimedMetadata = Box::leak(Box::new(TimedMetadata::new(false, None)));
+        let packager = Uuid::new_v4();
+
+        let init_path = format!("/live/{}_p1_2_init.mp4", packager);
+        assert_eq!(put(&upstream, timed_metadata, &init_path, audio_init_segment(b"eng")), StatusCode::Created);
+        assert_eq!(uploads.recv().unwrap(), (init_path, audio_init_segment(b"eng")));
+
+        let mpd = format!(
+            r#"<MPD><Period id="p1"><AdaptationSet contentType="audio"><SegmentTemplate initialization="{}_p1_$RepresentationID$_init.mp4"/><Representation id="2"/></AdaptationSet></Period></MPD>"#,
+            packager,
+        );
+        let mpd_path = format!("/live/{}.mpd", packager);
+        assert_eq!(put(&upstream, timed_metadata, &mpd_path, mpd.clone().into_bytes()), StatusCode::Created);
+        let expected = mpd.replace(r#"contentType="audio""#, r#"contentType="audio" lang="eng""#);
+        assert_eq!(uploads.recv().unwrap(), (mpd_path, expected.into_bytes()));
+    }
+
+    #[test]
+    fn reconnects_once_httpd_has_closed_the_kept_connection() {
+        let (httpd_addr, uploads) = httpd(false);
+        let upstream = Upstream::new(httpd_addr);
+        let timed_metadata: &'static TimedMetadata = Box::leak(Box::new(TimedMetadata::new(false, None)));
+        for i in 0..2 {
+            let path = format!("/live/{}.txt", i);
+            assert_eq!(put(&upstream, timed_metadata, &path, b"text".to_vec()), StatusCode::Created);
+            assert_eq!(uploads.recv().unwrap(), (path, b"text".to_vec()));
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use libsrt_sys::*;
+
+use crate::{SocketId, SrtError, check};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EpollFlags(SRT_EPOLL_T);
+
+impl EpollFlags {
+    pub const IN: EpollFlags = EpollFlags(SRT_EPOLL_IN as SRT_EPOLL_T);
+    pub const OUT: EpollFlags = EpollFlags(SRT_EPOLL_OUT as SRT_EPOLL_T);
+    pub const ERR: EpollFlags = EpollFlags(SRT_EPOLL_ERR as SRT_EPOLL_T);
+    /// Report readiness only when it changes, rather than for as long as it lasts
+    pub const ET: EpollFlags = EpollFlags(SRT_EPOLL_ET as SRT_EPOLL_T);
+
+    pub fn contains(self, other: EpollFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for EpollFlags {
+    type Output = EpollFlags;
+
+    fn bitor(self, other: EpollFlags) -> EpollFlags {
+        EpollFlags(self.0 | other.0)
+    }
+}
+
+/// An SRT epoll set, which is released when dropped
+pub struct Epoll(libc::c_int);
+
+impl Epoll {
+    pub fn new() -> Result<Epoll, SrtError> {
+        Ok(Epoll(check(unsafe { srt_epoll_create() })?))
+    }
+
+    pub fn add(&self, socket: SocketId, flags: EpollFlags) -> Result<(), SrtError> {
+        check(unsafe { srt_epoll_add_usock(self.0, socket, &flags.0) })?;
+        Ok(())
+    }
+
+    pub fn remove(&self, socket: SocketId) -> Result<(), SrtError> {
+        check(unsafe { srt_epoll_remove_usock(self.0, socket) })?;
+        Ok(())
+    }
+
+    /// Waits for sockets to become readable, filling `ready` with their ids and returning how many there were.
+    ///
+    /// A negative timeout waits forever.
+    pub fn wait(&self, ready: &mut [SocketId], timeout_ms: i64) -> Result<usize, SrtError> {
+        let mut ready_len = ready.len() as libc::c_int;
+        let res = unsafe {
+            srt_epoll_wait(self.0,
+                ready.as_mut_ptr(), &mut ready_len,
+                std::ptr::null_mut(), std::ptr::null_mut(),
+                timeout_ms,
+                std::ptr::null_mut(), std::ptr::null_mut(),
+                std::ptr::null_mut(), std::ptr::null_mut(),
+            )
+        };
+        match check(res) {
+            Ok(_) => Ok(ready_len as usize),
+            Err(e) if e.code() == SRT_ETIMEOUT => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe { srt_epoll_release(self.0) };
+    }
+}
@@ -0,0 +1,62 @@
+//! Safe wrappers over libsrt-sys
+
+use libsrt_sys::*;
+use std::ffi::CStr;
+use thiserror::Error;
+
+mod epoll;
+mod listener;
+pub mod options;
+mod socket;
+mod stats;
+
+pub use epoll::{Epoll, EpollFlags};
+pub use libsrt_sys as sys;
+pub use listener::{Listener, ListenCallback};
+pub use socket::{PendingSocket, Socket, SocketId, SocketStatus};
+pub use stats::Stats;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("{}", unsafe { CStr::from_ptr(srt_strerror(self.0, 0)) }.to_string_lossy())]
+pub struct SrtError(SRT_ERRNO);
+
+impl SrtError {
+    pub fn code(&self) -> SRT_ERRNO {
+        self.0
+    }
+
+    /// Whether the operation failed only because it would have blocked on a non-blocking socket
+    pub fn is_would_block(&self) -> bool {
+        self.0 == SRT_EASYNCRCV || self.0 == SRT_EASYNCSND
+    }
+
+    fn last() -> SrtError {
+        SrtError(unsafe { srt_getlasterror(std::ptr::null_mut()) })
+    }
+}
+
+fn check(res: libc::c_int) -> Result<libc::c_int, SrtError> {
+    if res != -1 {
+        Ok(res)
+    } else {
+        Err(SrtError::last())
+    }
+}
+
+/// Keeps libsrt initialised for as long as it is alive
+pub struct Srt(());
+
+impl Drop for Srt {
+    fn drop(&mut self) {
+        unsafe { srt_cleanup(); }
+    }
+}
+
+pub fn startup() -> Result<Srt, SrtError> {
+    check(unsafe { srt_startup() })?;
+    Ok(Srt(()))
+}
+
+pub fn set_log_level(level: libc::c_int) {
+    unsafe { srt_setloglevel(level) };
+}
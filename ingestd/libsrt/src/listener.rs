@@ -0,0 +1,134 @@
+use libsrt_sys::*;
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::IntoRawFd;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use crate::options::SocketOption;
+use crate::socket::sockaddr_to_std;
+use crate::{PendingSocket, Socket, SocketId, SrtError, check};
+
+/// Called for each incoming connection before it's accepted, with the new socket, the handshake version, the peer's
+/// address and the stream id. Returning false rejects the connection.
+pub type ListenCallback = dyn Fn(&PendingSocket, i32, Option<SocketAddr>, &CStr) -> bool + Send + Sync;
+
+pub struct Listener {
+    socket: Socket,
+    // Must outlive the socket, since libsrt holds a pointer to it
+    callback: Option<Box<Box<ListenCallback>>>,
+}
+
+unsafe extern "C" fn listen_callback(userdata: *mut libc::c_void, sock: SRTSOCKET, hsversion: libc::c_int, peeraddr: *const libc::sockaddr, stream_id: *const libc::c_char) -> libc::c_int {
+    let callback = &*(userdata as *const Box<ListenCallback>);
+    let stream_id = if stream_id.is_null() {
+        CStr::from_bytes_with_nul_unchecked(b"\0")
+    } else {
+        CStr::from_ptr(stream_id)
+    };
+    let peer_addr = sockaddr_to_std(peeraddr);
+    // Unwinding into libsrt would be undefined behaviour, so a panicking callback rejects the connection
+    match catch_unwind(AssertUnwindSafe(|| callback(&PendingSocket(sock), hsversion, peer_addr, stream_id))) {
+        Ok(true) => 0,
+        _ => -1,
+    }
+}
+
+impl Listener {
+    pub fn new() -> Result<Listener, SrtError> {
+        Ok(Listener {
+            socket: Socket::new()?,
+            callback: None,
+        })
+    }
+
+    pub fn id(&self) -> SocketId {
+        self.socket.id()
+    }
+
+    pub fn set<O: SocketOption>(&self, value: O::Value) -> Result<(), SrtError> {
+        self.socket.set::<O>(value)
+    }
+
+    pub fn get<O: SocketOption>(&self) -> Result<O::Value, SrtError> {
+        self.socket.get::<O>()
+    }
+
+    pub fn set_listen_callback<F>(&mut self, callback: F) -> Result<(), SrtError> where F: Fn(&PendingSocket, i32, Option<SocketAddr>, &CStr) -> bool + Send + Sync + 'static {
+        let callback: Box<Box<ListenCallback>> = Box::new(Box::new(callback));
+        let userdata = &*callback as *const Box<ListenCallback> as *mut libc::c_void;
+        check(unsafe { srt_listen_callback(self.socket.id(), Some(listen_callback), userdata) })?;
+        self.callback = Some(callback);
+        Ok(())
+    }
+
+    /// Binds to an already bound UDP socket, such as one passed in by systemd
+    pub fn bind_udp(&self, udp_socket: UdpSocket) -> Result<(), SrtError> {
+        check(unsafe { srt_bind_peerof(self.socket.id(), udp_socket.into_raw_fd()) })?;
+        Ok(())
+    }
+
+    pub fn listen(&self, backlog: i32) -> Result<(), SrtError> {
+        check(unsafe { srt_listen(self.socket.id(), backlog) })?;
+        Ok(())
+    }
+
+    pub fn accept(&self) -> Result<(Socket, Option<SocketAddr>), SrtError> {
+        let mut addr = MaybeUninit::<libc::sockaddr_storage>::uninit();
+        let mut addr_size = std::mem::size_of::<libc::sockaddr_storage>() as libc::c_int;
+        let id = check(unsafe { srt_accept(self.socket.id(), addr.as_mut_ptr() as *mut libc::sockaddr, &mut addr_size) })?;
+        let peer_addr = unsafe { sockaddr_to_std(addr.as_ptr() as *const libc::sockaddr) };
+        Ok((Socket::from_raw(id), peer_addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{Latency, OptionValue, PeerIdleTimeo, StreamId};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn connects_over_loopback() {
+        let _srt = crate::startup().unwrap();
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp_socket.local_addr().unwrap();
+
+        let mut listener = Listener::new().unwrap();
+        let seen = Arc::new(Mutex::new(None));
+        let callback_seen = seen.clone();
+        listener.set_listen_callback(move |socket, _, _, stream_id| {
+            socket.set::<PeerIdleTimeo>(2500).unwrap();
+            *callback_seen.lock().unwrap() = Some(stream_id.to_bytes().to_vec());
+            true
+        }).unwrap();
+        listener.bind_udp(udp_socket).unwrap();
+        listener.listen(1).unwrap();
+
+        let caller = Socket::new().unwrap();
+        caller.set::<StreamId>(b"#!::r=live".to_vec()).unwrap();
+        caller.set::<Latency>(300).unwrap();
+        caller.connect(addr).unwrap();
+
+        let (accepted, peer_addr) = listener.accept().unwrap();
+        assert_eq!(peer_addr.map(|peer_addr| peer_addr.ip()), Some(addr.ip()));
+        assert_eq!(seen.lock().unwrap().as_deref(), Some(&b"#!::r=live"[..]));
+        assert_eq!(accepted.get::<StreamId>().unwrap(), b"#!::r=live");
+        assert_eq!(accepted.get::<PeerIdleTimeo>().unwrap(), 2500);
+        // The receiver takes the larger of its own latency and the sender's
+        assert_eq!(accepted.get::<Latency>().unwrap(), 300);
+        assert_eq!(caller.get::<Latency>().unwrap(), 300);
+
+        caller.send_msg(b"hello").unwrap();
+        let mut buffer = [0; 1500];
+        let len = accepted.recv_msg(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"hello");
+    }
+
+    #[test]
+    fn rejects_short_option_values() {
+        assert_eq!(i32::from_raw(&[1, 0]).unwrap_err().code(), SRT_EINVPARAM);
+        assert_eq!(i64::from_raw(&[1, 0, 0, 0]).unwrap_err().code(), SRT_EINVPARAM);
+        assert_eq!(i32::from_raw(&1i32.to_ne_bytes()), Ok(1));
+    }
+}
@@ -0,0 +1,146 @@
+//! Typed socket options, for use with `Socket::set` and `Socket::get`
+
+use libsrt_sys::*;
+use std::convert::TryInto;
+
+use crate::SrtError;
+
+pub trait OptionValue: Sized {
+    fn with_raw<R>(&self, f: impl FnOnce(*const libc::c_void, libc::c_int) -> R) -> R;
+    /// Fails if libsrt returned too few bytes for the type
+    fn from_raw(buffer: &[u8]) -> Result<Self, SrtError>;
+}
+
+
+impl OptionValue for bool {
+    fn with_raw<R>(&self, f: impl FnOnce(*const libc::c_void, libc::c_int) -> R) -> R {
+        f(self as *const bool as *const libc::c_void, std::mem::size_of::<bool>() as libc::c_int)
+    }
+
+    fn from_raw(buffer: &[u8]) -> Result<bool, SrtError> {
+        Ok(buffer.iter().any(|&byte| byte != 0))
+    }
+}
+
+impl OptionValue for i32 {
+    fn with_raw<R>(&self, f: impl FnOnce(*const libc::c_void, libc::c_int) -> R) -> R {
+        f(self as *const i32 as *const libc::c_void, std::mem::size_of::<i32>() as libc::c_int)
+    }
+
+    fn from_raw(buffer: &[u8]) -> Result<i32, SrtError> {
+        let bytes = buffer.get(..4).ok_or(SrtError(SRT_EINVPARAM))?;
+        Ok(i32::from_ne_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+impl OptionValue for i64 {
+    fn with_raw<R>(&self, f: impl FnOnce(*const libc::c_void, libc::c_int) -> R) -> R {
+        f(self as *const i64 as *const libc::c_void, std::mem::size_of::<i64>() as libc::c_int)
+    }
+
+    fn from_raw(buffer: &[u8]) -> Result<i64, SrtError> {
+        let bytes = buffer.get(..8).ok_or(SrtError(SRT_EINVPARAM))?;
+        Ok(i64::from_ne_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+impl OptionValue for Vec<u8> {
+    fn with_raw<R>(&self, f: impl FnOnce(*const libc::c_void, libc::c_int) -> R) -> R {
+        f(self.as_ptr() as *const libc::c_void, self.len() as libc::c_int)
+    }
+
+    fn from_raw(buffer: &[u8]) -> Result<Vec<u8>, SrtError> {
+        Ok(buffer.to_vec())
+    }
+}
+
+impl OptionValue for String {
+    fn with_raw<R>(&self, f: impl FnOnce(*const libc::c_void, libc::c_int) -> R) -> R {
+        f(self.as_ptr() as *const libc::c_void, self.len() as libc::c_int)
+    }
+
+    fn from_raw(buffer: &[u8]) -> Result<String, SrtError> {
+        Ok(String::from_utf8_lossy(buffer).into_owned())
+    }
+}
+
+pub trait SocketOption {
+    type Value: OptionValue;
+    const OPTION: SRT_SOCKOPT;
+}
+
+macro_rules! socket_options {
+    ($($(#[$attr:meta])* $name:ident($value:ty) = $option:ident;)*) => {
+        $(
+            $(#[$attr])*
+            pub struct $name;
+
+            impl SocketOption for $name {
+                type Value = $value;
+                const OPTION: SRT_SOCKOPT = $option;
+            }
+        )*
+    }
+}
+
+socket_options! {
+    /// Whether receiving blocks
+    RcvSyn(bool) = SRTO_RCVSYN;
+    /// Whether sending blocks
+    SndSyn(bool) = SRTO_SNDSYN;
+    /// How far out of order a packet may arrive before it's reported as lost
+    LossMaxTtl(i32) = SRTO_LOSSMAXTTL;
+    Passphrase(String) = SRTO_PASSPHRASE;
+    StreamId(Vec<u8>) = SRTO_STREAMID;
+    /// Receiver and sender latency in milliseconds
+    Latency(i32) = SRTO_LATENCY;
+    RcvLatency(i32) = SRTO_RCVLATENCY;
+    PeerLatency(i32) = SRTO_PEERLATENCY;
+    /// Encryption key length in bytes: 0 (take the caller's), 16, 24 or 32
+    PbKeyLen(i32) = SRTO_PBKEYLEN;
+    /// Whether unencrypted or wrongly encrypted peers are rejected
+    EnforcedEncryption(bool) = SRTO_ENFORCEDENCRYPTION;
+    /// Maximum sending bandwidth in bytes per second, -1 for infinite or 0 for relative to `InputBw`
+    MaxBw(i64) = SRTO_MAXBW;
+    InputBw(i64) = SRTO_INPUTBW;
+    /// Recovery bandwidth overhead above `InputBw`, as a percentage
+    OheadBw(i32) = SRTO_OHEADBW;
+    /// Receive buffer size in bytes
+    RcvBuf(i32) = SRTO_RCVBUF;
+    /// Flow control window size in packets
+    Fc(i32) = SRTO_FC;
+    Mss(i32) = SRTO_MSS;
+    /// Maximum payload of a single message
+    PayloadSize(i32) = SRTO_PAYLOADSIZE;
+    /// Packet filter configuration, e.g. `fec,cols:10,rows:5`
+    PacketFilter(String) = SRTO_PACKETFILTER;
+    /// Whether packets that arrive too late to be played are dropped
+    TlPktDrop(bool) = SRTO_TLPKTDROP;
+    /// Whether lost packets are periodically reported again
+    NakReport(bool) = SRTO_NAKREPORT;
+    /// Milliseconds without a packet from the peer before the connection is broken
+    PeerIdleTimeo(i32) = SRTO_PEERIDLETIMEO;
+}
+
+/// Something socket options can be set on
+pub trait Configure {
+    fn set_option<O: SocketOption>(&self, value: O::Value) -> Result<(), SrtError>;
+}
+
+impl Configure for crate::Socket {
+    fn set_option<O: SocketOption>(&self, value: O::Value) -> Result<(), SrtError> {
+        self.set::<O>(value)
+    }
+}
+
+impl Configure for crate::PendingSocket {
+    fn set_option<O: SocketOption>(&self, value: O::Value) -> Result<(), SrtError> {
+        self.set::<O>(value)
+    }
+}
+
+impl Configure for crate::Listener {
+    fn set_option<O: SocketOption>(&self, value: O::Value) -> Result<(), SrtError> {
+        self.set::<O>(value)
+    }
+}
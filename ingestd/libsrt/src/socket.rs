@@ -0,0 +1,179 @@
+use libsrt_sys::*;
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+
+use crate::options::{OptionValue, SocketOption};
+use crate::{SrtError, Stats, check};
+
+pub type SocketId = SRTSOCKET;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketStatus {
+    Init,
+    Opened,
+    Listening,
+    Connecting,
+    Connected,
+    Broken,
+    Closing,
+    Closed,
+    NonExistent,
+}
+
+impl SocketStatus {
+    fn from_raw(status: SRT_SOCKSTATUS) -> SocketStatus {
+        match status {
+            SRTS_INIT => SocketStatus::Init,
+            SRTS_OPENED => SocketStatus::Opened,
+            SRTS_LISTENING => SocketStatus::Listening,
+            SRTS_CONNECTING => SocketStatus::Connecting,
+            SRTS_CONNECTED => SocketStatus::Connected,
+            SRTS_BROKEN => SocketStatus::Broken,
+            SRTS_CLOSING => SocketStatus::Closing,
+            SRTS_CLOSED => SocketStatus::Closed,
+            _ => SocketStatus::NonExistent,
+        }
+    }
+}
+
+pub(crate) unsafe fn set_option<O: SocketOption>(id: SRTSOCKET, value: &O::Value) -> Result<(), SrtError> {
+    value.with_raw(|ptr, len| check(srt_setsockflag(id, O::OPTION, ptr, len)))?;
+    Ok(())
+}
+
+pub(crate) unsafe fn get_option<O: SocketOption>(id: SRTSOCKET) -> Result<O::Value, SrtError> {
+    let mut buffer = [0u8; 512];
+    let mut len = buffer.len() as libc::c_int;
+    check(srt_getsockflag(id, O::OPTION, buffer.as_mut_ptr() as *mut libc::c_void, &mut len))?;
+    O::Value::from_raw(&buffer[..len as usize])
+}
+
+/// Converts a socket address filled in by libsrt to a std one
+pub(crate) unsafe fn sockaddr_to_std(addr: *const libc::sockaddr) -> Option<SocketAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    match (*addr).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = &*(addr as *const libc::sockaddr_in);
+            let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::new(ip.into(), u16::from_be(addr.sin_port)))
+        },
+        libc::AF_INET6 => {
+            let addr = &*(addr as *const libc::sockaddr_in6);
+            let ip = std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(std::net::SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), addr.sin6_flowinfo, addr.sin6_scope_id)))
+        },
+        _ => None,
+    }
+}
+
+/// Converts a std socket address to one for passing to libsrt
+pub(crate) fn std_to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::c_int) {
+    let mut storage = unsafe { std::mem::zeroed::<libc::sockaddr_storage>() };
+    let len = match *addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = addr.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            std::mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(addr) => {
+            let sockaddr = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = addr.port().to_be();
+            sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+            sockaddr.sin6_flowinfo = addr.flowinfo();
+            sockaddr.sin6_scope_id = addr.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+    (storage, len as libc::c_int)
+}
+
+/// An SRT socket, which is closed when dropped
+pub struct Socket(SRTSOCKET);
+
+impl Socket {
+    pub fn new() -> Result<Socket, SrtError> {
+        Ok(Socket(check(unsafe { srt_create_socket() })?))
+    }
+
+    /// Takes ownership of a socket id returned from libsrt
+    pub(crate) fn from_raw(id: SRTSOCKET) -> Socket {
+        Socket(id)
+    }
+
+    pub fn id(&self) -> SocketId {
+        self.0
+    }
+
+    pub fn set<O: SocketOption>(&self, value: O::Value) -> Result<(), SrtError> {
+        unsafe { set_option::<O>(self.0, &value) }
+    }
+
+    pub fn get<O: SocketOption>(&self) -> Result<O::Value, SrtError> {
+        unsafe { get_option::<O>(self.0) }
+    }
+
+    pub fn status(&self) -> SocketStatus {
+        SocketStatus::from_raw(unsafe { srt_getsockstate(self.0) })
+    }
+
+    /// Connects to a listener as a caller
+    pub fn connect(&self, addr: SocketAddr) -> Result<(), SrtError> {
+        let (addr, addr_len) = std_to_sockaddr(&addr);
+        check(unsafe { srt_connect(self.0, &addr as *const libc::sockaddr_storage as *const libc::sockaddr, addr_len) })?;
+        Ok(())
+    }
+
+    /// Sends a single message
+    pub fn send_msg(&self, buffer: &[u8]) -> Result<usize, SrtError> {
+        let len = check(unsafe { srt_sendmsg2(self.0, buffer.as_ptr() as *const libc::c_char, buffer.len() as libc::c_int, std::ptr::null_mut()) })?;
+        Ok(len as usize)
+    }
+
+    /// Receives a single message, failing with an error where `is_would_block()` is true if none is ready
+    pub fn recv_msg(&self, buffer: &mut [u8]) -> Result<usize, SrtError> {
+        unsafe {
+            let mut msg_ctrl = MaybeUninit::<SRT_MSGCTRL>::uninit();
+            srt_msgctrl_init(msg_ctrl.as_mut_ptr());
+            let mut msg_ctrl = msg_ctrl.assume_init();
+            let len = check(srt_recvmsg2(self.0, buffer.as_mut_ptr() as *mut libc::c_char, buffer.len() as libc::c_int, &mut msg_ctrl))?;
+            Ok(len as usize)
+        }
+    }
+
+    /// Fetches the socket's statistics, optionally resetting the interval counters
+    pub fn stats(&self, clear: bool) -> Result<Stats, SrtError> {
+        unsafe {
+            let mut perf = MaybeUninit::<CBytePerfMon>::uninit();
+            check(srt_bstats(self.0, perf.as_mut_ptr(), clear as libc::c_int))?;
+            Ok(Stats(perf.assume_init()))
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { srt_close(self.0) };
+    }
+}
+
+/// A socket which is still being accepted, as seen from a listen callback
+pub struct PendingSocket(pub(crate) SRTSOCKET);
+
+impl PendingSocket {
+    pub fn id(&self) -> SocketId {
+        self.0
+    }
+
+    pub fn set<O: SocketOption>(&self, value: O::Value) -> Result<(), SrtError> {
+        unsafe { set_option::<O>(self.0, &value) }
+    }
+
+    pub fn get<O: SocketOption>(&self) -> Result<O::Value, SrtError> {
+        unsafe { get_option::<O>(self.0) }
+    }
+}
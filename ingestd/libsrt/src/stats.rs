@@ -0,0 +1,41 @@
+use libsrt_sys::CBytePerfMon;
+
+/// A snapshot of a socket's statistics
+pub struct Stats(pub(crate) CBytePerfMon);
+
+impl Stats {
+    pub fn packets_received(&self) -> u64 {
+        self.0.pktRecvTotal as u64
+    }
+
+    pub fn packets_lost(&self) -> u64 {
+        self.0.pktRcvLossTotal as u64
+    }
+
+    pub fn packets_dropped(&self) -> u64 {
+        self.0.pktRcvDropTotal as u64
+    }
+
+    /// Retransmitted packets received since the statistics were last cleared, so over the whole connection when
+    /// they're never cleared
+    pub fn packets_retransmitted(&self) -> u64 {
+        self.0.pktRcvRetrans as u64
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.0.byteRecvTotal as u64
+    }
+
+    pub fn rtt_ms(&self) -> f64 {
+        self.0.msRTT
+    }
+
+    pub fn receive_rate_mbps(&self) -> f64 {
+        self.0.mbpsRecvRate
+    }
+
+    /// The raw statistics, for anything not covered above
+    pub fn raw(&self) -> &CBytePerfMon {
+        &self.0
+    }
+}